@@ -0,0 +1,54 @@
+/* The C interface of md5sum-uring, from src/ffi.rs. Link with -lmd5sum_uring. */
+#ifndef MD5SUM_URING_H
+#define MD5SUM_URING_H
+
+#include <stddef.h>
+#include <stdint.h>
+
+#ifdef __cplusplus
+extern "C" {
+#endif
+
+/* md5sum_uring_poll() filled in a result. */
+#define MD5SUM_URING_RESULT 1
+/* No result is ready yet. */
+#define MD5SUM_URING_PENDING 0
+/* Every file has had its result. */
+#define MD5SUM_URING_DONE (-1)
+/* The session or result pointer was null. */
+#define MD5SUM_URING_INVALID (-2)
+
+typedef struct Md5sumUringSession Md5sumUringSession;
+
+/* One file's result. The pointers are owned by the session, and are valid until the next poll
+ * or until the session is freed. */
+typedef struct Md5sumUringResult {
+    /* The path as it was submitted */
+    const char *path;
+    /* The digest, which is empty if the file failed */
+    const uint8_t *digest;
+    size_t digest_len;
+    /* Why the file couldn't be hashed, or NULL if it was */
+    const char *error;
+} Md5sumUringResult;
+
+/* Start hashing `count` files with `algorithm`, like "sha256", or md5 if it's NULL. The paths are
+ * copied. Returns NULL if the algorithm isn't known. */
+Md5sumUringSession *md5sum_uring_submit(const char *const *paths, size_t count,
+                                        const char *algorithm);
+
+/* Get the next result into `result`, blocking until one is ready if `wait` is nonzero. Returns
+ * one of the MD5SUM_URING_ codes above. */
+int md5sum_uring_poll(Md5sumUringSession *session, Md5sumUringResult *result, int wait);
+
+/* Stop starting new files. The files that weren't finished get an error result. */
+void md5sum_uring_cancel(const Md5sumUringSession *session);
+
+/* Cancel the session if it's still running and free it. */
+void md5sum_uring_free(Md5sumUringSession *session);
+
+#ifdef __cplusplus
+}
+#endif
+
+#endif
@@ -0,0 +1,346 @@
+// This module lists the supported hash algorithms and wraps their states in one type, so the
+// strategies can read files the same way whichever algorithm was chosen.
+use std::{
+    fmt,
+    str::FromStr,
+    time::{Duration, Instant},
+};
+
+use blake2::{Blake2b512, Blake2s256};
+use crc::{Crc, CRC_64_ECMA_182, CRC_64_XZ};
+use md5::{
+    digest::{
+        consts::{U16, U32, U4, U8},
+        DynDigest, FixedOutput, FixedOutputReset, HashMarker, Output, OutputSizeUser, Reset,
+        Update,
+    },
+    Digest, Md5,
+};
+use sha2::{Sha256, Sha384, Sha512};
+use sm3::Sm3;
+use xxhash_rust::xxh3;
+
+use crate::wipe_bytes;
+
+/// BLAKE3 only hashes on several threads when given this much data at once, so smaller reads are
+/// collected until there's this much. Below about 128 KiB, threads cost more than they save.
+const BLAKE3_PARALLEL_LEN: usize = 1024 * 1024;
+
+static CRC64_XZ: Crc<u64> = Crc::<u64>::new(&CRC_64_XZ);
+static CRC64_ECMA: Crc<u64> = Crc::<u64>::new(&CRC_64_ECMA_182);
+
+/// A hash algorithm that can be chosen at runtime.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, Default)]
+pub enum HashAlgorithm {
+    #[default]
+    Md5,
+    Sha256,
+    Sha384,
+    Sha512,
+    Blake3,
+    /// BLAKE2b-512, which b2sum prints by default
+    Blake2b,
+    /// BLAKE2s-256
+    Blake2s,
+    /// The Chinese national standard hash of GB/T 32905-2016
+    Sm3,
+    /// The 64-bit XXH3, which is fast but not cryptographic
+    Xxh3,
+    /// The 128-bit XXH3, which is fast but not cryptographic
+    Xxh128,
+    /// The Castagnoli CRC used by iSCSI, ext4 and Btrfs, with SSE 4.2 or ARM CRC instructions if
+    /// the CPU has them
+    Crc32c,
+    /// The CRC64 of the xz file format
+    Crc64Xz,
+    /// The CRC64 of ECMA-182, without the final inversion of xz
+    Crc64Ecma,
+}
+
+impl HashAlgorithm {
+    pub const ALL: &'static [HashAlgorithm] = &[
+        HashAlgorithm::Md5,
+        HashAlgorithm::Sha256,
+        HashAlgorithm::Sha384,
+        HashAlgorithm::Sha512,
+        HashAlgorithm::Blake3,
+        HashAlgorithm::Blake2b,
+        HashAlgorithm::Blake2s,
+        HashAlgorithm::Sm3,
+        HashAlgorithm::Xxh3,
+        HashAlgorithm::Xxh128,
+        HashAlgorithm::Crc32c,
+        HashAlgorithm::Crc64Xz,
+        HashAlgorithm::Crc64Ecma,
+    ];
+
+    /// The name used on the command line.
+    pub fn name(self) -> &'static str {
+        match self {
+            HashAlgorithm::Md5 => "md5",
+            HashAlgorithm::Sha256 => "sha256",
+            HashAlgorithm::Sha384 => "sha384",
+            HashAlgorithm::Sha512 => "sha512",
+            HashAlgorithm::Blake3 => "blake3",
+            HashAlgorithm::Blake2b => "blake2b",
+            HashAlgorithm::Blake2s => "blake2s",
+            HashAlgorithm::Sm3 => "sm3",
+            HashAlgorithm::Xxh3 => "xxh3",
+            HashAlgorithm::Xxh128 => "xxh128",
+            HashAlgorithm::Crc32c => "crc32c",
+            HashAlgorithm::Crc64Xz => "crc64-xz",
+            HashAlgorithm::Crc64Ecma => "crc64-ecma",
+        }
+    }
+
+    /// A new digest of this algorithm. Digests that are integers, like XXH3, are output
+    /// big-endian as xxhsum prints them.
+    pub fn new_dyn(self) -> Box<dyn DynDigest + Send> {
+        match self {
+            HashAlgorithm::Md5 => Box::new(Md5::new()),
+            HashAlgorithm::Sha256 => Box::new(Sha256::new()),
+            HashAlgorithm::Sha384 => Box::new(Sha384::new()),
+            HashAlgorithm::Sha512 => Box::new(Sha512::new()),
+            HashAlgorithm::Blake3 => Box::new(Blake3::default()),
+            HashAlgorithm::Blake2b => Box::new(Blake2b512::new()),
+            HashAlgorithm::Blake2s => Box::new(Blake2s256::new()),
+            HashAlgorithm::Sm3 => Box::new(Sm3::new()),
+            HashAlgorithm::Xxh3 => Box::new(Xxh3::default()),
+            HashAlgorithm::Xxh128 => Box::new(Xxh128::default()),
+            HashAlgorithm::Crc32c => Box::new(Crc32c::default()),
+            HashAlgorithm::Crc64Xz => Box::new(Crc64Xz::default()),
+            HashAlgorithm::Crc64Ecma => Box::new(Crc64Ecma::default()),
+        }
+    }
+
+    /// The length of a digest in bytes.
+    pub fn output_len(self) -> usize {
+        self.new_dyn().output_size()
+    }
+}
+
+impl fmt::Display for HashAlgorithm {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(self.name())
+    }
+}
+
+impl FromStr for HashAlgorithm {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        HashAlgorithm::ALL
+            .iter()
+            .copied()
+            .find(|algorithm| algorithm.name().eq_ignore_ascii_case(s))
+            .ok_or_else(|| {
+                let names: Vec<_> = HashAlgorithm::ALL.iter().map(|a| a.name()).collect();
+                format!("unknown algorithm {:?}; use one of {}", s, names.join(", "))
+            })
+    }
+}
+
+/// The state of a digest that's partway through a file. The algorithm is chosen at runtime, so
+/// the strategies are compiled once for every algorithm instead of once per algorithm.
+pub struct Hasher {
+    algorithm: HashAlgorithm,
+    ctx: Box<dyn DynDigest + Send>,
+    /// How many bytes have been hashed
+    bytes: u64,
+    started: Instant,
+}
+
+impl Hasher {
+    pub fn new(algorithm: HashAlgorithm) -> Hasher {
+        Hasher {
+            algorithm,
+            ctx: algorithm.new_dyn(),
+            bytes: 0,
+            started: Instant::now(),
+        }
+    }
+
+    pub fn algorithm(&self) -> HashAlgorithm {
+        self.algorithm
+    }
+
+    pub fn update(&mut self, data: impl AsRef<[u8]>) {
+        self.bytes += data.as_ref().len() as u64;
+        self.ctx.update(data.as_ref());
+    }
+
+    /// How many bytes have been hashed so far.
+    pub fn bytes(&self) -> u64 {
+        self.bytes
+    }
+
+    /// How long it's been since the hasher was made, which the strategies do as they start on a
+    /// file.
+    pub fn elapsed(&self) -> Duration {
+        self.started.elapsed()
+    }
+
+    pub fn finalize(self) -> Vec<u8> {
+        self.ctx.finalize().into_vec()
+    }
+
+    /// Return the digest and start over, ready for more data.
+    pub fn finalize_reset(&mut self) -> Vec<u8> {
+        self.bytes = 0;
+        self.ctx.finalize_reset().into_vec()
+    }
+
+    pub fn into_dyn(self) -> Box<dyn DynDigest + Send> {
+        self.ctx
+    }
+}
+
+/// This lets the strategies, which work with any digest, use a runtime choice of algorithm.
+impl Update for Hasher {
+    fn update(&mut self, data: &[u8]) {
+        Hasher::update(self, data);
+    }
+}
+
+/// Implement the traits that make a `Digest`, and so a `DynDigest`, out of a type with an update
+/// method and a way to write its output.
+macro_rules! impl_digest {
+    ($ty:ty, $output_size:ty, |$ctx:pat, $out:ident| $finalize:expr) => {
+        impl HashMarker for $ty {}
+
+        impl OutputSizeUser for $ty {
+            type OutputSize = $output_size;
+        }
+
+        impl FixedOutput for $ty {
+            fn finalize_into(self, $out: &mut Output<Self>) {
+                let $ctx = self;
+                $finalize
+            }
+        }
+
+        impl Reset for $ty {
+            fn reset(&mut self) {
+                *self = Default::default();
+            }
+        }
+
+        impl FixedOutputReset for $ty {
+            fn finalize_into_reset(&mut self, out: &mut Output<Self>) {
+                FixedOutput::finalize_into(std::mem::take(self), out);
+            }
+        }
+    };
+}
+
+/// A BLAKE3 hasher that spreads the work across the rayon thread pool. The reads of the io_uring
+/// strategies are too small for that on their own, so they're collected first.
+#[derive(Clone, Default)]
+pub struct Blake3 {
+    ctx: blake3::Hasher,
+    pending: Vec<u8>,
+}
+
+impl Blake3 {
+    fn flush(&mut self) {
+        self.ctx.update_rayon(&self.pending);
+        wipe_bytes(&mut self.pending);
+        self.pending.clear();
+    }
+}
+
+impl Update for Blake3 {
+    fn update(&mut self, data: &[u8]) {
+        if self.pending.is_empty() && data.len() >= BLAKE3_PARALLEL_LEN {
+            // Large inputs, like whole mapped files, don't need to be copied:
+            self.ctx.update_rayon(data);
+            return;
+        }
+        self.pending.extend_from_slice(data);
+        if self.pending.len() >= BLAKE3_PARALLEL_LEN {
+            self.flush();
+        }
+    }
+}
+
+impl_digest!(Blake3, U32, |mut ctx, out| {
+    ctx.flush();
+    out.copy_from_slice(ctx.ctx.finalize().as_bytes());
+});
+
+/// The 64-bit XXH3.
+#[derive(Clone, Default)]
+pub struct Xxh3(xxh3::Xxh3);
+
+impl Update for Xxh3 {
+    fn update(&mut self, data: &[u8]) {
+        self.0.update(data);
+    }
+}
+
+impl_digest!(Xxh3, U8, |ctx, out| out
+    .copy_from_slice(&ctx.0.digest().to_be_bytes()));
+
+/// The 128-bit XXH3.
+#[derive(Clone, Default)]
+pub struct Xxh128(xxh3::Xxh3);
+
+impl Update for Xxh128 {
+    fn update(&mut self, data: &[u8]) {
+        self.0.update(data);
+    }
+}
+
+impl_digest!(Xxh128, U16, |ctx, out| out
+    .copy_from_slice(&ctx.0.digest128().to_be_bytes()));
+
+/// CRC32C, the Castagnoli CRC.
+#[derive(Clone, Default)]
+pub struct Crc32c(u32);
+
+impl Update for Crc32c {
+    fn update(&mut self, data: &[u8]) {
+        self.0 = crc32c::crc32c_append(self.0, data);
+    }
+}
+
+impl_digest!(Crc32c, U4, |ctx, out| out
+    .copy_from_slice(&ctx.0.to_be_bytes()));
+
+/// CRC64 as xz computes it.
+#[derive(Clone)]
+pub struct Crc64Xz(crc::Digest<'static, u64>);
+
+impl Default for Crc64Xz {
+    fn default() -> Self {
+        Crc64Xz(CRC64_XZ.digest())
+    }
+}
+
+impl Update for Crc64Xz {
+    fn update(&mut self, data: &[u8]) {
+        self.0.update(data);
+    }
+}
+
+impl_digest!(Crc64Xz, U8, |ctx, out| out
+    .copy_from_slice(&ctx.0.finalize().to_be_bytes()));
+
+/// CRC64 as ECMA-182 defines it.
+#[derive(Clone)]
+pub struct Crc64Ecma(crc::Digest<'static, u64>);
+
+impl Default for Crc64Ecma {
+    fn default() -> Self {
+        Crc64Ecma(CRC64_ECMA.digest())
+    }
+}
+
+impl Update for Crc64Ecma {
+    fn update(&mut self, data: &[u8]) {
+        self.0.update(data);
+    }
+}
+
+impl_digest!(Crc64Ecma, U8, |ctx, out| out
+    .copy_from_slice(&ctx.0.finalize().to_be_bytes()));
@@ -0,0 +1,311 @@
+// This module hashes the members of tar archives as the archive is read, so backups can be
+// verified without unpacking them.
+use std::{
+    cmp::min,
+    fs::File,
+    io, mem,
+    path::{Path, PathBuf},
+    str::FromStr,
+};
+
+use anyhow::{bail, Result};
+#[allow(unused_imports)]
+use log::{debug, error, info, trace, warn};
+
+use crate::*;
+
+const BLOCK_LEN: usize = 512;
+
+/// Names and pax headers longer than this are assumed to be corrupt.
+const MAX_METADATA_LEN: u64 = 1024 * 1024;
+
+/// The kinds of archive whose members can be hashed.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ArchiveFormat {
+    Tar,
+}
+
+impl FromStr for ArchiveFormat {
+    type Err = String;
+
+    fn from_str(s: &str) -> std::result::Result<Self, Self::Err> {
+        match s {
+            "tar" => Ok(ArchiveFormat::Tar),
+            _ => Err(format!("unknown archive format {:?}; use \"tar\"", s)),
+        }
+    }
+}
+
+/// The name printed for a member of an archive, like `backup.tar//etc/hosts`.
+pub fn member_path(archive: &Path, member: &Path) -> PathBuf {
+    let mut path = archive.as_os_str().as_encoded_bytes().to_vec();
+    path.extend_from_slice(b"//");
+    path.extend_from_slice(member.as_os_str().as_encoded_bytes());
+    path_from_bytes(&path)
+}
+
+/// Hash each regular file in the tar archive at `path` and pass its name and digest to `emit`,
+/// in the order they're stored. The archive is read once, front to back; FIFOs and `-`, for
+/// stdin, work too. Compressed archives aren't supported.
+pub fn hash_tar<F>(path: &Path, o_direct: bool, algorithm: HashAlgorithm, emit: F) -> Result<()>
+where
+    F: FnMut(PathBuf, Vec<u8>) -> Result<()>,
+{
+    let mut parser = TarParser::new(algorithm, emit);
+    if input::is_stream(path) {
+        let consume = |data: &[u8]| {
+            parser.feed(data)?;
+            Ok(!parser.ended)
+        };
+        if input::is_stdin(path) {
+            read_stream(&io::stdin(), consume)?;
+        } else {
+            read_stream(&File::open(path)?, consume)?;
+        }
+    } else {
+        let fd = open(path, o_direct)?;
+        let file_len = fd.metadata()?.len();
+        read_sequentially(&fd, 0, file_len, |data| {
+            parser.feed(data)?;
+            Ok(!parser.ended)
+        })?;
+    }
+    parser.finish()
+}
+
+/// What the data after a header is.
+enum Member {
+    File {
+        name: PathBuf,
+        ctx: Hasher,
+    },
+    /// A GNU long name for the next member
+    LongName(Vec<u8>),
+    /// Pax extended attributes for the next member
+    Pax(Vec<u8>),
+    Skip,
+}
+
+/// Parses a tar archive given a chunk at a time, hashing the data of each regular file.
+struct TarParser<F> {
+    algorithm: HashAlgorithm,
+    emit: F,
+    /// A header that's only partly been given
+    header: Vec<u8>,
+    /// The member being read, and how many bytes of it are left
+    member: Option<(Member, u64)>,
+    /// Bytes to skip to reach the next header
+    padding: u64,
+    /// The name given by a GNU long name or pax header, for the next member
+    next_name: Option<Vec<u8>>,
+    /// Set at the zero block that ends the archive
+    ended: bool,
+}
+
+impl<F> TarParser<F>
+where
+    F: FnMut(PathBuf, Vec<u8>) -> Result<()>,
+{
+    fn new(algorithm: HashAlgorithm, emit: F) -> TarParser<F> {
+        TarParser {
+            algorithm,
+            emit,
+            header: Vec::with_capacity(BLOCK_LEN),
+            member: None,
+            padding: 0,
+            next_name: None,
+            ended: false,
+        }
+    }
+
+    fn feed(&mut self, mut data: &[u8]) -> Result<()> {
+        while !data.is_empty() && !self.ended {
+            if let Some((member, remaining)) = &mut self.member {
+                let (chunk, rest) = data.split_at(min(*remaining, data.len() as u64) as usize);
+                match member {
+                    Member::File { ctx, .. } => {
+                        ctx.update(chunk);
+                        progress::add_bytes(chunk.len() as u64);
+                    }
+                    Member::LongName(buf) | Member::Pax(buf) => buf.extend_from_slice(chunk),
+                    Member::Skip => {}
+                }
+                *remaining -= chunk.len() as u64;
+                data = rest;
+                if *remaining == 0 {
+                    self.finish_member()?;
+                }
+            } else if self.padding > 0 {
+                // The padding after the member's data:
+                let skip = min(self.padding, data.len() as u64);
+                self.padding -= skip;
+                data = &data[skip as usize..];
+            } else {
+                let needed = min(BLOCK_LEN - self.header.len(), data.len());
+                self.header.extend_from_slice(&data[..needed]);
+                data = &data[needed..];
+                if self.header.len() == BLOCK_LEN {
+                    let header = mem::replace(&mut self.header, Vec::with_capacity(BLOCK_LEN));
+                    self.start_member(&header)?;
+                }
+            }
+        }
+        Ok(())
+    }
+
+    fn start_member(&mut self, header: &[u8]) -> Result<()> {
+        if header.iter().all(|&byte| byte == 0) {
+            self.ended = true;
+            return Ok(());
+        }
+        if !checksum_matches(header) {
+            bail!("Not a tar archive, or it's corrupt (a header checksum is wrong).");
+        }
+
+        let size = parse_size(&header[124..136])?;
+        self.padding = (BLOCK_LEN as u64 - size % BLOCK_LEN as u64) % BLOCK_LEN as u64;
+        let typeflag = header[156];
+        let member = match typeflag {
+            b'L' | b'x' if size > MAX_METADATA_LEN => {
+                bail!("A tar header is {} bytes long, which is too long.", size)
+            }
+            b'L' => Member::LongName(Vec::new()),
+            b'x' => Member::Pax(Vec::new()),
+            b'0' | b'\0' | b'7' => {
+                let name = self.next_name.take().unwrap_or_else(|| header_name(header));
+                Member::File {
+                    name: path_from_bytes(&name),
+                    ctx: Hasher::new(self.algorithm),
+                }
+            }
+            _ => {
+                // Directories, links and the like have no data to hash, and their names don't
+                // carry over to the next member:
+                trace!("Skipping a tar member of type {:?}", typeflag as char);
+                self.next_name = None;
+                Member::Skip
+            }
+        };
+        self.member = Some((member, size));
+        if size == 0 {
+            self.finish_member()?;
+        }
+        Ok(())
+    }
+
+    fn finish_member(&mut self) -> Result<()> {
+        let Some((member, _)) = self.member.take() else {
+            return Ok(());
+        };
+        match member {
+            Member::File { name, ctx } => (self.emit)(name, ctx.finalize())?,
+            Member::LongName(mut name) => {
+                let len = name
+                    .iter()
+                    .position(|&byte| byte == 0)
+                    .unwrap_or(name.len());
+                name.truncate(len);
+                self.next_name = Some(name);
+            }
+            Member::Pax(records) => {
+                if let Some(path) = pax_path(&records) {
+                    self.next_name = Some(path);
+                }
+            }
+            Member::Skip => {}
+        }
+        Ok(())
+    }
+
+    fn finish(self) -> Result<()> {
+        if !self.header.is_empty() || self.member.is_some() {
+            bail!("The tar archive is truncated.");
+        }
+        Ok(())
+    }
+}
+
+/// Whether the checksum field of a header matches the header, which is summed with the field
+/// itself taken as spaces.
+fn checksum_matches(header: &[u8]) -> bool {
+    let Ok(expected) = parse_octal(&header[148..156]) else {
+        return false;
+    };
+    let sum: u64 = header
+        .iter()
+        .enumerate()
+        .map(|(i, &byte)| if (148..156).contains(&i) { b' ' } else { byte } as u64)
+        .sum();
+    sum == expected
+}
+
+/// The member name of a header, with the ustar prefix if there is one.
+fn header_name(header: &[u8]) -> Vec<u8> {
+    let field = |bytes: &[u8]| -> Vec<u8> {
+        let len = bytes
+            .iter()
+            .position(|&byte| byte == 0)
+            .unwrap_or(bytes.len());
+        bytes[..len].to_vec()
+    };
+    let name = field(&header[0..100]);
+    if &header[257..262] == b"ustar" {
+        let mut prefix = field(&header[345..500]);
+        if !prefix.is_empty() {
+            prefix.push(b'/');
+            prefix.extend_from_slice(&name);
+            return prefix;
+        }
+    }
+    name
+}
+
+/// A size field, which is octal, or big-endian binary if the high bit is set, as GNU tar writes
+/// sizes of 8 GiB and more.
+fn parse_size(field: &[u8]) -> Result<u64> {
+    if field[0] & 0x80 != 0 {
+        let mut size: u64 = (field[0] & 0x7f) as u64;
+        for &byte in &field[1..] {
+            let Some(shifted) = size.checked_mul(256) else {
+                bail!("A tar member is too large.");
+            };
+            size = shifted | byte as u64;
+        }
+        return Ok(size);
+    }
+    parse_octal(field)
+}
+
+fn parse_octal(field: &[u8]) -> Result<u64> {
+    let digits: Vec<u8> = field
+        .iter()
+        .copied()
+        .skip_while(|&byte| byte == b' ')
+        .take_while(|&byte| byte != 0 && byte != b' ')
+        .collect();
+    let digits = std::str::from_utf8(&digits)?;
+    if digits.is_empty() {
+        return Ok(0);
+    }
+    Ok(u64::from_str_radix(digits, 8)?)
+}
+
+/// The `path` record of pax extended attributes, which are lines like `23 path=some/file\n`
+/// that start with their own length.
+fn pax_path(mut records: &[u8]) -> Option<Vec<u8>> {
+    let mut path = None;
+    while !records.is_empty() {
+        let space = records.iter().position(|&byte| byte == b' ')?;
+        let len: usize = std::str::from_utf8(&records[..space]).ok()?.parse().ok()?;
+        if len <= space || len > records.len() {
+            return None;
+        }
+        let record = &records[space + 1..len];
+        let record = record.strip_suffix(b"\n").unwrap_or(record);
+        if let Some(value) = record.strip_prefix(b"path=") {
+            path = Some(value.to_vec());
+        }
+        records = &records[len..];
+    }
+    path
+}
@@ -0,0 +1,93 @@
+// This module lets async code, like a Tokio service, hash files without blocking its executor. A
+// strategy runs on its own thread as usual, and its results are handed to a `Stream` that wakes
+// the task polling it.
+use std::{
+    collections::{HashSet, VecDeque},
+    path::PathBuf,
+    pin::Pin,
+    sync::{mpsc::channel, Arc, Mutex},
+    task::{Context, Poll, Waker},
+    thread,
+};
+
+use anyhow::anyhow;
+use futures_core::Stream;
+#[allow(unused_imports)]
+use log::{debug, error, info, trace, warn};
+
+use crate::*;
+
+/// The results that have arrived and not been polled yet, and whether more are coming.
+#[derive(Default)]
+struct Shared {
+    results: VecDeque<ChecksumResult>,
+    done: bool,
+    waker: Option<Waker>,
+}
+
+impl Shared {
+    fn push(shared: &Mutex<Shared>, result: ChecksumResult) {
+        let mut shared = shared.lock().unwrap();
+        shared.results.push_back(result);
+        if let Some(waker) = shared.waker.take() {
+            waker.wake();
+        }
+    }
+}
+
+/// The digest of each file given to `checksum_stream()`, or the error that stopped it from being
+/// read, in the order they're finished.
+pub struct ChecksumStream {
+    shared: Arc<Mutex<Shared>>,
+}
+
+/// Hash `paths` as `config` says on another thread, and return a stream of their results.
+/// Each path gets exactly one result: if the strategy can't run at all, as when io_uring is
+/// blocked, the files it didn't finish get its error. Results are kept until they're polled, so
+/// a slow consumer doesn't slow the reads down. Dropping the stream doesn't stop the thread, but
+/// the results are dropped as they arrive.
+pub fn checksum_stream(paths: Vec<PathBuf>, config: Config) -> ChecksumStream {
+    let shared: Arc<Mutex<Shared>> = Default::default();
+    let thread_shared = shared.clone();
+    thread::spawn(move || {
+        let shared = thread_shared;
+        let (tx, rx) = channel();
+        let mut unfinished: HashSet<PathBuf> = paths.iter().cloned().collect();
+        let handle = thread::spawn(move || get_checksums(paths, tx, &config));
+        for result in rx {
+            unfinished.remove(&result.path);
+            Shared::push(&shared, result);
+        }
+        let result = handle
+            .join()
+            .unwrap_or_else(|_| Err(anyhow!("A checksum thread panicked.")));
+        if let Err(err) = result {
+            debug!("The strategy stopped early: {:#}", err);
+            for path in unfinished {
+                Shared::push(&shared, (path, Err(anyhow!("{:#}", err))).into());
+            }
+        }
+        let mut shared = shared.lock().unwrap();
+        shared.done = true;
+        if let Some(waker) = shared.waker.take() {
+            waker.wake();
+        }
+    });
+    ChecksumStream { shared }
+}
+
+impl Stream for ChecksumStream {
+    type Item = ChecksumResult;
+
+    fn poll_next(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<Self::Item>> {
+        let mut shared = self.shared.lock().unwrap();
+        if let Some(result) = shared.results.pop_front() {
+            return Poll::Ready(Some(result));
+        }
+        if shared.done {
+            return Poll::Ready(None);
+        }
+        shared.waker = Some(cx.waker().clone());
+        Poll::Pending
+    }
+}
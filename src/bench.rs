@@ -0,0 +1,400 @@
+// This module times every strategy on the same files and reports the results for people or for
+// tools that track performance over time.
+use std::{
+    fs,
+    path::{Path, PathBuf},
+    str::FromStr,
+    sync::mpsc::channel,
+    thread,
+    time::{Duration, Instant},
+};
+
+use anyhow::{anyhow, Result};
+#[allow(unused_imports)]
+use log::{debug, error, info, trace, warn};
+use serde::Serialize;
+use serde_json::json;
+
+use crate::*;
+
+/// How benchmark results are printed.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum BenchFormat {
+    Table,
+    Json,
+}
+
+impl FromStr for BenchFormat {
+    type Err = String;
+
+    fn from_str(s: &str) -> std::result::Result<Self, Self::Err> {
+        match s {
+            "table" => Ok(BenchFormat::Table),
+            "json" => Ok(BenchFormat::Json),
+            _ => Err(format!("unknown format {:?}; use \"table\" or \"json\"", s)),
+        }
+    }
+}
+
+/// Timing percentiles over all iterations, in nanoseconds.
+#[derive(Debug, Serialize)]
+pub struct Latency {
+    pub min: u64,
+    pub p50: u64,
+    pub p90: u64,
+    pub p99: u64,
+    pub max: u64,
+    pub mean: u64,
+}
+
+/// The results of running one strategy over the whole file set several times.
+#[derive(Debug, Serialize)]
+pub struct StrategyResult {
+    pub strategy: &'static str,
+    /// Set if the strategy couldn't run at all, e.g. for lack of privileges
+    pub error: Option<String>,
+    /// Files that failed to hash in any iteration
+    pub file_errors: usize,
+    /// Bytes per second at the median time
+    pub throughput: f64,
+    /// Wall time per iteration
+    pub latency_ns: Option<Latency>,
+    /// Mean user CPU time per iteration
+    pub user_cpu_ns: u64,
+    /// Mean system CPU time per iteration
+    pub system_cpu_ns: u64,
+    /// Mean read system calls per iteration, like read() and pread(). Reads through a ring
+    /// aren't system calls, so they aren't counted here.
+    pub read_syscalls: u64,
+    /// Mean reads submitted to rings per iteration
+    pub ring_reads: u64,
+    /// Wall time of each iteration
+    pub samples_ns: Vec<u64>,
+}
+
+/// The results for every strategy.
+#[derive(Debug, Serialize)]
+pub struct BenchReport {
+    pub files: usize,
+    pub bytes: u64,
+    pub iterations: usize,
+    /// Whether the files were dropped from the page cache before each iteration
+    pub drop_caches: bool,
+    pub o_direct: bool,
+    pub algorithm: &'static str,
+    pub results: Vec<StrategyResult>,
+}
+
+/// Run the files through every strategy `iterations` times, with the other settings of `config`.
+/// If `drop_caches` is set, the files are dropped from the page cache before each iteration, so
+/// every iteration reads from storage.
+pub fn run(
+    files: Vec<PathBuf>,
+    iterations: usize,
+    drop_caches: bool,
+    config: &Config,
+) -> Result<BenchReport> {
+    let bytes = files
+        .iter()
+        .filter_map(|path| fs::metadata(path).ok())
+        .map(|metadata| metadata.len())
+        .sum();
+
+    let mut results = Vec::new();
+    for &strategy in STRATEGIES {
+        if config.o_direct && strategy.name() == "no-uring" {
+            // This strategy doesn't support O_DIRECT.
+            continue;
+        }
+        info!("Benchmarking {}", strategy.name());
+        results.push(bench_strategy(
+            &config.clone().with_strategy(strategy),
+            &files,
+            iterations,
+            drop_caches,
+            bytes,
+        )?);
+    }
+
+    Ok(BenchReport {
+        files: files.len(),
+        bytes,
+        iterations,
+        drop_caches,
+        o_direct: config.o_direct,
+        algorithm: config.algorithm.name(),
+        results,
+    })
+}
+
+fn bench_strategy(
+    config: &Config,
+    files: &[PathBuf],
+    iterations: usize,
+    drop_caches: bool,
+    bytes: u64,
+) -> Result<StrategyResult> {
+    let mut result = StrategyResult {
+        strategy: config.strategy.name(),
+        error: None,
+        file_errors: 0,
+        throughput: 0.0,
+        latency_ns: None,
+        user_cpu_ns: 0,
+        system_cpu_ns: 0,
+        read_syscalls: 0,
+        ring_reads: 0,
+        samples_ns: Vec::new(),
+    };
+    let mut user_cpu = Duration::ZERO;
+    let mut system_cpu = Duration::ZERO;
+    let mut read_syscalls = 0;
+    let mut ring_reads = 0;
+
+    for _ in 0..iterations {
+        if drop_caches {
+            evict(files)?;
+        }
+        let cpu_before = cpu_times();
+        let syscalls_before = read_syscalls_so_far();
+        let reads_before = progress::reads_submitted();
+        let start = Instant::now();
+        match run_once(files.to_vec(), config) {
+            Ok(file_errors) => result.file_errors += file_errors,
+            Err(err) => {
+                result.error = Some(err.to_string());
+                result.samples_ns.clear();
+                return Ok(result);
+            }
+        }
+        result.samples_ns.push(start.elapsed().as_nanos() as u64);
+        let cpu_after = cpu_times();
+        user_cpu += cpu_after.0.saturating_sub(cpu_before.0);
+        system_cpu += cpu_after.1.saturating_sub(cpu_before.1);
+        read_syscalls += read_syscalls_so_far().saturating_sub(syscalls_before);
+        ring_reads += progress::reads_submitted() - reads_before;
+    }
+
+    if let Some(latency) = latency(&result.samples_ns) {
+        if latency.p50 > 0 {
+            result.throughput = bytes as f64 / (latency.p50 as f64 / 1e9);
+        }
+        result.latency_ns = Some(latency);
+    }
+    let iterations = iterations.max(1) as u32;
+    result.user_cpu_ns = (user_cpu / iterations).as_nanos() as u64;
+    result.system_cpu_ns = (system_cpu / iterations).as_nanos() as u64;
+    result.read_syscalls = read_syscalls / iterations as u64;
+    result.ring_reads = ring_reads / iterations as u64;
+    Ok(result)
+}
+
+/// Drop the files from the page cache. Pages that haven't been written back yet stay cached.
+#[cfg(target_os = "linux")]
+fn evict(files: &[PathBuf]) -> Result<()> {
+    for path in files {
+        // Files that can't be opened fail when they're hashed, and are counted there:
+        let Ok(file) = fs::File::open(path) else {
+            continue;
+        };
+        ring::fadvise(&file, libc::POSIX_FADV_DONTNEED)?;
+    }
+    Ok(())
+}
+
+/// Only Linux can be asked to drop a file from the page cache.
+#[cfg(not(target_os = "linux"))]
+fn evict(_files: &[PathBuf]) -> Result<()> {
+    Err(anyhow!("Dropping files from the page cache needs Linux."))
+}
+
+/// How many read system calls this process has made so far, from /proc/self/io. This is 0 on
+/// systems without it.
+fn read_syscalls_so_far() -> u64 {
+    fs::read_to_string("/proc/self/io")
+        .ok()
+        .and_then(|io| {
+            io.lines()
+                .find_map(|line| line.strip_prefix("syscr:"))?
+                .trim()
+                .parse()
+                .ok()
+        })
+        .unwrap_or(0)
+}
+
+/// Hash every file once. Returns how many files failed.
+fn run_once(files: Vec<PathBuf>, config: &Config) -> Result<usize> {
+    let (tx, rx) = channel();
+    let config = config.clone();
+    let handle = thread::spawn(move || get_checksums(files, tx, &config));
+    let mut file_errors = 0;
+    for result in rx {
+        if let Some(err) = result.error {
+            debug!("{}: {}", result.path.display(), err);
+            file_errors += 1;
+        }
+    }
+    handle
+        .join()
+        .map_err(|_| anyhow!("the strategy panicked"))??;
+    Ok(file_errors)
+}
+
+/// Nearest-rank percentiles of the samples.
+fn latency(samples: &[u64]) -> Option<Latency> {
+    if samples.is_empty() {
+        return None;
+    }
+    let mut sorted = samples.to_vec();
+    sorted.sort_unstable();
+    let percentile = |p: usize| sorted[((sorted.len() * p).div_ceil(100)).max(1) - 1];
+    Some(Latency {
+        min: sorted[0],
+        p50: percentile(50),
+        p90: percentile(90),
+        p99: percentile(99),
+        max: sorted[sorted.len() - 1],
+        mean: sorted.iter().sum::<u64>() / sorted.len() as u64,
+    })
+}
+
+/// User and system CPU time used by this process so far, across all threads.
+#[cfg(unix)]
+fn cpu_times() -> (Duration, Duration) {
+    let mut usage: libc::rusage = unsafe { std::mem::zeroed() };
+    // unsafe: the kernel only writes to the struct we pass in:
+    if unsafe { libc::getrusage(libc::RUSAGE_SELF, &mut usage) } != 0 {
+        return (Duration::ZERO, Duration::ZERO);
+    }
+    let to_duration =
+        |time: libc::timeval| Duration::new(time.tv_sec as u64, time.tv_usec as u32 * 1000);
+    (to_duration(usage.ru_utime), to_duration(usage.ru_stime))
+}
+
+/// CPU time isn't measured elsewhere than Unix, so it's reported as zero.
+#[cfg(not(unix))]
+fn cpu_times() -> (Duration, Duration) {
+    (Duration::ZERO, Duration::ZERO)
+}
+
+/// Print the report in the given format.
+pub fn print_report(report: &BenchReport, format: BenchFormat) -> Result<()> {
+    match format {
+        BenchFormat::Json => println!("{}", serde_json::to_string_pretty(report)?),
+        BenchFormat::Table => {
+            println!(
+                "{} files, {} bytes, {} iterations, {}{}",
+                report.files,
+                report.bytes,
+                report.iterations,
+                report.algorithm,
+                if report.drop_caches { ", uncached" } else { "" }
+            );
+            println!(
+                "{:<16} {:>10} {:>10} {:>10} {:>10} {:>10} {:>10} {:>10}",
+                "strategy",
+                "MB/s",
+                "p50 ms",
+                "p99 ms",
+                "user ms",
+                "sys ms",
+                "read()s",
+                "ring reads"
+            );
+            for result in &report.results {
+                match (&result.error, &result.latency_ns) {
+                    (Some(err), _) => println!("{:<16} failed: {}", result.strategy, err),
+                    (None, Some(latency)) => println!(
+                        "{:<16} {:>10.1} {:>10.2} {:>10.2} {:>10.2} {:>10.2} {:>10} {:>10}",
+                        result.strategy,
+                        result.throughput / 1e6,
+                        latency.p50 as f64 / 1e6,
+                        latency.p99 as f64 / 1e6,
+                        result.user_cpu_ns as f64 / 1e6,
+                        result.system_cpu_ns as f64 / 1e6,
+                        result.read_syscalls,
+                        result.ring_reads,
+                    ),
+                    (None, None) => println!("{:<16} no samples", result.strategy),
+                }
+            }
+        }
+    }
+    Ok(())
+}
+
+/// Write the results in the directory layout criterion uses (`<dir>/<group>/<strategy>/new/`),
+/// so tools that compare criterion baselines can track them.
+pub fn write_criterion(report: &BenchReport, dir: &Path) -> Result<()> {
+    // Each algorithm gets its own group, since their timings aren't comparable:
+    let group = &format!("md5sum-uring-{}", report.algorithm);
+    for result in &report.results {
+        let Some(latency) = &result.latency_ns else {
+            continue;
+        };
+        let id = format!("{}/{}", group, result.strategy);
+        let out_dir = dir.join(group).join(result.strategy).join("new");
+        fs::create_dir_all(&out_dir)?;
+
+        let benchmark = json!({
+            "group_id": group,
+            "function_id": result.strategy,
+            "value_str": null,
+            "throughput": { "Bytes": report.bytes },
+            "full_id": id,
+            "directory_name": id,
+            "title": id,
+        });
+        fs::write(
+            out_dir.join("benchmark.json"),
+            serde_json::to_string(&benchmark)?,
+        )?;
+
+        // Each sample is a single iteration:
+        let sample = json!({
+            "sampling_mode": "Flat",
+            "iters": vec![1.0; result.samples_ns.len()],
+            "times": result.samples_ns.iter().map(|&ns| ns as f64).collect::<Vec<_>>(),
+        });
+        fs::write(out_dir.join("sample.json"), serde_json::to_string(&sample)?)?;
+
+        let samples: Vec<f64> = result.samples_ns.iter().map(|&ns| ns as f64).collect();
+        let mean = latency.mean as f64;
+        let std_dev = if samples.len() > 1 {
+            (samples.iter().map(|x| (x - mean).powi(2)).sum::<f64>() / (samples.len() - 1) as f64)
+                .sqrt()
+        } else {
+            0.0
+        };
+        let standard_error = std_dev / (samples.len() as f64).sqrt();
+        let median = latency.p50 as f64;
+        let mut deviations: Vec<f64> = samples.iter().map(|x| (x - median).abs()).collect();
+        deviations.sort_by(f64::total_cmp);
+        let median_abs_dev = deviations[deviations.len() / 2];
+
+        let estimate = |point: f64, error: f64| {
+            json!({
+                "confidence_interval": {
+                    "confidence_level": 0.95,
+                    "lower_bound": point - 1.96 * error,
+                    "upper_bound": point + 1.96 * error,
+                },
+                "point_estimate": point,
+                "standard_error": error,
+            })
+        };
+        let estimates = json!({
+            "mean": estimate(mean, standard_error),
+            "median": estimate(median, standard_error),
+            "median_abs_dev": estimate(median_abs_dev, 0.0),
+            "slope": null,
+            "std_dev": estimate(std_dev, 0.0),
+        });
+        fs::write(
+            out_dir.join("estimates.json"),
+            serde_json::to_string(&estimates)?,
+        )?;
+    }
+    Ok(())
+}
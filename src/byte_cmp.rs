@@ -0,0 +1,240 @@
+// This module compares two files byte by byte, like cmp. Equal digests only say that files are
+// very likely the same, and different ones don't say where they differ, so this reads both files
+// side by side, a chunk of each at a time with both reads in flight together, and finds the first
+// byte that differs and how many chunks do.
+use std::{cmp::min, fs::File, path::Path};
+
+use anyhow::{anyhow, Result};
+#[allow(unused_imports)]
+use log::{debug, error, info, trace, warn};
+
+use crate::{manifest::display_path, *};
+
+/// What comparing two files found.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct CmpReport {
+    pub len_a: u64,
+    pub len_b: u64,
+    /// The offset of the first byte that differs, counting from 0. If one file is the start of
+    /// the other, it's the length of the shorter one.
+    pub first_difference: Option<u64>,
+    /// How many chunks have a byte that differs, counting those that only the longer file reaches
+    pub differing_chunks: u64,
+    /// How many chunks the longer file has
+    pub chunks: u64,
+}
+
+impl CmpReport {
+    pub fn is_identical(&self) -> bool {
+        self.first_difference.is_none()
+    }
+}
+
+/// Read `a` and `b` together in chunks of `chunk_size` bytes and compare them. The chunk size must
+/// be a multiple of `ALIGNMENT` for `config.o_direct`.
+pub fn compare_files(a: &Path, b: &Path, chunk_size: usize, config: &Config) -> Result<CmpReport> {
+    let open_len = |path: &Path| -> Result<(File, u64)> {
+        let file =
+            open(path, config.o_direct).map_err(|err| ChecksumError::open_failed(path, err))?;
+        let len = file.metadata()?.len();
+        Ok((file, len))
+    };
+    let (file_a, len_a) = open_len(a)?;
+    let (file_b, len_b) = open_len(b)?;
+    let chunk = chunk_size as u64;
+    let mut report = CmpReport {
+        len_a,
+        len_b,
+        chunks: len_a.max(len_b).div_ceil(chunk),
+        ..Default::default()
+    };
+    let common_len = min(len_a, len_b);
+    let mut reader = ChunkReader::new([(file_a, len_a), (file_b, len_b)], chunk_size, config)?;
+    let mut offset = 0;
+    let mut last_differed = false;
+    while offset < common_len {
+        let len = min(chunk, common_len - offset) as usize;
+        let [chunk_a, chunk_b] = reader.read(offset, len).map_err(|(i, err)| {
+            let path = [a, b][i];
+            anyhow!("{}: {}", display_path(path), err)
+        })?;
+        let difference = chunk_a.iter().zip(chunk_b).position(|(x, y)| x != y);
+        if let Some(i) = difference {
+            report.first_difference.get_or_insert(offset + i as u64);
+            report.differing_chunks += 1;
+        }
+        last_differed = difference.is_some();
+        progress::add_bytes(2 * len as u64);
+        offset += len as u64;
+    }
+    if len_a != len_b {
+        report.first_difference.get_or_insert(common_len);
+        // The chunks past the shorter file's end differ, and so does the one it ends partway
+        // through, if that wasn't counted already:
+        let partial = common_len % chunk != 0;
+        report.differing_chunks += report.chunks - common_len.div_ceil(chunk);
+        report.differing_chunks += u64::from(partial && !last_differed);
+    }
+    Ok(report)
+}
+
+/// Compare `a` and `b` and print what was found, like cmp. Returns whether they're the same.
+pub fn run(a: &Path, b: &Path, chunk_size: usize, config: &Config) -> Result<bool> {
+    let report = compare_files(a, b, chunk_size, config)?;
+    let (name_a, name_b) = (display_path(a), display_path(b));
+    let Some(first) = report.first_difference else {
+        println!("Files {} and {} are identical", name_a, name_b);
+        return Ok(true);
+    };
+    if first == min(report.len_a, report.len_b) {
+        let shorter = if report.len_a < report.len_b {
+            &name_a
+        } else {
+            &name_b
+        };
+        println!("EOF on {} after byte {}", shorter, first);
+    } else {
+        // Bytes are numbered from 1, as cmp does:
+        println!("{} {} differ: byte {}", name_a, name_b, first + 1);
+    }
+    println!(
+        "{} of {} chunks of {} bytes differ",
+        report.differing_chunks, report.chunks, chunk_size
+    );
+    Ok(false)
+}
+
+/// Reads the same range of two files at once, each into its own buffer.
+#[cfg(target_os = "linux")]
+struct ChunkReader {
+    ring: ring::Ring,
+    /// The files and their lengths
+    files: [(File, u64); 2],
+    bufs: [std::pin::Pin<Box<AlignedBuffer>>; 2],
+}
+
+#[cfg(target_os = "linux")]
+impl ChunkReader {
+    fn new(files: [(File, u64); 2], chunk_size: usize, config: &Config) -> Result<ChunkReader> {
+        Ok(ChunkReader {
+            ring: ring::new_ring(2, config)?,
+            files,
+            bufs: [(); 2].map(|()| Box::pin(AlignedBuffer::with_capacity(chunk_size))),
+        })
+    }
+
+    /// Read `len` bytes from `offset` of both files. On failure, the error says which file failed.
+    fn read(&mut self, offset: u64, len: usize) -> Result<[&[u8]; 2], (usize, anyhow::Error)> {
+        let mut filled = [0; 2];
+        let mut failed = None;
+        let mut in_flight = 0;
+        // Both reads point into the buffers, so both must complete even if one fails:
+        for i in 0..2 {
+            match self.push(i, offset, len, 0) {
+                Ok(()) => in_flight += 1,
+                Err(err) => {
+                    failed.get_or_insert((i, err));
+                }
+            }
+        }
+        while in_flight > 0 {
+            let cqe = match self.ring.next_completion() {
+                Ok(cqe) => cqe,
+                Err(err) => return Err((0, ChecksumError::Completion(err).into())),
+            };
+            in_flight -= 1;
+            let i = cqe.user_data() as usize;
+            let result = cqe.result();
+            let error = match self.ring.timeout_error(result) {
+                Some(err) => Some(err.into()),
+                None if result < 0 => Some(std::io::Error::from_raw_os_error(-result).into()),
+                None if result == 0 => {
+                    Some(ended_early(offset + filled[i] as u64, self.files[i].1))
+                }
+                None => None,
+            };
+            if let Some(err) = error {
+                failed.get_or_insert((i, err));
+                continue;
+            }
+            // Reads ask for whole blocks, so the kernel may go past the end of a file that grew:
+            filled[i] += min(result as usize, len - filled[i]);
+            if filled[i] < len && failed.is_none() {
+                match self.push(i, offset, len, filled[i]) {
+                    Ok(()) => in_flight += 1,
+                    Err(err) => failed = Some((i, err)),
+                }
+            }
+        }
+        if let Some(failed) = failed {
+            return Err(failed);
+        }
+        let [buf_a, buf_b] = &self.bufs;
+        Ok([&buf_a[..len], &buf_b[..len]])
+    }
+
+    /// Queue a read of the rest of `len` bytes from `offset` into buffer `i`, after the `filled`
+    /// bytes that have been read already.
+    fn push(&mut self, i: usize, offset: u64, len: usize, filled: usize) -> Result<()> {
+        use io_uring::{opcode, types};
+        use std::os::unix::io::AsRawFd;
+
+        let buf = &mut self.bufs[i];
+        // Ask for whole blocks, so the length stays aligned for O_DIRECT at the end of the file:
+        let request_len = (len - filled)
+            .next_multiple_of(ALIGNMENT)
+            .min(buf.len() - filled);
+        let read_e = opcode::Read::new(
+            types::Fd(self.files[i].0.as_raw_fd()),
+            // unsafe: `filled` is less than the buffer's length:
+            unsafe { buf.as_mut_ptr().add(filled) },
+            request_len as u32,
+        )
+        .offset(offset + filled as u64)
+        .build()
+        .user_data(i as u64);
+        // unsafe: the buffers aren't touched until their reads complete in `read()`:
+        unsafe { self.ring.push_read(&read_e) }.map_err(ChecksumError::Submit)?;
+        progress::add_read();
+        Ok(())
+    }
+}
+
+/// Reads the same range of two files, one after the other.
+#[cfg(not(target_os = "linux"))]
+struct ChunkReader {
+    /// The files and their lengths
+    files: [(File, u64); 2],
+    bufs: [Box<AlignedBuffer>; 2],
+}
+
+#[cfg(not(target_os = "linux"))]
+impl ChunkReader {
+    fn new(files: [(File, u64); 2], chunk_size: usize, _config: &Config) -> Result<ChunkReader> {
+        Ok(ChunkReader {
+            files,
+            bufs: [(); 2].map(|()| Box::new(AlignedBuffer::with_capacity(chunk_size))),
+        })
+    }
+
+    /// Read `len` bytes from `offset` of both files. On failure, the error says which file failed.
+    fn read(&mut self, offset: u64, len: usize) -> Result<[&[u8]; 2], (usize, anyhow::Error)> {
+        use std::io::{Read, Seek, SeekFrom};
+
+        for i in 0..2 {
+            let ((file, file_len), buf) = (&mut self.files[i], &mut self.bufs[i][..len]);
+            let read = file
+                .seek(SeekFrom::Start(offset))
+                .and_then(|_| file.read_exact(buf));
+            match read {
+                Err(err) if err.kind() == std::io::ErrorKind::UnexpectedEof => {
+                    return Err((i, ended_early(offset, *file_len)))
+                }
+                Err(err) => return Err((i, err.into())),
+                Ok(()) => {}
+            }
+        }
+        let [buf_a, buf_b] = &self.bufs;
+        Ok([&buf_a[..len], &buf_b[..len]])
+    }
+}
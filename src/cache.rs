@@ -0,0 +1,222 @@
+// This module skips reading files whose digest is already known. A cache, like the extended
+// attributes of `xattr_cache`, the database of `db` or a previous checksum list, keeps each file's
+// digest with its size and modification time: if neither changed, the file is taken to be the
+// same. With verification on, every file is read anyway, and one whose digest changed although
+// its size and modification time didn't has been corrupted.
+use std::{
+    collections::HashMap,
+    fs, io,
+    path::{Path, PathBuf},
+    sync::Mutex,
+    time::{Duration, UNIX_EPOCH},
+};
+
+use anyhow::{anyhow, Result};
+#[allow(unused_imports)]
+use log::{debug, error, info, trace, warn};
+
+use crate::{manifest::read_checksum_list, *};
+
+/// What identifies a version of a file's contents without reading them.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Stamp {
+    /// The modification time since the epoch
+    pub mtime: Duration,
+    pub size: u64,
+}
+
+impl Stamp {
+    pub fn of(path: &Path) -> io::Result<Stamp> {
+        let metadata = fs::metadata(path)?;
+        let mtime = metadata
+            .modified()?
+            .duration_since(UNIX_EPOCH)
+            .map_err(io::Error::other)?;
+        Ok(Stamp {
+            mtime,
+            size: metadata.len(),
+        })
+    }
+}
+
+/// A cached digest and the version of the file it's for.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct CacheEntry {
+    pub stamp: Stamp,
+    pub digest: Vec<u8>,
+}
+
+/// Somewhere digests are kept between runs.
+pub trait DigestCache: Send + Sync {
+    /// The digest of `algorithm` cached for `path`, if it has one.
+    fn load(&self, path: &Path, algorithm: HashAlgorithm) -> Option<CacheEntry>;
+
+    /// Cache the digest of `algorithm` for `path`, which was just read.
+    fn store(&self, path: &Path, algorithm: HashAlgorithm, entry: &CacheEntry) -> Result<()>;
+
+    /// Note that `path` was read again and still matches its cached digest.
+    fn verified(&self, _path: &Path, _algorithm: HashAlgorithm) -> Result<()> {
+        Ok(())
+    }
+}
+
+/// Digests from a checksum list written with stamp lines, as `--incremental` writes them. The
+/// stamps of the files read in this run are kept, so they can be written to the new list.
+#[derive(Debug, Default)]
+pub struct ManifestCache {
+    previous: HashMap<PathBuf, CacheEntry>,
+    stored: Mutex<HashMap<PathBuf, Stamp>>,
+}
+
+impl ManifestCache {
+    /// Read the entries with stamps from the checksum list at `path`. If it doesn't exist, as
+    /// before the first run, nothing is cached.
+    pub fn open(path: &Path, algorithm: HashAlgorithm) -> Result<ManifestCache> {
+        if !path.exists() {
+            info!(
+                "{} doesn't exist, so every file will be read.",
+                path.display()
+            );
+            return Ok(Default::default());
+        }
+        let list = read_checksum_list(path, algorithm.output_len() * 2)?;
+        let previous = list
+            .entries
+            .into_iter()
+            .filter_map(|entry| {
+                let stamp = entry.stamp?;
+                let digest = from_hex(&entry.digest)?;
+                Some((entry.path, CacheEntry { stamp, digest }))
+            })
+            .collect();
+        Ok(ManifestCache {
+            previous,
+            stored: Default::default(),
+        })
+    }
+
+    /// The stamp to write with `digest` for `path`: the one it was read with in this run, or the
+    /// previous one if its digest was reused.
+    pub fn stamp(&self, path: &Path, digest: &[u8]) -> Option<Stamp> {
+        if let Some(&stamp) = self.stored.lock().unwrap().get(path) {
+            return Some(stamp);
+        }
+        let previous = self.previous.get(path)?;
+        (previous.digest == digest).then_some(previous.stamp)
+    }
+}
+
+impl DigestCache for ManifestCache {
+    fn load(&self, path: &Path, _algorithm: HashAlgorithm) -> Option<CacheEntry> {
+        self.previous.get(path).cloned()
+    }
+
+    fn store(&self, path: &Path, _algorithm: HashAlgorithm, entry: &CacheEntry) -> Result<()> {
+        self.stored
+            .lock()
+            .unwrap()
+            .insert(path.to_owned(), entry.stamp);
+        Ok(())
+    }
+}
+
+/// Passes results on, caching the digests of files that didn't change while they were read, and
+/// failing files whose digest doesn't match the one cached for the same version.
+struct CachingSink<'a, S> {
+    inner: S,
+    cache: &'a dyn DigestCache,
+    algorithm: HashAlgorithm,
+    /// Each file's version before it was read, and the digest cached for that version if it's
+    /// being verified
+    before: HashMap<PathBuf, (Stamp, Option<Vec<u8>>)>,
+}
+
+impl<S: ResultSink<ChecksumResult>> ResultSink<ChecksumResult> for CachingSink<'_, S> {
+    fn send(&self, mut result: ChecksumResult) -> Result<()> {
+        if let (true, Some((stamp, cached))) = (result.is_ok(), self.before.get(&result.path)) {
+            let path = &result.path;
+            let stored = match cached {
+                Some(cached) if *cached == result.digest => {
+                    self.cache.verified(path, self.algorithm)
+                }
+                Some(_) => {
+                    result.digest.clear();
+                    result.error = Some(anyhow!(
+                        "The contents changed, but not the size or modification time. The file \
+                         may be corrupt."
+                    ));
+                    Ok(())
+                }
+                // A file that changed while it was read may not have been read as a whole:
+                None if Stamp::of(path).ok() != Some(*stamp) => {
+                    debug!("{} changed while it was read", path.display());
+                    Ok(())
+                }
+                None => {
+                    let entry = CacheEntry {
+                        stamp: *stamp,
+                        digest: result.digest.clone(),
+                    };
+                    self.cache.store(path, self.algorithm, &entry)
+                }
+            };
+            if let Err(err) = stored {
+                warn!(
+                    "Could not cache the checksum of {}: {}",
+                    path.display(),
+                    err
+                );
+            }
+        }
+        self.inner.send(result)
+    }
+}
+
+/// Hash `files` as `config` says, except that a file whose size and modification time match its
+/// digest in `cache` gets that digest without being read. The digests of the files that are read
+/// are cached. With `verify`, every file is read, and one whose digest doesn't match the cached
+/// one for the same size and modification time fails as corrupt.
+pub fn get_checksums_cached(
+    files: Vec<PathBuf>,
+    tx: impl ResultSink<ChecksumResult>,
+    config: &Config,
+    cache: &dyn DigestCache,
+    verify: bool,
+) -> Result<()> {
+    let algorithm = config.algorithm;
+    let mut to_hash = Vec::new();
+    let mut before = HashMap::new();
+    for path in files {
+        // Files that can't be examined fail when they're read:
+        if let Ok(stamp) = Stamp::of(&path) {
+            match cache.load(&path, algorithm) {
+                Some(entry) if entry.stamp == stamp && !verify => {
+                    trace!("Using the cached checksum of {}", path.display());
+                    tx.send(ChecksumResult {
+                        path,
+                        digest: entry.digest,
+                        bytes: stamp.size,
+                        elapsed: Duration::ZERO,
+                        error: None,
+                    })?;
+                    continue;
+                }
+                Some(entry) if entry.stamp == stamp => {
+                    before.insert(path.clone(), (stamp, Some(entry.digest)));
+                }
+                _ => {
+                    before.insert(path.clone(), (stamp, None));
+                }
+            }
+        }
+        to_hash.push(path);
+    }
+
+    let tx = CachingSink {
+        inner: tx,
+        cache,
+        algorithm,
+        before,
+    };
+    get_checksums(to_hash, tx, config)
+}
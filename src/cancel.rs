@@ -0,0 +1,53 @@
+// This module lets a long run be stopped early. Every strategy checks the token between
+// completions: once it's cancelled, no more files are started, the reads in flight are left to
+// finish, and the files that weren't finished are closed and get the error from `cancelled()`.
+// The files finished before that keep their results.
+use std::{
+    io,
+    path::PathBuf,
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        Arc,
+    },
+};
+
+use anyhow::Result;
+
+use crate::ResultSink;
+
+/// A flag that stops the runs it's given to. Clones share the flag, so one can be kept to cancel
+/// a run that was given another.
+#[derive(Debug, Clone, Default)]
+pub struct CancelToken(Arc<AtomicBool>);
+
+impl CancelToken {
+    pub fn new() -> Self {
+        Default::default()
+    }
+
+    /// Stop the runs with this token. They return once their reads in flight have completed.
+    pub fn cancel(&self) {
+        self.0.store(true, Ordering::Relaxed);
+    }
+
+    pub fn is_cancelled(&self) -> bool {
+        self.0.load(Ordering::Relaxed)
+    }
+}
+
+/// The error of a file that wasn't finished because its run was cancelled.
+pub fn cancelled() -> io::Error {
+    io::Error::new(io::ErrorKind::Interrupted, "Cancelled before it was hashed")
+}
+
+/// Send the error of a cancelled run for each of `files`, unless nothing is receiving any more.
+pub fn send_cancelled<D, R: From<(PathBuf, Result<D>)>>(
+    files: impl IntoIterator<Item = PathBuf>,
+    tx: &impl ResultSink<R>,
+) {
+    for path in files {
+        if tx.send((path, Err(cancelled().into())).into()).is_err() {
+            break;
+        }
+    }
+}
@@ -0,0 +1,208 @@
+// This module verifies files against a checksum list, like `md5sum --check`.
+use std::{
+    collections::{HashMap, HashSet},
+    io,
+    path::{Path, PathBuf},
+    sync::mpsc::channel,
+    thread,
+};
+
+use anyhow::{anyhow, Context, Result};
+#[allow(unused_imports)]
+use log::{debug, error, info, trace, warn};
+use structopt::StructOpt;
+
+use crate::{
+    diagnose::describe_first_mismatch,
+    manifest::{display_path, read_checksum_list},
+    *,
+};
+
+/// How strictly to check, with the same flags as md5sum. These are only meaningful with
+/// `--check`.
+#[derive(Debug, Clone, Copy, Default, StructOpt)]
+pub struct CheckOptions {
+    /// With --check, don't print OK for each file that matches.
+    #[structopt(long, requires = "check")]
+    pub quiet: bool,
+
+    /// With --check, don't print anything; the exit status shows whether every file matched.
+    #[structopt(long, requires = "check")]
+    pub status: bool,
+
+    /// With --check, exit with status 1 if any line of the list is improperly formatted.
+    #[structopt(long, requires = "check")]
+    pub strict: bool,
+
+    /// With --check, warn about each improperly formatted line of the list.
+    #[structopt(short, long, requires = "check")]
+    pub warn: bool,
+
+    /// With --check, skip files that don't exist instead of failing.
+    #[structopt(long, requires = "check")]
+    pub ignore_missing: bool,
+
+    /// With --check, re-read each file that doesn't match block by block and print the first
+    /// block that differs. The list must have been made with --block-digests.
+    #[structopt(long, requires = "check")]
+    pub diagnose: bool,
+}
+
+/// The counts md5sum reports after checking a list.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct CheckSummary {
+    pub ok: usize,
+    /// Files whose digest didn't match
+    pub failed: usize,
+    /// Files that couldn't be opened or read
+    pub unreadable: usize,
+    /// Files that don't exist, which `--ignore-missing` skips
+    pub missing: usize,
+    /// Lines that weren't checksum lines for this algorithm
+    pub malformed: usize,
+}
+
+impl CheckSummary {
+    /// Whether md5sum would exit successfully: every file that was read matched, and there was
+    /// at least one.
+    pub fn success(&self, options: &CheckOptions) -> bool {
+        self.ok > 0
+            && self.failed == 0
+            && self.unreadable == 0
+            && !(options.strict && self.malformed > 0)
+    }
+}
+
+/// Hash every file in the checksum list at `list` as `config` says, and print whether it matches,
+/// in the format of `md5sum --check`.
+pub fn run(list: &Path, config: &Config, options: &CheckOptions) -> Result<CheckSummary> {
+    let algorithm = config.algorithm;
+    let list_name = list.to_string_lossy();
+    // A digest of the wrong length was made with a different algorithm:
+    let checksum_list = read_checksum_list(list, algorithm.output_len() * 2)
+        .with_context(|| format!("Could not read {}", list_name))?;
+    let mut summary = CheckSummary {
+        malformed: checksum_list.malformed.len(),
+        ..Default::default()
+    };
+    if options.warn {
+        for line_number in &checksum_list.malformed {
+            eprintln!(
+                "{}: {}: improperly formatted {} checksum line",
+                list_name,
+                line_number,
+                algorithm.name().to_uppercase()
+            );
+        }
+    }
+    let entries = checksum_list.entries;
+    if entries.is_empty() {
+        eprintln!(
+            "{}: no properly formatted {} checksum lines found",
+            list_name,
+            algorithm.name().to_uppercase()
+        );
+        return Ok(summary);
+    }
+
+    // Hash each file once, even if it's listed more than once:
+    let mut seen = HashSet::new();
+    let files: Vec<PathBuf> = entries
+        .iter()
+        .filter(|entry| seen.insert(&entry.path))
+        .map(|entry| entry.path.clone())
+        .collect();
+    let (tx, rx) = channel();
+    let config = config.clone();
+    let handle = thread::spawn(move || get_checksums(files, tx, &config));
+    let actual: HashMap<PathBuf, Result<String>> = rx
+        .into_iter()
+        .map(|result| {
+            (
+                result.path.clone(),
+                result.into_result().map(|digest| to_hex(&digest)),
+            )
+        })
+        .collect();
+    handle
+        .join()
+        .map_err(|_| anyhow!("the strategy panicked"))??;
+
+    for entry in &entries {
+        let path = display_path(&entry.path);
+        match actual.get(&entry.path) {
+            Some(Ok(digest)) if *digest == entry.digest => {
+                summary.ok += 1;
+                if !options.quiet && !options.status {
+                    println!("{}: OK", path);
+                }
+            }
+            Some(Ok(_)) => {
+                summary.failed += 1;
+                if !options.status {
+                    println!("{}: FAILED", path);
+                    if options.diagnose {
+                        let description = open(&entry.path, false)
+                            .map(|fd| describe_first_mismatch(&fd, entry, algorithm))
+                            .unwrap_or_else(|err| format!("could not re-read the file: {}", err));
+                        println!("    {}", description);
+                    }
+                }
+            }
+            Some(Err(err)) if options.ignore_missing && is_missing(err) => summary.missing += 1,
+            result => {
+                if let Some(Err(err)) = result {
+                    eprintln!("{}: {}", path, err);
+                }
+                summary.unreadable += 1;
+                if !options.status {
+                    println!("{}: FAILED open or read", path);
+                }
+            }
+        }
+    }
+
+    let plural = |count: usize, one: &'static str, many: &'static str| {
+        if count == 1 {
+            one
+        } else {
+            many
+        }
+    };
+    if !options.status {
+        if summary.malformed > 0 {
+            eprintln!(
+                "WARNING: {} {} improperly formatted",
+                summary.malformed,
+                plural(summary.malformed, "line is", "lines are")
+            );
+        }
+        if summary.unreadable > 0 {
+            eprintln!(
+                "WARNING: {} listed {} could not be read",
+                summary.unreadable,
+                plural(summary.unreadable, "file", "files")
+            );
+        }
+        if summary.failed > 0 {
+            eprintln!(
+                "WARNING: {} computed {} did NOT match",
+                summary.failed,
+                plural(summary.failed, "checksum", "checksums")
+            );
+        }
+    }
+    if options.ignore_missing && summary.ok + summary.failed == 0 {
+        eprintln!("{}: no file was verified", list_name);
+    }
+    Ok(summary)
+}
+
+/// Whether the error is that the file doesn't exist.
+fn is_missing(err: &anyhow::Error) -> bool {
+    err.chain().any(|cause| {
+        cause
+            .downcast_ref::<io::Error>()
+            .is_some_and(|err| err.kind() == io::ErrorKind::NotFound)
+    })
+}
@@ -0,0 +1,42 @@
+// This module hashes files one after another in the order they're given, so that along with each
+// file's digest there's the digest of all of them concatenated, as if they were one stream. The
+// parts of a split dump can be checked against the digest of the whole that way. Since the
+// contents are needed in order, only one file is read at a time.
+use std::path::PathBuf;
+
+use anyhow::Result;
+#[allow(unused_imports)]
+use log::{debug, error, info, trace, warn};
+
+use crate::*;
+
+/// The comment line that records the combined digest, like `#combined <digest>`. md5sum ignores
+/// it.
+pub fn format_combined_line(digest: &str) -> Vec<u8> {
+    format!("#combined {}\n", digest).into_bytes()
+}
+
+/// Hash `files` in order, sending each file's result to `tx`, and return the digest of all their
+/// contents concatenated. If a file can't be read, there's no combined digest, but the files after
+/// it are still hashed.
+pub fn get_checksums_combined(
+    files: Vec<PathBuf>,
+    tx: impl ResultSink<ChecksumResult>,
+    config: &Config,
+) -> Result<Option<Vec<u8>>> {
+    let mut combined = Hasher::new(config.algorithm);
+    let mut complete = true;
+    for path in files {
+        let mut ctx = Hasher::new(config.algorithm);
+        let read = read_whole(&path, config.o_direct, |data| {
+            ctx.update(data);
+            combined.update(data);
+            progress::add_bytes(data.len() as u64);
+            Ok(true)
+        });
+        // Part of the file may have been hashed into the combined digest:
+        complete &= read.is_ok();
+        tx.send((path, read.map(|()| ctx)).into())?;
+    }
+    Ok(complete.then(|| combined.finalize()))
+}
@@ -0,0 +1,133 @@
+// This module compares two directory trees by content, like `diff -rq` but with the files of both
+// sides hashed together through one run of the strategy.
+use std::{
+    collections::{BTreeSet, HashMap},
+    fs,
+    path::{Path, PathBuf},
+    sync::mpsc::channel,
+    thread,
+};
+
+use anyhow::{anyhow, Result};
+#[allow(unused_imports)]
+use log::{debug, error, info, trace, warn};
+
+use crate::{input::Excludes, manifest::display_path, *};
+
+/// The counts of each kind of file found while comparing two trees.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct CompareSummary {
+    /// Files on both sides with the same contents
+    pub identical: usize,
+    /// Files on both sides whose contents differ
+    pub differ: usize,
+    pub only_in_a: usize,
+    pub only_in_b: usize,
+    /// Files on both sides that one side couldn't be read for
+    pub unreadable: usize,
+}
+
+impl CompareSummary {
+    /// Whether the trees have the same files with the same contents, which is when diff would
+    /// exit successfully.
+    pub fn is_identical(&self) -> bool {
+        self.differ == 0 && self.only_in_a == 0 && self.only_in_b == 0 && self.unreadable == 0
+    }
+}
+
+/// Every file under `dir`, relative to it, leaving out what `excludes` matches.
+fn relative_files(dir: &Path, excludes: &Excludes) -> BTreeSet<PathBuf> {
+    input::walk(dir, excludes, false, None)
+        .into_iter()
+        .filter_map(|path| Some(path.strip_prefix(dir).ok()?.to_owned()))
+        .collect()
+}
+
+/// Walk `a` and `b` and print each file that's only on one side or differs between them, and
+/// with `report_identical`, those that are the same. Files of different sizes differ without
+/// being read; the rest of both sides are hashed as `config` says.
+pub fn run(
+    a: &Path,
+    b: &Path,
+    excludes: &Excludes,
+    config: &Config,
+    report_identical: bool,
+) -> Result<CompareSummary> {
+    for dir in [a, b] {
+        if !dir.is_dir() {
+            return Err(anyhow!("{} is not a directory", dir.display()));
+        }
+    }
+    let files_a = relative_files(a, excludes);
+    let files_b = relative_files(b, excludes);
+
+    // Only files of the same size can be the same:
+    let len = |path: &Path| fs::metadata(path).map(|metadata| metadata.len()).ok();
+    let mut to_hash = Vec::new();
+    let mut same_size = HashMap::new();
+    for relative in files_a.intersection(&files_b) {
+        let (path_a, path_b) = (a.join(relative), b.join(relative));
+        let is_same_size = len(&path_a) == len(&path_b);
+        if is_same_size {
+            to_hash.push(path_a);
+            to_hash.push(path_b);
+        }
+        same_size.insert(relative, is_same_size);
+    }
+
+    let (tx, rx) = channel();
+    let config = config.clone();
+    let handle = thread::spawn(move || get_checksums(to_hash, tx, &config));
+    let digests: HashMap<PathBuf, Result<Vec<u8>>> = rx
+        .into_iter()
+        .map(|result| (result.path.clone(), result.into_result()))
+        .collect();
+    handle
+        .join()
+        .map_err(|_| anyhow!("the strategy panicked"))??;
+
+    let mut summary = CompareSummary::default();
+    for relative in files_a.union(&files_b) {
+        let (path_a, path_b) = (a.join(relative), b.join(relative));
+        let (name_a, name_b) = (display_path(&path_a), display_path(&path_b));
+        let Some(&is_same_size) = same_size.get(relative) else {
+            let dir = if files_a.contains(relative) {
+                summary.only_in_a += 1;
+                a
+            } else {
+                summary.only_in_b += 1;
+                b
+            };
+            println!("Only in {}: {}", display_path(dir), display_path(relative));
+            continue;
+        };
+        if !is_same_size {
+            summary.differ += 1;
+            println!("Files {} and {} differ", name_a, name_b);
+            continue;
+        }
+        match (digests.get(&path_a), digests.get(&path_b)) {
+            (Some(Ok(digest_a)), Some(Ok(digest_b))) if digest_a == digest_b => {
+                summary.identical += 1;
+                if report_identical {
+                    println!("Files {} and {} are identical", name_a, name_b);
+                }
+            }
+            (Some(Ok(_)), Some(Ok(_))) => {
+                summary.differ += 1;
+                println!("Files {} and {} differ", name_a, name_b);
+            }
+            results => {
+                summary.unreadable += 1;
+                for (name, result) in [(name_a, results.0), (name_b, results.1)] {
+                    match result {
+                        Some(Ok(_)) => {}
+                        Some(Err(err)) => eprintln!("{}: {}", name, err),
+                        None => eprintln!("{}: not hashed", name),
+                    }
+                }
+            }
+        }
+    }
+    Ok(summary)
+}
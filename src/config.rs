@@ -0,0 +1,273 @@
+// This module holds the settings of a run, so the library can be used without going through
+// `Opt`. The command line is turned into a `Config`, and every strategy reads its settings from
+// the one it's given.
+use std::{path::Path, sync::mpsc::Sender, time::Duration};
+
+#[allow(unused_imports)]
+use log::{debug, error, info, trace, warn};
+
+use crate::*;
+
+/// How long the SQPOLL kernel thread polls an idle ring before it sleeps, if not given.
+pub const DEFAULT_SQPOLL_IDLE_MS: u32 = 1000;
+
+/// Optional features of the rings the io_uring strategies set up.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct RingOptions {
+    /// Have a kernel thread poll the submission queue, so submitting doesn't take a system call.
+    /// The thread sleeps after the ring is idle for this many milliseconds.
+    pub sqpoll_idle: Option<u32>,
+    /// Poll the device for completed reads instead of waiting for interrupts. Only reads of
+    /// files opened with O_DIRECT can complete on such a ring.
+    pub iopoll: bool,
+}
+
+impl RingOptions {
+    /// The names of the features that are turned on, like "SQPOLL and IOPOLL".
+    #[cfg(target_os = "linux")]
+    pub(crate) fn describe(&self) -> String {
+        let mut features = Vec::new();
+        if self.sqpoll_idle.is_some() {
+            features.push("SQPOLL");
+        }
+        if self.iopoll {
+            features.push("IOPOLL");
+        }
+        features.join(" and ")
+    }
+}
+
+/// How to read and hash a set of files. Start from `Config::default()` and change what's needed:
+///
+/// ```
+/// use md5sum_uring::{config::Config, HashAlgorithm};
+///
+/// let config = Config::default()
+///     .strategy("fixed-buffers")
+///     .algorithm(HashAlgorithm::Sha256)
+///     .ring_size(64)
+///     .read_size(1 << 20)
+///     .o_direct(true);
+/// assert_eq!(config.ring_size, 64);
+/// ```
+#[derive(Debug, Clone)]
+pub struct Config {
+    /// The strategy that reads and hashes the files
+    pub strategy: &'static dyn ChecksumStrategy,
+    pub algorithm: HashAlgorithm,
+    /// Open files with O_DIRECT, except on filesystems that reject it
+    pub o_direct: bool,
+    /// How many reads the io_uring strategies keep in flight, which is also the number of
+    /// entries in their rings
+    pub ring_size: usize,
+    /// How many bytes each read asks for, which is also the size of each read buffer
+    pub read_size: usize,
+    /// How many reads of each file the default strategy keeps in flight
+    pub reads_per_file: usize,
+    /// How many worker threads the default strategy hashes on, or 0 to hash on the thread that
+    /// submits reads
+    pub hash_threads: usize,
+    /// How long a read may take before it's cancelled and its file fails
+    pub read_timeout: Option<Duration>,
+    /// Open files with OpenAt requests on the ring, in the default strategy
+    pub async_open: bool,
+    /// Try each read with RWF_NOWAIT before blocking on it
+    pub nowait_first: bool,
+    /// Back fixed buffers with huge pages
+    pub huge_pages: bool,
+    /// How many buffers the fixed-buffers strategy registers, if not one for each entry of the
+    /// ring. With two for each entry, each file has a spare that's read into while it's hashed.
+    pub fixed_buffers: Option<usize>,
+    /// Read files with sequential read-ahead and drop their pages from the page cache after
+    /// they're hashed
+    pub no_cache_pollution: bool,
+    /// Give a file whose extents are all shared with another's, as reflinked copies' are, the
+    /// other's digest instead of reading it
+    pub trust_reflinks: bool,
+    /// The optional features of the rings
+    pub ring_options: RingOptions,
+    /// How many threads to split the files between, each with its own ring
+    pub jobs: usize,
+    /// Which NUMA nodes to run those threads on
+    pub numa: Option<numa::Placement>,
+    /// The file descriptor of a ring whose kernel worker threads every ring shares, instead of
+    /// each starting its own. `get_checksums_in_jobs` sets it for the rings of its threads.
+    pub attach_wq: Option<i32>,
+    /// Stops the run early when it's cancelled
+    pub cancel: CancelToken,
+    /// Where to send an update on a file each time a read of it completes
+    pub file_progress: Option<Sender<progress::FileProgress>>,
+}
+
+impl Default for Config {
+    fn default() -> Self {
+        Config {
+            strategy: DEFAULT_STRATEGY,
+            algorithm: HashAlgorithm::default(),
+            o_direct: false,
+            ring_size: RING_SIZE,
+            read_size: MAX_READ_SIZE,
+            reads_per_file: 1,
+            hash_threads: 0,
+            read_timeout: None,
+            async_open: false,
+            nowait_first: false,
+            huge_pages: false,
+            fixed_buffers: None,
+            no_cache_pollution: false,
+            trust_reflinks: false,
+            ring_options: Default::default(),
+            jobs: 1,
+            numa: None,
+            attach_wq: None,
+            cancel: CancelToken::default(),
+            file_progress: None,
+        }
+    }
+}
+
+impl Config {
+    /// Use the strategy with this name from `STRATEGIES`. Panics if there's none.
+    pub fn strategy(self, name: &str) -> Self {
+        let strategy =
+            strategy_by_name(name).unwrap_or_else(|| panic!("unknown strategy {}", name));
+        self.with_strategy(strategy)
+    }
+
+    /// Use `strategy`, which needn't be one of `STRATEGIES`.
+    pub fn with_strategy(mut self, strategy: &'static dyn ChecksumStrategy) -> Self {
+        self.strategy = strategy;
+        self
+    }
+
+    pub fn algorithm(mut self, algorithm: HashAlgorithm) -> Self {
+        self.algorithm = algorithm;
+        self
+    }
+
+    pub fn o_direct(mut self, enabled: bool) -> Self {
+        self.o_direct = enabled;
+        self
+    }
+
+    /// Keep this many reads in flight. This must be a power of two up to `MAX_RING_SIZE`.
+    pub fn ring_size(mut self, entries: usize) -> Self {
+        assert!(
+            entries.is_power_of_two() && entries <= MAX_RING_SIZE,
+            "invalid ring size {}",
+            entries
+        );
+        self.ring_size = entries;
+        self
+    }
+
+    /// Read this many bytes at a time. This must be a multiple of `ALIGNMENT`, so reads stay
+    /// aligned for O_DIRECT, and at most `READ_SIZE_LIMIT`.
+    pub fn read_size(mut self, bytes: usize) -> Self {
+        assert!(
+            bytes > 0 && bytes.is_multiple_of(ALIGNMENT) && bytes <= READ_SIZE_LIMIT,
+            "invalid read size {}",
+            bytes
+        );
+        self.read_size = bytes;
+        self
+    }
+
+    /// Keep this many reads of each file in flight, from 1 to `MAX_READS_PER_FILE`. The ring
+    /// size is shared out between the files, so fewer are read at once.
+    pub fn reads_per_file(mut self, reads: usize) -> Self {
+        assert!(
+            (1..=MAX_READS_PER_FILE).contains(&reads),
+            "invalid number of reads per file {}",
+            reads
+        );
+        self.reads_per_file = reads;
+        self
+    }
+
+    pub fn hash_threads(mut self, threads: usize) -> Self {
+        self.hash_threads = threads;
+        self
+    }
+
+    pub fn read_timeout(mut self, timeout: Option<Duration>) -> Self {
+        self.read_timeout = timeout;
+        self
+    }
+
+    pub fn async_open(mut self, enabled: bool) -> Self {
+        self.async_open = enabled;
+        self
+    }
+
+    pub fn nowait_first(mut self, enabled: bool) -> Self {
+        self.nowait_first = enabled;
+        self
+    }
+
+    pub fn huge_pages(mut self, enabled: bool) -> Self {
+        self.huge_pages = enabled;
+        self
+    }
+
+    /// Register this many fixed buffers, from 1 to `MAX_FIXED_BUFFERS`, or one for each entry
+    /// of the ring if None.
+    pub fn fixed_buffers(mut self, count: Option<usize>) -> Self {
+        assert!(
+            count.is_none_or(|count| (1..=MAX_FIXED_BUFFERS).contains(&count)),
+            "invalid number of fixed buffers {:?}",
+            count
+        );
+        self.fixed_buffers = count;
+        self
+    }
+
+    pub fn no_cache_pollution(mut self, enabled: bool) -> Self {
+        self.no_cache_pollution = enabled;
+        self
+    }
+
+    pub fn trust_reflinks(mut self, enabled: bool) -> Self {
+        self.trust_reflinks = enabled;
+        self
+    }
+
+    pub fn ring_options(mut self, options: RingOptions) -> Self {
+        self.ring_options = options;
+        self
+    }
+
+    /// Split the files between this many threads, which must be at least 1.
+    pub fn jobs(mut self, jobs: usize) -> Self {
+        assert!(jobs > 0, "invalid number of jobs {}", jobs);
+        self.jobs = jobs;
+        self
+    }
+
+    pub fn numa(mut self, placement: Option<numa::Placement>) -> Self {
+        self.numa = placement;
+        self
+    }
+
+    /// Stop when `token` is cancelled. Keep a clone of it to cancel the run with.
+    pub fn cancel(mut self, token: CancelToken) -> Self {
+        self.cancel = token;
+        self
+    }
+
+    /// Send `tx` an update on each file as its reads complete, for showing how far along each
+    /// one is. The run doesn't wait for the updates to be received.
+    pub fn file_progress(mut self, tx: Sender<progress::FileProgress>) -> Self {
+        self.file_progress = Some(tx);
+        self
+    }
+
+    /// Send an update that `done` bytes of the `total` in `path` have been read, if updates were
+    /// asked for.
+    pub(crate) fn report_progress(&self, path: &Path, done: u64, total: u64) {
+        if let Some(tx) = &self.file_progress {
+            // Nobody listening any more isn't a reason to stop hashing:
+            let _ = tx.send((path.to_owned(), done, total));
+        }
+    }
+}
@@ -0,0 +1,131 @@
+// This module keeps checksums in a SQLite database, so the tool can be the core of a filesystem
+// integrity monitor: each run records every file's size, modification time and digest, and when
+// the file was last read and found to match.
+use std::{
+    path::Path,
+    sync::Mutex,
+    time::{Duration, SystemTime, UNIX_EPOCH},
+};
+
+use anyhow::{Context, Result};
+#[allow(unused_imports)]
+use log::{debug, error, info, trace, warn};
+use rusqlite::{params, Connection, OptionalExtension};
+
+use crate::{
+    cache::{CacheEntry, DigestCache, Stamp},
+    *,
+};
+
+const SCHEMA: &str = "
+CREATE TABLE IF NOT EXISTS checksums (
+    path BLOB NOT NULL,
+    algorithm TEXT NOT NULL,
+    size INTEGER NOT NULL,
+    -- Nanoseconds since the epoch
+    mtime INTEGER NOT NULL,
+    -- In lowercase hexadecimal
+    digest TEXT NOT NULL,
+    -- Seconds since the epoch when the file was last read and matched
+    verified INTEGER NOT NULL,
+    PRIMARY KEY (path, algorithm)
+);";
+
+/// A checksum database. Paths are recorded as they were given. Changes are made in one
+/// transaction, which `commit()` ends, so a run that fails or is interrupted leaves the database
+/// as it was.
+pub struct Database {
+    connection: Mutex<Connection>,
+}
+
+fn now() -> i64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map_or(0, |since| since.as_secs() as i64)
+}
+
+impl Database {
+    /// Open the database at `path`, creating it if it doesn't exist.
+    pub fn open(path: &Path) -> Result<Database> {
+        let connection = Connection::open(path)
+            .with_context(|| format!("Could not open the database {}", path.display()))?;
+        connection.execute_batch(SCHEMA)?;
+        connection.execute_batch("BEGIN")?;
+        Ok(Database {
+            connection: Mutex::new(connection),
+        })
+    }
+
+    /// Save the changes of this run.
+    pub fn commit(self) -> Result<()> {
+        let connection = self.connection.into_inner().unwrap();
+        connection.execute_batch("COMMIT")?;
+        Ok(())
+    }
+
+    /// When `path` was last read and found to match, if it's in the database.
+    pub fn verified(&self, path: &Path, algorithm: HashAlgorithm) -> Result<Option<SystemTime>> {
+        let verified: Option<i64> = self
+            .connection
+            .lock()
+            .unwrap()
+            .query_row(
+                "SELECT verified FROM checksums WHERE path = ?1 AND algorithm = ?2",
+                params![path.as_os_str().as_encoded_bytes(), algorithm.name()],
+                |row| row.get(0),
+            )
+            .optional()?;
+        Ok(verified.map(|secs| UNIX_EPOCH + Duration::from_secs(secs.max(0) as u64)))
+    }
+}
+
+impl DigestCache for Database {
+    fn load(&self, path: &Path, algorithm: HashAlgorithm) -> Option<CacheEntry> {
+        let row: Option<(i64, i64, String)> = self
+            .connection
+            .lock()
+            .unwrap()
+            .query_row(
+                "SELECT size, mtime, digest FROM checksums WHERE path = ?1 AND algorithm = ?2",
+                params![path.as_os_str().as_encoded_bytes(), algorithm.name()],
+                |row| Ok((row.get(0)?, row.get(1)?, row.get(2)?)),
+            )
+            .optional()
+            .unwrap_or_else(|err| {
+                warn!("Could not look up {}: {}", path.display(), err);
+                None
+            });
+        let (size, mtime, digest) = row?;
+        Some(CacheEntry {
+            stamp: Stamp {
+                mtime: Duration::from_nanos(u64::try_from(mtime).ok()?),
+                size: u64::try_from(size).ok()?,
+            },
+            digest: from_hex(&digest)?,
+        })
+    }
+
+    fn store(&self, path: &Path, algorithm: HashAlgorithm, entry: &CacheEntry) -> Result<()> {
+        self.connection.lock().unwrap().execute(
+            "INSERT OR REPLACE INTO checksums (path, algorithm, size, mtime, digest, verified)
+             VALUES (?1, ?2, ?3, ?4, ?5, ?6)",
+            params![
+                path.as_os_str().as_encoded_bytes(),
+                algorithm.name(),
+                entry.stamp.size as i64,
+                entry.stamp.mtime.as_nanos() as i64,
+                to_hex(&entry.digest),
+                now(),
+            ],
+        )?;
+        Ok(())
+    }
+
+    fn verified(&self, path: &Path, algorithm: HashAlgorithm) -> Result<()> {
+        self.connection.lock().unwrap().execute(
+            "UPDATE checksums SET verified = ?3 WHERE path = ?1 AND algorithm = ?2",
+            params![path.as_os_str().as_encoded_bytes(), algorithm.name(), now()],
+        )?;
+        Ok(())
+    }
+}
@@ -0,0 +1,192 @@
+// This module hashes files piecewise, so a failed verification can say where a file differs
+// instead of only that it differs.
+use std::{cmp::min, collections::HashMap, fs::File, path::PathBuf, sync::Mutex};
+
+use anyhow::Result;
+#[allow(unused_imports)]
+use log::{debug, error, info, trace, warn};
+
+use crate::{manifest::ManifestEntry, *};
+
+/// The block size used when none is given. Reads are this size, so it costs nothing extra.
+pub const DEFAULT_BLOCK_SIZE: u64 = MAX_READ_SIZE as u64;
+
+/// The first block of a file whose digest isn't the expected one.
+#[derive(Debug, PartialEq, Eq)]
+pub struct BlockMismatch {
+    /// The first byte of the block
+    pub start: u64,
+    /// One past the last byte of the block
+    pub end: u64,
+    /// None if the file is longer than it's supposed to be
+    pub expected: Option<String>,
+    /// None if the file is shorter than it's supposed to be
+    pub actual: Option<String>,
+}
+
+/// Hash each `block_size` byte block of a file separately. The last block may be shorter.
+pub fn block_digests(fd: &File, block_size: u64, algorithm: HashAlgorithm) -> Result<Vec<String>> {
+    let mut digests = Vec::new();
+    hash_blocks(fd, block_size, algorithm, None, |_, digest| {
+        digests.push(digest);
+        true
+    })?;
+    Ok(digests)
+}
+
+/// Hash a file as a whole and each `block_size` byte block of it separately, in one read.
+pub fn digest_with_blocks(
+    fd: &File,
+    block_size: u64,
+    algorithm: HashAlgorithm,
+) -> Result<(Hasher, Vec<String>)> {
+    let mut whole = Hasher::new(algorithm);
+    let mut digests = Vec::new();
+    hash_blocks(fd, block_size, algorithm, Some(&mut whole), |_, digest| {
+        digests.push(digest);
+        true
+    })?;
+    Ok((whole, digests))
+}
+
+/// Hash `files` one at a time with `digest_with_blocks`, sending each file's result to `tx`. The
+/// block digests of a file are put in `blocks` before its result is sent, for whoever receives
+/// the result to take.
+pub fn get_checksums_with_blocks(
+    files: Vec<PathBuf>,
+    tx: impl ResultSink<ChecksumResult>,
+    block_size: u64,
+    config: &Config,
+    blocks: &Mutex<HashMap<PathBuf, Vec<String>>>,
+) -> Result<()> {
+    for path in files {
+        let result = open(&path, config.o_direct)
+            .map_err(Into::into)
+            .and_then(|fd| digest_with_blocks(&fd, block_size, config.algorithm))
+            .map(|(whole, digests)| {
+                blocks.lock().unwrap().insert(path.clone(), digests);
+                whole
+            });
+        tx.send((path, result).into())?;
+    }
+    Ok(())
+}
+
+/// Re-read a file piecewise and compare each block with `expected`, stopping at the first one
+/// that differs. Returns None if every block matches. The digests in `expected` must be of the
+/// given algorithm.
+pub fn find_first_mismatch(
+    fd: &File,
+    block_size: u64,
+    expected: &[String],
+    algorithm: HashAlgorithm,
+) -> Result<Option<BlockMismatch>> {
+    let mut mismatch = None;
+    let mut block_count = 0;
+    let file_len = hash_blocks(fd, block_size, algorithm, None, |start, digest| {
+        let end = start + block_size;
+        block_count += 1;
+        match expected.get((start / block_size) as usize) {
+            Some(expected) if *expected == digest => true,
+            expected => {
+                mismatch = Some(BlockMismatch {
+                    start,
+                    end,
+                    expected: expected.cloned(),
+                    actual: Some(digest),
+                });
+                false
+            }
+        }
+    })?;
+
+    if let Some(mut mismatch) = mismatch {
+        mismatch.end = min(mismatch.end, file_len);
+        return Ok(Some(mismatch));
+    }
+    if block_count < expected.len() {
+        // The file was cut short:
+        let start = block_count as u64 * block_size;
+        return Ok(Some(BlockMismatch {
+            start,
+            end: start + block_size,
+            expected: Some(expected[block_count].clone()),
+            actual: None,
+        }));
+    }
+    Ok(None)
+}
+
+/// Re-read a file that failed verification piecewise and describe where it first differs from
+/// the block digests listed for it, to be printed under the line that says it failed.
+pub fn describe_first_mismatch(
+    fd: &File,
+    expected: &ManifestEntry,
+    algorithm: HashAlgorithm,
+) -> String {
+    let Some(blocks) = &expected.blocks else {
+        return "no block digests are listed for this file, so where it differs can't be found; \
+                make the list with --block-digests to have them"
+            .to_string();
+    };
+    let describe = |digest: Option<String>| digest.unwrap_or_else(|| "nothing".to_string());
+    match find_first_mismatch(fd, blocks.block_size, &blocks.digests, algorithm) {
+        Ok(Some(mismatch)) => format!(
+            "first difference in bytes {}-{} (expected {}, got {})",
+            mismatch.start,
+            mismatch.end,
+            describe(mismatch.expected),
+            describe(mismatch.actual)
+        ),
+        Ok(None) => "every block matches, so the block digests are out of date".to_string(),
+        Err(err) => format!("could not re-read the file: {}", err),
+    }
+}
+
+/// Call `on_block` with the offset and digest of each block, until it returns false. The data
+/// is also hashed into `whole`, if it's given. Returns the length of the file.
+fn hash_blocks<F>(
+    fd: &File,
+    block_size: u64,
+    algorithm: HashAlgorithm,
+    mut whole: Option<&mut Hasher>,
+    mut on_block: F,
+) -> Result<u64>
+where
+    F: FnMut(u64, String) -> bool,
+{
+    assert!(block_size > 0, "The block size must not be zero");
+    let file_len = fd.metadata()?.len();
+    let mut ctx = Hasher::new(algorithm);
+    let mut block_start = 0;
+    let mut position = 0;
+    let mut stopped = false;
+
+    read_sequentially(fd, 0, file_len, |mut data| {
+        if let Some(whole) = whole.as_deref_mut() {
+            whole.update(data);
+            progress::add_bytes(data.len() as u64);
+        }
+        while !data.is_empty() {
+            let needed = (block_start + block_size - position) as usize;
+            let (now, later) = data.split_at(min(needed, data.len()));
+            ctx.update(now);
+            position += now.len() as u64;
+            data = later;
+
+            if position == block_start + block_size {
+                if !on_block(block_start, to_hex(&ctx.finalize_reset())) {
+                    stopped = true;
+                    return Ok(false);
+                }
+                block_start = position;
+            }
+        }
+        Ok(true)
+    })?;
+
+    if !stopped && position > block_start {
+        on_block(block_start, to_hex(&ctx.finalize()));
+    }
+    Ok(file_len)
+}
@@ -0,0 +1,124 @@
+// This module finds files with the same contents. Only files that share their size with another
+// can be duplicates, so the others aren't read at all; the rest are hashed in one run of the
+// strategy and grouped by digest.
+use std::{collections::HashMap, fs, io::Write, path::PathBuf, sync::mpsc::channel, thread};
+
+use anyhow::{anyhow, Result};
+#[allow(unused_imports)]
+use log::{debug, error, info, trace, warn};
+
+use crate::{manifest::display_path, *};
+
+/// Files that have the same contents, in the order they were given.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct DuplicateGroup {
+    pub digest: Vec<u8>,
+    /// The size of each file
+    pub size: u64,
+    pub paths: Vec<PathBuf>,
+}
+
+impl DuplicateGroup {
+    /// The bytes that would be freed by keeping only one of the files.
+    pub fn reclaimable(&self) -> u64 {
+        self.size * (self.paths.len() as u64 - 1)
+    }
+}
+
+/// Group the files that have the same contents, largest first. Empty files and files that
+/// can't be read are left out; the errors of those that can't be read are printed.
+pub fn find(files: Vec<PathBuf>, config: &Config) -> Result<Vec<DuplicateGroup>> {
+    let mut by_size: HashMap<u64, Vec<PathBuf>> = HashMap::new();
+    // The order files were given in, to list each group in:
+    let mut order = HashMap::new();
+    for path in files {
+        match fs::metadata(&path) {
+            Ok(metadata) if metadata.is_file() && metadata.len() > 0 => {
+                order.insert(path.clone(), order.len());
+                by_size.entry(metadata.len()).or_default().push(path)
+            }
+            Ok(_) => debug!("Leaving out {}", path.display()),
+            Err(err) => eprintln!("{}: {}", display_path(&path), err),
+        }
+    }
+    let mut sizes = HashMap::new();
+    let mut to_hash = Vec::new();
+    for (size, paths) in by_size {
+        if paths.len() > 1 {
+            for path in paths {
+                sizes.insert(path.clone(), size);
+                to_hash.push(path);
+            }
+        }
+    }
+    to_hash.sort_by_key(|path| order[path]);
+
+    let (tx, rx) = channel();
+    let config = config.clone();
+    let handle = thread::spawn(move || get_checksums(to_hash, tx, &config));
+    let mut by_digest: HashMap<(u64, Vec<u8>), Vec<PathBuf>> = HashMap::new();
+    for result in rx {
+        let size = sizes[&result.path];
+        match result.error {
+            // Files of different sizes can't match, even if their digests collide:
+            None => by_digest
+                .entry((size, result.digest))
+                .or_default()
+                .push(result.path),
+            Some(err) => eprintln!("{}: {}", display_path(&result.path), err),
+        }
+    }
+    handle
+        .join()
+        .map_err(|_| anyhow!("the strategy panicked"))??;
+
+    let mut groups: Vec<DuplicateGroup> = by_digest
+        .into_iter()
+        .filter(|(_, paths)| paths.len() > 1)
+        .map(|((size, digest), mut paths)| {
+            paths.sort_by_key(|path| order[path]);
+            DuplicateGroup {
+                digest,
+                size,
+                paths,
+            }
+        })
+        .collect();
+    groups.sort_by(|a, b| {
+        b.size
+            .cmp(&a.size)
+            .then(order[&a.paths[0]].cmp(&order[&b.paths[0]]))
+    });
+    Ok(groups)
+}
+
+/// Print each group with a header line, then a blank line between groups, and a total of the
+/// bytes that could be freed on stderr.
+pub fn print(groups: &[DuplicateGroup], out: &mut dyn Write) -> Result<()> {
+    for (i, group) in groups.iter().enumerate() {
+        if i > 0 {
+            writeln!(out)?;
+        }
+        writeln!(
+            out,
+            "{} files of {} bytes, {}:",
+            group.paths.len(),
+            group.size,
+            to_hex(&group.digest)
+        )?;
+        for path in &group.paths {
+            writeln!(out, "{}", display_path(path))?;
+        }
+    }
+    let duplicates: usize = groups.iter().map(|group| group.paths.len() - 1).sum();
+    let reclaimable: u64 = groups.iter().map(DuplicateGroup::reclaimable).sum();
+    eprintln!(
+        "{} duplicate {} in {} {}; {} bytes could be freed",
+        duplicates,
+        if duplicates == 1 { "file" } else { "files" },
+        groups.len(),
+        if groups.len() == 1 { "group" } else { "groups" },
+        reclaimable
+    );
+    Ok(())
+}
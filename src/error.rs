@@ -0,0 +1,67 @@
+// This module names the ways the strategies fail, so a program that embeds the library can tell
+// them apart with `anyhow::Error::downcast_ref::<ChecksumError>()` instead of reading messages.
+// The strategies return these instead of panicking, so a failed ring or a receiver that went
+// away only ends the run it happened in.
+use std::{
+    error::Error,
+    fmt, io,
+    path::{Path, PathBuf},
+};
+
+/// Why a strategy stopped, or why a file couldn't be opened.
+#[derive(Debug)]
+pub enum ChecksumError {
+    /// A ring couldn't be set up, or what it needs couldn't be registered with it
+    RingSetup(io::Error),
+    /// Requests couldn't be queued on a ring or submitted to the kernel
+    Submit(io::Error),
+    /// Waiting for a completion failed, or one came back that wasn't asked for
+    Completion(io::Error),
+    /// A file couldn't be opened. Only that file fails.
+    OpenFailed { path: PathBuf, source: io::Error },
+    /// Nothing is receiving results any more
+    SinkClosed,
+}
+
+impl ChecksumError {
+    pub(crate) fn open_failed(path: &Path, source: io::Error) -> ChecksumError {
+        ChecksumError::OpenFailed {
+            path: path.to_path_buf(),
+            source,
+        }
+    }
+
+    /// The error for a completion whose user data doesn't match a request in flight.
+    #[cfg(target_os = "linux")]
+    pub(crate) fn unexpected_completion(user_data: u64) -> ChecksumError {
+        ChecksumError::Completion(io::Error::other(format!(
+            "A completion came back for a request that isn't in flight ({})",
+            user_data
+        )))
+    }
+}
+
+impl fmt::Display for ChecksumError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ChecksumError::RingSetup(err) => write!(f, "Could not set up a ring: {}", err),
+            ChecksumError::Submit(err) => write!(f, "Could not submit to the ring: {}", err),
+            ChecksumError::Completion(err) => write!(f, "Could not complete a request: {}", err),
+            // The path is printed with the result, like md5sum does:
+            ChecksumError::OpenFailed { source, .. } => write!(f, "{}", source),
+            ChecksumError::SinkClosed => f.write_str("Nothing is receiving results any more."),
+        }
+    }
+}
+
+impl Error for ChecksumError {
+    fn source(&self) -> Option<&(dyn Error + 'static)> {
+        match self {
+            ChecksumError::RingSetup(err)
+            | ChecksumError::Submit(err)
+            | ChecksumError::Completion(err)
+            | ChecksumError::OpenFailed { source: err, .. } => Some(err),
+            ChecksumError::SinkClosed => None,
+        }
+    }
+}
@@ -0,0 +1,274 @@
+// This module lets an application with its own event loop, as on epoll or Tokio, hash files on the
+// loop's thread instead of blocking another in `submit_and_wait`. The ring is given an eventfd,
+// which the kernel signals as reads complete. The loop waits for that descriptor to be readable
+// along with its others, and then calls `process()`, which hashes what was read, queues the next
+// reads and returns the files that are finished, all without waiting.
+use std::{
+    cmp::min,
+    collections::VecDeque,
+    fs::File,
+    io::{self, Read, Write},
+    os::unix::io::{AsRawFd, FromRawFd, RawFd},
+    path::PathBuf,
+    pin::Pin,
+};
+
+use anyhow::Result;
+use io_uring::{opcode, types, IoUring};
+#[allow(unused_imports)]
+use log::{debug, error, info, trace, warn};
+
+use crate::*;
+
+/// A file being read into the buffer of its slot.
+struct Reading {
+    path: PathBuf,
+    file: File,
+    file_len: u64,
+    /// How many bytes have been hashed, which is where the next read starts
+    position: u64,
+    ctx: Hasher,
+    /// Why the file failed or was cancelled, kept until its read in flight completes
+    error: Option<anyhow::Error>,
+}
+
+/// Hashes files as the caller's event loop drives it. Wait for the descriptor from `as_raw_fd()`
+/// to be readable, call `process()`, and repeat until `is_done()`:
+///
+/// ```no_run
+/// use md5sum_uring::{evented::EventedChecksums, Config};
+/// use std::os::unix::io::AsRawFd;
+///
+/// let mut checksums = EventedChecksums::new(vec!["a".into(), "b".into()], Config::default())?;
+/// while !checksums.is_done() {
+///     let mut pollfd = libc::pollfd {
+///         fd: checksums.as_raw_fd(),
+///         events: libc::POLLIN,
+///         revents: 0,
+///     };
+///     unsafe { libc::poll(&mut pollfd, 1, -1) };
+///     for result in checksums.process()? {
+///         println!("{}: {:?}", result.path.display(), result.digest);
+///     }
+/// }
+/// # Ok::<(), anyhow::Error>(())
+/// ```
+pub struct EventedChecksums {
+    ring: IoUring,
+    /// Readable when there's something for `process()` to do
+    eventfd: File,
+    config: Config,
+    queued: VecDeque<PathBuf>,
+    /// The file read in each slot, whose index is the user data of its reads
+    slots: Vec<Option<Reading>>,
+    buffers: Vec<Pin<Box<AlignedBuffer>>>,
+    /// Results that are ready without a read completing, like those of files that can't be opened
+    ready: Vec<ChecksumResult>,
+}
+
+impl EventedChecksums {
+    /// Set up a ring for `config.ring_size` reads of `config.read_size` bytes and start on
+    /// `paths`. Only the ring's size, the read size, the algorithm, O_DIRECT and cancelling are
+    /// taken from `config`.
+    pub fn new(paths: Vec<PathBuf>, config: Config) -> Result<EventedChecksums> {
+        // A plain ring posts completions as reads finish, rather than when the thread next
+        // enters the kernel, so the eventfd is signalled while the loop waits on it:
+        let ring = IoUring::new(config.ring_size as u32).map_err(ChecksumError::RingSetup)?;
+        // unsafe: eventfd returns a new descriptor or -1 and takes no pointers:
+        let fd = unsafe { libc::eventfd(0, libc::EFD_NONBLOCK | libc::EFD_CLOEXEC) };
+        if fd < 0 {
+            return Err(ChecksumError::RingSetup(io::Error::last_os_error()).into());
+        }
+        // unsafe: the descriptor is new, so nothing else owns it:
+        let eventfd = unsafe { File::from_raw_fd(fd) };
+        ring.submitter()
+            .register_eventfd(fd)
+            .map_err(ChecksumError::RingSetup)?;
+        let mut checksums = EventedChecksums {
+            ring,
+            eventfd,
+            queued: paths.into(),
+            slots: (0..config.ring_size).map(|_| None).collect(),
+            buffers: (0..config.ring_size)
+                .map(|_| Box::pin(AlignedBuffer::with_capacity(config.read_size)))
+                .collect(),
+            config,
+            ready: Vec::new(),
+        };
+        checksums.start_files()?;
+        // Files that were finished right away have no read to signal for them:
+        if !checksums.ready.is_empty() {
+            checksums
+                .eventfd
+                .write_all(&1u64.to_ne_bytes())
+                .map_err(ChecksumError::Submit)?;
+        }
+        Ok(checksums)
+    }
+
+    /// Hash the data of the reads that have completed, queue the next reads, and return the
+    /// results of the files that are finished. This never waits, so it returns nothing when
+    /// called before the descriptor is readable.
+    pub fn process(&mut self) -> Result<Vec<ChecksumResult>> {
+        // Clear the count first, so completions from here on signal it again:
+        let mut count = [0; 8];
+        match self.eventfd.read(&mut count) {
+            Err(err) if err.kind() != io::ErrorKind::WouldBlock => {
+                return Err(ChecksumError::Completion(err).into())
+            }
+            _ => {}
+        }
+        let completions: Vec<_> = self
+            .ring
+            .completion()
+            .map(|cqe| (cqe.user_data() as usize, cqe.result()))
+            .collect();
+        for (slot, result) in completions {
+            self.handle_completion(slot, result)?;
+        }
+        self.start_files()?;
+        Ok(std::mem::take(&mut self.ready))
+    }
+
+    /// Whether every file's result has been returned.
+    pub fn is_done(&self) -> bool {
+        self.queued.is_empty() && self.slots.iter().all(Option::is_none) && self.ready.is_empty()
+    }
+
+    fn handle_completion(&mut self, slot: usize, result: i32) -> Result<()> {
+        let reading = self.slots[slot]
+            .as_mut()
+            .ok_or_else(|| ChecksumError::unexpected_completion(slot as u64))?;
+        if result == -libc::EINTR || result == -libc::EAGAIN {
+            return self.submit_read(slot);
+        }
+        let remaining = reading.file_len - reading.position;
+        // Reads ask for whole blocks, so the kernel may go past the end of a file that grew:
+        let read_len = min(u64::try_from(result).unwrap_or(0), remaining);
+        if reading.error.is_none() {
+            reading.error = if result < 0 {
+                Some(io::Error::from_raw_os_error(-result).into())
+            } else if read_len == 0 {
+                Some(ended_early(reading.position, reading.file_len))
+            } else if self.config.cancel.is_cancelled() {
+                Some(cancel::cancelled().into())
+            } else {
+                None
+            };
+        }
+        if reading.error.is_none() {
+            let data = &self.buffers[slot][..read_len as usize];
+            reading.ctx.update(data);
+            progress::add_bytes(read_len);
+            reading.position += read_len;
+            if reading.position < reading.file_len {
+                return self.submit_read(slot);
+            }
+        }
+        let reading = self.slots[slot].take().unwrap();
+        self.buffers[slot].wipe();
+        let result = match reading.error {
+            Some(err) => Err(err),
+            None => Ok(reading.ctx),
+        };
+        self.ready.push((reading.path, result).into());
+        Ok(())
+    }
+
+    /// Open queued files into the free slots and submit their first reads. Files that can't be
+    /// opened, and empty ones, are finished right away.
+    fn start_files(&mut self) -> Result<()> {
+        if self.config.cancel.is_cancelled() {
+            for path in self.queued.drain(..) {
+                self.ready
+                    .push((path, Err(cancel::cancelled().into())).into());
+            }
+        }
+        for slot in 0..self.slots.len() {
+            if self.slots[slot].is_some() {
+                continue;
+            }
+            while let Some(path) = self.queued.pop_front() {
+                let opened = open(&path, self.config.o_direct)
+                    .map_err(|err| ChecksumError::open_failed(&path, err).into())
+                    .and_then(|file| Ok((file.metadata()?.len(), file)));
+                let (file_len, file) = match opened {
+                    Ok((0, _)) => {
+                        let ctx = Hasher::new(self.config.algorithm);
+                        self.ready.push((path, Ok(ctx)).into());
+                        continue;
+                    }
+                    Ok(opened) => opened,
+                    Err(err) => {
+                        self.ready.push((path, Err(err)).into());
+                        continue;
+                    }
+                };
+                self.slots[slot] = Some(Reading {
+                    path,
+                    file,
+                    file_len,
+                    position: 0,
+                    ctx: Hasher::new(self.config.algorithm),
+                    error: None,
+                });
+                self.submit_read(slot)?;
+                break;
+            }
+        }
+        self.ring.submit().map_err(ChecksumError::Submit)?;
+        Ok(())
+    }
+
+    /// Queue the next read of the file in `slot`, to be submitted with the others.
+    fn submit_read(&mut self, slot: usize) -> Result<()> {
+        let reading = self.slots[slot].as_ref().unwrap();
+        let buf = &mut self.buffers[slot];
+        let len = min(reading.file_len - reading.position, buf.len() as u64) as usize;
+        // Ask for whole blocks, so the length stays aligned for O_DIRECT at the end of the file:
+        let request_len = len.next_multiple_of(ALIGNMENT).min(buf.len()) as u32;
+        let read_e = opcode::Read::new(
+            types::Fd(reading.file.as_raw_fd()),
+            buf.as_mut_ptr(),
+            request_len,
+        )
+        .offset(reading.position)
+        .build()
+        .user_data(slot as u64);
+        // unsafe: the buffer and file stay in their slot until the read completes. There's at
+        // most one read for each entry of the ring, and the queue is submitted after each batch:
+        unsafe { self.ring.submission().push(&read_e) }
+            .map_err(|_| ChecksumError::Submit(io::Error::other("submission queue is full")))?;
+        progress::add_read();
+        Ok(())
+    }
+}
+
+impl AsRawFd for EventedChecksums {
+    /// The eventfd, which is readable when `process()` has something to do.
+    fn as_raw_fd(&self) -> RawFd {
+        self.eventfd.as_raw_fd()
+    }
+}
+
+impl Drop for EventedChecksums {
+    /// Wait for the reads in flight, which point into the buffers, before they're freed.
+    fn drop(&mut self) {
+        let mut in_flight = self.slots.iter().filter(|slot| slot.is_some()).count();
+        while in_flight > 0 {
+            if let Err(err) = self.ring.submit_and_wait(1) {
+                if err.raw_os_error() == Some(libc::EINTR) {
+                    continue;
+                }
+                error!(
+                    "Could not wait for reads before freeing their buffers: {}",
+                    err
+                );
+                // Leaking the buffers is safer than letting the kernel write to freed memory:
+                std::mem::forget(std::mem::take(&mut self.buffers));
+                return;
+            }
+            in_flight -= self.ring.completion().count();
+        }
+    }
+}
@@ -0,0 +1,200 @@
+// This module lets C and C++ programs, like backup software, hash files with the library instead
+// of running the binary. A session hashes its files on another thread, and the caller polls it
+// for results. include/md5sum_uring.h declares these functions.
+use std::{
+    collections::HashSet,
+    ffi::{c_char, c_int, CStr, CString},
+    path::PathBuf,
+    ptr, slice,
+    sync::mpsc::{channel, Receiver, RecvError, TryRecvError},
+    thread,
+};
+
+use anyhow::anyhow;
+#[allow(unused_imports)]
+use log::{debug, error, info, trace, warn};
+
+use crate::*;
+
+/// `md5sum_uring_poll()` filled in a result.
+pub const MD5SUM_URING_RESULT: c_int = 1;
+/// No result is ready yet.
+pub const MD5SUM_URING_PENDING: c_int = 0;
+/// Every file has had its result.
+pub const MD5SUM_URING_DONE: c_int = -1;
+/// The session or result pointer was null.
+pub const MD5SUM_URING_INVALID: c_int = -2;
+
+/// One file's result. The pointers are owned by the session, and are valid until the next poll
+/// or until the session is freed.
+#[repr(C)]
+#[derive(Debug)]
+pub struct Md5sumUringResult {
+    /// The path as it was submitted
+    pub path: *const c_char,
+    /// The digest, which is empty if the file failed
+    pub digest: *const u8,
+    pub digest_len: usize,
+    /// Why the file couldn't be hashed, or null if it was
+    pub error: *const c_char,
+}
+
+/// The files of one `md5sum_uring_submit()` call, being hashed on another thread.
+pub struct Md5sumUringSession {
+    rx: Receiver<ChecksumResult>,
+    cancel: CancelToken,
+    /// What the last result points into
+    path: CString,
+    digest: Vec<u8>,
+    error: Option<CString>,
+}
+
+/// A C string with the bytes of `bytes` up to the first NUL.
+fn c_string(bytes: &[u8]) -> CString {
+    let len = bytes
+        .iter()
+        .position(|&byte| byte == 0)
+        .unwrap_or(bytes.len());
+    CString::new(&bytes[..len]).unwrap_or_default()
+}
+
+/// Start hashing `count` files with the default strategy and `algorithm`, like "sha256", or md5
+/// if it's null. Returns null if `algorithm` isn't known.
+///
+/// # Safety
+///
+/// `paths` must point to `count` NUL-terminated strings, which are copied before this returns.
+/// `algorithm` must be null or a NUL-terminated string.
+#[no_mangle]
+pub unsafe extern "C" fn md5sum_uring_submit(
+    paths: *const *const c_char,
+    count: usize,
+    algorithm: *const c_char,
+) -> *mut Md5sumUringSession {
+    let algorithm = if algorithm.is_null() {
+        HashAlgorithm::Md5
+    } else {
+        match CStr::from_ptr(algorithm).to_str().map(str::parse) {
+            Ok(Ok(algorithm)) => algorithm,
+            _ => return ptr::null_mut(),
+        }
+    };
+    let paths: Vec<PathBuf> = if count == 0 {
+        Vec::new()
+    } else {
+        slice::from_raw_parts(paths, count)
+            .iter()
+            .map(|&path| path_from_bytes(CStr::from_ptr(path).to_bytes()))
+            .collect()
+    };
+
+    let cancel = CancelToken::new();
+    let config = Config::default()
+        .algorithm(algorithm)
+        .cancel(cancel.clone());
+    let (tx, rx) = channel();
+    thread::spawn(move || {
+        let (strategy_tx, strategy_rx) = channel();
+        let mut unfinished: HashSet<PathBuf> = paths.iter().cloned().collect();
+        let handle = thread::spawn(move || get_checksums(paths, strategy_tx, &config));
+        for result in strategy_rx {
+            unfinished.remove(&result.path);
+            if tx.send(result).is_err() {
+                // The session was freed:
+                break;
+            }
+        }
+        let result = handle
+            .join()
+            .unwrap_or_else(|_| Err(anyhow!("A checksum thread panicked.")));
+        if let Err(err) = result {
+            // Every file gets a result, even if the strategy couldn't run:
+            debug!("The strategy stopped early: {:#}", err);
+            for path in unfinished {
+                let _ = tx.send((path, Err(anyhow!("{:#}", err))).into());
+            }
+        }
+    });
+
+    Box::into_raw(Box::new(Md5sumUringSession {
+        rx,
+        cancel,
+        path: CString::default(),
+        digest: Vec::new(),
+        error: None,
+    }))
+}
+
+/// Get the next result of `session` into `result`. If `wait` is nonzero, this blocks until a
+/// result is ready. Returns `MD5SUM_URING_RESULT` if `result` was filled in,
+/// `MD5SUM_URING_PENDING` if nothing is ready yet, or `MD5SUM_URING_DONE` once every file has had
+/// its result.
+///
+/// # Safety
+///
+/// `session` must be null or come from `md5sum_uring_submit()` and not have been freed. `result`
+/// must be null or point to a writable `Md5sumUringResult`.
+#[no_mangle]
+pub unsafe extern "C" fn md5sum_uring_poll(
+    session: *mut Md5sumUringSession,
+    result: *mut Md5sumUringResult,
+    wait: c_int,
+) -> c_int {
+    let (Some(session), Some(result)) = (session.as_mut(), result.as_mut()) else {
+        return MD5SUM_URING_INVALID;
+    };
+    let next = if wait != 0 {
+        session
+            .rx
+            .recv()
+            .map_err(|RecvError| TryRecvError::Disconnected)
+    } else {
+        session.rx.try_recv()
+    };
+    let next = match next {
+        Ok(next) => next,
+        Err(TryRecvError::Empty) => return MD5SUM_URING_PENDING,
+        Err(TryRecvError::Disconnected) => return MD5SUM_URING_DONE,
+    };
+
+    session.path = c_string(next.path.as_os_str().as_encoded_bytes());
+    session.error = next.error.map(|err| c_string(err.to_string().as_bytes()));
+    session.digest = next.digest;
+    *result = Md5sumUringResult {
+        path: session.path.as_ptr(),
+        digest: session.digest.as_ptr(),
+        digest_len: session.digest.len(),
+        error: session
+            .error
+            .as_ref()
+            .map_or(ptr::null(), |err| err.as_ptr()),
+    };
+    MD5SUM_URING_RESULT
+}
+
+/// Stop starting new files. The files that weren't finished get an error result, and reads in
+/// flight still complete.
+///
+/// # Safety
+///
+/// `session` must be null or come from `md5sum_uring_submit()` and not have been freed.
+#[no_mangle]
+pub unsafe extern "C" fn md5sum_uring_cancel(session: *const Md5sumUringSession) {
+    if let Some(session) = session.as_ref() {
+        session.cancel.cancel();
+    }
+}
+
+/// Cancel the session if it's still running and free it. The results that weren't polled are
+/// dropped.
+///
+/// # Safety
+///
+/// `session` must be null or come from `md5sum_uring_submit()`, and it must not be used again.
+#[no_mangle]
+pub unsafe extern "C" fn md5sum_uring_free(session: *mut Md5sumUringSession) {
+    if !session.is_null() {
+        let session = Box::from_raw(session);
+        session.cancel.cancel();
+    }
+}
@@ -0,0 +1,85 @@
+// This module reads each file once even when it's named by several paths, as in backup trees full
+// of hard links. Paths with the same device and inode number are the same file, so only the first
+// is hashed, and its result is given to the others as well.
+use std::{
+    collections::{hash_map::Entry, HashMap},
+    fs,
+    path::PathBuf,
+};
+
+use anyhow::{anyhow, Result};
+#[allow(unused_imports)]
+use log::{debug, error, info, trace, warn};
+
+use crate::*;
+
+/// Leave out the paths of `files` that are links to a file named earlier. Returns the files to
+/// hash, and the paths left out for each of them, in order.
+pub fn split_links(files: Vec<PathBuf>) -> (Vec<PathBuf>, HashMap<PathBuf, Vec<PathBuf>>) {
+    let mut first_path: HashMap<(u64, u64), PathBuf> = HashMap::new();
+    let mut links: HashMap<PathBuf, Vec<PathBuf>> = HashMap::new();
+    let mut to_hash = Vec::with_capacity(files.len());
+    for path in files {
+        // Streams are read as they come, so each path is read by itself:
+        let id = fs::metadata(&path)
+            .ok()
+            .filter(|metadata| metadata.is_file() && !input::is_stdin(&path))
+            .and_then(|metadata| input::file_id(&metadata));
+        let Some(id) = id else {
+            to_hash.push(path);
+            continue;
+        };
+        match first_path.entry(id) {
+            Entry::Occupied(first) => {
+                trace!(
+                    "{} is the same file as {}",
+                    path.display(),
+                    first.get().display()
+                );
+                links.entry(first.get().clone()).or_default().push(path);
+            }
+            Entry::Vacant(entry) => {
+                entry.insert(path.clone());
+                to_hash.push(path);
+            }
+        }
+    }
+    (to_hash, links)
+}
+
+/// Passes results on, following each with a copy for every other path of the same file.
+pub struct LinkSink<S> {
+    pub inner: S,
+    /// The paths left out for each file that's hashed. A path that was left out may have others
+    /// left out for it in turn, as when a hard link is also a reflinked copy of another file.
+    pub links: HashMap<PathBuf, Vec<PathBuf>>,
+}
+
+impl<S: ResultSink<ChecksumResult>> ResultSink<ChecksumResult> for LinkSink<S> {
+    fn send(&self, result: ChecksumResult) -> Result<()> {
+        let copies: Vec<ChecksumResult> = self
+            .links
+            .get(&result.path)
+            .into_iter()
+            .flatten()
+            .map(|path| ChecksumResult {
+                path: path.clone(),
+                digest: result.digest.clone(),
+                bytes: result.bytes,
+                elapsed: result.elapsed,
+                error: result.error.as_ref().map(|err| anyhow!("{}", err)),
+            })
+            .collect();
+        let path = result.path.clone();
+        self.inner.send(result)?;
+        for copy in copies {
+            // A path given twice is left out for itself:
+            if copy.path == path {
+                self.inner.send(copy)?;
+            } else {
+                self.send(copy)?;
+            }
+        }
+        Ok(())
+    }
+}
@@ -0,0 +1,157 @@
+// This module hashes on worker threads, so the thread that submits reads only moves buffers. Each
+// file is hashed by one worker, which gets its chunks in order.
+use std::{
+    collections::HashMap,
+    mem,
+    path::PathBuf,
+    pin::Pin,
+    sync::{
+        mpsc::{sync_channel, Sender, SyncSender},
+        Arc, Barrier, Mutex,
+    },
+    thread::{self, JoinHandle},
+};
+
+use anyhow::Result;
+#[allow(unused_imports)]
+use log::{debug, error, info, trace, warn};
+use md5::digest::Update;
+
+use crate::*;
+
+/// How many jobs can wait for each worker before the submitting thread waits for it.
+const QUEUE_LEN: usize = 32;
+
+/// What a worker is asked to do with a file, identified by a number the caller chooses.
+enum Job<D> {
+    /// Start hashing a file with its digest state
+    Start { file: usize, path: PathBuf, ctx: D },
+    /// Hash the first `len` bytes of the buffer, the next part of the file
+    Hash {
+        file: usize,
+        buf: Pin<Box<AlignedBuffer>>,
+        len: usize,
+    },
+    /// Send the file's digest
+    Finish { file: usize },
+    /// Send this error instead of the file's digest
+    Fail { file: usize, err: anyhow::Error },
+    /// Wait at the barrier, which every worker and the caller meet at once the jobs before are done
+    Wait(Arc<Barrier>),
+}
+
+/// Worker threads that hash files and send their results. The buffers they're done with are kept
+/// for `replace_buffer()`.
+pub struct HashPool<D> {
+    workers: Vec<(SyncSender<Job<D>>, JoinHandle<()>)>,
+    spare_buffers: Arc<Mutex<Vec<Pin<Box<AlignedBuffer>>>>>,
+    /// The size of new buffers
+    read_size: usize,
+}
+
+impl<D: Update + Send + 'static> HashPool<D> {
+    /// Start `threads` workers, which send each file's result through `tx`. Buffers that are
+    /// needed are allocated with `read_size` bytes.
+    pub fn new(threads: usize, read_size: usize, tx: &Sender<(PathBuf, Result<D>)>) -> HashPool<D> {
+        let spare_buffers: Arc<Mutex<Vec<_>>> = Default::default();
+        let workers = (0..threads)
+            .map(|_| {
+                let (job_tx, job_rx) = sync_channel(QUEUE_LEN);
+                let tx = tx.clone();
+                let spare_buffers = spare_buffers.clone();
+                let handle = thread::spawn(move || {
+                    let mut files: HashMap<usize, (PathBuf, D)> = HashMap::new();
+                    for job in job_rx {
+                        match job {
+                            Job::Start { file, path, ctx } => {
+                                files.insert(file, (path, ctx));
+                            }
+                            Job::Hash { file, mut buf, len } => {
+                                let (_, ctx) = files.get_mut(&file).expect("the file was started");
+                                ctx.update(&buf[..len]);
+                                progress::add_bytes(len as u64);
+                                buf.wipe();
+                                spare_buffers.lock().unwrap().push(buf);
+                            }
+                            Job::Finish { file } => {
+                                let (path, ctx) =
+                                    files.remove(&file).expect("the file was started");
+                                // The receiver only hangs up when it no longer wants results:
+                                let _ = tx.send((path, Ok(ctx)));
+                            }
+                            Job::Fail { file, err } => {
+                                let (path, _) = files.remove(&file).expect("the file was started");
+                                let _ = tx.send((path, Err(err)));
+                            }
+                            Job::Wait(barrier) => {
+                                barrier.wait();
+                            }
+                        }
+                    }
+                });
+                (job_tx, handle)
+            })
+            .collect();
+        HashPool {
+            workers,
+            spare_buffers,
+            read_size,
+        }
+    }
+
+    fn send(&self, file: usize, job: Job<D>) {
+        let (job_tx, _) = &self.workers[file % self.workers.len()];
+        job_tx.send(job).expect("hashing workers don't exit early");
+    }
+
+    /// Start hashing `file` with `ctx`. The same number can be used again once the file is
+    /// finished or failed.
+    pub fn start(&self, file: usize, path: PathBuf, ctx: D) {
+        self.send(file, Job::Start { file, path, ctx });
+    }
+
+    /// Hash the first `len` bytes of `buf` as the next part of `file`.
+    pub fn hash(&self, file: usize, buf: Pin<Box<AlignedBuffer>>, len: usize) {
+        self.send(file, Job::Hash { file, buf, len });
+    }
+
+    /// Send the digest of `file` once the parts before are hashed.
+    pub fn finish(&self, file: usize) {
+        self.send(file, Job::Finish { file });
+    }
+
+    /// Send `err` for `file` instead of its digest.
+    pub fn fail(&self, file: usize, err: anyhow::Error) {
+        self.send(file, Job::Fail { file, err });
+    }
+
+    /// Wait until the workers have hashed what they were given and sent every result.
+    pub fn wait(&self) {
+        let barrier = Arc::new(Barrier::new(self.workers.len() + 1));
+        for (job_tx, _) in &self.workers {
+            job_tx
+                .send(Job::Wait(barrier.clone()))
+                .expect("hashing workers don't exit early");
+        }
+        barrier.wait();
+    }
+
+    /// Swap `buf` for a buffer a worker is done with, or a new one, and return the old one.
+    pub fn replace_buffer(&self, buf: &mut Pin<Box<AlignedBuffer>>) -> Pin<Box<AlignedBuffer>> {
+        let spare = self.spare_buffers.lock().unwrap().pop();
+        let new_buffer = || Box::pin(AlignedBuffer::with_capacity(self.read_size));
+        mem::replace(buf, spare.unwrap_or_else(new_buffer))
+    }
+}
+
+impl<D> Drop for HashPool<D> {
+    /// Wait for the workers to hash what they were given.
+    fn drop(&mut self) {
+        for (job_tx, handle) in self.workers.drain(..) {
+            drop(job_tx);
+            if handle.join().is_err() {
+                error!("A hashing thread panicked.");
+            }
+        }
+    }
+}
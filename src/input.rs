@@ -0,0 +1,435 @@
+// This module works out which files to hash from the command line.
+#[cfg(unix)]
+use std::os::unix::fs::{FileTypeExt, MetadataExt};
+#[cfg(target_os = "linux")]
+use std::os::unix::io::AsRawFd;
+use std::{
+    fs::{self, File},
+    io::{self, Read},
+    path::{Path, PathBuf},
+    str::FromStr,
+    time::{Duration, SystemTime, UNIX_EPOCH},
+};
+
+use anyhow::{Context, Result};
+use glob::Pattern;
+#[allow(unused_imports)]
+use log::{debug, error, info, trace, warn};
+use walkdir::WalkDir;
+
+use crate::{path_from_bytes, Opt};
+
+/// Every file to hash: those named as arguments, with glob patterns expanded and directories
+/// walked if `--recursive` is set, then those listed in `--files-from`. With neither, this is
+/// just `-`, stdin.
+pub fn collect_files(options: &Opt) -> Result<Vec<PathBuf>> {
+    if options.files.is_empty() && options.files_from.is_none() {
+        return Ok(vec![PathBuf::from("-")]);
+    }
+    let excludes = Excludes::new(&options.exclude, options.exclude_from.as_deref())?;
+    let symlinks = Symlinks::from_options(options);
+    let mut files = Vec::new();
+    for arg in &options.files {
+        for path in expand_glob(arg)? {
+            // A path that was named exactly is always hashed:
+            if path != *arg && excludes.is_excluded(&path) {
+                continue;
+            }
+            if symlinks == Symlinks::Never && path.is_symlink() {
+                eprintln!("{}: not following a symbolic link", path.display());
+                continue;
+            }
+            if options.recursive && path.is_dir() {
+                let follow_links = symlinks == Symlinks::Always;
+                let found = walk(&path, &excludes, follow_links, options.max_depth);
+                // Only pipes that are named are read. One found while recursing usually has no
+                // writer, so reading it would wait forever:
+                files.extend(found.into_iter().filter(|path| {
+                    let skip = !options.allow_special && is_pipe(path);
+                    if skip {
+                        eprintln!(
+                            "{}: a pipe found while recursing, skipping (--allow-special reads it)",
+                            path.display()
+                        );
+                    }
+                    !skip
+                }));
+            } else {
+                files.push(path);
+            }
+        }
+    }
+    if let Some(list) = &options.files_from {
+        files.extend(read_file_list(list, options.null)?);
+    }
+    if !options.allow_special {
+        // Pipes found while recursing are already left out, so those left were named:
+        files.retain(|path| {
+            let special = !is_stdin(path) && !is_pipe(path) && is_special(path);
+            if special {
+                eprintln!(
+                    "{}: not a regular file or pipe, skipping (--allow-special reads it)",
+                    path.display()
+                );
+            }
+            !special
+        });
+    }
+    if let Some(threshold) = options.newer_than {
+        // Like with the size filters, streams and files that can't be examined are kept:
+        files.retain(|path| {
+            if is_stream(path) {
+                return true;
+            }
+            match fs::metadata(path).and_then(|metadata| metadata.modified()) {
+                Ok(modified) => modified > threshold,
+                Err(_) => true,
+            }
+        });
+    }
+    if options.min_size.is_some() || options.max_size.is_some() {
+        files.retain(|path| {
+            // Streams have no size, and files that can't be examined are kept so the error is
+            // reported:
+            let Ok(metadata) = fs::metadata(path) else {
+                return true;
+            };
+            if is_stream(path) {
+                return true;
+            }
+            let len = metadata.len();
+            options.min_size.is_none_or(|min| len >= min)
+                && options.max_size.is_none_or(|max| len <= max)
+        });
+    }
+    if let Some(name) = &options.manifest_per_dir {
+        // Last run's lists would be out of date by the time this run's replace them:
+        files.retain(|path| path.file_name() != Some(name.as_os_str()));
+    }
+    match options.sort {
+        Some(SortOrder::Physical) => sort_physical(&mut files),
+        None => {}
+    }
+    Ok(files)
+}
+
+/// Parse a size in bytes, with an optional suffix of K, M, G or T for powers of 1024, like
+/// `512`, `64K` or `1MiB`.
+pub fn parse_size(s: &str) -> std::result::Result<u64, String> {
+    let digits = s.trim_end_matches(|c: char| c.is_ascii_alphabetic());
+    let multiplier: u64 = match s[digits.len()..].to_ascii_uppercase().as_str() {
+        "" | "B" => 1,
+        "K" | "KIB" => 1 << 10,
+        "M" | "MIB" => 1 << 20,
+        "G" | "GIB" => 1 << 30,
+        "T" | "TIB" => 1 << 40,
+        suffix => return Err(format!("unknown size suffix {:?}", suffix)),
+    };
+    let number: u64 = digits.parse().map_err(|err| format!("{}", err))?;
+    number
+        .checked_mul(multiplier)
+        .ok_or_else(|| format!("{} is too large", s))
+}
+
+/// Parse the argument of `--newer-than`: the modification time of a file if it names one,
+/// otherwise a time in UTC like `2024-05-01`, `2024-05-01 12:30:00` or `2024-05-01T12:30:00Z`, or
+/// seconds since the epoch like `@1714566600`.
+pub fn parse_time(s: &str) -> std::result::Result<SystemTime, String> {
+    if let Ok(metadata) = fs::metadata(s) {
+        return metadata.modified().map_err(|err| err.to_string());
+    }
+    if let Some(seconds) = s.strip_prefix('@') {
+        let seconds: u64 = seconds.parse().map_err(|err| format!("{}", err))?;
+        return Ok(UNIX_EPOCH + Duration::from_secs(seconds));
+    }
+    let with_time = if s.len() == "2024-05-01".len() {
+        format!("{} 00:00:00", s)
+    } else {
+        s.to_owned()
+    };
+    humantime::parse_rfc3339_weak(&with_time)
+        .map_err(|_| format!("{:?} is not a file, a date like 2024-05-01, or @SECONDS", s))
+}
+
+/// The order to hash files in, instead of the order they were given.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum SortOrder {
+    /// By where each file starts on disk, so a spinning disk reads them with less seeking
+    Physical,
+}
+
+impl FromStr for SortOrder {
+    type Err = String;
+
+    fn from_str(s: &str) -> std::result::Result<Self, Self::Err> {
+        match s {
+            "physical" => Ok(SortOrder::Physical),
+            _ => Err(format!("unknown order {:?}; use \"physical\"", s)),
+        }
+    }
+}
+
+/// Sort files by the device they're on, then by the disk address of their first extent. Files
+/// on filesystems without FIEMAP, like tmpfs, are sorted by inode number instead, which is
+/// usually close to allocation order. Files that can't be examined go last.
+pub fn sort_physical(files: &mut [PathBuf]) {
+    files.sort_by_cached_key(|path| {
+        let Ok(metadata) = fs::metadata(path) else {
+            return (u64::MAX, None, u64::MAX);
+        };
+        let physical = File::open(path).ok().and_then(|file| first_extent(&file));
+        // Without file numbers, files stay in the order they were given:
+        let (device, inode) = file_id(&metadata).unwrap_or_default();
+        (device, physical, inode)
+    });
+}
+
+/// The device and inode number of a file, which are the same for every hard link to it.
+#[cfg(unix)]
+pub fn file_id(metadata: &fs::Metadata) -> Option<(u64, u64)> {
+    Some((metadata.dev(), metadata.ino()))
+}
+
+/// Other systems don't number files the same way.
+#[cfg(not(unix))]
+pub fn file_id(_metadata: &fs::Metadata) -> Option<(u64, u64)> {
+    None
+}
+
+/// `struct fiemap` with room for one extent, from linux/fiemap.h.
+#[cfg(target_os = "linux")]
+#[repr(C)]
+#[derive(Default)]
+struct Fiemap {
+    start: u64,
+    length: u64,
+    flags: u32,
+    mapped_extents: u32,
+    extent_count: u32,
+    reserved: u32,
+    extent: FiemapExtent,
+}
+
+/// `struct fiemap_extent`.
+#[cfg(target_os = "linux")]
+#[repr(C)]
+#[derive(Debug, Clone, Copy, Default)]
+pub(crate) struct FiemapExtent {
+    pub logical: u64,
+    pub physical: u64,
+    pub length: u64,
+    reserved64: [u64; 2],
+    pub flags: u32,
+    reserved: [u32; 3],
+}
+
+#[cfg(target_os = "linux")]
+pub(crate) const FS_IOC_FIEMAP: libc::c_ulong = 0xc020_660b;
+
+/// The disk address of the first extent of `file`, or None if the filesystem can't say or the
+/// file is empty.
+#[cfg(target_os = "linux")]
+fn first_extent(file: &File) -> Option<u64> {
+    let mut fiemap = Fiemap {
+        length: u64::MAX,
+        extent_count: 1,
+        ..Default::default()
+    };
+    // unsafe: the kernel writes at most `extent_count` extents into the struct we pass in:
+    let result = unsafe { libc::ioctl(file.as_raw_fd(), FS_IOC_FIEMAP, &mut fiemap) };
+    if result != 0 || fiemap.mapped_extents == 0 {
+        return None;
+    }
+    Some(fiemap.extent.physical)
+}
+
+/// Only Linux has FIEMAP.
+#[cfg(not(target_os = "linux"))]
+fn first_extent(_file: &File) -> Option<u64> {
+    None
+}
+
+/// Whether `path` is `-`, which means stdin like it does for md5sum.
+pub fn is_stdin(path: &Path) -> bool {
+    path == Path::new("-")
+}
+
+/// Whether `path` has to be read as a stream, from start to end, rather than at offsets.
+pub fn is_stream(path: &Path) -> bool {
+    is_stdin(path) || is_special(path)
+}
+
+/// Whether `path` is a FIFO, including the `/dev/fd` paths of shell process substitution.
+#[cfg(unix)]
+pub fn is_pipe(path: &Path) -> bool {
+    fs::metadata(path).is_ok_and(|metadata| metadata.file_type().is_fifo())
+}
+
+/// Named pipes elsewhere than Unix aren't files that can be named on the command line.
+#[cfg(not(unix))]
+pub fn is_pipe(_path: &Path) -> bool {
+    false
+}
+
+/// Whether `path` is a FIFO, socket or device rather than a regular file or directory. These
+/// have no length to read up to, so they can only be read as streams.
+pub fn is_special(path: &Path) -> bool {
+    fs::metadata(path).is_ok_and(|metadata| !metadata.is_file() && !metadata.is_dir())
+}
+
+/// Which symbolic links are followed, like the options of the same names in du and find.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Symlinks {
+    /// `-P`: links named on the command line are errors, and links found while recursing are
+    /// skipped
+    Never,
+    /// `-H`: links named on the command line are followed, and links found while recursing are
+    /// skipped. This is the default.
+    CommandLine,
+    /// `-L`: every link is followed
+    Always,
+}
+
+impl Symlinks {
+    pub fn from_options(options: &Opt) -> Symlinks {
+        // The flags override each other, so at most one is set:
+        if options.dereference {
+            Symlinks::Always
+        } else if options.no_dereference {
+            Symlinks::Never
+        } else {
+            Symlinks::CommandLine
+        }
+    }
+}
+
+/// Every file under `dir`, in order by name, leaving out what `excludes` matches. Excluded
+/// directories aren't descended into. Symbolic links are skipped unless `follow_links` is set.
+/// With `max_depth`, only files that many levels down or fewer are found; those directly in
+/// `dir` are 1 level down.
+pub fn walk(
+    dir: &Path,
+    excludes: &Excludes,
+    follow_links: bool,
+    max_depth: Option<usize>,
+) -> Vec<PathBuf> {
+    let mut files = Vec::new();
+    let walker = WalkDir::new(dir)
+        .follow_links(follow_links)
+        .max_depth(max_depth.unwrap_or(usize::MAX))
+        .sort_by_file_name()
+        .into_iter()
+        .filter_entry(|entry| entry.depth() == 0 || !excludes.is_excluded(entry.path()));
+    for entry in walker {
+        match entry {
+            Ok(entry) if entry.file_type().is_dir() => {}
+            Ok(entry) if entry.path_is_symlink() && !follow_links => {
+                trace!("Skipping the symbolic link {}", entry.path().display());
+            }
+            Ok(entry) => files.push(entry.into_path()),
+            Err(err) => {
+                let path = err.path().unwrap_or(dir).to_owned();
+                eprintln!("{}: {}", path.display(), err);
+            }
+        }
+    }
+    files
+}
+
+/// Glob patterns of paths to leave out while expanding patterns and walking directories. A
+/// pattern without a slash is matched against file names at any depth, like `*.tmp` or `.git`;
+/// one with a slash is matched against the whole path.
+#[derive(Debug, Default)]
+pub struct Excludes {
+    patterns: Vec<Pattern>,
+}
+
+impl Excludes {
+    /// Combine the patterns with those in `exclude_from`, which has one per line. Blank lines
+    /// and lines starting with `#` are skipped there.
+    pub fn new(patterns: &[String], exclude_from: Option<&Path>) -> Result<Excludes> {
+        let mut all: Vec<String> = patterns.to_vec();
+        if let Some(exclude_from) = exclude_from {
+            let contents = fs::read_to_string(exclude_from)
+                .with_context(|| format!("Could not read {}", exclude_from.display()))?;
+            all.extend(
+                contents
+                    .lines()
+                    .map(str::trim_end)
+                    .filter(|line| !line.is_empty() && !line.starts_with('#'))
+                    .map(str::to_owned),
+            );
+        }
+        let patterns = all
+            .iter()
+            .map(|pattern| {
+                // A trailing slash, as in `target/`, means the directory itself:
+                let pattern = pattern.strip_suffix('/').unwrap_or(pattern);
+                Pattern::new(pattern).with_context(|| format!("Invalid pattern {}", pattern))
+            })
+            .collect::<Result<_>>()?;
+        Ok(Excludes { patterns })
+    }
+
+    pub fn is_excluded(&self, path: &Path) -> bool {
+        let name = path.file_name().map(Path::new);
+        self.patterns.iter().any(|pattern| {
+            if pattern.as_str().contains('/') {
+                pattern.matches_path(path)
+            } else {
+                name.is_some_and(|name| pattern.matches_path(name))
+            }
+        })
+    }
+}
+
+/// Expand a glob pattern like `data/**/*.bin` into the files it matches, in order, for callers
+/// that don't go through a shell. A path that exists is never treated as a pattern, and a
+/// pattern that matches nothing is kept as it is, like the shell does, so it's reported as
+/// missing.
+pub fn expand_glob(path: &Path) -> Result<Vec<PathBuf>> {
+    let is_pattern = path
+        .as_os_str()
+        .as_encoded_bytes()
+        .iter()
+        .any(|byte| matches!(byte, b'*' | b'?' | b'['));
+    if !is_pattern || path.symlink_metadata().is_ok() {
+        return Ok(vec![path.to_owned()]);
+    }
+    let Some(pattern) = path.to_str() else {
+        // The glob crate only handles UTF-8 patterns:
+        return Ok(vec![path.to_owned()]);
+    };
+
+    let mut matches = Vec::new();
+    for entry in glob::glob(pattern).with_context(|| format!("Invalid pattern {}", pattern))? {
+        let entry = entry?;
+        // `**` matches directories too, but only files can be hashed:
+        if !entry.is_dir() {
+            matches.push(entry);
+        }
+    }
+    if matches.is_empty() {
+        debug!("{} matched no files", pattern);
+        matches.push(path.to_owned());
+    }
+    Ok(matches)
+}
+
+/// Read a list of paths, one per line, or separated by NUL if `null` is set, as `find -print0`
+/// writes them. The path `-` means stdin. Empty entries are skipped.
+pub fn read_file_list(list: &Path, null: bool) -> Result<Vec<PathBuf>> {
+    let contents = if list == Path::new("-") {
+        let mut contents = Vec::new();
+        io::stdin().lock().read_to_end(&mut contents)?;
+        contents
+    } else {
+        fs::read(list).with_context(|| format!("Could not read {}", list.display()))?
+    };
+    let separator = if null { b'\0' } else { b'\n' };
+    Ok(contents
+        .split(|&byte| byte == separator)
+        .filter(|entry| !entry.is_empty())
+        .map(path_from_bytes)
+        .collect())
+}
@@ -1,322 +1,4912 @@
 use std::{
-    fs::{File, OpenOptions},
+    alloc::{self, Layout},
+    collections::HashMap,
+    ffi::OsString,
+    fs::File,
+    io::{self, Read},
     ops::{Deref, DerefMut},
-    os::unix::prelude::OpenOptionsExt,
     path::{Path, PathBuf},
+    ptr::NonNull,
     slice,
+    str::FromStr,
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        mpsc::channel,
+    },
+    thread,
+    time::{Duration, SystemTime},
 };
 
+use anyhow::{anyhow, Result};
+#[cfg(target_os = "linux")]
+use io_uring::{opcode, types, IoUring};
 #[allow(unused_imports)]
 use log::{debug, error, info, trace, warn};
+#[cfg(not(target_os = "linux"))]
+use std::io::{Seek, SeekFrom};
+#[cfg(unix)]
+use std::{ffi::OsStr, os::unix::ffi::OsStrExt};
+#[cfg(target_os = "linux")]
+use std::{
+    fs::OpenOptions,
+    os::unix::{
+        io::{AsRawFd, FromRawFd},
+        prelude::OpenOptionsExt,
+    },
+    pin::Pin,
+    sync::{Arc, OnceLock},
+};
 use structopt::StructOpt;
 
+pub use algorithm::{HashAlgorithm, Hasher};
+use archive::ArchiveFormat;
+use bench::BenchFormat;
+pub use cancel::CancelToken;
+use check::CheckOptions;
+pub use config::{Config, RingOptions};
+pub use error::ChecksumError;
+use input::SortOrder;
+use record::OutputFormat;
+pub use session::{ChecksumSession, StrategySession};
+pub use sink::ResultSink;
+
+pub mod algorithm;
+pub mod archive;
+pub mod async_stream;
+pub mod bench;
+pub mod byte_cmp;
+pub mod cache;
+pub mod cancel;
+pub mod check;
+pub mod combined;
+pub mod compare;
+pub mod config;
+#[cfg(feature = "sqlite")]
+pub mod db;
+pub mod diagnose;
+pub mod duplicates;
+pub mod error;
+#[cfg(target_os = "linux")]
+pub mod evented;
+pub mod ffi;
+pub mod hard_links;
+#[cfg(target_os = "linux")]
+pub mod hash_pool;
+pub mod input;
+pub mod manifest;
+pub mod midstate;
+pub mod numa;
+pub mod output;
+#[cfg(target_os = "linux")]
+pub mod probe;
+pub mod progress;
+pub mod record;
+pub mod reflinks;
+#[cfg(target_os = "linux")]
+pub mod ring;
+pub mod self_test;
+pub mod session;
+#[cfg(target_os = "linux")]
 pub mod simple_uring;
+pub mod sink;
+pub mod stream_verify;
+pub mod torrent;
+pub mod tree_digest;
+#[cfg(target_os = "linux")]
+pub mod watch;
+#[cfg(target_os = "linux")]
 pub mod with_fixed_buffers;
+#[cfg(target_os = "linux")]
+pub mod with_provided_buffers;
+#[cfg(target_os = "linux")]
 pub mod with_register_files;
 pub mod without_uring;
+pub mod xattr_cache;
 
+/// The number of reads the io_uring strategies keep in flight, unless `Config::ring_size` is
+/// another.
 pub const RING_SIZE: usize = 16;
+/// The most entries a ring can have.
+pub const MAX_RING_SIZE: usize = 32768;
+/// The most reads of one file the default strategy can keep in flight.
+pub const MAX_READS_PER_FILE: usize = 64;
+/// The most buffers that can be registered with a ring.
+pub const MAX_FIXED_BUFFERS: usize = 16384;
+/// The size of each read, unless `Config::read_size` is another.
 pub const MAX_READ_SIZE: usize = 4096 * 16;
+/// The largest read size that can be chosen. Registered buffers can't be larger.
+pub const READ_SIZE_LIMIT: usize = 1 << 30;
 pub const ALIGNMENT: usize = 4096;
+/// The size of the huge pages that `--huge-pages` backs fixed buffers with.
+pub const HUGE_PAGE_SIZE: usize = 2 << 20;
+/// File descriptors held back from the open-file cap for stdio, the ring itself, and anything
+/// else the process has open.
+#[cfg(target_os = "linux")]
+const RESERVED_FDS: u64 = 32;
+
+/// One way of reading and hashing files. Each strategy module has one, and `Config::strategy`
+/// holds the one a run uses, so a new strategy only has to implement this to be chosen.
+pub trait ChecksumStrategy: std::fmt::Debug + Sync {
+    /// The name it's chosen by, which is also how it's reported.
+    fn name(&self) -> &'static str;
+
+    /// Hash `files` with `config.algorithm`, sending each file's result through `tx` as it's
+    /// finished. An error, which is a `ChecksumError` unless the kernel lacks what the strategy
+    /// needs, means the strategy stopped, and the files it didn't send were never finished.
+    fn get_checksums(
+        &self,
+        files: Vec<PathBuf>,
+        tx: &dyn ResultSink<ChecksumResult>,
+        config: &Config,
+    ) -> Result<()>;
+
+    /// Set up what can be kept between batches of files, like a ring and its registered buffers,
+    /// for a `ChecksumSession`. None means there's nothing worth keeping, so each batch is run by
+    /// `get_checksums()`.
+    fn start_session(&self, _config: &Config) -> Result<Option<Box<dyn StrategySession>>> {
+        Ok(None)
+    }
+}
+
+/// Every strategy for computing checksums.
+#[cfg(target_os = "linux")]
+pub const STRATEGIES: &[&dyn ChecksumStrategy] = &[
+    &without_uring::NoUring,
+    &simple_uring::SimpleUring,
+    &with_register_files::RegisteredFiles,
+    &with_fixed_buffers::FixedBuffers,
+    &with_provided_buffers::ProvidedBuffers,
+];
+/// Every strategy for computing checksums. Without io_uring, that's only the one that maps files.
+#[cfg(not(target_os = "linux"))]
+pub const STRATEGIES: &[&dyn ChecksumStrategy] = &[&without_uring::NoUring];
+
+/// The strategy `Config::default()` uses.
+#[cfg(target_os = "linux")]
+pub const DEFAULT_STRATEGY: &dyn ChecksumStrategy = &simple_uring::SimpleUring;
+/// The strategy `Config::default()` uses.
+#[cfg(not(target_os = "linux"))]
+pub const DEFAULT_STRATEGY: &dyn ChecksumStrategy = &without_uring::NoUring;
+
+/// The strategy in `STRATEGIES` with this name.
+pub fn strategy_by_name(name: &str) -> Option<&'static dyn ChecksumStrategy> {
+    STRATEGIES
+        .iter()
+        .copied()
+        .find(|strategy| strategy.name() == name)
+}
+
+/// What came of hashing one file.
+#[derive(Debug)]
+pub struct ChecksumResult {
+    pub path: PathBuf,
+    /// The digest, which is empty if the file failed
+    pub digest: Vec<u8>,
+    /// How many bytes of the file were hashed
+    pub bytes: u64,
+    /// How long the file took, from when it was started on to when it was finished
+    pub elapsed: Duration,
+    /// Why the file couldn't be hashed
+    pub error: Option<anyhow::Error>,
+}
+
+impl ChecksumResult {
+    pub fn is_ok(&self) -> bool {
+        self.error.is_none()
+    }
+
+    /// The digest, or the error that stopped the file from being hashed.
+    pub fn into_result(self) -> Result<Vec<u8>> {
+        match self.error {
+            Some(err) => Err(err),
+            None => Ok(self.digest),
+        }
+    }
+}
+
+/// Finish the digest of a file that a strategy is done with.
+impl From<(PathBuf, Result<Hasher>)> for ChecksumResult {
+    fn from((path, result): (PathBuf, Result<Hasher>)) -> Self {
+        match result {
+            Ok(ctx) => ChecksumResult {
+                path,
+                bytes: ctx.bytes(),
+                elapsed: ctx.elapsed(),
+                digest: ctx.finalize(),
+                error: None,
+            },
+            Err(err) => ChecksumResult {
+                path,
+                digest: Vec::new(),
+                bytes: 0,
+                elapsed: Duration::ZERO,
+                error: Some(err),
+            },
+        }
+    }
+}
+
+/// Hash `files` as `config` says, sending each file's result to `tx`, which is dropped once every
+/// file has a result. This splits the files between threads if the config asks for more than one
+/// job. A file named by several paths, like hard links, is only read once, and so are reflinked
+/// copies if `config.trust_reflinks` is set.
+pub fn get_checksums(
+    files: Vec<PathBuf>,
+    tx: impl ResultSink<ChecksumResult>,
+    config: &Config,
+) -> Result<()> {
+    let (files, links) = split_same_files(files, config);
+    if !links.is_empty() {
+        let tx = hard_links::LinkSink { inner: tx, links };
+        return get_checksums_once(files, tx, config);
+    }
+    get_checksums_once(files, tx, config)
+}
+
+/// Leave out the paths of `files` that are the same file as one named earlier, like hard links,
+/// and reflinked copies if `config.trust_reflinks` is set. Returns the files to hash, and the
+/// paths left out for each of them.
+fn split_same_files(
+    files: Vec<PathBuf>,
+    config: &Config,
+) -> (Vec<PathBuf>, HashMap<PathBuf, Vec<PathBuf>>) {
+    let (mut files, mut links) = hard_links::split_links(files);
+    if config.trust_reflinks {
+        let (to_hash, reflinks) = reflinks::split_reflinks(files);
+        files = to_hash;
+        links.extend(reflinks);
+    }
+    (files, links)
+}
+
+/// Hash each of `files`, including paths that are the same file.
+fn get_checksums_once(
+    files: Vec<PathBuf>,
+    tx: impl ResultSink<ChecksumResult>,
+    config: &Config,
+) -> Result<()> {
+    if config.jobs > 1 || config.numa.is_some() {
+        get_checksums_in_jobs(files, tx, config)
+    } else {
+        config.strategy.get_checksums(files, &tx, config)
+    }
+}
+
+/// Split `files` between `config.jobs` threads that each run the config's strategy, so each has
+/// its own ring and buffers. The results of every thread are sent through `tx`. With
+/// `config.numa`, each thread is bound to a NUMA node before it allocates its buffers. The rings
+/// share one set of kernel worker threads, unless `config.attach_wq` says which to share already.
+pub fn get_checksums_in_jobs(
+    files: Vec<PathBuf>,
+    tx: impl ResultSink<ChecksumResult>,
+    config: &Config,
+) -> Result<()> {
+    #[cfg(target_os = "linux")]
+    let shared_workers = match config.attach_wq {
+        None if config.jobs > 1 => ring::new_shared_workers(config),
+        _ => None,
+    };
+    #[cfg(target_os = "linux")]
+    let config = &Config {
+        attach_wq: shared_workers
+            .as_ref()
+            .map(|ring| ring.as_raw_fd())
+            .or(config.attach_wq),
+        ..config.clone()
+    };
+    let (jobs, numa, strategy) = (config.jobs, config.numa, config.strategy);
+    // Deal the files out in turn, so each thread gets a share of the large and small ones, in
+    // the order they were given:
+    let mut shards = vec![Vec::new(); jobs];
+    for (i, path) in files.into_iter().enumerate() {
+        shards[i % jobs].push(path);
+    }
+    let tx = &tx;
+    thread::scope(|scope| {
+        let handles: Vec<_> = shards
+            .into_iter()
+            .filter(|shard: &Vec<PathBuf>| !shard.is_empty())
+            .enumerate()
+            .map(|(job, shard)| {
+                scope.spawn(move || {
+                    if let Some(node) = numa.and_then(|numa| numa.node_for(job, &shard[0])) {
+                        debug!("Running job {} on NUMA node {}.", job, node);
+                        numa::bind_thread(node)?;
+                    }
+                    strategy.get_checksums(shard, tx, config)
+                })
+            })
+            .collect();
+        let mut result = Ok(());
+        for handle in handles {
+            let job_result = handle
+                .join()
+                .unwrap_or_else(|_| Err(anyhow!("A checksum thread panicked.")));
+            if result.is_ok() {
+                result = job_result;
+            }
+        }
+        result
+    })
+}
+
+/// Hash `files` as `config` says and pass each file's result to `callback` on the calling thread
+/// as it arrives, so the callback doesn't need to be `Send`. The reads happen on another thread,
+/// which waits for the callback only when it has nothing else to do.
+pub fn get_checksums_with<F>(files: Vec<PathBuf>, config: &Config, mut callback: F) -> Result<()>
+where
+    F: FnMut(ChecksumResult),
+{
+    let (tx, rx) = channel();
+    thread::scope(|scope| {
+        let handle = scope.spawn(move || get_checksums(files, tx, config));
+        for result in rx {
+            callback(result);
+        }
+        handle
+            .join()
+            .unwrap_or_else(|_| Err(anyhow!("A checksum thread panicked.")))
+    })
+}
+
+/// The fastest strategy the kernel can run, for `--strategy auto`: fixed-buffers, register-files,
+/// simple-uring or no-uring, in that order. The kernel is only probed once.
+#[cfg(target_os = "linux")]
+pub fn auto_strategy_name() -> &'static str {
+    static NAME: OnceLock<&'static str> = OnceLock::new();
+
+    NAME.get_or_init(|| {
+        let name = probe::Capabilities::detect().best_strategy();
+        debug!("Chose the {} strategy for this kernel.", name);
+        name
+    })
+}
+
+/// The fastest strategy that can run, for `--strategy auto`, which is no-uring without io_uring.
+#[cfg(not(target_os = "linux"))]
+pub fn auto_strategy_name() -> &'static str {
+    "no-uring"
+}
+
+/// The strategy `--strategy` chooses.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum StrategyChoice {
+    /// Plain io_uring, which is the default
+    Simple,
+    /// Files registered with the ring before they're read
+    RegisterFiles,
+    /// Reads into buffers registered with the ring, of files registered with it
+    FixedBuffers,
+    /// Reads into whichever buffer of a ring of them the kernel picks (Linux 5.19)
+    ProvidedBuffers,
+    /// No io_uring
+    NoUring,
+    /// The fastest of fixed-buffers, register-files, simple and no-uring the kernel can run
+    Auto,
+}
+
+impl StrategyChoice {
+    /// The values `--strategy` takes, in the order of the variants.
+    pub const NAMES: &'static [&'static str] = &[
+        "simple",
+        "register-files",
+        "fixed-buffers",
+        "provided-buffers",
+        "no-uring",
+        "auto",
+    ];
+
+    /// The value of `--strategy` that chooses this.
+    pub fn name(self) -> &'static str {
+        StrategyChoice::NAMES[self as usize]
+    }
+
+    /// The name of the strategy in `STRATEGIES` this chooses.
+    pub fn strategy_name(self) -> &'static str {
+        match self {
+            StrategyChoice::Simple => "simple-uring",
+            StrategyChoice::RegisterFiles => "register-files",
+            StrategyChoice::FixedBuffers => "fixed-buffers",
+            StrategyChoice::ProvidedBuffers => "provided-buffers",
+            StrategyChoice::NoUring => "no-uring",
+            StrategyChoice::Auto => auto_strategy_name(),
+        }
+    }
+}
+
+impl FromStr for StrategyChoice {
+    type Err = String;
+
+    fn from_str(s: &str) -> std::result::Result<Self, Self::Err> {
+        match s {
+            "simple" => Ok(StrategyChoice::Simple),
+            "register-files" => Ok(StrategyChoice::RegisterFiles),
+            "fixed-buffers" => Ok(StrategyChoice::FixedBuffers),
+            "provided-buffers" => Ok(StrategyChoice::ProvidedBuffers),
+            "no-uring" => Ok(StrategyChoice::NoUring),
+            "auto" => Ok(StrategyChoice::Auto),
+            _ => Err(format!(
+                "unknown strategy {:?}; use one of {}",
+                s,
+                StrategyChoice::NAMES.join(", ")
+            )),
+        }
+    }
+}
 
 #[derive(StructOpt)]
 pub struct Opt {
     #[structopt()]
-    /// The files to be checksummed.
+    /// The files to be checksummed. Glob patterns like 'data/**/*.bin' are expanded, for when
+    /// there's no shell to do it. With no files, stdin is hashed and shown as -.
     pub files: Vec<PathBuf>,
 
-    /// Use the io_uring feature of pre-registering files to be read before the read is requested.
+    /// Hash every file under the directories that are named.
+    #[structopt(short, long)]
+    pub recursive: bool,
+
+    /// Only hash files at most N levels below the directories that are named. Files directly in
+    /// them are 1 level down.
+    #[structopt(long, value_name = "N", requires = "recursive")]
+    pub max_depth: Option<usize>,
+
+    /// Don't follow any symbolic links: skip those found while recursing, and report those that
+    /// are named.
+    #[structopt(short = "P", long, overrides_with_all = &["dereference", "dereference-args"])]
+    pub no_dereference: bool,
+
+    /// Follow symbolic links that are named, but skip those found while recursing. This is the
+    /// default.
+    #[structopt(short = "H", long, overrides_with_all = &["dereference", "no-dereference"])]
+    pub dereference_args: bool,
+
+    /// Follow every symbolic link, including those found while recursing.
+    #[structopt(short = "L", long, overrides_with_all = &["dereference-args", "no-dereference"])]
+    pub dereference: bool,
+
+    /// Skip files and directories that match GLOB while recursing or expanding patterns. A
+    /// pattern without a slash matches names at any depth, like '*.tmp' or '.git'. This can be
+    /// repeated.
+    #[structopt(long, value_name = "GLOB", number_of_values = 1)]
+    pub exclude: Vec<String>,
+
+    /// Skip what matches the patterns in FILE, one per line, like --exclude.
+    #[structopt(long, value_name = "FILE")]
+    pub exclude_from: Option<PathBuf>,
+
+    /// Read sockets, devices, and pipes found while recursing until they end instead of skipping
+    /// them. Pipes that are named are always read this way.
     #[structopt(long)]
+    pub allow_special: bool,
+
+    /// Also hash the files listed in FILE, one per line. Use - to read the list from stdin.
+    #[structopt(long, value_name = "FILE")]
+    pub files_from: Option<PathBuf>,
+
+    /// The list given to --files-from is separated by NUL instead of newlines, as `find
+    /// -print0` writes it.
+    #[structopt(short = "0", long, requires = "files-from")]
+    pub null: bool,
+
+    /// Skip files smaller than SIZE bytes. SIZE may end in K, M, G or T, as in 1M.
+    #[structopt(long, value_name = "SIZE", parse(try_from_str = input::parse_size))]
+    pub min_size: Option<u64>,
+
+    /// Skip files larger than SIZE bytes. SIZE may end in K, M, G or T.
+    #[structopt(long, value_name = "SIZE", parse(try_from_str = input::parse_size))]
+    pub max_size: Option<u64>,
+
+    /// Only hash files modified after TIME, which is a date like 2024-05-01 or '2024-05-01
+    /// 12:30:00' in UTC, @ and seconds since the epoch, or a file whose modification time is
+    /// used.
+    #[structopt(long, value_name = "TIME", parse(try_from_str = input::parse_time))]
+    pub newer_than: Option<SystemTime>,
+
+    /// Hash files in this order instead of the order they're given. "physical" orders them by
+    /// where they start on disk (or by inode number), which saves seeking on spinning disks.
+    #[structopt(long, value_name = "ORDER")]
+    pub sort: Option<SortOrder>,
+
+    /// How to read the files. "simple" is plain io_uring. "register-files" registers files with
+    /// the ring before they're read. "fixed-buffers" also reads into buffers registered with the
+    /// ring, fewer or smaller ones if they don't fit in the locked memory limit (ulimit -l).
+    /// "provided-buffers" lets the kernel pick a free buffer from a ring of them for each read
+    /// (Linux 5.19). "no-uring" doesn't use io_uring. "auto" uses the fastest of fixed-buffers,
+    /// register-files, simple and no-uring that the kernel supports.
+    #[structopt(
+        long,
+        value_name = "STRATEGY",
+        possible_values = StrategyChoice::NAMES,
+        conflicts_with_all = &[
+            "pre-register-files",
+            "use-fixed-buffers",
+            "use-provided-buffers",
+            "auto",
+            "no-uring",
+        ]
+    )]
+    pub strategy: Option<StrategyChoice>,
+
+    /// The same as --strategy register-files.
+    #[structopt(long, hidden = true)]
     pub pre_register_files: bool,
 
-    /// Use the io_uring feature of reading into fixed position buffers.
-    #[structopt(long)]
+    /// The same as --strategy fixed-buffers.
+    #[structopt(long, hidden = true)]
     pub use_fixed_buffers: bool,
 
-    /// Compute checksums without the io_uring feature.
-    #[structopt(long, conflicts_with_all = &["pre-register-files", "use-fixed-buffers", "o-direct"])]
+    /// The same as --strategy provided-buffers.
+    #[structopt(
+        long,
+        hidden = true,
+        conflicts_with_all = &["pre-register-files", "use-fixed-buffers"]
+    )]
+    pub use_provided_buffers: bool,
+
+    /// The same as --strategy auto.
+    #[structopt(
+        long,
+        hidden = true,
+        conflicts_with_all = &[
+            "pre-register-files",
+            "use-fixed-buffers",
+            "use-provided-buffers",
+            "no-uring",
+        ]
+    )]
+    pub auto: bool,
+
+    /// The same as --strategy no-uring.
+    #[structopt(
+        long,
+        hidden = true,
+        conflicts_with_all = &[
+            "pre-register-files",
+            "use-fixed-buffers",
+            "use-provided-buffers",
+            "o-direct",
+        ]
+    )]
     pub no_uring: bool,
 
-    /// Open files with the O_DIRECT flag for performance.
+    /// Have a kernel thread poll for reads to submit, so submitting them doesn't take a system
+    /// call. The thread sleeps after IDLE_MS milliseconds without reads (default 1000). Rings
+    /// are set up without it if the kernel or privileges don't allow it.
+    #[structopt(
+        long,
+        value_name = "IDLE_MS",
+        require_equals = true,
+        conflicts_with = "no-uring"
+    )]
+    pub sqpoll: Option<Option<u32>>,
+
+    /// Poll the device for completed reads instead of waiting for interrupts, which cuts the
+    /// latency of each read on fast NVMe drives. The drive needs poll queues, e.g. from the
+    /// nvme module's poll_queues parameter.
+    #[structopt(long, requires = "o-direct")]
+    pub iopoll: bool,
+
+    /// Open files through the ring, so waiting for slow opens, as on network filesystems,
+    /// overlaps with reading other files. On Linux 5.15 and later, files are opened straight into
+    /// the ring's file table and take no descriptors. Only the default strategy can do this.
+    #[structopt(
+        long,
+        conflicts_with_all = &[
+            "pre-register-files",
+            "use-fixed-buffers",
+            "use-provided-buffers",
+            "no-uring",
+            "auto",
+        ]
+    )]
+    pub async_open: bool,
+
+    /// Drop each file's pages from the page cache after hashing it, so hashing a whole disk
+    /// doesn't push everything else out of the cache, such as the working set of other services.
+    /// Files are also read with sequential read-ahead.
+    #[structopt(long, visible_alias = "drop-cache")]
+    pub no_cache_pollution: bool,
+
+    /// Don't read a file whose extents are all shared with a file named earlier, as with
+    /// reflinked copies and snapshots on btrfs and XFS, and give it that file's checksum. The
+    /// extents are looked up with FIEMAP before reading, so a file that's changed in between may
+    /// get an out of date checksum. Files whose extents are compressed aren't trusted.
+    #[structopt(long)]
+    pub trust_reflinks: bool,
+
+    /// Try each read without blocking first, so data already in the page cache is read right away
+    /// instead of by a kernel worker thread. Data that isn't cached is read again as usual. This
+    /// suits checking files that were read recently.
+    #[structopt(long, conflicts_with_all = &["no-uring", "use-provided-buffers"])]
+    pub nowait_first: bool,
+
+    /// Back the fixed buffers with 2 MiB huge pages, which take fewer entries to describe to the
+    /// kernel and in the TLB when the read size is megabytes. Transparent huge pages are used if
+    /// none are reserved in /proc/sys/vm/nr_hugepages. Only --strategy fixed-buffers uses them.
+    #[structopt(long, conflicts_with_all = &["no-uring", "use-provided-buffers"])]
+    pub huge_pages: bool,
+
+    /// How many buffers --strategy fixed-buffers registers, instead of one for each of the
+    /// --ring-size entries. With at least two for each entry, each file gets two, so its next read
+    /// is in flight while the last is hashed. Either way, as many files are read at once as there
+    /// are buffers for, which can be more than the ring holds.
+    #[structopt(
+        long,
+        value_name = "N",
+        parse(try_from_str = parse_fixed_buffers),
+        conflicts_with_all = &["no-uring", "use-provided-buffers", "pre-register-files"]
+    )]
+    pub fixed_buffers: Option<usize>,
+
+    /// How many reads to keep in flight at once. This must be a power of two up to 32768. More
+    /// helps with many small files or fast drives; fewer saves memory.
+    #[structopt(
+        long,
+        value_name = "N",
+        default_value = "16",
+        parse(try_from_str = parse_ring_size)
+    )]
+    pub ring_size: usize,
+
+    /// Split the files between this many threads, each with its own ring and buffers. One ring
+    /// can't keep up with striped or multi-device storage; each thread gets --ring-size reads.
+    /// The rings share the kernel's worker threads, so there aren't more of them with more jobs.
+    #[structopt(
+        long,
+        value_name = "N",
+        default_value = "1",
+        parse(try_from_str = parse_jobs)
+    )]
+    pub jobs: usize,
+
+    /// Run each thread of --jobs on a NUMA node, with its buffers in that node's memory. "auto"
+    /// picks the node of the storage controller each thread's files are on, or spreads the
+    /// threads over the nodes when that isn't known; "node:N" uses node N for every thread.
+    #[structopt(long, value_name = "PLACEMENT")]
+    pub numa: Option<numa::Placement>,
+
+    /// How many bytes each read asks for, which is also the size of each read buffer. Larger
+    /// reads, like 1M to 8M, suit fast drives. This must be a multiple of 4096 so reads stay
+    /// aligned for O_DIRECT. BYTES may end in K, M or G.
+    #[structopt(
+        long,
+        value_name = "BYTES",
+        default_value = "64K",
+        parse(try_from_str = parse_read_size)
+    )]
+    pub read_size: usize,
+
+    /// How many reads of each file to keep in flight, hashing the data in order as they complete.
+    /// The default is 1. More speeds up large files on fast drives. The --ring-size reads in
+    /// flight are shared out, so fewer files are read at once. Only the default strategy can do
+    /// this.
+    #[structopt(
+        long,
+        value_name = "N",
+        parse(try_from_str = parse_reads_per_file),
+        conflicts_with_all = &[
+            "pre-register-files",
+            "use-fixed-buffers",
+            "use-provided-buffers",
+            "no-uring",
+            "auto",
+        ]
+    )]
+    pub reads_per_file: Option<usize>,
+
+    /// Hash on this many worker threads, while the main thread only submits reads. This helps
+    /// when hashing is slower than the drive, as with MD5 on NVMe. Each file is hashed by one
+    /// thread. Only the default strategy can do this.
+    #[structopt(
+        long,
+        value_name = "N",
+        conflicts_with_all = &[
+            "pre-register-files",
+            "use-fixed-buffers",
+            "use-provided-buffers",
+            "no-uring",
+            "auto",
+        ]
+    )]
+    pub hash_threads: Option<usize>,
+
+    /// Give up on a read that hasn't finished after this many seconds, as on a failing disk or a
+    /// hung network server, and report its file as failed. Other files are still hashed.
+    #[structopt(
+        long,
+        value_name = "SECONDS",
+        parse(try_from_str = parse_read_timeout),
+        conflicts_with = "no-uring"
+    )]
+    pub read_timeout: Option<Duration>,
+
+    /// Open files with the O_DIRECT flag for performance. Files on filesystems that reject it,
+    /// like tmpfs on older kernels, are opened without it.
     #[structopt(long)]
     pub o_direct: bool,
+
+    /// The hash algorithm: md5, sha256, sha384, sha512, blake3, blake2b, blake2s, sm3, xxh3,
+    /// xxh128, crc32c, crc64-xz or crc64-ecma. The output has the format of the matching
+    /// coreutils program, e.g. sha256sum or b2sum.
+    #[structopt(short, long, default_value = "md5")]
+    pub algorithm: HashAlgorithm,
+
+    /// Also print the digest of each block of SIZE bytes, as comment lines that --check
+    /// --diagnose and `watch --diagnose` use to locate changes. SIZE may end in K, M, G or T.
+    /// Each file is read once, hashing it as a whole and block by block, but files are read one
+    /// at a time.
+    #[structopt(
+        long,
+        visible_alias = "chunk-hashes",
+        value_name = "SIZE",
+        parse(try_from_str = parse_block_size)
+    )]
+    pub block_digests: Option<u64>,
+
+    /// Overwrite read buffers with zeros after each file, so file contents don't linger in
+    /// memory. The digest state is always wiped in builds with this feature.
+    #[cfg(feature = "zeroize")]
+    #[structopt(long)]
+    pub zeroize: bool,
+
+    /// Cache each file's checksum in a user.checksum extended attribute with its size and
+    /// modification time, and don't read files whose cached checksum is for the same size and
+    /// modification time. Linux only.
+    #[structopt(long, conflicts_with = "check")]
+    pub xattr_cache: bool,
+
+    /// With --xattr-cache, read every file anyway, and fail those whose checksum changed although
+    /// their size and modification time didn't, which means they're corrupt.
+    #[structopt(long, requires = "xattr-cache")]
+    pub verify_cache: bool,
+
+    /// Reuse the checksums in OLD_MANIFEST, a list written by an earlier run with this option,
+    /// for files whose size and modification time haven't changed, and only read the others.
+    /// Each file's size and modification time are written to the new list as comment lines. If
+    /// OLD_MANIFEST doesn't exist, every file is read.
+    #[structopt(
+        long,
+        value_name = "OLD_MANIFEST",
+        conflicts_with_all = &["check", "xattr-cache", "archive", "zero"]
+    )]
+    pub incremental: Option<PathBuf>,
+
+    /// Record each file's size, modification time and checksum in the SQLite database FILE, with
+    /// when it was last read and found to match. Every file is read, and one whose checksum
+    /// changed although its size and modification time didn't fails as corrupt.
+    #[cfg(feature = "sqlite")]
+    #[structopt(
+        long,
+        value_name = "FILE",
+        conflicts_with_all = &["check", "xattr-cache", "incremental", "combined", "torrent-pieces"]
+    )]
+    pub db: Option<PathBuf>,
+
+    /// With --db, only read files that are new or whose size or modification time changed.
+    #[cfg(feature = "sqlite")]
+    #[structopt(long, requires = "db")]
+    pub update: bool,
+
+    /// Write the checksums to FILE instead of standard output. The file is only replaced once
+    /// every file has been read, so it's never left half written.
+    #[structopt(short, long, value_name = "FILE", conflicts_with = "check")]
+    pub output: Option<PathBuf>,
+
+    /// Show the files and bytes hashed so far on stderr.
+    #[structopt(long, conflicts_with = "check")]
+    pub progress: bool,
+
+    /// After the checksums, print the bytes hashed, the time taken, the number of reads and the
+    /// settings used to stderr.
+    #[structopt(long, conflicts_with = "check")]
+    pub stats: bool,
+
+    /// Print each file's result as "json", one object per line, or "csv", with the file's size,
+    /// modification time, permissions and owner looked up as it's hashed, so the output is an
+    /// inventory as well. Files that fail are included with the error.
+    #[structopt(
+        long,
+        value_name = "FORMAT",
+        conflicts_with_all = &[
+            "check",
+            "archive",
+            "combined",
+            "incremental",
+            "block-digests",
+            "zero",
+        ]
+    )]
+    pub format: Option<OutputFormat>,
+
+    /// End each output line with NUL instead of newline, so file names that contain newlines can
+    /// be passed to `xargs -0`.
+    #[structopt(short, long, conflicts_with_all = &["check", "block-digests"])]
+    pub zero: bool,
+
+    /// After the checksums, print a comment line with the checksum of every file's contents
+    /// concatenated in the order they're given, as if they were one stream, like the parts of a
+    /// split dump. Files are read one at a time, in order, without the io_uring strategies.
+    #[structopt(
+        long,
+        conflicts_with_all = &["check", "archive", "incremental", "xattr-cache", "zero", "sort"]
+    )]
+    pub combined: bool,
+
+    /// Instead of printing each file's checksum, print the SHA-1 hash of each LENGTH-byte piece of
+    /// the files taken together in the order they're given, one per line in hexadecimal, as the
+    /// pieces of a BitTorrent torrent of those files. LENGTH is a power of two of at least 16K,
+    /// like 256K or 1M.
+    #[structopt(
+        long,
+        value_name = "LENGTH",
+        parse(try_from_str = parse_piece_length),
+        conflicts_with_all = &[
+            "check",
+            "archive",
+            "combined",
+            "incremental",
+            "xattr-cache",
+            "block-digests",
+            "zero",
+            "progress",
+            "stats",
+            "match-list",
+            "format",
+            "sort",
+        ]
+    )]
+    pub torrent_pieces: Option<u64>,
+
+    /// Only print the files whose checksum is in FILE, a list of known checksums like a hash set
+    /// of malware, with a count of them on stderr. Each line of FILE starts with a checksum, so a
+    /// checksum list can be used too.
+    #[structopt(
+        long,
+        value_name = "FILE",
+        conflicts_with_all = &["check", "archive", "incremental", "manifest-per-dir"]
+    )]
+    pub match_list: Option<PathBuf>,
+
+    /// Also write a checksum file called NAME, like .md5sums, into each directory with files that
+    /// were hashed, listing those files by name so it can be checked from inside the directory.
+    /// Existing files called NAME are replaced, and aren't hashed themselves.
+    #[structopt(
+        long,
+        value_name = "NAME",
+        parse(from_os_str),
+        conflicts_with_all = &["check", "archive", "zero"]
+    )]
+    pub manifest_per_dir: Option<OsString>,
+
+    /// Instead of printing each file's checksum, print groups of files with the same contents,
+    /// largest first, and how many bytes could be freed by keeping one of each. Only files that
+    /// have the same size as another are read. Empty files are left out.
+    #[structopt(
+        long,
+        conflicts_with_all = &[
+            "check",
+            "archive",
+            "incremental",
+            "xattr-cache",
+            "block-digests",
+            "zero",
+            "manifest-per-dir",
+            "combined",
+            "torrent-pieces",
+            "match-list",
+            "format",
+        ]
+    )]
+    pub find_duplicates: bool,
+
+    /// Instead of printing each file's checksum, print one digest for each directory that's
+    /// named, folded from the checksums and relative paths of every file under it like the root
+    /// of a Merkle tree. Copies of a directory have the same digest wherever they are.
+    #[structopt(
+        long,
+        conflicts_with_all = &[
+            "check",
+            "archive",
+            "files-from",
+            "find-duplicates",
+            "incremental",
+            "xattr-cache",
+            "block-digests",
+            "manifest-per-dir",
+            "zero",
+            "progress",
+            "stats",
+            "combined",
+            "torrent-pieces",
+            "match-list",
+            "format",
+        ]
+    )]
+    pub tree_digest: bool,
+
+    /// Treat each file as an archive of this format and hash its members instead, printing
+    /// them as ARCHIVE//MEMBER. Only "tar" is supported, without compression.
+    #[structopt(
+        long,
+        value_name = "FORMAT",
+        conflicts_with_all = &["check", "block-digests", "zero", "progress", "stats"]
+    )]
+    pub archive: Option<ArchiveFormat>,
+
+    /// Read checksums from FILE and check them, like `md5sum --check`. The exit status is 1 if
+    /// any file doesn't match or can't be read.
+    #[structopt(
+        short = "c",
+        long,
+        value_name = "FILE",
+        conflicts_with_all = &["files", "files-from", "block-digests"]
+    )]
+    pub check: Option<PathBuf>,
+
+    #[structopt(flatten)]
+    pub check_options: CheckOptions,
+
+    #[structopt(subcommand)]
+    pub command: Option<Command>,
+}
+
+impl Opt {
+    /// The strategy chosen by --strategy or the older flags it replaces, which is simple unless
+    /// one is given.
+    pub fn strategy_choice(&self) -> StrategyChoice {
+        if let Some(choice) = self.strategy {
+            choice
+        } else if self.auto {
+            StrategyChoice::Auto
+        } else if self.no_uring {
+            StrategyChoice::NoUring
+        } else if self.use_provided_buffers {
+            StrategyChoice::ProvidedBuffers
+        } else if self.use_fixed_buffers {
+            StrategyChoice::FixedBuffers
+        } else if self.pre_register_files {
+            StrategyChoice::RegisterFiles
+        } else {
+            StrategyChoice::Simple
+        }
+    }
+
+    /// The name of the strategy the flags select. Without io_uring, that's always no-uring.
+    pub fn strategy_name(&self) -> &'static str {
+        if cfg!(target_os = "linux") {
+            self.strategy_choice().strategy_name()
+        } else {
+            auto_strategy_name()
+        }
+    }
+
+    /// Check that the options that only some strategies can use aren't given with
+    /// --strategy for another. The older flags are checked as they're parsed, but which value
+    /// --strategy has is only known after.
+    pub fn check_strategy(&self) -> std::result::Result<(), String> {
+        use StrategyChoice::*;
+
+        let Some(choice) = self.strategy else {
+            return Ok(());
+        };
+        let buffers_of_its_own = !matches!(choice, NoUring | ProvidedBuffers);
+        // Each option, whether it's given, and whether the strategy can use it:
+        let options = [
+            ("--async-open", self.async_open, choice == Simple),
+            (
+                "--reads-per-file",
+                self.reads_per_file.is_some(),
+                choice == Simple,
+            ),
+            (
+                "--hash-threads",
+                self.hash_threads.is_some(),
+                choice == Simple,
+            ),
+            ("--o-direct", self.o_direct, choice != NoUring),
+            ("--sqpoll", self.sqpoll.is_some(), choice != NoUring),
+            (
+                "--read-timeout",
+                self.read_timeout.is_some(),
+                choice != NoUring,
+            ),
+            ("--nowait-first", self.nowait_first, buffers_of_its_own),
+            ("--huge-pages", self.huge_pages, buffers_of_its_own),
+            (
+                "--fixed-buffers",
+                self.fixed_buffers.is_some(),
+                buffers_of_its_own && choice != RegisterFiles,
+            ),
+        ];
+        match options.iter().find(|(_, given, usable)| *given && !usable) {
+            Some((flag, _, _)) => Err(format!(
+                "{} can't be used with --strategy {}",
+                flag,
+                choice.name()
+            )),
+            None => Ok(()),
+        }
+    }
+
+    /// The settings the flags choose.
+    pub fn config(&self) -> Config {
+        Config::default()
+            .strategy(self.strategy_name())
+            .algorithm(self.algorithm)
+            .o_direct(self.o_direct)
+            .ring_size(self.ring_size)
+            .read_size(self.read_size)
+            .reads_per_file(self.reads_per_file.unwrap_or(1))
+            .hash_threads(self.hash_threads.unwrap_or(0))
+            .read_timeout(self.read_timeout)
+            .async_open(self.async_open)
+            .nowait_first(self.nowait_first)
+            .huge_pages(self.huge_pages)
+            .fixed_buffers(self.fixed_buffers)
+            .no_cache_pollution(self.no_cache_pollution)
+            .trust_reflinks(self.trust_reflinks)
+            .ring_options(RingOptions {
+                sqpoll_idle: self
+                    .sqpoll
+                    .map(|idle| idle.unwrap_or(config::DEFAULT_SQPOLL_IDLE_MS)),
+                iopoll: self.iopoll,
+            })
+            .jobs(self.jobs)
+            .numa(self.numa)
+    }
+}
+
+#[derive(StructOpt)]
+pub enum Command {
+    /// Time every strategy on the same files.
+    Bench {
+        /// How many times to hash the files with each strategy.
+        #[structopt(long, default_value = "5")]
+        iterations: usize,
+
+        /// Drop the files from the page cache before each iteration, so every iteration reads
+        /// from storage instead of memory. Linux only.
+        #[structopt(long)]
+        drop_caches: bool,
+
+        /// How to print the results: "table" or "json".
+        #[structopt(long, default_value = "table")]
+        format: BenchFormat,
+
+        /// Also write the results in criterion's layout under this directory, for tools that
+        /// compare criterion baselines.
+        #[structopt(long)]
+        criterion_dir: Option<PathBuf>,
+
+        /// The files to hash.
+        files: Vec<PathBuf>,
+    },
+
+    /// Check every strategy against known test vectors, to validate this kernel and filesystem.
+    SelfTest {
+        /// The directory to write test files into. Defaults to the system temporary directory.
+        #[structopt(long)]
+        dir: Option<PathBuf>,
+    },
+
+    /// Compare two directory trees by content, like `diff -rq`, and print the files that differ
+    /// or are only in one of them. Exits with status 1 unless the trees are the same.
+    Compare {
+        /// Also print the files that are the same.
+        #[structopt(short = "s", long)]
+        report_identical_files: bool,
+
+        dir_a: PathBuf,
+
+        dir_b: PathBuf,
+    },
+
+    /// Compare two files byte by byte, like cmp, and print the first byte that differs and how
+    /// many chunks differ. Both files are read at once. Exits with status 1 unless they're the
+    /// same.
+    Cmp {
+        /// How many bytes each chunk has, which is also the size of each read. SIZE may end in
+        /// K, M or G.
+        #[structopt(
+            long,
+            value_name = "SIZE",
+            default_value = "1M",
+            parse(try_from_str = parse_read_size)
+        )]
+        chunk_size: usize,
+
+        file_a: PathBuf,
+
+        file_b: PathBuf,
+    },
+
+    /// Verify files against a checksum list whenever they're opened under a directory.
+    /// Requires root.
+    Watch {
+        /// The checksum list, in the format of the checksum program of --algorithm, like md5sum.
+        #[structopt(long)]
+        manifest: PathBuf,
+
+        /// Make opens of files that don't match the checksum list fail.
+        #[structopt(long)]
+        deny: bool,
+
+        /// When a file doesn't match, re-read it block by block to find the first block that
+        /// changed. The checksum list must have been made with --block-digests.
+        #[structopt(long)]
+        diagnose: bool,
+
+        /// The directory tree to watch.
+        dir: PathBuf,
+    },
+
+    /// Print which io_uring features this kernel supports, the limits that matter, and which
+    /// strategies can run.
+    Probe,
 }
 
-#[repr(C, align(4096))]
 #[derive(std::fmt::Debug)]
-/// Aligned buffer. Put this in a box to avoid overfilling the stack.
+/// Aligned buffer, allocated on the heap with room for one read.
 pub struct AlignedBuffer {
-    buf: [u8; MAX_READ_SIZE],
-    // A lot of space is wasted by storing such a small value with 4096 byte alignment:
+    buf: NonNull<u8>,
+    capacity: usize,
+    len: usize,
+    /// The mapping the buffer was carved from, if it wasn't allocated on the heap
+    #[cfg(target_os = "linux")]
+    mapping: Option<Arc<Mapping>>,
+}
+
+/// Memory mapped for several buffers, which is unmapped when the last of them is dropped.
+#[cfg(target_os = "linux")]
+#[derive(std::fmt::Debug)]
+struct Mapping {
+    ptr: NonNull<u8>,
     len: usize,
 }
 
+// The mapping is only written through the buffers carved from it:
+#[cfg(target_os = "linux")]
+unsafe impl Send for Mapping {}
+#[cfg(target_os = "linux")]
+unsafe impl Sync for Mapping {}
+
+#[cfg(target_os = "linux")]
+impl Drop for Mapping {
+    fn drop(&mut self) {
+        // unsafe: the buffers that pointed into the mapping are gone:
+        unsafe { libc::munmap(self.ptr.as_ptr() as *mut _, self.len) };
+    }
+}
+
+// The buffer owns its allocation, like a Vec:
+unsafe impl Send for AlignedBuffer {}
+unsafe impl Sync for AlignedBuffer {}
+
 impl AlignedBuffer {
+    /// A buffer of the default read size, `MAX_READ_SIZE`.
     pub fn new() -> AlignedBuffer {
+        Self::with_capacity(MAX_READ_SIZE)
+    }
+
+    /// A buffer of `capacity` bytes, which must not be zero.
+    pub fn with_capacity(capacity: usize) -> AlignedBuffer {
+        let layout = Self::layout(capacity);
+        // unsafe: the layout has a non-zero size:
+        let buf = unsafe { alloc::alloc_zeroed(layout) };
+        let Some(buf) = NonNull::new(buf) else {
+            alloc::handle_alloc_error(layout);
+        };
         AlignedBuffer {
-            buf: [0u8; MAX_READ_SIZE],
-            len: MAX_READ_SIZE,
+            buf,
+            capacity,
+            len: capacity,
+            #[cfg(target_os = "linux")]
+            mapping: None,
         }
     }
 
+    /// `count` buffers of `capacity` bytes, carved from one mapping of 2 MiB huge pages, so the
+    /// kernel needs fewer pages to describe them when they're registered. Without huge pages
+    /// reserved in /proc/sys/vm/nr_hugepages, transparent huge pages are asked for instead.
+    #[cfg(target_os = "linux")]
+    pub fn with_huge_pages(count: usize, capacity: usize) -> io::Result<Vec<AlignedBuffer>> {
+        assert!(
+            capacity > 0 && capacity.is_multiple_of(ALIGNMENT),
+            "invalid buffer size {}",
+            capacity
+        );
+        let len = (count * capacity).div_ceil(HUGE_PAGE_SIZE) * HUGE_PAGE_SIZE;
+        let map = |flags| {
+            // unsafe: a new anonymous mapping doesn't alias any memory:
+            let ptr = unsafe {
+                libc::mmap(
+                    std::ptr::null_mut(),
+                    len,
+                    libc::PROT_READ | libc::PROT_WRITE,
+                    libc::MAP_PRIVATE | libc::MAP_ANONYMOUS | flags,
+                    -1,
+                    0,
+                )
+            };
+            if ptr == libc::MAP_FAILED {
+                Err(io::Error::last_os_error())
+            } else {
+                Ok(ptr)
+            }
+        };
+        let ptr = match map(libc::MAP_HUGETLB | libc::MAP_HUGE_2MB) {
+            Ok(ptr) => ptr,
+            Err(err) => {
+                warn!(
+                    "Could not map {} bytes of huge pages ({}), so transparent huge pages are \
+                     used if they're enabled. Reserve huge pages with /proc/sys/vm/nr_hugepages.",
+                    len, err
+                );
+                let ptr = map(0)?;
+                // unsafe: this is only advice about memory we mapped:
+                if unsafe { libc::madvise(ptr, len, libc::MADV_HUGEPAGE) } != 0 {
+                    debug!(
+                        "Could not ask for transparent huge pages: {}",
+                        io::Error::last_os_error()
+                    );
+                }
+                ptr
+            }
+        };
+        let mapping = Arc::new(Mapping {
+            ptr: NonNull::new(ptr as *mut u8).expect("mmap doesn't map address 0"),
+            len,
+        });
+        Ok((0..count)
+            .map(|i| AlignedBuffer {
+                // unsafe: each buffer is a separate part of the mapping:
+                buf: unsafe { NonNull::new_unchecked(mapping.ptr.as_ptr().add(i * capacity)) },
+                capacity,
+                len: capacity,
+                mapping: Some(mapping.clone()),
+            })
+            .collect())
+    }
+
+    fn layout(capacity: usize) -> Layout {
+        assert!(capacity > 0, "Cannot allocate an empty buffer");
+        Layout::from_size_align(capacity, ALIGNMENT).expect("the buffer is too large")
+    }
+
     pub fn len(&self) -> usize {
         self.len
     }
 
-    /// Panics if the size is over the capacity (the default size).
+    pub fn is_empty(&self) -> bool {
+        self.len == 0
+    }
+
+    /// The size the buffer was allocated with, which is the most it can be resized to.
+    pub fn capacity(&self) -> usize {
+        self.capacity
+    }
+
+    /// Panics if the size is over the capacity.
     pub fn resize(&mut self, len: usize) {
         assert!(
-            len <= MAX_READ_SIZE,
+            len <= self.capacity,
             "Cannot resize buffer to {} bytes--larger than the full allocated region: {}",
             len,
-            MAX_READ_SIZE
+            self.capacity
         );
         self.len = len;
     }
 }
 
-impl Default for AlignedBuffer {
-    fn default() -> Self {
-        Self::new()
+impl AlignedBuffer {
+    /// Overwrite the whole buffer with zeros if wiping was turned on with `set_zeroize()`.
+    pub fn wipe(&mut self) {
+        // unsafe: the whole capacity is allocated and initialized:
+        wipe_bytes(unsafe { slice::from_raw_parts_mut(self.buf.as_ptr(), self.capacity) });
+    }
+}
+
+impl Drop for AlignedBuffer {
+    fn drop(&mut self) {
+        self.wipe();
+        #[cfg(target_os = "linux")]
+        if self.mapping.is_some() {
+            // The mapping is unmapped when its last buffer is dropped:
+            return;
+        }
+        // unsafe: the memory came from `alloc_zeroed` with the same layout:
+        unsafe { alloc::dealloc(self.buf.as_ptr(), Self::layout(self.capacity)) };
+    }
+}
+
+impl Default for AlignedBuffer {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl AsRef<[u8]> for AlignedBuffer {
+    fn as_ref(&self) -> &[u8] {
+        self
+    }
+}
+
+impl Deref for AlignedBuffer {
+    type Target = [u8];
+
+    fn deref(&self) -> &Self::Target {
+        // unsafe: copied from Vec for performance:
+        unsafe { slice::from_raw_parts(self.buf.as_ptr(), self.len) }
+    }
+}
+
+impl DerefMut for AlignedBuffer {
+    fn deref_mut(&mut self) -> &mut Self::Target {
+        // unsafe: copied from Vec for performance:
+        unsafe { slice::from_raw_parts_mut(self.buf.as_ptr(), self.len) }
+    }
+}
+
+/// The error for a file whose read at `position` found its end, though it was found to be
+/// `file_len` bytes long before it was read, as when it's truncated meanwhile.
+pub fn ended_early(position: u64, file_len: u64) -> anyhow::Error {
+    anyhow!(
+        "The file ended at byte {}, but it should be {} bytes.",
+        position,
+        file_len
+    )
+}
+
+/// Read a file from `offset` to `file_len` one buffer at a time, passing each chunk of data to
+/// `consume` in order until it returns false. This uses a ring with a single read in flight.
+#[cfg(target_os = "linux")]
+pub fn read_sequentially<F>(fd: &File, mut offset: u64, file_len: u64, mut consume: F) -> Result<()>
+where
+    F: FnMut(&[u8]) -> Result<bool>,
+{
+    let mut ring = IoUring::new(1).map_err(ChecksumError::RingSetup)?;
+    let mut buf: Pin<Box<AlignedBuffer>> = Box::pin(Default::default());
+
+    while offset < file_len {
+        // Always ask for the whole buffer, so the length stays aligned for O_DIRECT. The
+        // kernel stops at the end of the file.
+        let read_e = opcode::Read::new(types::Fd(fd.as_raw_fd()), buf.as_mut_ptr(), buf.len() as _)
+            .offset(offset)
+            .build();
+        unsafe {
+            ring.submission()
+                .push(&read_e)
+                .map_err(|_| ChecksumError::Submit(io::Error::other("submission queue is full")))?;
+        }
+        ring.submit_and_wait(1).map_err(ChecksumError::Submit)?;
+        progress::add_read();
+        let result = next_result(&mut ring)?;
+
+        if result < 0 {
+            return Err(std::io::Error::from_raw_os_error(-result).into());
+        }
+        let read_len = result as usize;
+        if read_len == 0 {
+            return Err(ended_early(offset, file_len));
+        }
+
+        if !consume(&buf[..read_len])? {
+            break;
+        }
+        offset += read_len as u64;
+    }
+
+    Ok(())
+}
+
+/// Read a file from `offset` to `file_len` one buffer at a time, passing each chunk of data to
+/// `consume` in order until it returns false. Without io_uring, this seeks and reads.
+#[cfg(not(target_os = "linux"))]
+pub fn read_sequentially<F>(
+    mut fd: &File,
+    mut offset: u64,
+    file_len: u64,
+    mut consume: F,
+) -> Result<()>
+where
+    F: FnMut(&[u8]) -> Result<bool>,
+{
+    let mut buf: Box<AlignedBuffer> = Default::default();
+    fd.seek(SeekFrom::Start(offset))?;
+
+    while offset < file_len {
+        let read_len = match fd.read(&mut buf) {
+            Ok(read_len) => read_len,
+            Err(err) if err.kind() == io::ErrorKind::Interrupted => continue,
+            Err(err) => return Err(err.into()),
+        };
+        if read_len == 0 {
+            return Err(ended_early(offset, file_len));
+        }
+
+        if !consume(&buf[..read_len])? {
+            break;
+        }
+        offset += read_len as u64;
+    }
+
+    Ok(())
+}
+
+/// The result of the completion that `submit_and_wait(1)` waited for.
+#[cfg(target_os = "linux")]
+fn next_result(ring: &mut IoUring) -> Result<i32> {
+    match ring.completion().next() {
+        Some(cqe) => Ok(cqe.result()),
+        None => {
+            let err = io::Error::other("completion queue is empty");
+            Err(ChecksumError::Completion(err).into())
+        }
+    }
+}
+
+/// Read `fd` from its current position until it ends, one buffer at a time, passing each chunk
+/// of data to `consume` in order until it returns false. Each read has an offset of -1, which
+/// means the current position, so this works on pipes and other files that can't be read at
+/// offsets, and on files that were already partly read, as by another process that passed the
+/// descriptor on.
+#[cfg(target_os = "linux")]
+pub fn read_stream<F>(fd: &impl AsRawFd, mut consume: F) -> Result<()>
+where
+    F: FnMut(&[u8]) -> Result<bool>,
+{
+    let mut ring = IoUring::new(1).map_err(ChecksumError::RingSetup)?;
+    // Before Linux 5.6, an offset of -1 is an error on the ring:
+    if !ring.params().is_feature_rw_cur_pos() {
+        // unsafe: the caller keeps the descriptor open, and it isn't closed when this is dropped:
+        let file = std::mem::ManuallyDrop::new(unsafe { File::from_raw_fd(fd.as_raw_fd()) });
+        return read_until_end(&*file, consume);
+    }
+    let mut buf: Pin<Box<AlignedBuffer>> = Box::pin(Default::default());
+
+    loop {
+        let read_e = opcode::Read::new(types::Fd(fd.as_raw_fd()), buf.as_mut_ptr(), buf.len() as _)
+            // -1, which means the current position:
+            .offset(u64::MAX)
+            .build();
+        unsafe {
+            ring.submission()
+                .push(&read_e)
+                .map_err(|_| ChecksumError::Submit(io::Error::other("submission queue is full")))?;
+        }
+        ring.submit_and_wait(1).map_err(ChecksumError::Submit)?;
+        progress::add_read();
+        let result = next_result(&mut ring)?;
+
+        if result == -libc::EINTR {
+            continue;
+        }
+        if result < 0 {
+            return Err(std::io::Error::from_raw_os_error(-result).into());
+        }
+        if result == 0 || !consume(&buf[..result as usize])? {
+            return Ok(());
+        }
+    }
+}
+
+/// Read `reader` until it ends, one buffer at a time, passing each chunk of data to `consume` in
+/// order until it returns false. Without io_uring, this is a loop of plain reads.
+#[cfg(not(target_os = "linux"))]
+pub fn read_stream<F>(reader: impl Read, consume: F) -> Result<()>
+where
+    F: FnMut(&[u8]) -> Result<bool>,
+{
+    read_until_end(reader, consume)
+}
+
+/// Read `reader` until it ends with plain reads, like `read_stream()`.
+fn read_until_end<F>(mut reader: impl Read, mut consume: F) -> Result<()>
+where
+    F: FnMut(&[u8]) -> Result<bool>,
+{
+    let mut buf: Box<AlignedBuffer> = Default::default();
+    loop {
+        let read_len = match reader.read(&mut buf) {
+            Ok(read_len) => read_len,
+            Err(err) if err.kind() == io::ErrorKind::Interrupted => continue,
+            Err(err) => return Err(err.into()),
+        };
+        if read_len == 0 || !consume(&buf[..read_len])? {
+            return Ok(());
+        }
+    }
+}
+
+/// Hash a file that isn't seekable or has no length, like a pipe or device, by reading it until
+/// it ends. The path `-` means stdin.
+pub fn checksum_stream<D: md5::digest::Update>(path: &Path, mut ctx: D) -> Result<D> {
+    let consume = |data: &[u8]| {
+        ctx.update(data);
+        progress::add_bytes(data.len() as u64);
+        Ok(true)
+    };
+    if input::is_stdin(path) {
+        read_stream(&std::io::stdin(), consume)?;
+    } else {
+        read_stream(&File::open(path)?, consume)?;
+    }
+    Ok(ctx)
+}
+
+/// Read the file at `path` from start to end, passing each chunk of data to `consume` in order
+/// until it returns false. Streams, including stdin as -, are read until they end.
+pub fn read_whole<F>(path: &Path, o_direct: bool, consume: F) -> Result<()>
+where
+    F: FnMut(&[u8]) -> Result<bool>,
+{
+    if input::is_stdin(path) {
+        read_stream(&io::stdin(), consume)
+    } else if input::is_stream(path) {
+        read_stream(&File::open(path)?, consume)
+    } else {
+        let fd = open(path, o_direct)?;
+        let file_len = fd.metadata()?.len();
+        read_sequentially(&fd, 0, file_len, consume)
+    }
+}
+
+/// Hash everything `reader` returns until it ends.
+pub fn checksum_reader<D: md5::digest::Update>(reader: impl Read, mut ctx: D) -> Result<D> {
+    read_until_end(reader, |data| {
+        ctx.update(data);
+        progress::add_bytes(data.len() as u64);
+        Ok(true)
+    })?;
+    Ok(ctx)
+}
+
+/// Hash `file` from its current position until it ends, leaving the position at the end. The
+/// file's length isn't looked at, so this suits a descriptor that came from elsewhere, like stdin
+/// or one passed over a Unix socket, which may have been partly read already or be a pipe.
+pub fn checksum_file<D: md5::digest::Update>(file: &File, mut ctx: D) -> Result<D> {
+    read_stream(file, |data| {
+        ctx.update(data);
+        progress::add_bytes(data.len() as u64);
+        Ok(true)
+    })?;
+    Ok(ctx)
+}
+
+/// The path whose name is `bytes`, as written by `OsStr::as_encoded_bytes()` in a manifest,
+/// list or state file. Other systems than Unix read names that aren't UTF-8 lossily.
+pub fn path_from_bytes(bytes: &[u8]) -> PathBuf {
+    #[cfg(unix)]
+    return PathBuf::from(OsStr::from_bytes(bytes));
+    #[cfg(not(unix))]
+    return PathBuf::from(String::from_utf8_lossy(bytes).into_owned());
+}
+
+/// Format a digest as lowercase hexadecimal, the way md5sum prints it.
+pub fn to_hex(digest: &[u8]) -> String {
+    digest.iter().map(|byte| format!("{:02x}", byte)).collect()
+}
+
+/// Parse a hexadecimal digest in either case. Returns None if it isn't valid hexadecimal.
+pub fn from_hex(hex: &str) -> Option<Vec<u8>> {
+    if !hex.len().is_multiple_of(2) {
+        return None;
+    }
+    (0..hex.len())
+        .step_by(2)
+        .map(|i| u8::from_str_radix(hex.get(i..i + 2)?, 16).ok())
+        .collect()
+}
+
+static ZEROIZE: AtomicBool = AtomicBool::new(false);
+
+/// Wipe read buffers after each file and when they're freed. This has no effect unless the
+/// crate is built with the `zeroize` feature.
+pub fn set_zeroize(enabled: bool) {
+    if enabled && !cfg!(feature = "zeroize") {
+        warn!("Buffers will not be wiped because this was built without the zeroize feature.");
+    }
+    ZEROIZE.store(enabled, Ordering::Relaxed);
+}
+
+/// Overwrite `bytes` with zeros if wiping was turned on with `set_zeroize()`.
+pub(crate) fn wipe_bytes(bytes: &mut [u8]) {
+    #[cfg(feature = "zeroize")]
+    if ZEROIZE.load(Ordering::Relaxed) {
+        zeroize::Zeroize::zeroize(bytes);
+    }
+    #[cfg(not(feature = "zeroize"))]
+    let _ = bytes;
+}
+
+/// Open a file for reading. Note that O_DIRECT seems not to work on some systems like
+/// WSL2. Files whose filesystem rejects it are opened without it.
+#[cfg(target_os = "linux")]
+pub fn open(path: impl AsRef<Path>, o_direct: bool) -> std::io::Result<File> {
+    if o_direct {
+        let result = OpenOptions::new()
+            .read(true)
+            // see man 2 open, search for O_DIRECT
+            .custom_flags(libc::O_DIRECT)
+            .open(&path);
+        match result {
+            // Filesystems without direct IO, like procfs and older tmpfs, reject the flag:
+            Err(err) if err.raw_os_error() == Some(libc::EINVAL) => {
+                debug!(
+                    "{:?} can't be opened with O_DIRECT, so it's opened without it.",
+                    path.as_ref()
+                );
+                File::open(path)
+            }
+            result => result,
+        }
+    } else {
+        File::open(path)
+    }
+}
+
+/// Open a file for reading. O_DIRECT is only asked for on Linux.
+#[cfg(not(target_os = "linux"))]
+pub fn open(path: impl AsRef<Path>, o_direct: bool) -> std::io::Result<File> {
+    if o_direct {
+        debug!(
+            "O_DIRECT needs Linux, so {:?} is opened without it.",
+            path.as_ref()
+        );
+    }
+    File::open(path)
+}
+
+/// Open each of `paths`, sending a `ChecksumError::OpenFailed` through `tx` for each that can't
+/// be opened.
+pub fn open_batch<D, R: From<(PathBuf, Result<D>)>>(
+    paths: impl Iterator<Item = PathBuf>,
+    o_direct: bool,
+    tx: &impl ResultSink<R>,
+) -> Result<Vec<(PathBuf, File)>> {
+    let mut opened = Vec::new();
+    for path in paths {
+        match open(&path, o_direct) {
+            Ok(fd) => opened.push((path, fd)),
+            Err(err) => {
+                let err = ChecksumError::open_failed(&path, err);
+                tx.send((path, Err(err.into())).into())?;
+            }
+        }
+    }
+    Ok(opened)
+}
+
+fn parse_ring_size(s: &str) -> std::result::Result<usize, String> {
+    let entries: usize = s.parse().map_err(|err| format!("{}", err))?;
+    if !entries.is_power_of_two() || entries > MAX_RING_SIZE {
+        return Err(format!(
+            "the ring size must be a power of two from 1 to {}",
+            MAX_RING_SIZE
+        ));
+    }
+    Ok(entries)
+}
+
+fn parse_fixed_buffers(s: &str) -> std::result::Result<usize, String> {
+    let buffers: usize = s.parse().map_err(|err| format!("{}", err))?;
+    if !(1..=MAX_FIXED_BUFFERS).contains(&buffers) {
+        return Err(format!(
+            "the number of fixed buffers must be from 1 to {}",
+            MAX_FIXED_BUFFERS
+        ));
+    }
+    Ok(buffers)
+}
+
+fn parse_jobs(s: &str) -> std::result::Result<usize, String> {
+    let jobs: usize = s.parse().map_err(|err| format!("{}", err))?;
+    if jobs == 0 {
+        return Err("there must be at least 1 job".to_string());
+    }
+    Ok(jobs)
+}
+
+fn parse_read_size(s: &str) -> std::result::Result<usize, String> {
+    let bytes = input::parse_size(s)?;
+    if bytes == 0 || !bytes.is_multiple_of(ALIGNMENT as u64) || bytes > READ_SIZE_LIMIT as u64 {
+        return Err(format!(
+            "the read size must be a multiple of {} from {} to {}, like 64K or 1M",
+            ALIGNMENT, ALIGNMENT, READ_SIZE_LIMIT
+        ));
+    }
+    Ok(bytes as usize)
+}
+
+fn parse_reads_per_file(s: &str) -> std::result::Result<usize, String> {
+    let reads: usize = s.parse().map_err(|err| format!("{}", err))?;
+    if !(1..=MAX_READS_PER_FILE).contains(&reads) {
+        return Err(format!(
+            "the reads per file must be from 1 to {}",
+            MAX_READS_PER_FILE
+        ));
+    }
+    Ok(reads)
+}
+
+fn parse_read_timeout(s: &str) -> std::result::Result<Duration, String> {
+    s.parse::<f64>()
+        .ok()
+        .filter(|&seconds| seconds > 0.0)
+        .and_then(|seconds| Duration::try_from_secs_f64(seconds).ok())
+        .ok_or_else(|| {
+            "the read timeout must be a positive number of seconds, like 30 or 0.5".to_string()
+        })
+}
+
+fn parse_piece_length(s: &str) -> std::result::Result<u64, String> {
+    let bytes = input::parse_size(s)?;
+    if bytes < torrent::MIN_PIECE_LENGTH || !bytes.is_power_of_two() {
+        return Err(format!(
+            "the piece length must be a power of two of at least {}, like 256K or 1M",
+            torrent::MIN_PIECE_LENGTH
+        ));
+    }
+    Ok(bytes)
+}
+
+fn parse_block_size(s: &str) -> std::result::Result<u64, String> {
+    match input::parse_size(s)? {
+        0 => Err("the block size must not be zero".to_string()),
+        bytes => Ok(bytes),
+    }
+}
+
+/// How many input files may be open at the same time. The first call raises the soft
+/// RLIMIT_NOFILE as far toward the hard limit as the system allows.
+#[cfg(target_os = "linux")]
+pub fn max_open_files() -> usize {
+    static MAX_OPEN_FILES: OnceLock<usize> = OnceLock::new();
+
+    *MAX_OPEN_FILES.get_or_init(|| {
+        let soft_limit = raise_nofile_limit();
+        let cap = soft_limit.saturating_sub(RESERVED_FDS).max(1);
+        debug!("Keeping at most {} input files open at once.", cap);
+        usize::try_from(cap).unwrap_or(usize::MAX)
+    })
+}
+
+/// Raise the soft limit on open files to the hard limit if possible. Returns the soft
+/// limit that is in effect afterward.
+#[cfg(target_os = "linux")]
+fn raise_nofile_limit() -> u64 {
+    // Assume the traditional default if it can't be read:
+    raise_soft_limit(libc::RLIMIT_NOFILE, "open file", 1024)
+}
+
+/// Raise the soft limit on locked memory, which registered buffers count against, to the hard
+/// limit if possible. Returns the limit in bytes that is in effect afterward.
+#[cfg(target_os = "linux")]
+pub fn raise_memlock_limit() -> u64 {
+    // Assume the old default of 64 KiB if it can't be read:
+    raise_soft_limit(libc::RLIMIT_MEMLOCK, "locked memory", 64 * 1024)
+}
+
+#[cfg(target_os = "linux")]
+fn raise_soft_limit(resource: libc::__rlimit_resource_t, name: &str, default: u64) -> u64 {
+    let mut limit = libc::rlimit {
+        rlim_cur: 0,
+        rlim_max: 0,
+    };
+    // unsafe: the kernel only writes to the struct we pass in:
+    if unsafe { libc::getrlimit(resource, &mut limit) } != 0 {
+        warn!(
+            "Could not read the {} limit: {}",
+            name,
+            std::io::Error::last_os_error()
+        );
+        return default;
+    }
+
+    if limit.rlim_cur < limit.rlim_max {
+        let raised = libc::rlimit {
+            rlim_cur: limit.rlim_max,
+            rlim_max: limit.rlim_max,
+        };
+        if unsafe { libc::setrlimit(resource, &raised) } == 0 {
+            debug!(
+                "Raised the {} limit from {} to {}.",
+                name, limit.rlim_cur, raised.rlim_cur
+            );
+            limit = raised;
+        } else {
+            debug!(
+                "Could not raise the {} limit from {}: {}",
+                name,
+                limit.rlim_cur,
+                std::io::Error::last_os_error()
+            );
+        }
+    }
+
+    limit.rlim_cur
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{
+        collections::{BTreeMap, HashMap},
+        fs::{File, OpenOptions},
+        io::{ErrorKind, Read, Write},
+        os::unix::io::{AsRawFd, FromRawFd},
+        path::{Path, PathBuf},
+        sync::{
+            atomic::{AtomicBool, Ordering},
+            mpsc::{channel, Sender},
+            Mutex,
+        },
+        time::{Duration, UNIX_EPOCH},
+    };
+
+    use anyhow::{anyhow, Result};
+    #[allow(unused_imports)]
+    use log::{debug, error, info, trace, warn};
+    use md5::{Digest, Md5};
+    use sha1::Sha1;
+    use structopt::lazy_static::lazy_static;
+    use structopt::StructOpt;
+
+    use crate::{
+        algorithm::Crc32c,
+        archive, async_stream, bench, byte_cmp,
+        cache::{self, DigestCache},
+        check::{self, CheckOptions, CheckSummary},
+        combined, compare,
+        diagnose::{self, block_digests, find_first_mismatch},
+        duplicates, evented, from_hex, get_checksums, hard_links, input,
+        manifest::{format_line, format_stamp_line, parse_line, read_digest_set, ManifestEntry},
+        max_open_files,
+        midstate::{get_checksum_resumable, Midstate},
+        open,
+        output::{AtomicFile, DirManifests},
+        progress,
+        record::{OutputFormat, Record},
+        reflinks, ring, self_test, simple_uring,
+        stream_verify::{StreamVerifier, Verdict},
+        to_hex, torrent, tree_digest, watch, with_fixed_buffers, with_provided_buffers,
+        with_register_files, without_uring, xattr_cache, AlignedBuffer, ChecksumError,
+        ChecksumResult, ChecksumSession, Config, HashAlgorithm, Hasher, Opt, ResultSink, ALIGNMENT,
+        MAX_READ_SIZE, STRATEGIES,
+    };
+
+    fn setup() {
+        // Try init because multiple tests may invoke this:
+        let _ = env_logger::try_init();
+    }
+
+    fn file_setup() -> Result<HashMap<PathBuf, [u8; 16]>> {
+        lazy_static! {
+            static ref CHECKSUMS: Mutex<HashMap<PathBuf, [u8; 16]>> = Default::default();
+        };
+
+        let mut mutex_guard = CHECKSUMS.lock().unwrap();
+
+        setup();
+
+        let checksums: &mut HashMap<_, _> = &mut mutex_guard;
+        if !checksums.is_empty() {
+            // Don't create/modify the files twice
+            return Ok(checksums.clone());
+        }
+
+        match std::fs::create_dir("test") {
+            Ok(_) => Ok(()),
+            Err(err) if err.raw_os_error() == Some(17) => Ok(()), // directory exists; okay
+            Err(err) => Err(err),
+        }?;
+
+        // Repeat characters in a pattern that's easy to read/debug:
+        let iter = &mut std::iter::repeat_with(|| {
+            (b'0'..b'f').flat_map(|character| std::iter::repeat_n(character, 10))
+        })
+        .flatten();
+
+        let mut hasher = Md5::new();
+
+        for size in [
+            25,
+            4096,
+            MAX_READ_SIZE - 1,
+            MAX_READ_SIZE,
+            MAX_READ_SIZE + 1,
+            MAX_READ_SIZE * 3,
+        ] {
+            let fname = PathBuf::from(format!("test/file-{}", size));
+
+            let data = iter.take(size).collect::<Vec<_>>();
+            hasher.update(&data);
+            let checksum: [u8; 16] = hasher.finalize_reset().into();
+            assert!(checksums.insert(fname.clone(), checksum).is_none());
+
+            let file = OpenOptions::new()
+                .write(true)
+                .read(false)
+                // fail on existing:
+                .create_new(true)
+                .open(&fname);
+
+            if matches!(&file, Err(err) if err.kind() == ErrorKind::AlreadyExists) {
+                // Skip files that already exist. They should already have the same contents,
+                // and overwriting them is a security risk since some of these tests need to
+                // run as root:
+                continue;
+            }
+
+            file?.write_all(&data)?;
+        }
+
+        Ok(checksums.clone())
+    }
+
+    /// Check a strategy with a plain RustCrypto digest, without going through `Hasher`.
+    fn assert_checksums<F>(get_checksums: F, config: &Config) -> Result<()>
+    where
+        F: Fn(Vec<PathBuf>, Sender<(PathBuf, Result<Md5>)>, &Config, fn() -> Md5) -> Result<()>
+            + Sync
+            + 'static,
+    {
+        let checksums = file_setup()?;
+
+        let (tx, rx) = channel();
+        crossbeam_utils::thread::scope(|s| -> Result<()> {
+            let handle = s.spawn(|_| -> Result<()> {
+                get_checksums(checksums.keys().cloned().collect(), tx, config, Md5::new)?;
+                Ok(())
+            });
+
+            for (path, result) in rx {
+                let checksum: [u8; 16] = result?.finalize().into();
+                assert_eq!(checksums.get(&path).unwrap(), &checksum);
+            }
+            handle.join().unwrap()?;
+            Ok(())
+        })
+        .unwrap()?;
+
+        Ok(())
+    }
+
+    fn assert_algorithm_checksums<F>(get_checksums: F, config: &Config) -> Result<()>
+    where
+        F: Fn(Vec<PathBuf>, Sender<ChecksumResult>, &Config) -> Result<()> + Sync + 'static,
+    {
+        let checksums = file_setup()?;
+
+        let (tx, rx) = channel();
+        crossbeam_utils::thread::scope(|s| -> Result<()> {
+            let handle = s.spawn(|_| -> Result<()> {
+                get_checksums(checksums.keys().cloned().collect(), tx, config)?;
+                Ok(())
+            });
+
+            for result in rx {
+                let path = result.path.clone();
+                assert!(result.error.is_some() || result.bytes == std::fs::metadata(&path)?.len());
+                let checksum = result.into_result()?;
+                // Hash the whole file in one go, without any of the strategies:
+                let mut ctx = Hasher::new(config.algorithm);
+                ctx.update(std::fs::read(&path)?);
+                let expected = ctx.finalize();
+                assert_eq!(expected, checksum);
+            }
+            handle.join().unwrap()?;
+            Ok(())
+        })
+        .unwrap()?;
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_algorithms_with_md5() -> Result<()> {
+        setup();
+        for &strategy in STRATEGIES {
+            let config = Config::default().with_strategy(strategy);
+            assert_algorithm_checksums(crate::get_checksums, &config)?;
+        }
+        Ok(())
+    }
+
+    #[test]
+    fn test_runtime_algorithm() -> Result<()> {
+        setup();
+        let data: Vec<u8> = (0..MAX_READ_SIZE * 2 + 5)
+            .map(|i| (i % 251) as u8)
+            .collect();
+        for &algorithm in HashAlgorithm::ALL {
+            let name = algorithm.to_string();
+            assert_eq!(name.to_uppercase().parse::<HashAlgorithm>(), Ok(algorithm));
+
+            let mut whole = name.parse::<HashAlgorithm>().unwrap().new_dyn();
+            whole.update(&data);
+            let whole = whole.finalize();
+            assert_eq!(whole.len(), algorithm.output_len(), "{}", name);
+
+            let mut ctx = Hasher::new(algorithm);
+            for chunk in data.chunks(1000) {
+                ctx.update(chunk);
+            }
+            assert_eq!(ctx.finalize_reset(), &whole[..], "{}", name);
+            ctx.update(&data);
+            assert_eq!(ctx.finalize(), &whole[..], "{}", name);
+        }
+        assert!("md4".parse::<HashAlgorithm>().is_err());
+
+        // The algorithms that aren't from RustCrypto work as a plain `Digest` too:
+        let (tx, rx) = channel();
+        simple_uring::get_checksums(
+            vec!["Cargo.toml".into()],
+            tx,
+            &Config::default(),
+            Crc32c::new,
+        )?;
+        let (_, result) = rx.recv()?;
+        let mut expected = Hasher::new(HashAlgorithm::Crc32c);
+        expected.update(std::fs::read("Cargo.toml")?);
+        assert_eq!(result?.finalize()[..], expected.finalize());
+        Ok(())
+    }
+
+    #[test]
+    fn test_sha2() -> Result<()> {
+        setup();
+        for algorithm in [
+            HashAlgorithm::Sha256,
+            HashAlgorithm::Sha384,
+            HashAlgorithm::Sha512,
+        ] {
+            for &strategy in STRATEGIES {
+                let config = Config::default()
+                    .with_strategy(strategy)
+                    .algorithm(algorithm);
+                assert_algorithm_checksums(crate::get_checksums, &config)?;
+            }
+        }
+        Ok(())
+    }
+
+    #[test]
+    fn test_xxh3() -> Result<()> {
+        setup();
+        for algorithm in [HashAlgorithm::Xxh3, HashAlgorithm::Xxh128] {
+            for &strategy in STRATEGIES {
+                let config = Config::default()
+                    .with_strategy(strategy)
+                    .algorithm(algorithm);
+                assert_algorithm_checksums(crate::get_checksums, &config)?;
+            }
+        }
+        let mut ctx = Hasher::new(HashAlgorithm::Xxh3);
+        ctx.update(b"abc");
+        assert_eq!(
+            ctx.finalize(),
+            xxhash_rust::xxh3::xxh3_64(b"abc").to_be_bytes()
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn test_crc32c() -> Result<()> {
+        setup();
+        for &strategy in STRATEGIES {
+            let config = Config::default()
+                .with_strategy(strategy)
+                .algorithm(HashAlgorithm::Crc32c);
+            assert_algorithm_checksums(crate::get_checksums, &config)?;
+        }
+        let mut ctx = Hasher::new(HashAlgorithm::Crc32c);
+        ctx.update(b"1234");
+        ctx.update(b"56789");
+        assert_eq!(ctx.finalize(), [0xe3, 0x06, 0x92, 0x83]);
+        Ok(())
+    }
+
+    #[test]
+    fn test_crc64() -> Result<()> {
+        setup();
+        for algorithm in [HashAlgorithm::Crc64Xz, HashAlgorithm::Crc64Ecma] {
+            for &strategy in STRATEGIES {
+                let config = Config::default()
+                    .with_strategy(strategy)
+                    .algorithm(algorithm);
+                assert_algorithm_checksums(crate::get_checksums, &config)?;
+            }
+        }
+        let mut ctx = Hasher::new(HashAlgorithm::Crc64Xz);
+        ctx.update(b"12345");
+        ctx.update(b"6789");
+        assert_eq!(crate::to_hex(&ctx.finalize()), "995dc9bbdf1939fa");
+        Ok(())
+    }
+
+    #[test]
+    fn test_blake2() -> Result<()> {
+        setup();
+        for algorithm in [HashAlgorithm::Blake2b, HashAlgorithm::Blake2s] {
+            for &strategy in STRATEGIES {
+                let config = Config::default()
+                    .with_strategy(strategy)
+                    .algorithm(algorithm);
+                assert_algorithm_checksums(crate::get_checksums, &config)?;
+            }
+        }
+        Ok(())
+    }
+
+    #[test]
+    fn test_sm3() -> Result<()> {
+        setup();
+        for &strategy in STRATEGIES {
+            let config = Config::default()
+                .with_strategy(strategy)
+                .algorithm(HashAlgorithm::Sm3);
+            assert_algorithm_checksums(crate::get_checksums, &config)?;
+        }
+
+        // The examples of GB/T 32905-2016, appendix A, split up to exercise the buffering:
+        let mut ctx = Hasher::new(HashAlgorithm::Sm3);
+        ctx.update(b"a");
+        ctx.update(b"bc");
+        assert_eq!(
+            crate::to_hex(&ctx.finalize()),
+            "66c7f0f462eeedd9d1f2d46bdc10e4e24167c4875cf2f7a2297da02b8f4ba8e0"
+        );
+        let data = b"abcd".repeat(16);
+        for split in [0, 1, 63, 64] {
+            let mut ctx = Hasher::new(HashAlgorithm::Sm3);
+            ctx.update(&data[..split]);
+            ctx.update(&data[split..]);
+            assert_eq!(
+                crate::to_hex(&ctx.finalize()),
+                "debe9ff92275b8a138604889c18e5a4d6fdb70e5387e5765293dcba39c0c5732"
+            );
+        }
+        Ok(())
+    }
+
+    #[test]
+    fn test_blake3() -> Result<()> {
+        setup();
+        for &strategy in STRATEGIES {
+            let config = Config::default()
+                .with_strategy(strategy)
+                .algorithm(HashAlgorithm::Blake3);
+            assert_algorithm_checksums(crate::get_checksums, &config)?;
+        }
+
+        // Collected and direct updates must agree with hashing in one go:
+        let data: Vec<u8> = (0..3 * 1024 * 1024 + 7).map(|i| (i % 251) as u8).collect();
+        let mut ctx = Hasher::new(HashAlgorithm::Blake3);
+        for chunk in data.chunks(MAX_READ_SIZE) {
+            ctx.update(chunk);
+        }
+        assert_eq!(ctx.finalize(), blake3::hash(&data).as_bytes());
+        let mut ctx = Hasher::new(HashAlgorithm::Blake3);
+        ctx.update(&data[..100]);
+        ctx.update(&data[100..]);
+        assert_eq!(ctx.finalize(), blake3::hash(&data).as_bytes());
+        Ok(())
+    }
+
+    #[test]
+    fn test_without_uring() -> Result<()> {
+        setup();
+        assert_checksums(without_uring::get_checksums, &Config::default())?;
+        Ok(())
+    }
+
+    #[test]
+    fn test_simple_uring() -> Result<()> {
+        setup();
+        assert_checksums(simple_uring::get_checksums, &Config::default())?;
+        Ok(())
+    }
+
+    #[test]
+    fn test_simple_uring_o_direct() -> Result<()> {
+        setup();
+        assert_checksums(
+            simple_uring::get_checksums,
+            &Config::default().o_direct(true),
+        )?;
+        Ok(())
+    }
+
+    #[test]
+    fn test_preregistered_files() -> Result<()> {
+        setup();
+        assert_checksums(with_register_files::get_checksums, &Config::default())?;
+        Ok(())
+    }
+
+    #[test]
+    fn test_preregistered_files_o_direct() -> Result<()> {
+        setup();
+        assert_checksums(
+            with_register_files::get_checksums,
+            &Config::default().o_direct(true),
+        )?;
+        Ok(())
+    }
+
+    #[test]
+    fn test_fixed_buffers() -> Result<()> {
+        setup();
+        assert_checksums(with_fixed_buffers::get_checksums, &Config::default())?;
+        // Fewer files than entries, more, and a spare buffer for each file, with more files than
+        // entries too:
+        for count in [1, 3, 4, 9] {
+            let config = Config::default()
+                .ring_size(2)
+                .read_size(ALIGNMENT)
+                .fixed_buffers(Some(count));
+            assert_checksums(with_fixed_buffers::get_checksums, &config)?;
+        }
+
+        let args = Opt::from_iter_safe(["", "--use-fixed-buffers", "--fixed-buffers", "64", "f"])?;
+        assert_eq!(args.config().fixed_buffers, Some(64));
+        assert!(Opt::from_iter_safe(["", "--fixed-buffers", "0", "f"]).is_err());
+        Ok(())
+    }
+
+    #[test]
+    fn test_o_direct_fallback() -> Result<()> {
+        setup();
+        // procfs rejects O_DIRECT when a file is opened:
+        let path = PathBuf::from("/proc/version");
+        let mut contents = String::new();
+        crate::open(&path, true)?.read_to_string(&mut contents)?;
+        assert!(contents.starts_with("Linux"));
+
+        // Opens through the ring fall back too:
+        for async_open in [false, true] {
+            let config = Config::default().o_direct(true).async_open(async_open);
+            let (tx, rx) = channel::<(PathBuf, Result<Md5>)>();
+            simple_uring::get_checksums(vec![path.clone()], tx, &config, Md5::new)?;
+            rx.recv()?.1?;
+        }
+        for strategy in STRATEGIES {
+            // Files in procfs can't be memory mapped:
+            if strategy.name() == "no-uring" {
+                continue;
+            }
+            let (tx, rx) = channel();
+            strategy.get_checksums(vec![path.clone()], &tx, &Config::default().o_direct(true))?;
+            drop(tx);
+            rx.recv()?.into_result()?;
+        }
+        Ok(())
+    }
+
+    #[test]
+    fn test_huge_pages() -> Result<()> {
+        setup();
+        let mut buffers = AlignedBuffer::with_huge_pages(3, ALIGNMENT * 2)?;
+        for (i, buffer) in buffers.iter_mut().enumerate() {
+            assert_eq!(buffer.len(), ALIGNMENT * 2);
+            assert_eq!(buffer.as_ptr() as usize % ALIGNMENT, 0);
+            buffer.fill(i as u8);
+        }
+        // The buffers don't overlap:
+        for (i, buffer) in buffers.iter().enumerate() {
+            assert!(buffer.iter().all(|&byte| byte == i as u8));
+        }
+        drop(buffers);
+
+        assert_checksums(
+            with_fixed_buffers::get_checksums,
+            &Config::default().huge_pages(true),
+        )
+    }
+
+    #[test]
+    fn test_auto_strategy() -> Result<()> {
+        setup();
+        let name = crate::auto_strategy_name();
+        assert!([
+            "fixed-buffers",
+            "register-files",
+            "simple-uring",
+            "no-uring"
+        ]
+        .contains(&name));
+        let args = Opt::from_iter_safe(["", "--auto", "file"])?;
+        assert_eq!(args.strategy_name(), name);
+        assert_algorithm_checksums(crate::get_checksums, &args.config())?;
+
+        assert!(Opt::from_iter_safe(["", "--auto", "--no-uring", "file"]).is_err());
+        assert!(Opt::from_iter_safe(["", "--auto", "--async-open", "file"]).is_err());
+        Ok(())
+    }
+
+    #[test]
+    fn test_strategy_flag() -> Result<()> {
+        let strategy_name = |args: &[&str]| -> Result<&'static str> {
+            let args = Opt::from_iter_safe([""].iter().chain(args).chain(&["file"]))?;
+            args.check_strategy().map_err(|err| anyhow!(err))?;
+            Ok(args.strategy_name())
+        };
+        assert_eq!(strategy_name(&[])?, "simple-uring");
+        assert_eq!(strategy_name(&["--strategy", "simple"])?, "simple-uring");
+        assert_eq!(
+            strategy_name(&["--strategy", "auto"])?,
+            crate::auto_strategy_name()
+        );
+        // The older flags choose the same strategies:
+        for (flag, name) in [
+            ("--pre-register-files", "register-files"),
+            ("--use-fixed-buffers", "fixed-buffers"),
+            ("--use-provided-buffers", "provided-buffers"),
+            ("--no-uring", "no-uring"),
+        ] {
+            assert_eq!(strategy_name(&[flag])?, name);
+            assert_eq!(strategy_name(&["--strategy", name])?, name);
+            assert!(strategy_name(&["--strategy", name, flag]).is_err());
+        }
+        assert!(strategy_name(&["--strategy", "simple-uring"]).is_err());
+        assert!(strategy_name(&["--strategy", "auto", "--async-open"]).is_err());
+        assert!(strategy_name(&["--strategy", "register-files", "--fixed-buffers", "4"]).is_err());
+        assert!(strategy_name(&["--strategy", "no-uring", "--o-direct"]).is_err());
+        assert!(strategy_name(&["--strategy", "simple", "--hash-threads", "2"]).is_ok());
+        assert!(strategy_name(&["--strategy", "fixed-buffers", "--huge-pages"]).is_ok());
+        Ok(())
+    }
+
+    #[test]
+    fn test_probe() -> Result<()> {
+        setup();
+        let capabilities = crate::probe::Capabilities::detect();
+        assert!(!capabilities.kernel.is_empty());
+        assert_eq!(capabilities.best_strategy(), crate::auto_strategy_name());
+        assert!(capabilities.usable("no-uring").is_ok());
+        // Every strategy the probe calls usable works:
+        for &strategy in STRATEGIES {
+            if capabilities.usable(strategy.name()).is_ok() {
+                let config = Config::default().with_strategy(strategy);
+                assert_algorithm_checksums(crate::get_checksums, &config)?;
+            }
+        }
+
+        let args = Opt::from_iter_safe(["", "probe"])?;
+        assert!(matches!(args.command, Some(crate::Command::Probe)));
+        crate::probe::run()
+    }
+
+    #[test]
+    fn test_shrink_fixed_buffers() {
+        setup();
+        let shrink = with_fixed_buffers::shrink_buffers;
+        // Large reads are made smaller before there are fewer of them:
+        assert_eq!(shrink(16, 8 << 20, 1 << 20), Some((16, MAX_READ_SIZE)));
+        assert_eq!(shrink(16, MAX_READ_SIZE, 1 << 20), Some((8, MAX_READ_SIZE)));
+        assert_eq!(
+            shrink(16, MAX_READ_SIZE, 64 * 1024),
+            Some((1, MAX_READ_SIZE))
+        );
+        assert_eq!(shrink(1, MAX_READ_SIZE, 8192), Some((1, 8192)));
+        // Sizes stay aligned for O_DIRECT:
+        assert_eq!(shrink(1, ALIGNMENT * 3, u64::MAX), Some((1, ALIGNMENT)));
+        assert_eq!(shrink(1, ALIGNMENT, u64::MAX), None);
+        assert_eq!(shrink(4, MAX_READ_SIZE, 1000), None);
+    }
+
+    #[test]
+    fn test_fixed_buffers_o_direct() -> Result<()> {
+        setup();
+        assert_checksums(
+            with_fixed_buffers::get_checksums,
+            &Config::default().o_direct(true),
+        )?;
+        Ok(())
+    }
+
+    #[test]
+    fn test_provided_buffers() -> Result<()> {
+        setup();
+        assert_checksums(with_provided_buffers::get_checksums, &Config::default())?;
+        Ok(())
+    }
+
+    #[test]
+    fn test_provided_buffers_o_direct() -> Result<()> {
+        setup();
+        assert_checksums(
+            with_provided_buffers::get_checksums,
+            &Config::default().o_direct(true),
+        )?;
+        Ok(())
+    }
+
+    #[test]
+    /// This will fail on WSL2 and networked files.
+    fn test_simplest_o_direct() -> Result<()> {
+        setup();
+        let _ = file_setup()?;
+        let len = 25;
+        let expected_contents = "0000000000111111111122222";
+
+        let mut file = open("test/file-25", true)?;
+        let mut buf: Box<AlignedBuffer> = Default::default();
+        // O_DIRECT reads must ask for whole blocks, and stop short at the end of the file:
+        assert_eq!(file.read(&mut buf)?, len);
+        let data = String::from_utf8_lossy(&buf[..len]);
+        trace!("Read file: {}", data);
+        assert_eq!(data, expected_contents);
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_arguments() {
+        setup();
+
+        assert!(
+            Opt::from_iter_safe(&["", "--o-direct", "--no-uring"]).is_err(),
+            "--o-direct and --no-uring should be an illegal combination."
+        );
+        assert!(
+            Opt::from_iter_safe(&["", "--zero", "--check", "list"]).is_err(),
+            "md5sum doesn't support --zero when verifying checksums."
+        );
+        assert!(
+            Opt::from_iter_safe(&["", "--strict", "file"]).is_err(),
+            "--strict is only meaningful with --check."
+        );
+    }
+
+    #[test]
+    fn test_parse_manifest_line() {
+        setup();
+        assert_eq!(
+            parse_line(b"D41D8CD98F00B204E9800998ECF8427E  dir/two  spaces\n"),
+            Some(ManifestEntry {
+                digest: "d41d8cd98f00b204e9800998ecf8427e".to_string(),
+                path: PathBuf::from("dir/two  spaces"),
+                blocks: None,
+                stamp: None,
+            })
+        );
+        assert_eq!(
+            parse_line(b"d41d8cd98f00b204e9800998ecf8427e *binary").map(|entry| entry.path),
+            Some(PathBuf::from("binary"))
+        );
+        assert_eq!(
+            parse_line(b"d41d8cd98f00b204e9800998ecf8427e one-space"),
+            None
+        );
+        assert_eq!(parse_line(b"not-hex  file"), None);
+        assert_eq!(parse_line(b"d41d8cd98f00b204e9800998ecf8427e  "), None);
+
+        // Paths that would break the line are escaped:
+        let path = Path::new("back\\slash\nnewline");
+        let line = format_line("d41d8cd98f00b204e9800998ecf8427e", path);
+        assert_eq!(
+            line,
+            b"\\d41d8cd98f00b204e9800998ecf8427e  back\\\\slash\\nnewline\n"
+        );
+        assert_eq!(parse_line(&line).map(|entry| entry.path), Some(path.into()));
+        assert_eq!(
+            parse_line(b"\\d41d8cd98f00b204e9800998ecf8427e  bad\\escape"),
+            None
+        );
+    }
+
+    #[test]
+    fn test_check() -> Result<()> {
+        setup();
+        let checksums = file_setup()?;
+        let good = PathBuf::from("test/file-25");
+        let bad = PathBuf::from("test/file-4096");
+        let list = Path::new("test/check.md5");
+        std::fs::write(
+            list,
+            format!(
+                "{}  {}\n{}  {}\n{}  test/missing\nnot a checksum line\n{}  {}\n",
+                to_hex(&checksums[&good]),
+                good.display(),
+                "0".repeat(32),
+                bad.display(),
+                "0".repeat(32),
+                // A SHA-256 digest doesn't belong in an MD5 list:
+                "0".repeat(64),
+                good.display(),
+            ),
+        )?;
+
+        let options = CheckOptions::default();
+        for &strategy in STRATEGIES {
+            let config = Config::default().with_strategy(strategy);
+            let summary = check::run(list, &config, &options)?;
+            assert_eq!(
+                summary,
+                CheckSummary {
+                    ok: 1,
+                    failed: 1,
+                    unreadable: 1,
+                    missing: 0,
+                    malformed: 2,
+                }
+            );
+            assert!(!summary.success(&options));
+        }
+
+        std::fs::write(
+            list,
+            format!(
+                "{}  {}\n{}  test/missing\n",
+                to_hex(&checksums[&good]),
+                good.display(),
+                "0".repeat(32),
+            ),
+        )?;
+        let run = |algorithm, options: &CheckOptions| {
+            check::run(list, &Config::default().algorithm(algorithm), options)
+        };
+        assert!(!run(HashAlgorithm::Md5, &options)?.success(&options));
+        let options = CheckOptions {
+            ignore_missing: true,
+            ..Default::default()
+        };
+        let summary = run(HashAlgorithm::Md5, &options)?;
+        assert_eq!((summary.ok, summary.missing), (1, 1));
+        assert!(summary.success(&options));
+        assert!(!run(HashAlgorithm::Sha256, &options)?.success(&options));
+
+        // Improperly formatted lines only fail the check with --strict:
+        let mut contents = std::fs::read(list)?;
+        contents.extend_from_slice(b"not a checksum line\n");
+        std::fs::write(list, contents)?;
+        assert!(run(HashAlgorithm::Md5, &options)?.success(&options));
+        let options = CheckOptions {
+            strict: true,
+            ..options
+        };
+        assert!(!run(HashAlgorithm::Md5, &options)?.success(&options));
+        Ok(())
+    }
+
+    #[test]
+    fn test_check_diagnose() -> Result<()> {
+        setup();
+        let _ = file_setup()?;
+        let path = Path::new("test/check-diagnose");
+        let list = Path::new("test/check-diagnose.md5");
+        let block_size = 1000;
+        let mut data: Vec<u8> = (0..3500u32).map(|i| (i % 251) as u8).collect();
+        std::fs::write(path, &data)?;
+        let (whole, digests) =
+            diagnose::digest_with_blocks(&open(path, false)?, block_size, HashAlgorithm::Md5)?;
+        let mut contents = format_line(&to_hex(&whole.finalize()), path);
+        contents.extend(crate::manifest::format_block_lines(
+            block_size, &digests, path,
+        ));
+        std::fs::write(list, contents)?;
+
+        // One corrupted block fails the file, and the diagnosis finds that block:
+        data[1500] ^= 0xff;
+        std::fs::write(path, &data)?;
+        let options = CheckOptions {
+            diagnose: true,
+            ..Default::default()
+        };
+        let summary = check::run(list, &Config::default(), &options)?;
+        assert_eq!((summary.ok, summary.failed), (0, 1));
+        let entries = crate::manifest::read_checksum_list(list, 32)?.entries;
+        assert_eq!(
+            diagnose::describe_first_mismatch(&open(path, false)?, &entries[0], HashAlgorithm::Md5),
+            format!(
+                "first difference in bytes 1000-2000 (expected {}, got {})",
+                digests[1],
+                to_hex(&Md5::digest(&data[1000..2000]))
+            )
+        );
+
+        // --diagnose is only for --check:
+        assert!(Opt::from_iter_safe(["", "--diagnose", "file"]).is_err());
+        Ok(())
+    }
+
+    #[test]
+    fn test_atomic_file() -> Result<()> {
+        setup();
+        let _ = file_setup()?;
+        let path = Path::new("test/atomic-output");
+        let _ = std::fs::remove_file(path);
+
+        let mut file = AtomicFile::create(path)?;
+        file.write_all(b"unfinished")?;
+        drop(file);
+        assert!(!path.exists());
+
+        let mut file = AtomicFile::create(path)?;
+        file.write_all(b"first\n")?;
+        assert!(
+            !path.exists(),
+            "Nothing is written until the file is committed."
+        );
+        file.commit()?;
+        assert_eq!(std::fs::read(path)?, b"first\n");
+
+        let mut file = AtomicFile::create(path)?;
+        file.write_all(b"second\n")?;
+        drop(file);
+        assert_eq!(std::fs::read(path)?, b"first\n");
+
+        let leftovers = std::fs::read_dir("test")?
+            .filter(|entry| {
+                entry.as_ref().is_ok_and(|entry| {
+                    entry
+                        .file_name()
+                        .to_string_lossy()
+                        .starts_with(".atomic-output")
+                })
+            })
+            .count();
+        assert_eq!(leftovers, 0);
+        Ok(())
+    }
+
+    #[test]
+    fn test_progress() -> Result<()> {
+        setup();
+        let checksums = file_setup()?;
+        let total: u64 = checksums
+            .keys()
+            .map(|path| std::fs::metadata(path).map(|metadata| metadata.len()))
+            .sum::<std::io::Result<_>>()?;
+        for &strategy in STRATEGIES {
+            // Other tests hash files at the same time, so this can only check a lower bound:
+            let before = progress::bytes_hashed();
+            let config = Config::default().with_strategy(strategy);
+            assert_algorithm_checksums(crate::get_checksums, &config)?;
+            assert!(progress::bytes_hashed() - before >= total);
+        }
+        Ok(())
+    }
+
+    #[test]
+    fn test_files_from() -> Result<()> {
+        setup();
+        let _ = file_setup()?;
+        let list = Path::new("test/files-from");
+        std::fs::write(list, b"test/file-25\0test/with\nnewline\0\0")?;
+        assert_eq!(
+            input::read_file_list(list, true)?,
+            [PathBuf::from("test/file-25"), "test/with\nnewline".into()]
+        );
+        assert_eq!(input::read_file_list(list, false)?.len(), 2);
+
+        let options = Opt::from_iter_safe(&[
+            "",
+            "test/file-4096",
+            "--files-from",
+            "test/files-from",
+            "-0",
+        ])?;
+        assert_eq!(input::collect_files(&options)?.len(), 3);
+        assert!(Opt::from_iter_safe(&["", "-0", "file"]).is_err());
+        Ok(())
+    }
+
+    #[test]
+    fn test_recursive_exclude() -> Result<()> {
+        setup();
+        let _ = file_setup()?;
+        for dir in [
+            "test/tree/.git",
+            "test/tree/src/cache",
+            "test/tree/src/nested",
+        ] {
+            std::fs::create_dir_all(dir)?;
+        }
+        for file in [
+            "test/tree/.git/HEAD",
+            "test/tree/src/cache/data",
+            "test/tree/src/nested/keep.rs",
+            "test/tree/src/nested/scratch.tmp",
+            "test/tree/top.rs",
+        ] {
+            std::fs::write(file, file)?;
+        }
+        std::fs::write("test/tree-excludes", "# comment\n\ntest/tree/src/cache/\n")?;
+
+        let options = Opt::from_iter_safe(&[
+            "",
+            "-r",
+            "test/tree",
+            "--exclude",
+            ".git",
+            "--exclude",
+            "*.tmp",
+            "--exclude-from",
+            "test/tree-excludes",
+        ])?;
+        assert_eq!(
+            input::collect_files(&options)?,
+            [
+                PathBuf::from("test/tree/src/nested/keep.rs"),
+                "test/tree/top.rs".into()
+            ]
+        );
+
+        // Without --recursive, a directory is hashed like any file, and fails:
+        let options = Opt::from_iter_safe(&["", "test/tree"])?;
+        assert_eq!(
+            input::collect_files(&options)?,
+            [PathBuf::from("test/tree")]
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn test_max_depth() -> Result<()> {
+        setup();
+        let dir = Path::new("test/depth");
+        std::fs::create_dir_all(dir.join("a/b"))?;
+        for file in ["top", "a/middle", "a/b/bottom"] {
+            std::fs::write(dir.join(file), file)?;
+        }
+        let collect = |args: &[&str]| -> Result<Vec<PathBuf>> {
+            let args = [&["", "-r", "test/depth"], args].concat();
+            input::collect_files(&Opt::from_iter_safe(&args)?)
+        };
+
+        assert_eq!(collect(&[])?.len(), 3);
+        assert_eq!(collect(&["--max-depth", "1"])?, [dir.join("top")]);
+        assert_eq!(
+            collect(&["--max-depth", "2"])?,
+            [dir.join("a/middle"), dir.join("top")]
+        );
+        assert!(collect(&["--max-depth", "0"])?.is_empty());
+        assert!(Opt::from_iter_safe(["", "--max-depth", "1", "test/depth"]).is_err());
+        Ok(())
+    }
+
+    #[test]
+    fn test_sort_physical() -> Result<()> {
+        setup();
+        let files = file_setup()?;
+        let mut sorted: Vec<PathBuf> = files.keys().cloned().collect();
+        sorted.push(PathBuf::from("test/does-not-exist"));
+        input::sort_physical(&mut sorted);
+        assert_eq!(sorted.len(), files.len() + 1);
+        assert_eq!(sorted.last().unwrap(), Path::new("test/does-not-exist"));
+
+        let options = Opt::from_iter_safe(["", "--sort", "physical", "test/a", "test/b"])?;
+        assert_eq!(options.sort, Some(input::SortOrder::Physical));
+        assert!(Opt::from_iter_safe(["", "--sort", "sideways"]).is_err());
+        Ok(())
+    }
+
+    #[test]
+    fn test_size_filters() -> Result<()> {
+        setup();
+        let dir = Path::new("test/sizes");
+        std::fs::create_dir_all(dir)?;
+        for (name, len) in [("empty", 0), ("small", 100), ("large", 2048)] {
+            std::fs::write(dir.join(name), vec![0u8; len])?;
+        }
+        let collect = |args: &[&str]| -> Result<Vec<PathBuf>> {
+            let args = [&["", "-r", "test/sizes"], args].concat();
+            input::collect_files(&Opt::from_iter_safe(&args)?)
+        };
+
+        assert_eq!(collect(&["--min-size", "1"])?.len(), 2);
+        assert_eq!(collect(&["--min-size", "1K"])?, [dir.join("large")]);
+        assert_eq!(
+            collect(&["--max-size", "100"])?,
+            [dir.join("empty"), dir.join("small")]
+        );
+        assert_eq!(
+            collect(&["--min-size", "1", "--max-size", "1KiB"])?,
+            [dir.join("small")]
+        );
+
+        assert_eq!(input::parse_size("64k"), Ok(64 * 1024));
+        assert_eq!(input::parse_size("3G"), Ok(3 << 30));
+        assert!(input::parse_size("1X").is_err());
+        assert!(input::parse_size("M").is_err());
+        assert!(input::parse_size("99999999999T").is_err());
+        Ok(())
+    }
+
+    #[test]
+    fn test_newer_than() -> Result<()> {
+        setup();
+        let dir = Path::new("test/mtimes");
+        std::fs::create_dir_all(dir)?;
+        for (name, mtime) in [
+            ("old", 1_000_000_000),
+            ("reference", 1_500_000_000),
+            ("new", 2_000_000_000),
+        ] {
+            let file = std::fs::File::create(dir.join(name))?;
+            file.set_modified(UNIX_EPOCH + Duration::from_secs(mtime))?;
+        }
+        let collect = |args: &[&str]| -> Result<Vec<PathBuf>> {
+            let args = [&["", "-r", "test/mtimes"], args].concat();
+            input::collect_files(&Opt::from_iter_safe(&args)?)
+        };
+
+        assert_eq!(
+            collect(&["--newer-than", "test/mtimes/reference"])?,
+            [dir.join("new")]
+        );
+        assert_eq!(collect(&["--newer-than", "@1400000000"])?.len(), 2);
+        assert_eq!(collect(&["--newer-than", "2001-01-01"])?.len(), 3);
+        assert_eq!(collect(&["--newer-than", "2030-01-01 00:00:00"])?.len(), 1);
+        assert!(Opt::from_iter_safe(["", "--newer-than", "yesterday"]).is_err());
+        Ok(())
+    }
+
+    #[test]
+    fn test_symlinks() -> Result<()> {
+        setup();
+        let _ = file_setup()?;
+        std::fs::create_dir_all("test/links/dir")?;
+        std::fs::write("test/links/dir/file", b"file")?;
+        for (target, link) in [
+            ("dir/file", "test/links/file-link"),
+            ("dir", "test/links/dir-link"),
+        ] {
+            let _ = std::fs::remove_file(link);
+            std::os::unix::fs::symlink(target, link)?;
+        }
+        let collect = |args: &[&str]| -> Result<Vec<PathBuf>> {
+            let args = [&[""], args].concat();
+            input::collect_files(&Opt::from_iter_safe(&args)?)
+        };
+
+        // By default, only named links are followed:
+        assert_eq!(
+            collect(&["-r", "test/links"])?,
+            [PathBuf::from("test/links/dir/file")]
+        );
+        assert_eq!(collect(&["test/links/file-link"])?.len(), 1);
+        assert_eq!(collect(&["-r", "test/links/dir-link"])?.len(), 1);
+
+        assert_eq!(collect(&["-L", "-r", "test/links"])?.len(), 3);
+        assert!(collect(&["-P", "test/links/file-link"])?.is_empty());
+        // The last of the flags wins:
+        assert_eq!(collect(&["-P", "-L", "test/links/file-link"])?.len(), 1);
+        Ok(())
+    }
+
+    #[test]
+    fn test_special_files() -> Result<()> {
+        setup();
+        let _ = file_setup()?;
+        let fifo = Path::new("test/fifo");
+        let _ = std::fs::remove_file(fifo);
+        let c_path = std::ffi::CString::new("test/fifo")?;
+        assert_eq!(unsafe { libc::mkfifo(c_path.as_ptr(), 0o600) }, 0);
+
+        let collect = |args: &[&str]| -> Result<Vec<PathBuf>> {
+            let args = [&[""], args].concat();
+            input::collect_files(&Opt::from_iter_safe(&args)?)
+        };
+        // Pipes are read without --allow-special, but devices are skipped:
+        assert_eq!(collect(&["test/fifo"])?, [fifo]);
+        assert!(collect(&["/dev/null"])?.is_empty());
+        assert_eq!(
+            collect(&["--allow-special", "/dev/null"])?,
+            [Path::new("/dev/null")]
+        );
+        // Pipes found while recursing are skipped, since they'd usually never be written to:
+        let dir = Path::new("test/fifo-dir");
+        let _ = std::fs::remove_dir_all(dir);
+        std::fs::create_dir(dir)?;
+        std::fs::write(dir.join("file"), b"file")?;
+        let c_path = std::ffi::CString::new("test/fifo-dir/fifo")?;
+        assert_eq!(unsafe { libc::mkfifo(c_path.as_ptr(), 0o600) }, 0);
+        assert_eq!(collect(&["-r", "test/fifo-dir"])?, [dir.join("file")]);
+        assert_eq!(
+            collect(&["-r", "--allow-special", "test/fifo-dir"])?,
+            [dir.join("fifo"), dir.join("file")]
+        );
+        assert_eq!(collect(&["test/fifo-dir/fifo"])?, [dir.join("fifo")]);
+
+        let data = vec![7u8; MAX_READ_SIZE * 3 + 5];
+        let writer = {
+            let data = data.clone();
+            std::thread::spawn(move || std::fs::write("test/fifo", data))
+        };
+        let ctx = crate::checksum_stream(fifo, Md5::new())?;
+        writer.join().unwrap()?;
+        assert_eq!(ctx.finalize(), Md5::digest(&data));
+        Ok(())
+    }
+
+    #[test]
+    fn test_stdin() -> Result<()> {
+        setup();
+        // Without files, stdin is hashed:
+        let files = input::collect_files(&Opt::from_iter_safe([""])?)?;
+        assert_eq!(files, [PathBuf::from("-")]);
+        assert!(input::is_stream(&files[0]));
+
+        let data = b"stdin data".repeat(10000);
+        let ctx = crate::checksum_reader(&data[..], Md5::new())?;
+        assert_eq!(ctx.finalize(), Md5::digest(&data));
+        Ok(())
+    }
+
+    #[test]
+    fn test_checksum_file() -> Result<()> {
+        setup();
+        let _ = file_setup()?;
+        let data: Vec<u8> = (0..MAX_READ_SIZE * 2 + 7).map(|i| i as u8).collect();
+        std::fs::write("test/positioned", &data)?;
+        // A descriptor that was partly read is hashed from where it is:
+        let mut file = File::open("test/positioned")?;
+        let mut start = [0; 100];
+        file.read_exact(&mut start)?;
+        let ctx = crate::checksum_file(&file, Md5::new())?;
+        assert_eq!(ctx.finalize(), Md5::digest(&data[100..]));
+        assert_eq!(
+            std::io::Seek::stream_position(&mut file)?,
+            data.len() as u64
+        );
+
+        let (reader, mut writer) = std::io::pipe()?;
+        let writer = {
+            let data = data.clone();
+            std::thread::spawn(move || writer.write_all(&data))
+        };
+        let file = File::from(std::os::fd::OwnedFd::from(reader));
+        let ctx = crate::checksum_file(&file, Md5::new())?;
+        writer.join().unwrap()?;
+        assert_eq!(ctx.finalize(), Md5::digest(&data));
+        Ok(())
+    }
+
+    #[test]
+    fn test_evented() -> Result<()> {
+        setup();
+        let checksums = file_setup()?;
+        std::fs::write("test/evented-empty", "")?;
+        let mut paths: Vec<PathBuf> = checksums.keys().cloned().collect();
+        paths.push("test/evented-empty".into());
+        paths.push("test/evented-missing".into());
+        let config = Config::default().ring_size(4).read_size(ALIGNMENT);
+        let mut evented = evented::EventedChecksums::new(paths.clone(), config)?;
+
+        let mut results = Vec::new();
+        while !evented.is_done() {
+            // The descriptor becomes readable when there's something to process:
+            let mut pollfd = libc::pollfd {
+                fd: evented.as_raw_fd(),
+                events: libc::POLLIN,
+                revents: 0,
+            };
+            assert_eq!(unsafe { libc::poll(&mut pollfd, 1, 10_000) }, 1);
+            results.extend(evented.process()?);
+        }
+        assert_eq!(results.len(), paths.len());
+        for result in results {
+            if result.path == Path::new("test/evented-missing") {
+                assert!(result.error.is_some());
+            } else if result.path == Path::new("test/evented-empty") {
+                assert_eq!(result.digest, Md5::digest(b"").to_vec());
+            } else {
+                assert_eq!(result.digest, checksums[&result.path]);
+            }
+        }
+
+        // Dropping it with reads in flight waits for them:
+        drop(evented::EventedChecksums::new(paths, Config::default())?);
+        Ok(())
+    }
+
+    #[test]
+    fn test_byte_cmp() -> Result<()> {
+        setup();
+        let _ = file_setup()?;
+        let data: Vec<u8> = (0..ALIGNMENT * 5 + 100).map(|i| i as u8).collect();
+        let mut changed = data.clone();
+        changed[ALIGNMENT + 7] ^= 1;
+        changed[ALIGNMENT * 3] ^= 1;
+        let paths = [
+            "test/cmp-a",
+            "test/cmp-b",
+            "test/cmp-changed",
+            "test/cmp-short",
+        ];
+        std::fs::write(paths[0], &data)?;
+        std::fs::write(paths[1], &data)?;
+        std::fs::write(paths[2], &changed)?;
+        std::fs::write(paths[3], &data[..ALIGNMENT * 2 + 1])?;
+
+        let compare = |a: &str, b: &str| {
+            byte_cmp::compare_files(Path::new(a), Path::new(b), ALIGNMENT, &Config::default())
+        };
+        let report = compare(paths[0], paths[1])?;
+        assert!(report.is_identical());
+        assert_eq!((report.chunks, report.differing_chunks), (6, 0));
+
+        let report = compare(paths[0], paths[2])?;
+        assert_eq!(report.first_difference, Some(ALIGNMENT as u64 + 7));
+        assert_eq!(report.differing_chunks, 2);
+
+        // Past the end of the shorter file, the chunk it ends in and those after it differ:
+        let report = compare(paths[3], paths[0])?;
+        assert_eq!(report.first_difference, Some(ALIGNMENT as u64 * 2 + 1));
+        assert_eq!((report.chunks, report.differing_chunks), (6, 4));
+        let report = compare(paths[2], paths[3])?;
+        assert_eq!(report.first_difference, Some(ALIGNMENT as u64 + 7));
+        assert_eq!(report.differing_chunks, 5);
+
+        assert!(compare(paths[0], "test/cmp-missing").is_err());
+        let args = Opt::from_iter_safe(["", "cmp", "--chunk-size", "64K", "a", "b"])?;
+        assert!(matches!(
+            args.command,
+            Some(crate::Command::Cmp {
+                chunk_size: 65536,
+                ..
+            })
+        ));
+        Ok(())
+    }
+
+    #[test]
+    fn test_tar_archive() -> Result<()> {
+        setup();
+        let dir = Path::new("test/tar");
+        let long_name = format!("{}/file", "d".repeat(120));
+        std::fs::create_dir_all(dir.join("src").join(&long_name).parent().unwrap())?;
+        let members = [
+            ("a", b"first".to_vec()),
+            ("empty", Vec::new()),
+            (&long_name, vec![9u8; 70000]),
+        ];
+        for (name, data) in &members {
+            std::fs::write(dir.join("src").join(name), data)?;
+        }
+
+        for format in ["gnu", "pax"] {
+            // GNU tar stores the long name in a GNU long name member or in a pax header:
+            let archive = dir.join(format!("{}.tar", format));
+            let status = std::process::Command::new("tar")
+                .arg(format!("--format={}", format))
+                .arg("-cf")
+                .arg(std::env::current_dir()?.join(&archive))
+                .args(["-C", "test/tar/src", "a", "empty", &long_name])
+                .status()?;
+            assert!(status.success());
+
+            let mut hashed = Vec::new();
+            archive::hash_tar(&archive, false, HashAlgorithm::Md5, |member, digest| {
+                hashed.push((member, digest));
+                Ok(())
+            })?;
+            let expected: Vec<_> = members
+                .iter()
+                .map(|(name, data)| (PathBuf::from(name), Md5::digest(data).to_vec()))
+                .collect();
+            assert_eq!(hashed, expected, "{} format", format);
+        }
+
+        assert_eq!(
+            archive::member_path(Path::new("x.tar"), Path::new("dir/file")),
+            Path::new("x.tar//dir/file")
+        );
+        let not_tar = archive::hash_tar(
+            Path::new("Cargo.toml"),
+            false,
+            HashAlgorithm::Md5,
+            |_, _| Ok(()),
+        );
+        assert!(not_tar.is_err());
+        Ok(())
+    }
+
+    #[test]
+    fn test_sqpoll() -> Result<()> {
+        setup();
+        let options = ring::RingOptions {
+            sqpoll_idle: Some(10),
+            ..Default::default()
+        };
+        // This falls back to a plain ring where SQPOLL isn't allowed, which works the same:
+        let mut ring = ring::new_ring(4, &Config::default().ring_options(options))?;
+        let nop = io_uring::opcode::Nop::new().build().user_data(42);
+        unsafe { ring.submission().push(&nop)? };
+        ring.submit_and_wait(1)?;
+        assert_eq!(
+            ring.completion().next().map(|cqe| cqe.user_data()),
+            Some(42)
+        );
+
+        let args = Opt::from_iter_safe(["", "--sqpoll", "file"])?;
+        assert_eq!((args.sqpoll, args.files.len()), (Some(None), 1));
+        let args = Opt::from_iter_safe(["", "--sqpoll=50", "file"])?;
+        assert_eq!(args.sqpoll, Some(Some(50)));
+        Ok(())
+    }
+
+    #[test]
+    fn test_registered_ring() -> Result<()> {
+        setup();
+        for options in [
+            ring::RingOptions::default(),
+            ring::RingOptions {
+                sqpoll_idle: Some(10),
+                ..Default::default()
+            },
+        ] {
+            let mut ring = ring::new_ring(4, &Config::default().ring_options(options))?;
+            // The descriptor can be registered since Linux 5.18, but entering works either way:
+            debug!("Registered: {}", ring.is_registered());
+            for i in 0..3 {
+                let nop = io_uring::opcode::Nop::new().build().user_data(i);
+                unsafe { ring.submission().push(&nop)? };
+            }
+            ring.submit_and_wait(3)?;
+            let mut done: Vec<_> = ring.completion().map(|cqe| cqe.user_data()).collect();
+            done.sort();
+            assert_eq!(done, [0, 1, 2]);
+        }
+        Ok(())
+    }
+
+    #[test]
+    fn test_shared_workers() -> Result<()> {
+        setup();
+        let Some(shared) = ring::new_shared_workers(&Default::default()) else {
+            // Rings can't share their workers before Linux 5.6:
+            return Ok(());
+        };
+        let config = Config {
+            attach_wq: Some(shared.as_raw_fd()),
+            ..Default::default()
+        };
+        let mut ring = ring::new_ring(4, &config)?;
+        // Reads that block are done on the shared workers:
+        let file = File::open("Cargo.toml")?;
+        let mut buf = vec![0; 16];
+        let read = io_uring::opcode::Read::new(
+            io_uring::types::Fd(file.as_raw_fd()),
+            buf.as_mut_ptr(),
+            buf.len() as u32,
+        )
+        .build()
+        .flags(io_uring::squeue::Flags::ASYNC)
+        .user_data(42);
+        unsafe { ring.push(&read)? };
+        let cqe = ring.next_completion()?;
+        assert_eq!((cqe.user_data(), cqe.result()), (42, 16));
+
+        // A descriptor that isn't a ring's is ignored:
+        let config = Config {
+            attach_wq: Some(file.as_raw_fd()),
+            ..Default::default()
+        };
+        ring::new_ring(4, &config)?;
+        Ok(())
+    }
+
+    #[test]
+    fn test_task_run_flags() -> Result<()> {
+        setup();
+        // The second ring starts from the flags the first found to work:
+        for _ in 0..2 {
+            let mut ring = ring::new_ring(4, &Default::default())?;
+            // SINGLE_ISSUER is set since Linux 6.0. With DEFER_TASKRUN, completions only arrive
+            // while waiting for them:
+            debug!("Single issuer: {}", ring.params().is_setup_single_issuer());
+            let nop = io_uring::opcode::Nop::new().build().user_data(42);
+            unsafe { ring.push(&nop)? };
+            assert_eq!(ring.next_completion()?.user_data(), 42);
+        }
+        Ok(())
+    }
+
+    #[test]
+    fn test_file_lens() -> Result<()> {
+        let checksums = file_setup()?;
+        let files = checksums
+            .keys()
+            .map(File::open)
+            .collect::<std::io::Result<Vec<_>>>()?;
+        let files: Vec<_> = files.iter().collect();
+        // A small ring, so the lookups take several rounds:
+        let mut ring = ring::new_ring(4, &Default::default())?;
+        let lens = ring::file_lens(&mut ring, &files)?;
+        for (file, len) in files.iter().zip(lens) {
+            assert_eq!(len?, file.metadata()?.len());
+        }
+        Ok(())
+    }
+
+    #[test]
+    fn test_file_slots() -> Result<()> {
+        setup();
+        let mut ring = ring::new_ring(4, &Default::default())?;
+        ring::register_file_slots(&ring, 4)?;
+        let file = File::open("Cargo.toml")?;
+        let mut buf = [0u8; 16];
+        let mut read_slot = |ring: &mut ring::Ring| -> Result<i32> {
+            let read_e = io_uring::opcode::Read::new(
+                io_uring::types::Fixed(2),
+                buf.as_mut_ptr(),
+                buf.len() as _,
+            )
+            .build();
+            unsafe { ring.push(&read_e)? };
+            Ok(ring.next_completion()?.result())
+        };
+
+        ring::set_file_slot(&ring, 2, Some(file.as_raw_fd()))?;
+        assert_eq!(read_slot(&mut ring)?, 16);
+        // An empty slot can't be read:
+        ring::set_file_slot(&ring, 2, None)?;
+        assert_eq!(read_slot(&mut ring)?, -libc::EBADF);
+        Ok(())
+    }
+
+    #[test]
+    fn test_read_timeout() -> Result<()> {
+        setup();
+        let config = Config::default().read_timeout(Some(Duration::from_millis(200)));
+        let mut ring = ring::new_ring(4, &config)?;
+        let mut buf = [0u8; 16];
+        let mut read = |ring: &mut ring::Ring, file: &File| -> Result<io_uring::cqueue::Entry> {
+            let read_e = io_uring::opcode::Read::new(
+                io_uring::types::Fd(file.as_raw_fd()),
+                buf.as_mut_ptr(),
+                buf.len() as _,
+            )
+            // The current position, since a pipe has no offsets:
+            .offset(u64::MAX)
+            .build()
+            .user_data(7);
+            unsafe { ring.push_read(&read_e)? };
+            Ok(ring.next_completion()?)
+        };
+
+        // The timeout's completion is skipped:
+        let cqe = read(&mut ring, &File::open("Cargo.toml")?)?;
+        assert_eq!((cqe.user_data(), cqe.result()), (7, 16));
+        assert!(ring.timeout_error(cqe.result()).is_none());
+
+        // Nothing is ever written to the pipe, so the read is cancelled:
+        let mut fds = [0; 2];
+        assert_eq!(unsafe { libc::pipe(fds.as_mut_ptr()) }, 0);
+        let (reader, _writer) = unsafe { (File::from_raw_fd(fds[0]), File::from_raw_fd(fds[1])) };
+        let cqe = read(&mut ring, &reader)?;
+        assert_eq!(cqe.user_data(), 7);
+        let err = ring
+            .timeout_error(cqe.result())
+            .expect("the read timed out");
+        assert_eq!(err.kind(), ErrorKind::TimedOut);
+
+        let args = Opt::from_iter_safe(["", "--read-timeout", "0.5", "file"])?;
+        assert_eq!(args.read_timeout, Some(Duration::from_millis(500)));
+        assert!(Opt::from_iter_safe(["", "--read-timeout", "0", "file"]).is_err());
+        assert!(Opt::from_iter_safe(["", "--read-timeout", "1", "--no-uring", "file"]).is_err());
+        Ok(())
+    }
+
+    #[test]
+    fn test_nowait_first() -> Result<()> {
+        setup();
+        let path = PathBuf::from("test/nowait-first");
+        let data: Vec<u8> = (0..MAX_READ_SIZE * 2).map(|i| (i % 251) as u8).collect();
+        std::fs::write(&path, &data)?;
+        // Dirty pages can't be dropped:
+        File::open(&path)?.sync_all()?;
+        let file = File::open(&path)?;
+
+        let mut ring = ring::new_ring(4, &Config::default().nowait_first(true))?;
+        let mut buf = AlignedBuffer::new();
+        let mut read = |ring: &mut ring::Ring| -> Result<io_uring::cqueue::Entry> {
+            let (ptr, len) = (buf.as_mut_ptr(), buf.len() as u32);
+            unsafe {
+                ring.push_read_with(len, |rw_flags| {
+                    io_uring::opcode::Read::new(io_uring::types::Fd(file.as_raw_fd()), ptr, len)
+                        .rw_flags(rw_flags)
+                        .build()
+                        .user_data(3)
+                })?
+            };
+            Ok(ring.next_completion()?)
+        };
+
+        // Uncached data is read by the second try, and only its completion is seen:
+        let advice = libc::POSIX_FADV_DONTNEED;
+        assert_eq!(
+            unsafe { libc::posix_fadvise(file.as_raw_fd(), 0, 0, advice) },
+            0
+        );
+        let cqe = read(&mut ring)?;
+        assert_eq!((cqe.user_data(), cqe.result()), (3, MAX_READ_SIZE as i32));
+        assert!(ring.pop_completion().is_none());
+        // Now it's cached:
+        let cqe = read(&mut ring)?;
+        assert_eq!((cqe.user_data(), cqe.result()), (3, MAX_READ_SIZE as i32));
+        assert_eq!(buf[..], data[..MAX_READ_SIZE]);
+
+        assert_checksums(
+            with_fixed_buffers::get_checksums,
+            &Config::default().nowait_first(true),
+        )?;
+        assert!(
+            Opt::from_iter_safe(["", "--nowait-first", "--use-provided-buffers", "f"]).is_err()
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn test_short_reads() -> Result<()> {
+        setup();
+        let checksums = file_setup()?;
+        let paths: Vec<PathBuf> = checksums.keys().cloned().collect();
+
+        // The mock cuts reads down, and the rest of the buffer is left as it was:
+        ring::faults::set_max_read_len(Some(1000));
+        let mut ring = ring::new_ring(1, &Default::default())?;
+        let file = File::open("test/file-4096")?;
+        let mut buf = vec![0u8; 4096];
+        let read_e = io_uring::opcode::Read::new(
+            io_uring::types::Fd(file.as_raw_fd()),
+            buf.as_mut_ptr(),
+            buf.len() as u32,
+        )
+        .build();
+        unsafe { ring.push(&read_e)? };
+        assert_eq!(ring.next_completion()?.result(), 1000);
+        assert!(buf[1000..].iter().all(|&byte| byte == 0));
+
+        type GetMd5s =
+            fn(Vec<PathBuf>, Sender<(PathBuf, Result<Md5>)>, &Config, fn() -> Md5) -> Result<()>;
+        let strategies: [GetMd5s; 4] = [
+            simple_uring::get_checksums,
+            with_register_files::get_checksums,
+            with_fixed_buffers::get_checksums,
+            with_provided_buffers::get_checksums,
+        ];
+        let mut result = Ok(());
+        for get_checksums in strategies {
+            // Run on this thread, where reads are cut short:
+            let (tx, rx) = channel();
+            result = get_checksums(paths.clone(), tx, &Config::default(), Md5::new);
+            if result.is_err() {
+                break;
+            }
+            for (path, digest) in rx {
+                let digest: [u8; 16] = digest?.finalize().into();
+                assert_eq!(checksums.get(&path).unwrap(), &digest, "{:?}", path);
+            }
+        }
+        ring::faults::set_max_read_len(None);
+        result
+    }
+
+    #[test]
+    fn test_transient_retries() -> Result<()> {
+        setup();
+        let checksums = file_setup()?;
+        let paths: Vec<PathBuf> = checksums.keys().cloned().collect();
+
+        type GetMd5s =
+            fn(Vec<PathBuf>, Sender<(PathBuf, Result<Md5>)>, &Config, fn() -> Md5) -> Result<()>;
+        let strategies: [GetMd5s; 4] = [
+            simple_uring::get_checksums,
+            with_register_files::get_checksums,
+            with_fixed_buffers::get_checksums,
+            with_provided_buffers::get_checksums,
+        ];
+        let mut result = Ok(());
+        for get_checksums in strategies {
+            // Run on this thread, where the first reads are interrupted:
+            ring::faults::fail_reads(3, libc::EINTR);
+            let (tx, rx) = channel();
+            result = get_checksums(paths.clone(), tx, &Config::default(), Md5::new);
+            if result.is_err() {
+                break;
+            }
+            for (path, digest) in rx {
+                let digest: [u8; 16] = digest?.finalize().into();
+                assert_eq!(checksums.get(&path).unwrap(), &digest, "{:?}", path);
+            }
+
+            // A read that keeps failing fails its file after the last retry:
+            ring::faults::fail_reads(ring::MAX_READ_RETRIES + 1, libc::EAGAIN);
+            let (tx, rx) = channel();
+            result = get_checksums(
+                vec![PathBuf::from("test/file-4096")],
+                tx,
+                &Config::default(),
+                Md5::new,
+            );
+            if result.is_err() {
+                break;
+            }
+            let (_, digest) = rx.recv()?;
+            let Err(err) = digest else {
+                panic!("a read that failed every time was hashed");
+            };
+            let err = err.downcast_ref::<std::io::Error>().unwrap();
+            assert_eq!(err.raw_os_error(), Some(libc::EAGAIN));
+        }
+        ring::faults::fail_reads(0, 0);
+        result
+    }
+
+    #[test]
+    fn test_read_errors() -> Result<()> {
+        setup();
+        let checksums = file_setup()?;
+        // A directory opens, but reading it fails with EISDIR:
+        let paths = vec![
+            PathBuf::from("test/file-25"),
+            PathBuf::from("src"),
+            PathBuf::from("test/file-4096"),
+        ];
+        for strategy in STRATEGIES {
+            let name = strategy.name();
+            let (tx, rx) = channel();
+            strategy.get_checksums(paths.clone(), &tx, &Config::default())?;
+            drop(tx);
+            let mut results = 0;
+            for result in rx {
+                results += 1;
+                let path = result.path.clone();
+                if path == Path::new("src") {
+                    let Some(err) = result.error else {
+                        panic!("{} hashed a directory", name);
+                    };
+                    // Memory mapping a directory fails before it's read:
+                    if name != "no-uring" {
+                        let err = err.downcast_ref::<std::io::Error>().expect(name);
+                        assert_eq!(err.raw_os_error(), Some(libc::EISDIR), "{}", name);
+                    }
+                } else {
+                    let digest = result.into_result()?;
+                    assert_eq!(checksums.get(&path).unwrap()[..], digest[..], "{}", name);
+                }
+            }
+            assert_eq!(results, paths.len(), "{}", name);
+        }
+        Ok(())
+    }
+
+    #[test]
+    fn test_watch_list_algorithm() -> Result<()> {
+        setup();
+        file_setup()?;
+        let list = Path::new("test/watch.sha256");
+        let digest = "0".repeat(64);
+        std::fs::write(list, format!("{}  test/file-25\n", digest))?;
+        // The list is checked before fanotify, which needs root, is set up:
+        let err = watch::run(Path::new("test"), list, HashAlgorithm::Md5, false, false)
+            .expect_err("a SHA-256 list was read as MD5");
+        assert!(
+            err.to_string().contains("improperly formatted MD5"),
+            "{}",
+            err
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn test_session_batches() -> Result<()> {
+        let checksums = file_setup()?;
+        let paths: Vec<_> = checksums.keys().cloned().collect();
+        let configs = STRATEGIES
+            .iter()
+            .map(|strategy| Config::default().with_strategy(*strategy))
+            .chain([Config::default().hash_threads(2)]);
+        for config in configs {
+            let name = config.strategy.name();
+            let mut session = ChecksumSession::new(config)?;
+            // The same path twice is read once, and the session is reused for each batch:
+            let batches = [
+                &paths[..1],
+                &paths[1..],
+                &paths[..],
+                &[paths[0].clone(), paths[0].clone()],
+            ];
+            for batch in batches {
+                let (tx, rx) = channel();
+                session.checksum(batch.to_vec(), tx)?;
+                let mut results = 0;
+                for result in rx {
+                    results += 1;
+                    let expected = checksums.get(&result.path).unwrap();
+                    assert_eq!(expected[..], result.into_result()?[..], "{}", name);
+                }
+                assert_eq!(results, batch.len(), "{}", name);
+            }
+        }
+        Ok(())
+    }
+
+    #[test]
+    fn test_close_on_ring() -> Result<()> {
+        setup();
+        let mut ring = ring::new_ring(4, &Default::default())?;
+        ring.close(File::open("Cargo.toml")?);
+        let nop = io_uring::opcode::Nop::new().build().user_data(7);
+        unsafe { ring.push(&nop)? };
+        // The close's completion is skipped:
+        assert_eq!(ring.next_completion()?.user_data(), 7);
+        assert!(ring.pop_completion().is_none());
+        Ok(())
+    }
+
+    #[test]
+    fn test_wait_for_all() -> Result<()> {
+        setup();
+        let mut ring = ring::new_ring(4, &Default::default())?;
+        let file = File::open("Cargo.toml")?;
+        let mut buf = [0; 32];
+        for (i, part) in buf.chunks_mut(16).enumerate() {
+            let read = io_uring::opcode::Read::new(
+                io_uring::types::Fd(file.as_raw_fd()),
+                part.as_mut_ptr(),
+                part.len() as u32,
+            )
+            .offset(i as u64 * 16)
+            .build()
+            .user_data(i as u64);
+            unsafe { ring.push_read(&read)? };
+        }
+        ring.close(file);
+        // The reads and the close have all completed, so the buffer can be freed:
+        ring.wait_for_all()?;
+        assert!(ring.completion().is_empty());
+        assert!(ring.pop_completion().is_none());
+        assert_eq!(&buf[..], &std::fs::read("Cargo.toml")?[..32]);
+        Ok(())
+    }
+
+    #[test]
+    fn test_no_cache_pollution() -> Result<()> {
+        setup();
+        let path = PathBuf::from("test/cache-pollution");
+        let data = vec![3u8; MAX_READ_SIZE * 4];
+        std::fs::write(&path, &data)?;
+        // Dirty pages can't be dropped:
+        File::open(&path)?.sync_all()?;
+
+        type GetMd5s =
+            fn(Vec<PathBuf>, Sender<(PathBuf, Result<Md5>)>, &Config, fn() -> Md5) -> Result<()>;
+        let strategies: [GetMd5s; 2] = [simple_uring::get_checksums, without_uring::get_checksums];
+        for get_checksums in strategies {
+            let config = Config::default().no_cache_pollution(true);
+            let (tx, rx) = channel();
+            get_checksums(vec![path.clone()], tx, &config, Md5::new)?;
+            assert_eq!(rx.recv()?.1?.finalize()[..], Md5::digest(&data)[..]);
+
+            // Count the pages of the file that are still cached:
+            let file = File::open(&path)?;
+            let mut resident = vec![0u8; data.len() / 4096];
+            unsafe {
+                let map = libc::mmap(
+                    std::ptr::null_mut(),
+                    data.len(),
+                    libc::PROT_READ,
+                    libc::MAP_SHARED,
+                    std::os::unix::io::AsRawFd::as_raw_fd(&file),
+                    0,
+                );
+                assert_ne!(map, libc::MAP_FAILED);
+                assert_eq!(libc::mincore(map, data.len(), resident.as_mut_ptr()), 0);
+                libc::munmap(map, data.len());
+            }
+            assert_eq!(resident.iter().filter(|&&page| page & 1 != 0).count(), 0);
+        }
+
+        let args = Opt::from_iter_safe(["", "--drop-cache", "--no-uring", "file"])?;
+        assert!(args.no_cache_pollution);
+        Ok(())
+    }
+
+    #[test]
+    fn test_ring_size() -> Result<()> {
+        setup();
+        for ring_size in [1, 64] {
+            let config = Config::default().ring_size(ring_size);
+            for &strategy in &STRATEGIES[1..] {
+                let config = config.clone().with_strategy(strategy);
+                assert_algorithm_checksums(crate::get_checksums, &config)?;
+            }
+        }
+
+        assert_eq!(Opt::from_iter_safe(["", "file"])?.ring_size, 16);
+        assert_eq!(
+            Opt::from_iter_safe(["", "--ring-size", "256"])?.ring_size,
+            256
+        );
+        assert!(Opt::from_iter_safe(["", "--ring-size", "24"]).is_err());
+        assert!(Opt::from_iter_safe(["", "--ring-size", "0"]).is_err());
+        assert!(Opt::from_iter_safe(["", "--ring-size", "65536"]).is_err());
+        // The default doesn't count as given:
+        assert!(Opt::from_iter_safe(["", "--no-uring", "file"]).is_ok());
+        Ok(())
+    }
+
+    #[test]
+    fn test_iopoll() -> Result<()> {
+        setup();
+        let options = ring::RingOptions {
+            iopoll: true,
+            ..Default::default()
+        };
+        // A polled ring can't complete reads of the page cache, so only check that it's set up:
+        let ring = ring::new_ring(4, &Config::default().ring_options(options))?;
+        assert_eq!(ring.params().sq_entries(), 4);
+
+        assert!(Opt::from_iter_safe(["", "--iopoll", "file"]).is_err());
+        assert!(Opt::from_iter_safe(["", "--iopoll", "--o-direct", "file"])?.iopoll);
+        Ok(())
+    }
+
+    #[test]
+    fn test_reads_per_file() -> Result<()> {
+        setup();
+        let path = PathBuf::from("test/reads-per-file");
+        // Several reads' worth, and a short one at the end:
+        let data: Vec<u8> = (0..MAX_READ_SIZE * 7 + 100)
+            .map(|i| (i % 253) as u8)
+            .collect();
+        std::fs::write(&path, &data)?;
+
+        let config = Config::default().reads_per_file(3);
+        assert_checksums(simple_uring::get_checksums, &config)?;
+        let (tx, rx) = channel::<(PathBuf, Result<Md5>)>();
+        simple_uring::get_checksums(vec![path.clone()], tx, &config, Md5::new)?;
+        assert_eq!(rx.recv()?.1?.finalize()[..], Md5::digest(&data)[..]);
+
+        let args = Opt::from_iter_safe(["", "--reads-per-file", "4", "file"])?;
+        assert_eq!(args.reads_per_file, Some(4));
+        assert!(Opt::from_iter_safe(["", "--reads-per-file", "0", "file"]).is_err());
+        assert!(Opt::from_iter_safe(["", "--reads-per-file", "65", "file"]).is_err());
+        assert!(
+            Opt::from_iter_safe(["", "--reads-per-file", "2", "--use-fixed-buffers", "f"]).is_err()
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn test_jobs() -> Result<()> {
+        setup();
+        for &strategy in STRATEGIES {
+            for jobs in [2, 100] {
+                let config = Config::default().with_strategy(strategy).jobs(jobs);
+                assert_algorithm_checksums(crate::get_checksums, &config)?;
+            }
+        }
+
+        let args = Opt::from_iter_safe(["", "--jobs", "4", "file"])?;
+        assert_eq!(args.jobs, 4);
+        assert!(Opt::from_iter_safe(["", "--jobs", "0", "file"]).is_err());
+        Ok(())
+    }
+
+    #[test]
+    fn test_cancel() -> Result<()> {
+        setup();
+        let checksums = file_setup()?;
+        let paths: Vec<PathBuf> = checksums.keys().cloned().collect();
+        for &strategy in STRATEGIES {
+            let name = strategy.name();
+            // A run that's cancelled before it starts reads nothing:
+            let token = crate::CancelToken::new();
+            token.cancel();
+            let (tx, rx) = channel();
+            let config = Config::default().with_strategy(strategy).cancel(token);
+            crate::get_checksums(paths.clone(), tx, &config)?;
+            let results: Vec<_> = rx.into_iter().collect();
+            assert_eq!(results.len(), paths.len(), "{}", name);
+            for result in results {
+                let err = result.error.unwrap();
+                assert_eq!(err.to_string(), crate::cancel::cancelled().to_string());
+            }
+
+            // Cancelling a run partway gives each file one result, and the finished ones are
+            // still right:
+            let token = crate::CancelToken::new();
+            let config = Config::default()
+                .with_strategy(strategy)
+                .ring_size(1)
+                .cancel(token.clone());
+            let mut seen = Vec::new();
+            crate::get_checksums_with(paths.clone(), &config, |result| {
+                token.cancel();
+                if result.is_ok() {
+                    assert_eq!(checksums.get(&result.path).unwrap()[..], result.digest[..]);
+                }
+                seen.push(result.path);
+            })?;
+            seen.sort();
+            let mut expected = paths.clone();
+            expected.sort();
+            assert_eq!(seen, expected, "{}", name);
+        }
+        Ok(())
+    }
+
+    #[test]
+    fn test_file_progress() -> Result<()> {
+        setup();
+        let checksums = file_setup()?;
+        let paths: Vec<PathBuf> = checksums.keys().cloned().collect();
+        for &strategy in STRATEGIES {
+            let name = strategy.name();
+            let (progress_tx, progress_rx) = channel();
+            let config = Config::default()
+                .with_strategy(strategy)
+                .file_progress(progress_tx);
+            let (tx, rx) = channel();
+            crate::get_checksums(paths.clone(), tx, &config)?;
+            drop(config);
+            assert_eq!(rx.into_iter().count(), paths.len());
+
+            // Each file's updates count up to its length:
+            let mut done: HashMap<PathBuf, u64> = HashMap::new();
+            for (path, bytes, total) in progress_rx {
+                assert_eq!(total, std::fs::metadata(&path)?.len(), "{}", name);
+                let last = done.insert(path, bytes).unwrap_or(0);
+                assert!(last <= bytes && bytes <= total, "{}", name);
+            }
+            for path in &paths {
+                let len = std::fs::metadata(path)?.len();
+                if len > 0 {
+                    assert_eq!(done.get(path), Some(&len), "{} {:?}", name, path);
+                }
+            }
+        }
+        Ok(())
+    }
+
+    #[test]
+    fn test_get_checksums_with() -> Result<()> {
+        setup();
+        let checksums = file_setup()?;
+        let paths: Vec<PathBuf> = checksums.keys().cloned().collect();
+        for &strategy in STRATEGIES {
+            let name = strategy.name();
+            // The callback can hold what can't be sent between threads:
+            let seen = std::rc::Rc::new(std::cell::RefCell::new(Vec::new()));
+            crate::get_checksums_with(
+                paths.clone(),
+                &Config::default().with_strategy(strategy),
+                |result| {
+                    let path = result.path.clone();
+                    let digest = result.into_result().unwrap();
+                    assert_eq!(checksums.get(&path).unwrap()[..], digest[..], "{}", name);
+                    seen.borrow_mut().push(path);
+                },
+            )?;
+            assert_eq!(seen.borrow().len(), paths.len(), "{}", name);
+        }
+        Ok(())
+    }
+
+    /// A sink that takes one result, then fails as if its receiver hung up.
+    struct TakesOne(AtomicBool);
+
+    impl ResultSink<ChecksumResult> for TakesOne {
+        fn send(&self, _result: ChecksumResult) -> Result<()> {
+            if self.0.swap(true, Ordering::Relaxed) {
+                return Err(ChecksumError::SinkClosed.into());
+            }
+            Ok(())
+        }
+    }
+
+    #[test]
+    fn test_checksum_errors() -> Result<()> {
+        setup();
+        let paths_all: Vec<PathBuf> = file_setup()?.keys().cloned().collect();
+        let missing = PathBuf::from("test/missing");
+        for &strategy in STRATEGIES {
+            let name = strategy.name();
+            let config = Config::default().with_strategy(strategy);
+
+            // A file that can't be opened only fails itself:
+            let (tx, rx) = channel();
+            strategy.get_checksums(vec![missing.clone()], &tx, &config)?;
+            drop(tx);
+            let err = rx.recv()?.error.expect(name);
+            match err.downcast_ref::<ChecksumError>() {
+                Some(ChecksumError::OpenFailed { path, source }) => {
+                    assert_eq!(path, &missing, "{}", name);
+                    assert_eq!(source.kind(), std::io::ErrorKind::NotFound, "{}", name);
+                }
+                other => panic!("{}: {:?}", name, other),
+            }
+
+            // A receiver that hung up stops the run instead of panicking:
+            let (tx, rx) = channel();
+            drop(rx);
+            let paths = vec![PathBuf::from("test/file-4096")];
+            let err = strategy.get_checksums(paths, &tx, &config).unwrap_err();
+            assert!(
+                matches!(err.downcast_ref(), Some(ChecksumError::SinkClosed)),
+                "{}: {}",
+                name,
+                err
+            );
+
+            // So does one that hangs up while other files are being read, once their reads
+            // have completed:
+            let err = strategy
+                .get_checksums(
+                    paths_all.clone(),
+                    &TakesOne(AtomicBool::new(false)),
+                    &config,
+                )
+                .unwrap_err();
+            assert!(
+                matches!(err.downcast_ref(), Some(ChecksumError::SinkClosed)),
+                "{}: {}",
+                name,
+                err
+            );
+        }
+        Ok(())
+    }
+
+    #[test]
+    fn test_result_sinks() -> Result<()> {
+        setup();
+        let checksums = file_setup()?;
+        let paths: Vec<PathBuf> = checksums.keys().cloned().collect();
+        for &strategy in STRATEGIES {
+            let name = strategy.name();
+            let config = Config::default().with_strategy(strategy);
+
+            // A callback sees each result on whichever thread finished it:
+            let seen = std::sync::Mutex::new(Vec::new());
+            let callback: Box<dyn Fn(ChecksumResult) + Send + Sync + '_> =
+                Box::new(|result| seen.lock().unwrap().push(result.into_result().unwrap()));
+            crate::get_checksums(paths.clone(), callback, &config)?;
+            assert_eq!(seen.into_inner().unwrap().len(), paths.len(), "{}", name);
+
+            // A bounded channel holds every result, as long as it has room for them all:
+            let (tx, rx) = std::sync::mpsc::sync_channel(paths.len());
+            crate::get_checksums(paths.clone(), tx, &config)?;
+            for result in rx {
+                let digest = result.into_result()?;
+                assert!(checksums.values().any(|c| c[..] == digest[..]), "{}", name);
+            }
+        }
+        Ok(())
+    }
+
+    /// Poll `future` on this thread until it's ready, as an async runtime would.
+    fn block_on<T>(future: impl std::future::Future<Output = T>) -> T {
+        use std::task::{Context, Poll, Wake, Waker};
+
+        struct Unpark(std::thread::Thread);
+        impl Wake for Unpark {
+            fn wake(self: std::sync::Arc<Self>) {
+                self.0.unpark();
+            }
+        }
+        let waker = Waker::from(std::sync::Arc::new(Unpark(std::thread::current())));
+        let mut cx = Context::from_waker(&waker);
+        let mut future = std::pin::pin!(future);
+        loop {
+            match future.as_mut().poll(&mut cx) {
+                Poll::Ready(output) => return output,
+                Poll::Pending => std::thread::park(),
+            }
+        }
+    }
+
+    #[test]
+    fn test_async_stream() -> Result<()> {
+        setup();
+        let checksums = file_setup()?;
+        let paths: Vec<PathBuf> = checksums.keys().cloned().collect();
+        let next = |stream: &mut async_stream::ChecksumStream| {
+            block_on(std::future::poll_fn(|cx| {
+                futures_core::Stream::poll_next(std::pin::Pin::new(&mut *stream), cx)
+            }))
+        };
+
+        for &strategy in STRATEGIES {
+            let name = strategy.name();
+            let mut stream = async_stream::checksum_stream(
+                paths.clone(),
+                Config::default().with_strategy(strategy),
+            );
+            let mut results = 0;
+            while let Some(result) = next(&mut stream) {
+                results += 1;
+                let path = result.path.clone();
+                let digest = result.into_result()?;
+                assert_eq!(checksums.get(&path).unwrap()[..], digest[..], "{}", name);
+            }
+            assert_eq!(results, paths.len(), "{}", name);
+        }
+
+        // Files a strategy didn't get to get the error that stopped it:
+        #[derive(Debug)]
+        struct Blocked;
+        impl crate::ChecksumStrategy for Blocked {
+            fn name(&self) -> &'static str {
+                "blocked"
+            }
+
+            fn get_checksums(
+                &self,
+                files: Vec<PathBuf>,
+                tx: &dyn crate::ResultSink<ChecksumResult>,
+                config: &Config,
+            ) -> Result<()> {
+                tx.send((files[0].clone(), Ok(Hasher::new(config.algorithm))).into())?;
+                Err(anyhow!("io_uring is blocked"))
+            }
+        }
+        let config = Config::default().with_strategy(&Blocked);
+        let mut stream = async_stream::checksum_stream(paths.clone(), config);
+        let mut failed = 0;
+        while let Some(result) = next(&mut stream) {
+            if result.path == paths[0] {
+                assert!(result.is_ok());
+            } else {
+                assert_eq!(result.error.unwrap().to_string(), "io_uring is blocked");
+                failed += 1;
+            }
+        }
+        assert_eq!(failed, paths.len() - 1);
+        Ok(())
+    }
+
+    #[test]
+    fn test_numa() -> Result<()> {
+        setup();
+        let mut placements = vec![crate::numa::Placement::Auto];
+        // Every machine with NUMA has node 0:
+        if !crate::numa::online_nodes().is_empty() {
+            placements.push(crate::numa::Placement::Node(0));
+        }
+        for placement in placements {
+            let config = Config::default().jobs(2).numa(Some(placement));
+            assert_algorithm_checksums(crate::get_checksums, &config)?;
+        }
+
+        let args = Opt::from_iter_safe(["", "--numa", "node:1", "file"])?;
+        assert_eq!(args.numa, Some(crate::numa::Placement::Node(1)));
+        let args = Opt::from_iter_safe(["", "--numa", "auto", "file"])?;
+        assert_eq!(args.numa, Some(crate::numa::Placement::Auto));
+        assert!(Opt::from_iter_safe(["", "--numa", "node:x", "file"]).is_err());
+        assert!(Opt::from_iter_safe(["", "--numa", "nearest", "file"]).is_err());
+        Ok(())
+    }
+
+    #[test]
+    fn test_hash_threads() -> Result<()> {
+        setup();
+        let path = PathBuf::from("test/hash-threads");
+        let data: Vec<u8> = (0..MAX_READ_SIZE * 5 + 7)
+            .map(|i| (i % 251) as u8)
+            .collect();
+        std::fs::write(&path, &data)?;
+
+        let config = Config::default().hash_threads(3);
+        assert_checksums(simple_uring::get_checksums, &config)?;
+        let (tx, rx) = channel::<(PathBuf, Result<Md5>)>();
+        simple_uring::get_checksums(vec![path.clone()], tx, &config, Md5::new)?;
+        assert_eq!(rx.recv()?.1?.finalize()[..], Md5::digest(&data)[..]);
+
+        let args = Opt::from_iter_safe(["", "--hash-threads", "4", "file"])?;
+        assert_eq!(args.hash_threads, Some(4));
+        assert!(Opt::from_iter_safe(["", "--hash-threads", "2", "--no-uring", "file"]).is_err());
+        Ok(())
+    }
+
+    #[test]
+    fn test_async_open() -> Result<()> {
+        setup();
+        let config = Config::default().async_open(true);
+        assert_checksums(simple_uring::get_checksums, &config)?;
+
+        let (tx, rx) = channel();
+        let missing = PathBuf::from("test/does-not-exist");
+        simple_uring::get_checksums(vec![missing.clone()], tx, &config, Md5::new)?;
+        let (path, result) = rx.recv()?;
+        assert_eq!(path, missing);
+        assert!(result.is_err());
+
+        // A file opened into a slot of the file table is read until a read returns nothing:
+        let empty = PathBuf::from("test/async-open-empty");
+        File::create(&empty)?;
+        let (tx, rx) = channel();
+        simple_uring::get_checksums(vec![empty.clone()], tx, &config, Md5::new)?;
+        let (path, result) = rx.recv()?;
+        assert_eq!(path, empty);
+        assert_eq!(result?.finalize(), Md5::new().finalize());
+
+        assert!(Opt::from_iter_safe(["", "--async-open", "--no-uring", "file"]).is_err());
+        Ok(())
+    }
+
+    #[test]
+    fn test_expand_glob() -> Result<()> {
+        setup();
+        let _ = file_setup()?;
+        std::fs::create_dir_all("test/glob/deeper")?;
+        std::fs::write("test/glob/a.bin", b"a")?;
+        std::fs::write("test/glob/deeper/b.bin", b"b")?;
+        std::fs::write("test/glob/deeper/c.txt", b"c")?;
+
+        assert_eq!(
+            input::expand_glob(Path::new("test/glob/**/*.bin"))?,
+            [
+                PathBuf::from("test/glob/a.bin"),
+                "test/glob/deeper/b.bin".into()
+            ]
+        );
+        // Directories aren't files to hash:
+        assert_eq!(input::expand_glob(Path::new("test/glob/*"))?.len(), 1);
+        // A pattern that matches nothing is kept, so it's reported as missing:
+        assert_eq!(
+            input::expand_glob(Path::new("test/glob/*.none"))?,
+            [PathBuf::from("test/glob/*.none")]
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn test_digest_with_blocks() -> Result<()> {
+        setup();
+        let _ = file_setup()?;
+        let path = PathBuf::from("test/chunk-hashes");
+        let data: Vec<u8> = (0..2500u32).map(|i| i as u8).collect();
+        std::fs::write(&path, &data)?;
+
+        let blocks = Mutex::new(HashMap::new());
+        let (tx, rx) = channel();
+        diagnose::get_checksums_with_blocks(
+            vec![path.clone()],
+            tx,
+            1000,
+            &Config::default(),
+            &blocks,
+        )?;
+        let result = rx.recv()?;
+        assert_eq!(result.digest, Md5::digest(&data).to_vec());
+        assert_eq!(result.bytes, 2500);
+        let expected: Vec<String> = data
+            .chunks(1000)
+            .map(|block| to_hex(&Md5::digest(block)))
+            .collect();
+        assert_eq!(blocks.lock().unwrap().remove(&path), Some(expected));
+        Ok(())
+    }
+
+    #[test]
+    fn test_find_first_mismatch() -> Result<()> {
+        setup();
+        let _ = file_setup()?;
+        let path = "test/diagnose";
+        let block_size = 1000;
+        let mut data = vec![b'x'; 3500];
+        std::fs::write(path, &data)?;
+        let expected = block_digests(&open(path, false)?, block_size, HashAlgorithm::Md5)?;
+        assert_eq!(expected.len(), 4);
+        assert_eq!(
+            find_first_mismatch(
+                &open(path, false)?,
+                block_size,
+                &expected,
+                HashAlgorithm::Md5
+            )?,
+            None
+        );
+
+        data[2500] = b'y';
+        data[3100] = b'y';
+        std::fs::write(path, &data)?;
+        let mismatch = find_first_mismatch(
+            &open(path, false)?,
+            block_size,
+            &expected,
+            HashAlgorithm::Md5,
+        )?
+        .unwrap();
+        assert_eq!((mismatch.start, mismatch.end), (2000, 3000));
+        assert_eq!(mismatch.expected.as_ref(), Some(&expected[2]));
+
+        // A truncated file differs where it ends:
+        std::fs::write(path, &data[..1000])?;
+        let mismatch = find_first_mismatch(
+            &open(path, false)?,
+            block_size,
+            &expected,
+            HashAlgorithm::Md5,
+        )?
+        .unwrap();
+        assert_eq!((mismatch.start, mismatch.actual), (1000, None));
+
+        // The description says where, or that it can't be found without block digests:
+        let mut entry = ManifestEntry {
+            digest: to_hex(&Md5::digest(b"")),
+            path: PathBuf::from(path),
+            blocks: Some(crate::manifest::BlockDigests {
+                block_size,
+                digests: expected.clone(),
+            }),
+            stamp: None,
+        };
+        assert_eq!(
+            diagnose::describe_first_mismatch(&open(path, false)?, &entry, HashAlgorithm::Md5),
+            format!(
+                "first difference in bytes 1000-2000 (expected {}, got nothing)",
+                expected[1]
+            )
+        );
+        entry.blocks = None;
+        let description =
+            diagnose::describe_first_mismatch(&open(path, false)?, &entry, HashAlgorithm::Md5);
+        assert!(description.starts_with("no block digests are listed"));
+        Ok(())
+    }
+
+    #[test]
+    fn test_resume_from_midstate() -> Result<()> {
+        setup();
+        let checksums = file_setup()?;
+        let path = PathBuf::from(format!("test/file-{}", MAX_READ_SIZE * 3));
+        let md5 = HashAlgorithm::Md5;
+
+        // Interrupt hashing at the first checkpoint:
+        let mut saved = None;
+        let interrupted = get_checksum_resumable(&path, md5, false, None, 1, |midstate| {
+            saved = Some(midstate.to_bytes());
+            Err(anyhow!("interrupted"))
+        });
+        assert!(interrupted.is_err());
+
+        let bytes = saved.unwrap();
+        let midstate = Midstate::from_bytes(&bytes)?;
+        assert_eq!(midstate.offset, MAX_READ_SIZE as u64);
+        assert_eq!(midstate.path, path);
+        assert_eq!(midstate.file.size, MAX_READ_SIZE as u64 * 3);
+
+        // The midstate survives a state file, as it would a reboot:
+        let state_file =
+            std::env::temp_dir().join(format!("md5sum-uring-{}.mid", std::process::id()));
+        assert!(Midstate::load(&state_file)?.is_none());
+        midstate.save(&state_file)?;
+        let midstate = Midstate::load(&state_file)?.unwrap();
+        std::fs::remove_file(&state_file)?;
+
+        let other = PathBuf::from("test/file-4096");
+        let resume = |path: &Path, algorithm, midstate: &Midstate| {
+            get_checksum_resumable(
+                path,
+                algorithm,
+                false,
+                Some(midstate.clone()),
+                u64::MAX,
+                |_| Ok(()),
+            )
+        };
+        assert!(resume(&other, md5, &midstate).is_err());
+        let ctx = resume(&path, md5, &midstate)?;
+        let checksum: [u8; 16] = ctx.finalize().into();
+        assert_eq!(checksums.get(&path).unwrap(), &checksum);
+
+        // Only md5 hashing can be resumed, and a midstate says which algorithm it's of:
+        let err = resume(&path, HashAlgorithm::Sha256, &midstate).unwrap_err();
+        assert!(err.to_string().contains("sha256"), "{}", err);
+        let mut sha256 = b"HASHMID3\x06sha256".to_vec();
+        sha256.extend_from_slice(&bytes[12..]);
+        let err = Midstate::from_bytes(&sha256).err().unwrap();
+        assert!(err.to_string().contains("sha256"), "{}", err);
+
+        // A file that changed since its midstate was saved is hashed from the start instead:
+        let changed = PathBuf::from("test/midstate-changed");
+        std::fs::copy(&path, &changed)?;
+        let mut saved = None;
+        let _ = get_checksum_resumable(&changed, md5, false, None, 1, |midstate| {
+            saved = Some(midstate.clone());
+            Err(anyhow!("interrupted"))
+        });
+        let midstate = saved.unwrap();
+        let mut contents = std::fs::read(&changed)?;
+        contents[0] ^= 0xff;
+        std::fs::write(&changed, &contents[..MAX_READ_SIZE * 2])?;
+        let err = resume(&changed, md5, &midstate).err().unwrap();
+        assert!(err.to_string().contains("changed"), "{}", err);
+        // Even if it's put back to the same length:
+        std::fs::write(&changed, &contents)?;
+        let file = OpenOptions::new().write(true).open(&changed)?;
+        file.set_modified(UNIX_EPOCH + Duration::from_secs(1))?;
+        assert!(resume(&changed, md5, &midstate).is_err());
+
+        assert!(Midstate::from_bytes(b"not a midstate").is_err());
+        assert!(Midstate::from_bytes(&bytes[..30]).is_err());
+        // The formats before the file's identity was recorded can't be trusted:
+        let err = Midstate::from_bytes(b"MD5MID02\0\0\0\0\0\0\0\0\0\0\0\0\0\0\0\0")
+            .err()
+            .unwrap();
+        assert!(err.to_string().contains("older version"), "{}", err);
+        Ok(())
     }
-}
 
-impl AsRef<[u8]> for AlignedBuffer {
-    fn as_ref(&self) -> &[u8] {
-        &self.buf[0..self.len]
+    #[test]
+    fn test_bench() -> Result<()> {
+        setup();
+        let checksums = file_setup()?;
+        let report = bench::run(
+            checksums.keys().cloned().collect(),
+            2,
+            true,
+            &Config::default(),
+        )?;
+        assert_eq!(report.results.len(), STRATEGIES.len());
+        for result in &report.results {
+            assert_eq!(result.error, None, "{} failed", result.strategy);
+            assert_eq!(result.file_errors, 0);
+            if result.strategy != "no-uring" {
+                assert!(result.ring_reads > 0, "{} read nothing", result.strategy);
+            }
+            assert_eq!(result.samples_ns.len(), 2);
+            let latency = result.latency_ns.as_ref().unwrap();
+            assert!(latency.min <= latency.p50 && latency.p50 <= latency.max);
+        }
+        Ok(())
     }
-}
 
-impl Deref for AlignedBuffer {
-    type Target = [u8];
+    #[test]
+    fn test_compare() -> Result<()> {
+        setup();
+        let _ = file_setup()?;
+        for dir in ["test/compare-a/sub", "test/compare-b/sub"] {
+            std::fs::create_dir_all(dir)?;
+        }
+        for (path, contents) in [
+            ("test/compare-a/same", "same"),
+            ("test/compare-b/same", "same"),
+            ("test/compare-a/sub/changed", "abcd"),
+            ("test/compare-b/sub/changed", "abce"),
+            ("test/compare-a/sub/resized", "abc"),
+            ("test/compare-b/sub/resized", "abcd"),
+            ("test/compare-a/only-a", ""),
+            ("test/compare-b/sub/only-b", ""),
+        ] {
+            std::fs::write(path, contents)?;
+        }
 
-    fn deref(&self) -> &Self::Target {
-        // unsafe: copied from Vec for performance:
-        unsafe { slice::from_raw_parts(self.buf.as_ptr(), self.len) }
+        let (a, b) = (Path::new("test/compare-a"), Path::new("test/compare-b"));
+        let excludes = input::Excludes::default();
+        for &strategy in STRATEGIES {
+            let config = Config::default().with_strategy(strategy);
+            let summary = compare::run(a, b, &excludes, &config, false)?;
+            let expected = compare::CompareSummary {
+                identical: 1,
+                differ: 2,
+                only_in_a: 1,
+                only_in_b: 1,
+                unreadable: 0,
+            };
+            assert_eq!(summary, expected, "{}", strategy.name());
+            assert!(!summary.is_identical());
+        }
+        assert!(compare::run(a, a, &excludes, &Config::default(), false)?.is_identical());
+        assert!(compare::run(
+            a,
+            Path::new("test/missing"),
+            &excludes,
+            &Config::default(),
+            false
+        )
+        .is_err());
+        Ok(())
     }
-}
 
-impl DerefMut for AlignedBuffer {
-    fn deref_mut(&mut self) -> &mut Self::Target {
-        // unsafe: copied from Vec for performance:
-        unsafe { slice::from_raw_parts_mut(self.buf.as_mut_ptr(), self.len) }
-    }
-}
+    #[test]
+    fn test_find_duplicates() -> Result<()> {
+        setup();
+        let _ = file_setup()?;
+        std::fs::create_dir_all("test/duplicates")?;
+        let files: Vec<PathBuf> = [
+            ("c", "same size"),
+            ("a", "abc"),
+            ("b", "abc"),
+            ("d", "same-size"),
+            ("e", "abcd"),
+            ("f", ""),
+            ("g", ""),
+            ("h", "abc"),
+        ]
+        .into_iter()
+        .map(|(name, contents)| {
+            let path = Path::new("test/duplicates").join(name);
+            std::fs::write(&path, contents).map(|_| path)
+        })
+        .collect::<Result<_, _>>()?;
 
-/// Open a file for reading. Note that O_DIRECT seems not to work on some systems like
-/// WSL2.
-pub fn open(path: impl AsRef<Path>, o_direct: bool) -> std::io::Result<File> {
-    if o_direct {
-        OpenOptions::new()
-            .read(true)
-            // see man 2 open, search for O_DIRECT
-            .custom_flags(libc::O_DIRECT)
-            .open(path)
-    } else {
-        File::open(path)
+        for &strategy in STRATEGIES {
+            let config = Config::default().with_strategy(strategy);
+            let groups = duplicates::find(files.clone(), &config)?;
+            assert_eq!(groups.len(), 1, "{}", strategy.name());
+            assert_eq!(groups[0].digest, Md5::digest(b"abc").to_vec());
+            assert_eq!(groups[0].size, 3);
+            assert_eq!(
+                groups[0].paths,
+                [files[1].clone(), files[2].clone(), files[7].clone()]
+            );
+            assert_eq!(groups[0].reclaimable(), 6);
+        }
+        Ok(())
     }
-}
 
-#[cfg(test)]
-mod tests {
-    use std::{
-        collections::HashMap,
-        fs::OpenOptions,
-        io::{ErrorKind, Read, Write},
-        mem::align_of,
-        path::PathBuf,
-        sync::{
-            mpsc::{channel, Sender},
-            Mutex,
-        },
-    };
+    #[test]
+    fn test_combined() -> Result<()> {
+        setup();
+        let _ = file_setup()?;
+        let parts: Vec<PathBuf> = ["abc", "", "defg"]
+            .into_iter()
+            .enumerate()
+            .map(|(i, contents)| {
+                let path = PathBuf::from(format!("test/combined-part{}", i));
+                std::fs::write(&path, contents).map(|_| path)
+            })
+            .collect::<Result<_, _>>()?;
 
-    use anyhow::Result;
-    #[allow(unused_imports)]
-    use log::{debug, error, info, trace, warn};
-    use md5::{Digest, Md5};
-    use structopt::lazy_static::lazy_static;
-    use structopt::StructOpt;
+        let config = Config::default();
+        let (tx, rx) = channel();
+        let digest = combined::get_checksums_combined(parts.clone(), tx, &config)?;
+        assert_eq!(digest, Some(Md5::digest(b"abcdefg").to_vec()));
+        let results: Vec<ChecksumResult> = rx.into_iter().collect();
+        assert_eq!(
+            results
+                .iter()
+                .map(|result| &result.path)
+                .collect::<Vec<_>>(),
+            parts.iter().collect::<Vec<_>>()
+        );
+        assert_eq!(results[2].digest, Md5::digest(b"defg").to_vec());
 
-    use crate::{
-        open, simple_uring, with_fixed_buffers, with_register_files, without_uring, AlignedBuffer,
-        Opt, ALIGNMENT, MAX_READ_SIZE,
-    };
+        // A file that can't be read leaves a gap, so there's no combined digest:
+        let mut files = parts.clone();
+        files.insert(1, PathBuf::from("test/combined-missing"));
+        let (tx, rx) = channel();
+        assert_eq!(combined::get_checksums_combined(files, tx, &config)?, None);
+        assert_eq!(rx.into_iter().filter(ChecksumResult::is_ok).count(), 3);
 
-    fn setup() {
-        // Try init because multiple tests may invoke this:
-        let _ = env_logger::try_init();
+        // The files are taken in the order they're given, so they can't be sorted:
+        assert!(Opt::from_iter_safe(["", "--combined", "--sort", "physical", "file"]).is_err());
+        // Nor is the combined digest recorded in a database:
+        #[cfg(feature = "sqlite")]
+        assert!(Opt::from_iter_safe(["", "--combined", "--db", "test/db", "file"]).is_err());
+        Ok(())
     }
 
-    fn file_setup() -> Result<HashMap<PathBuf, [u8; 16]>> {
-        lazy_static! {
-            static ref CHECKSUMS: Mutex<HashMap<PathBuf, [u8; 16]>> = Default::default();
-        };
-
-        let mut mutex_guard = CHECKSUMS.lock().unwrap();
-
+    #[test]
+    fn test_torrent_pieces() -> Result<()> {
         setup();
+        let _ = file_setup()?;
+        let piece_length = torrent::MIN_PIECE_LENGTH;
+        let data: Vec<u8> = (0..40_000u32).map(|i| (i % 251) as u8).collect();
+        // The second piece starts in the first file and ends in the third:
+        let (first, rest) = data.split_at(20_000);
+        let (second, third) = rest.split_at(5_000);
+        let files: Vec<PathBuf> = [first, second, &[], third]
+            .into_iter()
+            .enumerate()
+            .map(|(i, contents)| {
+                let path = PathBuf::from(format!("test/torrent-{}", i));
+                std::fs::write(&path, contents).map(|_| path)
+            })
+            .collect::<Result<_, _>>()?;
 
-        let checksums: &mut HashMap<_, _> = &mut mutex_guard;
-        if checksums.len() > 0 {
-            // Don't create/modify the files twice
-            return Ok(checksums.clone());
-        }
+        let pieces = torrent::piece_hashes(&files, piece_length, false)?;
+        let expected: Vec<[u8; 20]> = data
+            .chunks(piece_length as usize)
+            .map(|piece| Sha1::digest(piece).into())
+            .collect();
+        assert_eq!(pieces.hashes.len(), 3);
+        assert_eq!(pieces.hashes, expected);
+        assert_eq!(pieces.total_length, 40_000);
 
-        match std::fs::create_dir("test") {
-            Ok(_) => Ok(()),
-            Err(err) if err.raw_os_error() == Some(17) => Ok(()), // directory exists; okay
-            Err(err) => Err(err),
-        }?;
+        // The pieces after a missing file would be wrong:
+        let missing = [files[0].clone(), PathBuf::from("test/torrent-missing")];
+        assert!(torrent::piece_hashes(&missing, piece_length, false).is_err());
 
-        // Repeat characters in a pattern that's easy to read/debug:
-        let iter = &mut std::iter::repeat_with(|| {
-            ('0' as u8..'f' as u8)
-                .map(|character| std::iter::repeat(character).take(10))
-                .flatten()
-        })
-        .flatten();
+        // Pieces span the files in the order they're given, so they can't be sorted:
+        let args = ["", "--torrent-pieces", "256K", "--sort", "physical", "file"];
+        assert!(Opt::from_iter_safe(args).is_err());
+        Ok(())
+    }
 
-        let mut hasher = Md5::new();
+    #[test]
+    fn test_read_digest_set() -> Result<()> {
+        setup();
+        let _ = file_setup()?;
+        let path = Path::new("test/known-digests");
+        std::fs::write(
+            path,
+            "# known files\n\
+             D41D8CD98F00B204E9800998ECF8427E\n\
+             \n\
+             900150983cd24fb0d6963f7d28e17f72  abc\n\
+             \\0cc175b9c0f1b6a831c399e269772661  a\\nb\n",
+        )?;
+        let known = read_digest_set(path, 32)?;
+        assert_eq!(known.len(), 3);
+        assert!(known.contains(&Md5::digest(b"").to_vec()));
+        assert!(known.contains(&Md5::digest(b"abc").to_vec()));
+        assert!(known.contains(&Md5::digest(b"a").to_vec()));
 
-        for size in [
-            25,
-            4096,
-            MAX_READ_SIZE - 1,
-            MAX_READ_SIZE,
-            MAX_READ_SIZE + 1,
-            MAX_READ_SIZE * 3,
-        ] {
-            let fname = PathBuf::from(format!("test/file-{}", size));
+        // sha256 digests can't be in an md5 list:
+        let err = read_digest_set(path, 64).unwrap_err();
+        assert_eq!(
+            err.to_string(),
+            "test/known-digests:2: not a digest of 64 hexadecimal digits"
+        );
+        Ok(())
+    }
 
-            let data = iter.take(size).collect::<Vec<_>>();
-            hasher.update(&data);
-            let checksum: [u8; 16] = hasher.finalize_reset().try_into()?;
-            assert!(checksums.insert(fname.clone(), checksum).is_none());
+    #[test]
+    fn test_records() -> Result<()> {
+        setup();
+        let _ = file_setup()?;
+        let path = Path::new("test/record, \"quoted\"");
+        std::fs::write(path, "abc")?;
+        let record = Record::new(path, Ok("900150983cd24fb0d6963f7d28e17f72"));
+        assert_eq!(record.size, Some(3));
+        assert!(record.mtime.is_some());
+        assert!(record.mode.is_some());
+        let csv = String::from_utf8(record.to_line(OutputFormat::Csv)?)?;
+        assert!(
+            csv.starts_with("\"test/record, \"\"quoted\"\"\",900150983cd24fb0d6963f7d28e17f72,3,")
+        );
+        assert_eq!(csv.matches(',').count(), 8);
 
-            let file = OpenOptions::new()
-                .write(true)
-                .read(false)
-                // fail on existing:
-                .create_new(true)
-                .open(&fname);
+        let err = anyhow!("No such file or directory");
+        let missing = Record::new(Path::new("test/record-missing"), Err(&err));
+        assert_eq!(
+            String::from_utf8(missing.to_line(OutputFormat::Json)?)?,
+            "{\"path\":\"test/record-missing\",\"digest\":null,\"size\":null,\"mtime\":null,\
+             \"mode\":null,\"uid\":null,\"gid\":null,\"error\":\"No such file or directory\"}\n"
+        );
+        Ok(())
+    }
 
-            if matches!(&file, Err(err) if err.kind() == ErrorKind::AlreadyExists) {
-                // Skip files that already exist. They should already have the same contents,
-                // and overwriting them is a security risk since some of these tests need to
-                // run as root:
-                continue;
-            }
+    #[test]
+    fn test_hard_links() -> Result<()> {
+        setup();
+        let _ = file_setup()?;
+        let (first, link, other) = (
+            PathBuf::from("test/hard-link-1"),
+            PathBuf::from("test/hard-link-2"),
+            PathBuf::from("test/hard-link-other"),
+        );
+        std::fs::write(&first, "abc")?;
+        std::fs::write(&other, "abc")?;
+        let _ = std::fs::remove_file(&link);
+        std::fs::hard_link(&first, &link)?;
 
-            file?.write(&data)?;
-        }
+        let files = vec![first.clone(), other.clone(), link.clone(), first.clone()];
+        let (to_hash, links) = hard_links::split_links(files.clone());
+        assert_eq!(to_hash, [first.clone(), other.clone()]);
+        assert_eq!(links[&first], [link.clone(), first.clone()]);
 
-        Ok(checksums.clone())
+        for &strategy in STRATEGIES {
+            let config = Config::default().with_strategy(strategy);
+            let (tx, rx) = channel();
+            get_checksums(files.clone(), tx, &config)?;
+            let mut paths: Vec<PathBuf> = rx
+                .into_iter()
+                .map(|result| {
+                    assert_eq!(result.digest, Md5::digest(b"abc").to_vec());
+                    result.path
+                })
+                .collect();
+            paths.sort();
+            let mut expected = files.clone();
+            expected.sort();
+            assert_eq!(paths, expected, "{}", strategy.name());
+        }
+        Ok(())
     }
 
-    fn assert_checksums<F>(get_checksums: F, o_direct: bool) -> Result<()>
-    where
-        F: Fn(Vec<PathBuf>, Sender<(PathBuf, Result<Md5>)>, bool) -> Result<()> + Sync + 'static,
-    {
-        let checksums = file_setup()?;
+    #[test]
+    fn test_reflinks() -> Result<()> {
+        setup();
+        let _ = file_setup()?;
+        let (original, copy, other) = (
+            PathBuf::from("test/reflink-original"),
+            PathBuf::from("test/reflink-copy"),
+            PathBuf::from("test/reflink-other"),
+        );
+        std::fs::write(&original, "abc")?;
+        std::fs::write(&other, "abc")?;
+        let _ = std::fs::remove_file(&copy);
+        // Only some filesystems can share extents between files:
+        let cloned = std::process::Command::new("cp")
+            .arg("--reflink=always")
+            .arg(&original)
+            .arg(&copy)
+            .status()
+            .is_ok_and(|status| status.success());
+        if !cloned {
+            std::fs::copy(&original, &copy)?;
+        }
+
+        let files = vec![original.clone(), other.clone(), copy.clone()];
+        let (to_hash, links) = reflinks::split_reflinks(files.clone());
+        if cloned && cfg!(target_os = "linux") {
+            assert_eq!(to_hash, [original.clone(), other.clone()]);
+            assert_eq!(links[&original], std::slice::from_ref(&copy));
+        } else {
+            assert_eq!(to_hash, files);
+            assert!(links.is_empty());
+        }
 
+        let config = Config::default().trust_reflinks(true);
         let (tx, rx) = channel();
-        crossbeam_utils::thread::scope(|s| -> Result<()> {
-            let handle = s.spawn(|_| -> Result<()> {
-                get_checksums(checksums.keys().cloned().collect(), tx, o_direct)?;
-                Ok(())
-            });
+        get_checksums(files.clone(), tx, &config)?;
+        let mut paths: Vec<PathBuf> = rx
+            .into_iter()
+            .map(|result| {
+                assert_eq!(result.digest, Md5::digest(b"abc").to_vec());
+                result.path
+            })
+            .collect();
+        paths.sort();
+        let mut expected = files;
+        expected.sort();
+        assert_eq!(paths, expected);
+        Ok(())
+    }
 
-            for (path, result) in rx {
-                let checksum: [u8; 16] = result?.finalize().try_into()?;
-                assert_eq!(checksums.get(&path).unwrap(), &checksum);
-            }
-            handle.join().unwrap()?;
-            Ok(())
-        })
-        .unwrap()?;
+    #[test]
+    fn test_tree_digest() -> Result<()> {
+        setup();
+        let _ = file_setup()?;
+        std::fs::create_dir_all("test/tree-digest/sub")?;
+        std::fs::write("test/tree-digest/a", "abc")?;
+        std::fs::write("test/tree-digest/sub/b", "")?;
+        // A pipe under the directory is left out rather than waited on:
+        let _ = std::fs::remove_file("test/tree-digest/sub/fifo");
+        let c_path = std::ffi::CString::new("test/tree-digest/sub/fifo")?;
+        assert_eq!(unsafe { libc::mkfifo(c_path.as_ptr(), 0o600) }, 0);
 
+        let md5 = |data: &[u8]| Md5::digest(data).to_vec();
+        let sub = md5(format!("{}  b\n", to_hex(&md5(b""))).as_bytes());
+        let expected =
+            md5(format!("{}  a\n{}  sub/\n", to_hex(&md5(b"abc")), to_hex(&sub)).as_bytes());
+        let excludes = input::Excludes::new(&[], None)?;
+        for &strategy in STRATEGIES {
+            let config = Config::default().with_strategy(strategy);
+            let digest = tree_digest::digest_dir(
+                Path::new("test/tree-digest"),
+                &excludes,
+                false,
+                None,
+                &config,
+            )?;
+            assert_eq!(digest, expected, "{}", strategy.name());
+        }
+
+        // Moving a file into a subdirectory changes the root digest:
+        let flat = BTreeMap::from([
+            (PathBuf::from("a"), md5(b"abc")),
+            (PathBuf::from("b"), md5(b"")),
+        ]);
+        assert_ne!(tree_digest::fold(HashAlgorithm::Md5, &flat)?, expected);
         Ok(())
     }
 
     #[test]
-    fn test_without_uring() -> Result<()> {
+    fn test_dir_manifests() -> Result<()> {
         setup();
-        assert_checksums(without_uring::get_checksums, false)?;
+        let _ = file_setup()?;
+        std::fs::create_dir_all("test/per-dir/sub")?;
+        let mut manifests = DirManifests::new(".md5sums");
+        manifests.add(Path::new("test/per-dir/sub/b"), "0123");
+        manifests.add(Path::new("test/per-dir/a"), "4567");
+        manifests.add(Path::new("test/per-dir/sub/a\\b"), "89ab");
+        assert_eq!(manifests.write(), 2);
+
+        assert_eq!(
+            std::fs::read_to_string("test/per-dir/.md5sums")?,
+            "4567  a\n"
+        );
+        assert_eq!(
+            std::fs::read_to_string("test/per-dir/sub/.md5sums")?,
+            "\\89ab  a\\\\b\n0123  b\n"
+        );
         Ok(())
     }
 
     #[test]
-    fn test_simple_uring() -> Result<()> {
+    fn test_incremental() -> Result<()> {
         setup();
-        assert_checksums(simple_uring::get_checksums, false)?;
+        let _ = file_setup()?;
+        let (changed, unchanged) = (
+            PathBuf::from("test/incremental-changed"),
+            PathBuf::from("test/incremental unchanged\n"),
+        );
+        std::fs::write(&changed, "abc")?;
+        std::fs::write(&unchanged, "abc")?;
+
+        // A list whose digests are wrong, so reusing them shows:
+        let wrong = to_hex(&Md5::digest(b"not abc"));
+        let list = Path::new("test/incremental.md5");
+        let mut contents = Vec::new();
+        for path in [&changed, &unchanged] {
+            contents.extend(format_line(&wrong, path));
+            contents.extend(format_stamp_line(&cache::Stamp::of(path)?, &wrong, path));
+        }
+        std::fs::write(list, contents)?;
+        let entries = crate::manifest::read_manifest(list)?;
+        assert_eq!(entries[1].stamp, Some(cache::Stamp::of(&unchanged)?));
+        std::fs::write(&changed, "abcd")?;
+
+        let incremental = cache::ManifestCache::open(list, HashAlgorithm::Md5)?;
+        let (tx, rx) = channel();
+        let files = vec![changed.clone(), unchanged.clone()];
+        cache::get_checksums_cached(files, tx, &Config::default(), &incremental, false)?;
+        let results: HashMap<PathBuf, String> = rx
+            .into_iter()
+            .map(|result| (result.path.clone(), to_hex(&result.into_result().unwrap())))
+            .collect();
+        assert_eq!(results[&unchanged], wrong);
+        assert_eq!(results[&changed], to_hex(&Md5::digest(b"abcd")));
+
+        // The stamps to write are the new one for the file that was read, and the old one for
+        // the file that wasn't:
+        let digest = Md5::digest(b"abcd");
+        assert_eq!(
+            incremental.stamp(&changed, &digest),
+            Some(cache::Stamp::of(&changed)?)
+        );
+        let digest = from_hex(&wrong).unwrap();
+        assert_eq!(
+            incremental.stamp(&unchanged, &digest),
+            Some(cache::Stamp::of(&unchanged)?)
+        );
+        assert_eq!(incremental.stamp(&unchanged, &[0; 16]), None);
+
+        // Without a list, nothing is reused:
+        let incremental =
+            cache::ManifestCache::open(Path::new("test/missing"), HashAlgorithm::Md5)?;
+        assert!(incremental.load(&unchanged, HashAlgorithm::Md5).is_none());
         Ok(())
     }
 
+    #[cfg(target_os = "linux")]
     #[test]
-    fn test_simple_uring_o_direct() -> Result<()> {
+    fn test_xattr_cache() -> Result<()> {
         setup();
-        assert_checksums(simple_uring::get_checksums, true)?;
+        let _ = file_setup()?;
+        let path = PathBuf::from("test/xattr-cache");
+        std::fs::write(&path, "abc")?;
+        let run = |verify: bool| -> Result<ChecksumResult> {
+            let (tx, rx) = channel();
+            let cache = xattr_cache::XattrCache;
+            cache::get_checksums_cached(
+                vec![path.clone()],
+                tx,
+                &Config::default(),
+                &cache,
+                verify,
+            )?;
+            Ok(rx.recv()?)
+        };
+        let abc = Md5::digest(b"abc").to_vec();
+
+        assert_eq!(run(false)?.into_result()?, abc);
+        let mut entry = xattr_cache::XattrCache
+            .load(&path, HashAlgorithm::Md5)
+            .unwrap();
+        assert_eq!(entry.digest, abc);
+        assert_eq!(entry.stamp, cache::Stamp::of(&path)?);
+
+        // A cached digest for the same version is trusted without reading the file:
+        entry.digest = Md5::digest(b"not abc").to_vec();
+        xattr_cache::XattrCache.store(&path, HashAlgorithm::Md5, &entry)?;
+        assert_eq!(run(false)?.into_result()?, entry.digest);
+        // Unless it's verified:
+        let result = run(true)?;
+        assert!(result.error.unwrap().to_string().contains("corrupt"));
+
+        // Changing the file makes it be read again:
+        std::fs::write(&path, "abcd")?;
+        assert_eq!(run(false)?.into_result()?, Md5::digest(b"abcd").to_vec());
+        assert_eq!(
+            xattr_cache::XattrCache
+                .load(&path, HashAlgorithm::Md5)
+                .unwrap()
+                .digest,
+            Md5::digest(b"abcd").to_vec()
+        );
         Ok(())
     }
 
+    #[cfg(feature = "sqlite")]
     #[test]
-    fn test_preregistered_files() -> Result<()> {
+    fn test_db() -> Result<()> {
         setup();
-        assert_checksums(with_register_files::get_checksums, false)?;
+        let _ = file_setup()?;
+        let path = PathBuf::from("test/db-file");
+        std::fs::write(&path, "abc")?;
+        let db_path = std::env::temp_dir().join(format!("md5sum-uring-{}.db", std::process::id()));
+        let _ = std::fs::remove_file(&db_path);
+        let run = |verify: bool| -> Result<ChecksumResult> {
+            let database = crate::db::Database::open(&db_path)?;
+            let (tx, rx) = channel();
+            cache::get_checksums_cached(
+                vec![path.clone()],
+                tx,
+                &Config::default(),
+                &database,
+                verify,
+            )?;
+            database.commit()?;
+            Ok(rx.recv()?)
+        };
+        let abc = Md5::digest(b"abc").to_vec();
+
+        assert_eq!(run(true)?.into_result()?, abc);
+        let database = crate::db::Database::open(&db_path)?;
+        let mut entry = database.load(&path, HashAlgorithm::Md5).unwrap();
+        assert_eq!(entry.digest, abc);
+        assert!(database.verified(&path, HashAlgorithm::Md5)?.is_some());
+        assert!(database.load(&path, HashAlgorithm::Sha256).is_none());
+
+        // Updating trusts the recorded digest of a file that didn't change:
+        entry.digest = Md5::digest(b"not abc").to_vec();
+        database.store(&path, HashAlgorithm::Md5, &entry)?;
+        database.commit()?;
+        assert_eq!(run(false)?.into_result()?, entry.digest);
+        // But a full run reads it again and finds it corrupt:
+        assert!(run(true)?.error.unwrap().to_string().contains("corrupt"));
+
+        // Changes are only saved when they're committed:
+        let database = crate::db::Database::open(&db_path)?;
+        database.store(
+            &path,
+            HashAlgorithm::Md5,
+            &cache::CacheEntry {
+                digest: abc.clone(),
+                ..entry.clone()
+            },
+        )?;
+        drop(database);
+        let database = crate::db::Database::open(&db_path)?;
+        assert_eq!(
+            database.load(&path, HashAlgorithm::Md5).unwrap().digest,
+            entry.digest
+        );
+        drop(database);
+        std::fs::remove_file(&db_path)?;
         Ok(())
     }
 
     #[test]
-    fn test_preregistered_files_o_direct() -> Result<()> {
+    fn test_ffi() -> Result<()> {
+        use std::ffi::{CStr, CString};
+
+        use crate::ffi::*;
+
         setup();
-        assert_checksums(with_register_files::get_checksums, true)?;
+        let checksums = file_setup()?;
+        let names: Vec<CString> = checksums
+            .keys()
+            .map(|path| CString::new(path.as_os_str().as_encoded_bytes()))
+            .collect::<Result<_, _>>()?;
+        let pointers: Vec<_> = names.iter().map(|name| name.as_ptr()).collect();
+
+        unsafe {
+            let session = md5sum_uring_submit(pointers.as_ptr(), pointers.len(), std::ptr::null());
+            assert!(!session.is_null());
+            let mut result = std::mem::zeroed();
+            let mut results = 0;
+            while md5sum_uring_poll(session, &mut result, 1) == MD5SUM_URING_RESULT {
+                results += 1;
+                assert!(result.error.is_null());
+                let path = PathBuf::from(CStr::from_ptr(result.path).to_str()?);
+                let digest = std::slice::from_raw_parts(result.digest, result.digest_len);
+                assert_eq!(checksums.get(&path).unwrap()[..], digest[..]);
+            }
+            assert_eq!(results, names.len());
+            assert_eq!(
+                md5sum_uring_poll(session, &mut result, 0),
+                MD5SUM_URING_DONE
+            );
+            md5sum_uring_free(session);
+
+            assert_eq!(
+                md5sum_uring_poll(std::ptr::null_mut(), &mut result, 0),
+                MD5SUM_URING_INVALID
+            );
+            let unknown = CString::new("not an algorithm")?;
+            assert!(md5sum_uring_submit(std::ptr::null(), 0, unknown.as_ptr()).is_null());
+        }
         Ok(())
     }
 
+    #[cfg(feature = "zeroize")]
     #[test]
-    fn test_fixed_buffers() -> Result<()> {
+    fn test_zeroize() {
         setup();
-        assert_checksums(with_fixed_buffers::get_checksums, false)?;
-        Ok(())
+        let mut buf = AlignedBuffer::new();
+        buf.fill(0xaa);
+        buf.wipe();
+        assert!(
+            buf.iter().all(|&byte| byte == 0xaa),
+            "Wiping is off by default"
+        );
+        crate::set_zeroize(true);
+        buf.wipe();
+        assert!(buf.iter().all(|&byte| byte == 0));
     }
 
     #[test]
-    fn test_fixed_buffers_o_direct() -> Result<()> {
+    fn test_stream_verifier() -> Result<()> {
         setup();
-        assert_checksums(with_fixed_buffers::get_checksums, true)?;
+        let data = b"message digest";
+        let mut verifier =
+            StreamVerifier::new(HashAlgorithm::Md5, "F96B697D7CB7938D525A2F31AAF161D0")?
+                .with_total(data.len() as u64);
+        verifier.push(&data[..7]);
+        let progress = verifier.push(&data[7..]);
+        assert_eq!(progress.bytes, data.len() as u64);
+        assert_eq!(progress.total, Some(data.len() as u64));
+        assert!(verifier.finish().is_match());
+
+        let mut verifier =
+            StreamVerifier::new(HashAlgorithm::Md5, "f96b697d7cb7938d525a2f31aaf161d0")?;
+        std::io::copy(&mut &b"message digesT"[..], &mut verifier)?;
+        assert!(matches!(verifier.finish(), Verdict::Mismatch { .. }));
+
+        assert!(StreamVerifier::new(HashAlgorithm::Md5, "xyz").is_err());
+        assert!(StreamVerifier::new(HashAlgorithm::Md5, "abcd").is_err());
+
+        let mut verifier = StreamVerifier::new(
+            HashAlgorithm::Sha256,
+            "ba7816bf8f01cfea414140de5dae2223b00361a396177a9cb410ff61f20015ad",
+        )?;
+        verifier.push(b"abc");
+        assert!(verifier.finish().is_match());
         Ok(())
     }
 
     #[test]
-    /// This will fail on WSL2 and networked files.
-    fn test_simplest_o_direct() -> Result<()> {
+    fn test_self_test() -> Result<()> {
         setup();
         let _ = file_setup()?;
-        let len = 25;
-        let expected_contents = "0000000000111111111122222";
-
-        let mut file = open("test/file-25", true)?;
-        let mut buf: Box<AlignedBuffer> = Default::default();
-        buf.resize(len);
-        file.read_exact(&mut buf)?;
-        let data = String::from_utf8_lossy(&buf);
-        trace!("Read file: {}", data);
-        assert_eq!(data, expected_contents);
-
+        assert!(self_test::run(Path::new("test"), &Config::default())?);
         Ok(())
     }
 
     #[test]
-    fn test_arguments() {
+    fn test_max_open_files() {
         setup();
+        let cap = max_open_files();
 
+        let mut limit = libc::rlimit {
+            rlim_cur: 0,
+            rlim_max: 0,
+        };
+        assert_eq!(
+            unsafe { libc::getrlimit(libc::RLIMIT_NOFILE, &mut limit) },
+            0
+        );
+        assert!(cap >= 1);
         assert!(
-            matches!(
-                Opt::from_iter_safe(&["", "--o-direct", "--no-uring"]),
-                Err(_)
-            ),
-            "--o-direct and --no-uring should be an illegal combination."
+            (cap as u64) < limit.rlim_cur,
+            "The cap ({}) must leave room below the soft limit ({}).",
+            cap,
+            limit.rlim_cur
         );
     }
 
@@ -331,12 +4921,33 @@ mod tests {
         let ptr = buf.as_mut_ptr();
         assert_eq!(ptr as usize % ALIGNMENT, 0);
 
+        let mut buf = AlignedBuffer::with_capacity(8 << 20);
+        let ptr = buf.as_mut_ptr();
+        assert_eq!(ptr as usize % ALIGNMENT, 0);
+        assert_eq!((buf.len(), buf.capacity()), (8 << 20, 8 << 20));
+    }
+
+    #[test]
+    fn test_read_size() -> Result<()> {
+        setup();
+        let mut buf = AlignedBuffer::with_capacity(ALIGNMENT * 3);
+        buf.resize(ALIGNMENT + 1);
+        assert_eq!((buf.len(), buf.capacity()), (ALIGNMENT + 1, ALIGNMENT * 3));
+        assert!(std::panic::catch_unwind(move || buf.resize(ALIGNMENT * 3 + 1)).is_err());
+
+        assert_eq!(Opt::from_iter_safe(["", "file"])?.read_size, MAX_READ_SIZE);
+        assert_eq!(
+            Opt::from_iter_safe(["", "--read-size", "8M"])?.read_size,
+            8 << 20
+        );
         assert_eq!(
-            align_of::<AlignedBuffer>(),
-            ALIGNMENT,
-            "Aligned buffer size is {}, should be {}.",
-            align_of::<AlignedBuffer>(),
-            ALIGNMENT
+            Opt::from_iter_safe(["", "--read-size", "12288"])?.read_size,
+            ALIGNMENT * 3
         );
+        // Reads must stay aligned for O_DIRECT:
+        assert!(Opt::from_iter_safe(["", "--read-size", "1000"]).is_err());
+        assert!(Opt::from_iter_safe(["", "--read-size", "0"]).is_err());
+        assert!(Opt::from_iter_safe(["", "--read-size", "2G"]).is_err());
+        Ok(())
     }
 }
@@ -1,52 +1,408 @@
-use std::{sync::mpsc::channel, thread};
+use std::{
+    collections::HashMap,
+    io::{self, Write},
+    sync::{mpsc::channel, Arc, Mutex},
+    thread,
+    time::Instant,
+};
 
-use anyhow::Result;
+use anyhow::{anyhow, Result};
 #[allow(unused_imports)]
 use log::{debug, error, info, trace, warn};
-use md5::Digest;
 use structopt::StructOpt;
 
-use md5sum_uring::*;
-
-mod simple_uring;
-mod with_fixed_buffers;
-mod with_register_files;
-mod without_uring;
+use md5sum_uring::{
+    manifest::{display_path, format_block_lines, format_line, format_stamp_line, read_digest_set},
+    *,
+};
 
 fn main() -> Result<()> {
     env_logger::init();
 
-    let options = Opt::from_args();
+    let mut options = Opt::from_args();
+    if let Err(message) = options.check_strategy() {
+        structopt::clap::Error::with_description(
+            &message,
+            structopt::clap::ErrorKind::ArgumentConflict,
+        )
+        .exit();
+    }
+    #[cfg(feature = "zeroize")]
+    set_zeroize(options.zeroize);
+    let config = options.config();
+
+    match options.command {
+        Some(Command::Bench {
+            iterations,
+            drop_caches,
+            format,
+            criterion_dir,
+            files,
+        }) => {
+            let report = bench::run(files, iterations, drop_caches, &config)?;
+            bench::print_report(&report, format)?;
+            if let Some(dir) = criterion_dir {
+                bench::write_criterion(&report, &dir)?;
+            }
+            return Ok(());
+        }
+        Some(Command::Compare {
+            report_identical_files,
+            dir_a,
+            dir_b,
+        }) => {
+            let excludes = input::Excludes::new(&options.exclude, options.exclude_from.as_deref())?;
+            let summary = compare::run(&dir_a, &dir_b, &excludes, &config, report_identical_files)?;
+            if !summary.is_identical() {
+                std::process::exit(1);
+            }
+            return Ok(());
+        }
+        Some(Command::Cmp {
+            chunk_size,
+            file_a,
+            file_b,
+        }) => {
+            if !byte_cmp::run(&file_a, &file_b, chunk_size, &config)? {
+                std::process::exit(1);
+            }
+            return Ok(());
+        }
+        Some(Command::SelfTest { dir }) => {
+            let dir = dir.unwrap_or_else(std::env::temp_dir);
+            if !self_test::run(&dir, &config)? {
+                std::process::exit(1);
+            }
+            return Ok(());
+        }
+        #[cfg(target_os = "linux")]
+        Some(Command::Watch {
+            manifest,
+            deny,
+            diagnose,
+            dir,
+        }) => return watch::run(&dir, &manifest, config.algorithm, deny, diagnose),
+        #[cfg(target_os = "linux")]
+        Some(Command::Probe) => return probe::run(),
+        // Both are built on fanotify and io_uring:
+        #[cfg(not(target_os = "linux"))]
+        Some(Command::Watch { .. } | Command::Probe) => {
+            return Err(anyhow!("This subcommand needs Linux."))
+        }
+        None => {}
+    }
+
+    if let Some(list) = &options.check {
+        let summary = check::run(list, &config, &options.check_options)?;
+        if !summary.success(&options.check_options) {
+            std::process::exit(1);
+        }
+        return Ok(());
+    }
+
+    let mut output = match &options.output {
+        Some(path) => Some(output::AtomicFile::create(path)?),
+        None => None,
+    };
+    let mut stdout = io::stdout().lock();
+    let out: &mut dyn Write = match &mut output {
+        Some(file) => file,
+        None => &mut stdout,
+    };
+
+    if options.tree_digest {
+        // The directories are walked one at a time, rather than as one list of files:
+        let excludes = input::Excludes::new(&options.exclude, options.exclude_from.as_deref())?;
+        let follow_links = input::Symlinks::from_options(&options) == input::Symlinks::Always;
+        for dir in &options.files {
+            match tree_digest::digest_dir(dir, &excludes, follow_links, options.max_depth, &config)
+            {
+                Ok(digest) => out.write_all(&format_line(&to_hex(&digest), dir))?,
+                Err(err) => eprintln!("{}: {}", display_path(dir), err),
+            }
+        }
+        if let Some(file) = output {
+            file.commit()?;
+        }
+        return Ok(());
+    }
+
+    options.files = input::collect_files(&options)?;
+
+    if options.find_duplicates {
+        let groups = duplicates::find(options.files, &config)?;
+        duplicates::print(&groups, out)?;
+        if let Some(file) = output {
+            file.commit()?;
+        }
+        return Ok(());
+    }
+
+    if let Some(piece_length) = options.torrent_pieces {
+        let pieces = torrent::piece_hashes(&options.files, piece_length, config.o_direct)?;
+        for hash in &pieces.hashes {
+            writeln!(out, "{}", to_hex(hash))?;
+        }
+        eprintln!(
+            "{} {} of {} bytes, {} bytes in all",
+            pieces.hashes.len(),
+            if pieces.hashes.len() == 1 {
+                "piece"
+            } else {
+                "pieces"
+            },
+            piece_length,
+            pieces.total_length
+        );
+        if let Some(file) = output {
+            file.commit()?;
+        }
+        return Ok(());
+    }
+
+    if options.archive.is_some() {
+        for archive in &options.files {
+            let result = archive::hash_tar(
+                archive,
+                options.o_direct,
+                options.algorithm,
+                |member, digest| {
+                    let path = archive::member_path(archive, &member);
+                    out.write_all(&format_line(&to_hex(&digest), &path))?;
+                    Ok(())
+                },
+            );
+            if let Err(err) = result {
+                eprintln!("{}: {}", display_path(archive), err);
+            }
+        }
+        if let Some(file) = output {
+            file.commit()?;
+        }
+        return Ok(());
+    }
+
+    let strategy_name = config.strategy.name();
+    let file_count = options.files.len();
+    let start = Instant::now();
+    let start_bytes = progress::bytes_hashed();
+    let start_reads = progress::reads_submitted();
+    let mut failed = 0;
+
+    let progress_bar = options
+        .progress
+        .then(|| progress::ProgressBar::start(&options.files));
 
     let (tx, rx) = channel();
+    let incremental = match &options.incremental {
+        Some(old) => Some(Arc::new(cache::ManifestCache::open(old, config.algorithm)?)),
+        None => None,
+    };
 
+    let match_list = match &options.match_list {
+        Some(list) => Some(read_digest_set(list, config.algorithm.output_len() * 2)?),
+        None => None,
+    };
+    let mut matched = 0;
+    let mut dir_manifests = options
+        .manifest_per_dir
+        .as_ref()
+        .map(output::DirManifests::new);
+    let block_digests = options.block_digests;
+    let zero = options.zero;
+    let show_stats = options.stats;
+    let o_direct = config.o_direct;
+    let algorithm = config.algorithm;
+    let jobs = config.jobs;
+    let (ring_size, read_size) = (config.ring_size, config.read_size);
+    let thread_incremental = incremental.clone();
+    let blocks = Arc::new(Mutex::new(HashMap::new()));
+    let thread_blocks = blocks.clone();
+    let (combined_tx, combined_rx) = channel();
+    let combined = options.combined;
     let handle = thread::spawn(move || {
-        if options.no_uring {
-            without_uring::get_checksums(options.files, tx, options.o_direct)
-        } else if options.use_fixed_buffers {
-            if !options.pre_register_files {
-                warn!("Fixed buffers without preregistered files is not implemented. Using preregistered files.");
-            }
-            with_fixed_buffers::get_checksums(options.files, tx, options.o_direct)
-        } else if options.pre_register_files {
-            with_register_files::get_checksums(options.files, tx, options.o_direct)
+        if combined {
+            let digest = combined::get_checksums_combined(options.files, tx, &config)?;
+            let _ = combined_tx.send(digest);
+            return Ok(());
+        }
+        // Streams can't be read at offsets, so they're read one at a time first:
+        let (special, files) = options
+            .files
+            .into_iter()
+            .partition(|path| input::is_stream(path));
+        options.files = files;
+        for path in special {
+            let result = checksum_stream(&path, Hasher::new(algorithm));
+            tx.send((path, result).into())
+                .map_err(|err| anyhow!("{}", err))?;
+        }
+
+        #[cfg(feature = "sqlite")]
+        if let Some(db) = &options.db {
+            let database = db::Database::open(db)?;
+            let verify = !options.update;
+            cache::get_checksums_cached(options.files, tx, &config, &database, verify)?;
+            return database.commit();
+        }
+        if let Some(incremental) = &thread_incremental {
+            return cache::get_checksums_cached(options.files, tx, &config, &**incremental, false);
+        }
+        if options.xattr_cache {
+            let xattrs = xattr_cache::XattrCache;
+            cache::get_checksums_cached(options.files, tx, &config, &xattrs, options.verify_cache)
+        } else if let Some(block_size) = block_digests {
+            let files = options.files;
+            diagnose::get_checksums_with_blocks(files, tx, block_size, &config, &thread_blocks)
         } else {
-            simple_uring::get_checksums(options.files, tx, options.o_direct)
+            get_checksums(options.files, tx, &config)
         }
     });
 
-    for (path, result) in rx {
-        let path = path.to_string_lossy();
-        match result {
-            Ok(checksum) => {
-                println!("{:x}  {}", checksum.finalize(), path);
+    let format = options.format;
+    if format == Some(record::OutputFormat::Csv) {
+        out.write_all(record::CSV_HEADER)?;
+    }
+    for result in rx {
+        if !result.is_ok() {
+            failed += 1;
+        }
+        let listed = match &match_list {
+            Some(known) => result.is_ok() && known.contains(&result.digest),
+            None => true,
+        };
+        if listed && match_list.is_some() {
+            matched += 1;
+        }
+        let path = result.path;
+        let print_result = || -> Result<()> {
+            match result.error {
+                None if !listed => {}
+                None => {
+                    let digest = to_hex(&result.digest);
+                    if let Some(format) = format {
+                        out.write_all(&record::Record::new(&path, Ok(&digest)).to_line(format)?)?;
+                    } else if zero {
+                        // Like md5sum, don't escape names, since NUL is the only byte they can't
+                        // contain:
+                        let mut line = format!("{}  ", digest).into_bytes();
+                        line.extend_from_slice(path.as_os_str().as_encoded_bytes());
+                        line.push(b'\0');
+                        out.write_all(&line)?;
+                    } else {
+                        out.write_all(&format_line(&digest, &path))?;
+                    }
+                    // Streams can't be read again to check them:
+                    if let Some(dir_manifests) = &mut dir_manifests {
+                        if !input::is_stream(&path) {
+                            dir_manifests.add(&path, &digest);
+                        }
+                    }
+                    let stamp = incremental
+                        .as_ref()
+                        .and_then(|incremental| incremental.stamp(&path, &result.digest));
+                    if let Some(stamp) = stamp {
+                        out.write_all(&format_stamp_line(&stamp, &digest, &path))?;
+                    }
+                    if let Some(block_size) = block_digests {
+                        let taken = blocks.lock().unwrap().remove(&path);
+                        let digests = match taken {
+                            Some(digests) => Ok(digests),
+                            // Files whose digest was cached weren't read, so their blocks are
+                            // read now:
+                            None => open(&path, o_direct)
+                                .map_err(Into::into)
+                                .and_then(|fd| diagnose::block_digests(&fd, block_size, algorithm)),
+                        };
+                        match digests {
+                            Ok(digests) => {
+                                out.write_all(&format_block_lines(block_size, &digests, &path))?
+                            }
+                            Err(err) => eprintln!("{}: {}", display_path(&path), err),
+                        }
+                    }
+                }
+                Some(err) => {
+                    eprintln!("{}: {}", display_path(&path), err);
+                    if let Some(format) = format {
+                        out.write_all(&record::Record::new(&path, Err(&err)).to_line(format)?)?;
+                    }
+                }
             }
-            Err(err) => {
-                eprintln!("{}: {}", path, err);
+            if progress_bar.is_some() {
+                // Finish the output before the progress line is redrawn:
+                out.flush()?;
             }
+            Ok(())
+        };
+        match &progress_bar {
+            Some(progress_bar) => {
+                progress_bar.file_done();
+                progress_bar.suspend(print_result)?;
+            }
+            None => print_result()?,
         }
     }
 
     handle.join().unwrap()?;
+    if let Some(progress_bar) = progress_bar {
+        progress_bar.finish();
+    }
+    match combined_rx.try_recv() {
+        Ok(Some(digest)) => out.write_all(&combined::format_combined_line(&to_hex(&digest)))?,
+        Ok(None) => eprintln!("No combined checksum, since not every file could be read"),
+        Err(_) => {}
+    }
+    if let Some(dir_manifests) = dir_manifests {
+        let written = dir_manifests.write();
+        info!("Wrote checksum files in {} directories", written);
+    }
+    if match_list.is_some() {
+        eprintln!(
+            "{} of {} {} matched",
+            matched,
+            file_count,
+            if file_count == 1 { "file" } else { "files" }
+        );
+    }
+    if let Some(file) = output {
+        file.commit()?;
+    }
+
+    if show_stats {
+        let bytes = progress::bytes_hashed() - start_bytes;
+        let reads = progress::reads_submitted() - start_reads;
+        let elapsed = start.elapsed();
+        if strategy_name == "no-uring" {
+            eprintln!("Strategy:   {}", strategy_name);
+        } else {
+            eprintln!(
+                "Strategy:   {} ({} job{}, ring size {}, read size {}, O_DIRECT {})",
+                strategy_name,
+                jobs,
+                if jobs == 1 { "" } else { "s" },
+                ring_size,
+                read_size,
+                if o_direct { "on" } else { "off" }
+            );
+        }
+        eprintln!("Algorithm:  {}", algorithm);
+        eprintln!("Files:      {} ({} failed)", file_count, failed);
+        eprintln!("Bytes:      {}", bytes);
+        eprintln!("Wall time:  {:.3} s", elapsed.as_secs_f64());
+        eprintln!(
+            "Throughput: {:.1} MB/s",
+            bytes as f64 / elapsed.as_secs_f64() / 1e6
+        );
+        if reads > 0 {
+            eprintln!(
+                "Reads:      {} submitted, {} bytes on average",
+                reads,
+                bytes / reads
+            );
+        } else {
+            eprintln!("Reads:      none submitted (the files were memory mapped)");
+        }
+    }
     Ok(())
 }
@@ -0,0 +1,316 @@
+// This module reads checksum lists in the format md5sum writes.
+use std::{
+    borrow::Cow,
+    collections::{HashMap, HashSet},
+    fs,
+    path::{Path, PathBuf},
+    time::Duration,
+};
+
+use anyhow::{anyhow, Result};
+#[allow(unused_imports)]
+use log::{debug, error, info, trace, warn};
+
+use crate::{cache::Stamp, from_hex, path_from_bytes};
+
+/// One file of a checksum list.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ManifestEntry {
+    /// The expected digest in lowercase hexadecimal
+    pub digest: String,
+    pub path: PathBuf,
+    /// Digests of each block of the file, if the list has them
+    pub blocks: Option<BlockDigests>,
+    /// The file's size and modification time when it was read, if the list has them
+    pub stamp: Option<Stamp>,
+}
+
+/// The digest of each `block_size` byte block of a file, in order. These are stored as comment
+/// lines like `#block 65536 <digest>  <path>`, which md5sum ignores.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct BlockDigests {
+    pub block_size: u64,
+    pub digests: Vec<String>,
+}
+
+/// The comment lines that record a file's block digests.
+pub fn format_block_lines(block_size: u64, digests: &[String], path: &Path) -> Vec<u8> {
+    let mut lines = Vec::new();
+    for digest in digests {
+        lines.extend_from_slice(format!("{}{} ", BLOCK_PREFIX, block_size).as_bytes());
+        lines.extend_from_slice(&format_line(digest, path));
+    }
+    lines
+}
+
+/// The comment line that records the size and modification time a file had when it was read,
+/// like `#stamp 4096 1714566600.123456789 <digest>  <path>`. md5sum ignores it.
+pub fn format_stamp_line(stamp: &Stamp, digest: &str, path: &Path) -> Vec<u8> {
+    let mut line = format!(
+        "{}{} {}.{:09} ",
+        STAMP_PREFIX,
+        stamp.size,
+        stamp.mtime.as_secs(),
+        stamp.mtime.subsec_nanos()
+    )
+    .into_bytes();
+    line.extend_from_slice(&format_line(digest, path));
+    line
+}
+
+/// A line of a checksum list, the way md5sum writes it. If the path contains a newline,
+/// carriage return or backslash, those are escaped and the line starts with a backslash.
+pub fn format_line(digest: &str, path: &Path) -> Vec<u8> {
+    let (name, escaped) = escape_path(path);
+    let mut line = Vec::with_capacity(digest.len() + name.len() + 4);
+    if escaped {
+        line.push(b'\\');
+    }
+    line.extend_from_slice(digest.as_bytes());
+    line.extend_from_slice(b"  ");
+    line.extend_from_slice(&name);
+    line.push(b'\n');
+    line
+}
+
+/// A path as md5sum prints it in messages. Unlike in checksum lines, backslashes alone aren't
+/// escaped, but if there's a newline, the path is escaped and starts with a backslash.
+pub fn display_path(path: &Path) -> String {
+    let name = path.as_os_str().as_encoded_bytes();
+    if !name.iter().any(|byte| matches!(byte, b'\n' | b'\r')) {
+        return String::from_utf8_lossy(name).into_owned();
+    }
+    format!("\\{}", String::from_utf8_lossy(&escape_path(path).0))
+}
+
+/// Escape the characters that would break a checksum line. Returns whether any were escaped.
+fn escape_path(path: &Path) -> (Cow<'_, [u8]>, bool) {
+    let name = path.as_os_str().as_encoded_bytes();
+    if !name
+        .iter()
+        .any(|byte| matches!(byte, b'\n' | b'\r' | b'\\'))
+    {
+        return (Cow::Borrowed(name), false);
+    }
+    let mut escaped = Vec::with_capacity(name.len() + 2);
+    for &byte in name {
+        match byte {
+            b'\n' => escaped.extend_from_slice(b"\\n"),
+            b'\r' => escaped.extend_from_slice(b"\\r"),
+            b'\\' => escaped.extend_from_slice(b"\\\\"),
+            _ => escaped.push(byte),
+        }
+    }
+    (Cow::Owned(escaped), true)
+}
+
+/// Undo `escape_path`. Returns None if there's a backslash that doesn't start an escape.
+fn unescape_path(name: &[u8]) -> Option<Vec<u8>> {
+    let mut unescaped = Vec::with_capacity(name.len());
+    let mut bytes = name.iter();
+    while let Some(&byte) = bytes.next() {
+        if byte != b'\\' {
+            unescaped.push(byte);
+            continue;
+        }
+        match bytes.next()? {
+            b'n' => unescaped.push(b'\n'),
+            b'r' => unescaped.push(b'\r'),
+            b'\\' => unescaped.push(b'\\'),
+            _ => return None,
+        }
+    }
+    Some(unescaped)
+}
+
+const BLOCK_PREFIX: &str = "#block ";
+const STAMP_PREFIX: &str = "#stamp ";
+
+/// Parse a line like `d41d8cd98f00b204e9800998ecf8427e  path/to/file`. A `*` in place of the
+/// second space (binary mode) is also accepted, and so is a leading backslash for an escaped
+/// path. Returns None if the line isn't in that format.
+pub fn parse_line(line: &[u8]) -> Option<ManifestEntry> {
+    let line = line.strip_suffix(b"\n").unwrap_or(line);
+    let line = line.strip_suffix(b"\r").unwrap_or(line);
+    let (escaped, line) = match line.strip_prefix(b"\\") {
+        Some(line) => (true, line),
+        None => (false, line),
+    };
+
+    let separator = line.iter().position(|&byte| byte == b' ')?;
+    let (digest, rest) = line.split_at(separator);
+    if digest.is_empty() || digest.len() % 2 != 0 || !digest.iter().all(u8::is_ascii_hexdigit) {
+        return None;
+    }
+    let path = match rest {
+        [b' ', b' ' | b'*', path @ ..] if !path.is_empty() => path,
+        _ => return None,
+    };
+
+    let path = if escaped {
+        Cow::Owned(unescape_path(path)?)
+    } else {
+        Cow::Borrowed(path)
+    };
+
+    Some(ManifestEntry {
+        digest: String::from_utf8_lossy(digest).to_ascii_lowercase(),
+        path: path_from_bytes(&path),
+        blocks: None,
+        stamp: None,
+    })
+}
+
+/// Parse a block digest line into its block size and an entry for that block.
+fn parse_block_line(line: &[u8]) -> Option<(u64, ManifestEntry)> {
+    let line = line.strip_prefix(BLOCK_PREFIX.as_bytes())?;
+    let separator = line.iter().position(|&byte| byte == b' ')?;
+    let block_size = std::str::from_utf8(&line[..separator]).ok()?.parse().ok()?;
+    if block_size == 0 {
+        return None;
+    }
+    Some((block_size, parse_line(&line[separator + 1..])?))
+}
+
+/// Parse a stamp line into the stamp and the entry it's for.
+fn parse_stamp_line(line: &[u8]) -> Option<(Stamp, ManifestEntry)> {
+    let line = std::str::from_utf8(line.strip_prefix(STAMP_PREFIX.as_bytes())?).ok()?;
+    let (size, line) = line.split_once(' ')?;
+    let (mtime, line) = line.split_once(' ')?;
+    let (secs, nanos) = mtime.split_once('.')?;
+    let stamp = Stamp {
+        mtime: Duration::new(secs.parse().ok()?, nanos.parse().ok()?),
+        size: size.parse().ok()?,
+    };
+    Some((stamp, parse_line(line.as_bytes())?))
+}
+
+/// Read every entry of a checksum list. Blank lines and other lines starting with `#` are
+/// skipped; any other line that can't be parsed is an error.
+pub fn read_manifest(path: &Path) -> Result<Vec<ManifestEntry>> {
+    Ok(parse_manifest(&fs::read(path)?, path, None)?.entries)
+}
+
+/// The entries of a checksum list read leniently, the way `md5sum --check` reads it.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ChecksumList {
+    pub entries: Vec<ManifestEntry>,
+    /// The line numbers, starting at 1, of lines that couldn't be parsed and were skipped
+    pub malformed: Vec<usize>,
+}
+
+/// Read every entry of a checksum list, noting the lines that can't be parsed instead of failing
+/// on them. Digests that aren't `digest_len` hexadecimal digits long were made with some other
+/// algorithm, so those lines can't be parsed either.
+pub fn read_checksum_list(path: &Path, digest_len: usize) -> Result<ChecksumList> {
+    parse_manifest(&fs::read(path)?, path, Some(digest_len))
+}
+
+/// Read a list of known digests, like a hash set of malware samples: the first field of each line
+/// is a digest, so both bare digests and checksum lists can be read. Blank lines and comments
+/// starting with # are skipped. Every digest must be `digest_len` hexadecimal digits long, since
+/// one that isn't was made with some other algorithm and could never match.
+pub fn read_digest_set(path: &Path, digest_len: usize) -> Result<HashSet<Vec<u8>>> {
+    let contents = fs::read(path)?;
+    let mut digests = HashSet::new();
+    for (line_number, line) in contents.split(|&byte| byte == b'\n').enumerate() {
+        let line = line.trim_ascii();
+        if line.is_empty() || line.starts_with(b"#") {
+            continue;
+        }
+        // An escaped checksum line starts with a backslash:
+        let line = line.strip_prefix(b"\\").unwrap_or(line);
+        let field = line.split(u8::is_ascii_whitespace).next().unwrap_or(line);
+        let digest = std::str::from_utf8(field)
+            .ok()
+            .filter(|digest| {
+                digest.len() == digest_len && digest.bytes().all(|byte| byte.is_ascii_hexdigit())
+            })
+            .and_then(from_hex)
+            .ok_or_else(|| {
+                anyhow!(
+                    "{}:{}: not a digest of {} hexadecimal digits",
+                    path.display(),
+                    line_number + 1,
+                    digest_len
+                )
+            })?;
+        digests.insert(digest);
+    }
+    Ok(digests)
+}
+
+/// Parse a checksum list. If `digest_len` is None, any line that can't be parsed is an error.
+fn parse_manifest(contents: &[u8], path: &Path, digest_len: Option<usize>) -> Result<ChecksumList> {
+    let strict = digest_len.is_none();
+    let mut list = ChecksumList {
+        entries: Vec::new(),
+        malformed: Vec::new(),
+    };
+    let mut blocks: HashMap<PathBuf, BlockDigests> = HashMap::new();
+    // Each file's stamp and the digest it was read with:
+    let mut stamps: HashMap<PathBuf, (Stamp, String)> = HashMap::new();
+    for (line_number, line) in contents.split(|&byte| byte == b'\n').enumerate() {
+        let malformed = || {
+            anyhow!(
+                "{}:{}: improperly formatted checksum line",
+                path.display(),
+                line_number + 1
+            )
+        };
+
+        if line.starts_with(BLOCK_PREFIX.as_bytes()) {
+            let parsed =
+                parse_block_line(line)
+                    .ok_or_else(malformed)
+                    .and_then(|(block_size, block)| {
+                        let file_blocks = blocks.entry(block.path).or_insert(BlockDigests {
+                            block_size,
+                            digests: Vec::new(),
+                        });
+                        if file_blocks.block_size != block_size {
+                            return Err(malformed()
+                                .context("the block size changed partway through a file"));
+                        }
+                        file_blocks.digests.push(block.digest);
+                        Ok(())
+                    });
+            match parsed {
+                Err(err) if strict => return Err(err),
+                Err(_) => list.malformed.push(line_number + 1),
+                Ok(()) => {}
+            }
+            continue;
+        }
+        if line.starts_with(STAMP_PREFIX.as_bytes()) {
+            match parse_stamp_line(line) {
+                Some((stamp, entry)) => {
+                    stamps.insert(entry.path, (stamp, entry.digest));
+                }
+                None if strict => return Err(malformed()),
+                None => list.malformed.push(line_number + 1),
+            }
+            continue;
+        }
+        if line.iter().all(u8::is_ascii_whitespace) || line.starts_with(b"#") {
+            continue;
+        }
+        match parse_line(line) {
+            Some(entry) if digest_len.is_none_or(|len| entry.digest.len() == len) => {
+                list.entries.push(entry)
+            }
+            _ if strict => return Err(malformed()),
+            _ => list.malformed.push(line_number + 1),
+        }
+    }
+
+    for entry in &mut list.entries {
+        entry.blocks = blocks.remove(&entry.path);
+        // A stamp is only for the version of the file with that digest:
+        entry.stamp = stamps
+            .remove(&entry.path)
+            .filter(|(_, digest)| *digest == entry.digest)
+            .map(|(stamp, _)| stamp);
+    }
+    Ok(list)
+}
@@ -0,0 +1,262 @@
+// This module saves and restores the progress of a partially hashed file, so hashing a huge
+// file can resume where it stopped instead of starting over. A midstate names its file, so one
+// saved to a state file is enough to pick up a long run again after a reboot, and records the
+// file's size, modification time and inode, so it isn't resumed once the file has changed.
+use std::{
+    fs::{self, File, Metadata},
+    io::{self, Write},
+    path::{Path, PathBuf},
+    time::{Duration, UNIX_EPOCH},
+};
+
+use anyhow::{bail, Context, Result};
+#[allow(unused_imports)]
+use log::{debug, error, info, trace, warn};
+use md5::{
+    digest::common::hazmat::{SerializableState, SerializedState},
+    Digest, Md5,
+};
+
+use crate::*;
+
+/// Identifies serialized midstates and their format version.
+const MAGIC: &[u8; 8] = b"HASHMID3";
+/// The start of the magic of the first two versions, which didn't record the algorithm or the
+/// file's identity.
+const OLD_MAGIC: &[u8; 6] = b"MD5MID";
+
+/// The version of a file a midstate was taken of. If any of it changed, the bytes that were
+/// hashed may not be the file's any more, so the midstate can't be resumed.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct FileIdentity {
+    pub size: u64,
+    /// The modification time since the epoch
+    pub mtime: Duration,
+    /// The device and inode number, which are zero elsewhere than Unix
+    pub dev: u64,
+    pub ino: u64,
+}
+
+impl FileIdentity {
+    pub fn of(metadata: &Metadata) -> io::Result<FileIdentity> {
+        let mtime = metadata
+            .modified()?
+            .duration_since(UNIX_EPOCH)
+            .map_err(io::Error::other)?;
+        #[cfg(unix)]
+        let (dev, ino) = {
+            use std::os::unix::fs::MetadataExt;
+            (metadata.dev(), metadata.ino())
+        };
+        #[cfg(not(unix))]
+        let (dev, ino) = (0, 0);
+        Ok(FileIdentity {
+            size: metadata.len(),
+            mtime,
+            dev,
+            ino,
+        })
+    }
+}
+
+/// The digest state of the first `offset` bytes of a file.
+#[derive(Clone, Default)]
+pub struct Midstate {
+    /// The file being hashed, or empty if it's only known to the caller
+    pub path: PathBuf,
+    /// The version of the file that was being hashed, recorded when hashing starts
+    pub file: FileIdentity,
+    /// How many bytes of the file have been hashed
+    pub offset: u64,
+    /// The md5 state after hashing those bytes
+    pub ctx: Md5,
+}
+
+impl Midstate {
+    /// The algorithm of the digest state. Only md5 midstates can be saved so far.
+    pub fn algorithm(&self) -> HashAlgorithm {
+        HashAlgorithm::Md5
+    }
+
+    /// Serialize the midstate so it can be saved and resumed in a later run.
+    pub fn to_bytes(&self) -> Vec<u8> {
+        let algorithm = self.algorithm().name().as_bytes();
+        let path = self.path.as_os_str().as_encoded_bytes();
+        let mut bytes = MAGIC.to_vec();
+        bytes.push(algorithm.len() as u8);
+        bytes.extend_from_slice(algorithm);
+        for number in [
+            self.offset,
+            self.file.size,
+            self.file.mtime.as_secs(),
+            self.file.mtime.subsec_nanos() as u64,
+            self.file.dev,
+            self.file.ino,
+            path.len() as u64,
+        ] {
+            bytes.extend_from_slice(&number.to_le_bytes());
+        }
+        bytes.extend_from_slice(path);
+        bytes.extend_from_slice(&self.ctx.serialize());
+        bytes
+    }
+
+    /// Restore a midstate that was serialized by `to_bytes`.
+    pub fn from_bytes(bytes: &[u8]) -> Result<Midstate> {
+        let Some(mut bytes) = bytes.strip_prefix(MAGIC) else {
+            if bytes.starts_with(OLD_MAGIC) {
+                bail!(
+                    "The midstate was saved by an older version, which didn't record the file's \
+                     size and modification time, so the file has to be hashed from the start."
+                );
+            }
+            bail!("Not a saved midstate.");
+        };
+        let algorithm_len = take(&mut bytes, 1)?[0] as usize;
+        let algorithm = String::from_utf8_lossy(take(&mut bytes, algorithm_len)?);
+        if algorithm != HashAlgorithm::Md5.name() {
+            bail!(
+                "The midstate is of a {} digest, but only md5 hashing can be resumed.",
+                algorithm
+            );
+        }
+        let offset = take_u64(&mut bytes)?;
+        let size = take_u64(&mut bytes)?;
+        let mtime_secs = take_u64(&mut bytes)?;
+        let mtime_nanos = take_u64(&mut bytes)?;
+        let dev = take_u64(&mut bytes)?;
+        let ino = take_u64(&mut bytes)?;
+        let path_len = take_u64(&mut bytes)?;
+        let Ok(path_len) = usize::try_from(path_len) else {
+            bail!("The saved midstate is truncated.");
+        };
+        let path = path_from_bytes(take(&mut bytes, path_len)?);
+        let Ok(state) = SerializedState::<Md5>::try_from(bytes) else {
+            bail!("The saved md5 midstate has the wrong length.");
+        };
+        let ctx = match Md5::deserialize(&state) {
+            Ok(ctx) => ctx,
+            Err(_) => bail!("The saved md5 midstate is corrupt."),
+        };
+        let file = FileIdentity {
+            size,
+            mtime: Duration::new(mtime_secs, mtime_nanos as u32),
+            dev,
+            ino,
+        };
+        Ok(Midstate {
+            path,
+            file,
+            offset,
+            ctx,
+        })
+    }
+
+    /// Save the midstate to `state_file`, replacing what was there. The file is replaced
+    /// atomically, so a crash while saving leaves the previous midstate.
+    pub fn save(&self, state_file: &Path) -> Result<()> {
+        let mut file = output::AtomicFile::create(state_file)?;
+        file.write_all(&self.to_bytes())?;
+        file.commit()
+    }
+
+    /// Load the midstate saved to `state_file`, or `None` if nothing was saved there.
+    pub fn load(state_file: &Path) -> Result<Option<Midstate>> {
+        match fs::read(state_file) {
+            Ok(bytes) => Midstate::from_bytes(&bytes)
+                .with_context(|| format!("Could not resume from {}", state_file.display()))
+                .map(Some),
+            Err(err) if err.kind() == io::ErrorKind::NotFound => Ok(None),
+            Err(err) => Err(err.into()),
+        }
+    }
+}
+
+/// Take the next `len` bytes of a serialized midstate.
+fn take<'a>(bytes: &mut &'a [u8], len: usize) -> Result<&'a [u8]> {
+    let Some((taken, rest)) = bytes.split_at_checked(len) else {
+        bail!("The saved midstate is truncated.");
+    };
+    *bytes = rest;
+    Ok(taken)
+}
+
+fn take_u64(bytes: &mut &[u8]) -> Result<u64> {
+    Ok(u64::from_le_bytes(take(bytes, 8)?.try_into()?))
+}
+
+/// Hash one file with `algorithm`, starting from `resume_from` if given. `checkpoint` is called
+/// with the progress so far about every `checkpoint_interval` bytes; if it returns an error,
+/// hashing stops with that error, and the last midstate it was given can be used to resume later.
+/// A midstate saved for another file, or for this one before it changed, is an error, and so is
+/// an algorithm other than md5, whose midstates can't be saved yet.
+pub fn get_checksum_resumable<F>(
+    path: &Path,
+    algorithm: HashAlgorithm,
+    o_direct: bool,
+    resume_from: Option<Midstate>,
+    checkpoint_interval: u64,
+    checkpoint: F,
+) -> Result<Md5>
+where
+    F: FnMut(&Midstate) -> Result<()>,
+{
+    if algorithm != HashAlgorithm::Md5 {
+        bail!("Only md5 hashing can be resumed, not {}.", algorithm);
+    }
+    let state = match resume_from {
+        Some(state) if !state.path.as_os_str().is_empty() && state.path != path => bail!(
+            "The midstate is of {}, not {}.",
+            state.path.display(),
+            path.display()
+        ),
+        Some(state) => Midstate {
+            path: path.to_path_buf(),
+            ..state
+        },
+        None => Midstate {
+            path: path.to_path_buf(),
+            ..Default::default()
+        },
+    };
+    let fd = open(path, o_direct)?;
+    continue_checksum(&fd, state, checkpoint_interval, checkpoint)
+}
+
+/// Hash the rest of a file that's already open, starting from `state`. See
+/// `get_checksum_resumable`.
+pub fn continue_checksum<F>(
+    fd: &File,
+    mut state: Midstate,
+    checkpoint_interval: u64,
+    mut checkpoint: F,
+) -> Result<Md5>
+where
+    F: FnMut(&Midstate) -> Result<()>,
+{
+    let file = FileIdentity::of(&fd.metadata()?)?;
+    if state.offset == 0 {
+        state.file = file;
+    } else if state.file != file {
+        bail!(
+            "The file changed since the midstate was saved, so it has to be hashed from the start."
+        );
+    }
+    let file_len = file.size;
+
+    let mut since_checkpoint = 0;
+    read_sequentially(fd, state.offset, file_len, |data| {
+        state.ctx.update(data);
+        state.offset += data.len() as u64;
+        since_checkpoint += data.len() as u64;
+
+        if since_checkpoint >= checkpoint_interval && state.offset < file_len {
+            trace!("Checkpoint at byte {} of {}", state.offset, file_len);
+            checkpoint(&state)?;
+            since_checkpoint = 0;
+        }
+        Ok(true)
+    })?;
+
+    Ok(state.ctx)
+}
@@ -0,0 +1,167 @@
+// This module places the threads of `--jobs` on NUMA nodes, so each ring's buffers are in memory
+// near the CPUs that hash them, and near the storage controller when that's known.
+use std::{fs, path::Path, str::FromStr};
+#[cfg(target_os = "linux")]
+use std::{io, mem, os::unix::fs::MetadataExt};
+
+use anyhow::{bail, Result};
+#[allow(unused_imports)]
+use log::{debug, error, info, trace, warn};
+
+const NODE_DIR: &str = "/sys/devices/system/node";
+/// `MPOL_PREFERRED` from linux/mempolicy.h.
+#[cfg(target_os = "linux")]
+const MPOL_PREFERRED: libc::c_int = 1;
+
+/// Where to run the threads that read and hash files.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Placement {
+    /// On the node of the storage each thread's first file is on, or spread over the nodes when
+    /// that isn't known
+    Auto,
+    /// All on this node
+    Node(usize),
+}
+
+impl FromStr for Placement {
+    type Err = String;
+
+    fn from_str(s: &str) -> std::result::Result<Self, Self::Err> {
+        if s == "auto" {
+            return Ok(Placement::Auto);
+        }
+        match s.strip_prefix("node:") {
+            Some(node) => node
+                .parse()
+                .map(Placement::Node)
+                .map_err(|err| format!("invalid node {:?}: {}", node, err)),
+            None => Err(format!(
+                "unknown placement {:?}; use \"auto\" or \"node:N\"",
+                s
+            )),
+        }
+    }
+}
+
+impl Placement {
+    /// The node for thread number `job`, whose first file is `first_file`.
+    pub fn node_for(self, job: usize, first_file: &Path) -> Option<usize> {
+        match self {
+            Placement::Node(node) => Some(node),
+            Placement::Auto => storage_node(first_file).or_else(|| {
+                let nodes = online_nodes();
+                (!nodes.is_empty()).then(|| nodes[job % nodes.len()])
+            }),
+        }
+    }
+}
+
+/// Parse a list like "0-3,8,10-11", as the kernel writes lists of CPUs and nodes.
+fn parse_list(list: &str) -> Vec<usize> {
+    list.trim()
+        .split(',')
+        .filter(|range| !range.is_empty())
+        .filter_map(|range| {
+            let (first, last) = range.split_once('-').unwrap_or((range, range));
+            Some(first.parse().ok()?..=last.parse().ok()?)
+        })
+        .flatten()
+        .collect()
+}
+
+/// The nodes with memory or CPUs, or none on kernels without NUMA.
+pub fn online_nodes() -> Vec<usize> {
+    fs::read_to_string(Path::new(NODE_DIR).join("online"))
+        .map(|list| parse_list(&list))
+        .unwrap_or_default()
+}
+
+/// The node that the device holding `path` is attached to, if the kernel knows it. Virtual
+/// devices and machines with one node don't have one.
+#[cfg(target_os = "linux")]
+pub fn storage_node(path: &Path) -> Option<usize> {
+    let dev = fs::metadata(path).ok()?.dev();
+    let sys_path = format!("/sys/dev/block/{}:{}", libc::major(dev), libc::minor(dev));
+    // The device, like a partition, may not have a node itself, but the controller above it does:
+    let device = fs::canonicalize(sys_path).ok()?;
+    device.ancestors().find_map(|dir| {
+        let node: i64 = fs::read_to_string(dir.join("numa_node"))
+            .ok()?
+            .trim()
+            .parse()
+            .ok()?;
+        // -1 means the device isn't closer to any node:
+        usize::try_from(node).ok()
+    })
+}
+
+/// Run the calling thread, and the threads it starts from now on, on the CPUs of `node`, and
+/// allocate its memory there. Buffers are placed when they're first written, so they should be
+/// allocated after this.
+#[cfg(target_os = "linux")]
+pub fn bind_thread(node: usize) -> Result<()> {
+    let node_path = Path::new(NODE_DIR).join(format!("node{}", node));
+    if !node_path.exists() {
+        bail!("NUMA node {} doesn't exist", node);
+    }
+    let cpus = parse_list(&fs::read_to_string(node_path.join("cpulist"))?);
+    if cpus.is_empty() {
+        // Nodes of memory alone have nothing to run on:
+        warn!(
+            "NUMA node {} has no CPUs, so threads won't be pinned to it.",
+            node
+        );
+    } else {
+        // unsafe: a cpu_set_t is a bit mask, for which zeros are valid:
+        let mut set: libc::cpu_set_t = unsafe { mem::zeroed() };
+        for cpu in cpus {
+            // CPU_SET panics on CPUs beyond the set's size:
+            if cpu < 8 * mem::size_of_val(&set) {
+                // unsafe: this only sets a bit of the mask:
+                unsafe { libc::CPU_SET(cpu, &mut set) };
+            }
+        }
+        // unsafe: the kernel only reads the set we pass in:
+        if unsafe { libc::sched_setaffinity(0, mem::size_of_val(&set), &set) } != 0 {
+            warn!(
+                "Could not pin a thread to NUMA node {}: {}",
+                node,
+                io::Error::last_os_error()
+            );
+        }
+    }
+
+    let bits = 8 * mem::size_of::<libc::c_ulong>();
+    let mut mask = vec![0 as libc::c_ulong; node / bits + 1];
+    mask[node / bits] |= 1 << (node % bits);
+    // unsafe: the kernel reads `maxnode` bits of the mask, which has at least that many:
+    let result = unsafe {
+        libc::syscall(
+            libc::SYS_set_mempolicy,
+            MPOL_PREFERRED,
+            mask.as_ptr(),
+            mask.len() * bits,
+        )
+    };
+    if result != 0 {
+        // Kernels without NUMA don't have the call; memory is then close to every CPU anyway:
+        debug!(
+            "Could not prefer memory on NUMA node {}: {}",
+            node,
+            io::Error::last_os_error()
+        );
+    }
+    Ok(())
+}
+
+/// Only Linux says which node a device is attached to.
+#[cfg(not(target_os = "linux"))]
+pub fn storage_node(_path: &Path) -> Option<usize> {
+    None
+}
+
+/// Threads can only be placed on NUMA nodes on Linux.
+#[cfg(not(target_os = "linux"))]
+pub fn bind_thread(node: usize) -> Result<()> {
+    bail!("Placing threads on NUMA node {} needs Linux", node)
+}
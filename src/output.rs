@@ -0,0 +1,140 @@
+// This module writes output files so that they're either complete or not there at all, including
+// the checksum file kept in each directory.
+use std::{
+    collections::BTreeMap,
+    ffi::OsString,
+    fs::{self, File, OpenOptions},
+    io::{self, BufWriter, Write},
+    path::{Path, PathBuf},
+};
+
+use anyhow::{anyhow, Context, Result};
+#[allow(unused_imports)]
+use log::{debug, error, info, trace, warn};
+
+use crate::manifest::format_line;
+
+/// A file that's written under a temporary name next to its final path, and renamed into place
+/// by `commit()`. If it's dropped without being committed, the temporary file is removed and
+/// whatever was at the final path is left alone.
+pub struct AtomicFile {
+    file: Option<BufWriter<File>>,
+    path: PathBuf,
+    tmp_path: PathBuf,
+}
+
+impl AtomicFile {
+    pub fn create(path: impl AsRef<Path>) -> Result<AtomicFile> {
+        let path = path.as_ref().to_path_buf();
+        let Some(name) = path.file_name() else {
+            return Err(anyhow!("{} is not a file name", path.display()));
+        };
+        // The temporary file must be on the same filesystem for the rename to be atomic:
+        let mut tmp_name = OsString::from(".");
+        tmp_name.push(name);
+        tmp_name.push(format!(".{}.tmp", std::process::id()));
+        let tmp_path = path.with_file_name(tmp_name);
+
+        let file = OpenOptions::new()
+            .write(true)
+            .create_new(true)
+            .open(&tmp_path)
+            .with_context(|| format!("Could not create {}", tmp_path.display()))?;
+        Ok(AtomicFile {
+            file: Some(BufWriter::new(file)),
+            path,
+            tmp_path,
+        })
+    }
+
+    /// Flush everything to disk and rename the file into place.
+    pub fn commit(mut self) -> Result<()> {
+        let file = self.file.take().expect("the file is only taken here");
+        let file = file.into_inner().map_err(|err| err.into_error())?;
+        file.sync_all()?;
+        fs::rename(&self.tmp_path, &self.path)
+            .with_context(|| format!("Could not write {}", self.path.display()))?;
+
+        // Make the rename itself durable:
+        let dir = match self.path.parent() {
+            Some(dir) if !dir.as_os_str().is_empty() => dir,
+            _ => Path::new("."),
+        };
+        File::open(dir)?.sync_all()?;
+        Ok(())
+    }
+}
+
+impl Write for AtomicFile {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.file.as_mut().expect("the file is open").write(buf)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.file.as_mut().expect("the file is open").flush()
+    }
+}
+
+impl Drop for AtomicFile {
+    fn drop(&mut self) {
+        if self.file.take().is_some() {
+            if let Err(err) = fs::remove_file(&self.tmp_path) {
+                warn!("Could not remove {}: {}", self.tmp_path.display(), err);
+            }
+        }
+    }
+}
+
+/// The checksum files that `--manifest-per-dir` writes: one named `name` in each directory that
+/// has files hashed, listing those files by name alone, so `md5sum -c` can be run in the
+/// directory. Files in subdirectories are in the subdirectory's own list.
+pub struct DirManifests {
+    name: OsString,
+    dirs: BTreeMap<PathBuf, Vec<(OsString, String)>>,
+}
+
+impl DirManifests {
+    pub fn new(name: impl Into<OsString>) -> DirManifests {
+        DirManifests {
+            name: name.into(),
+            dirs: BTreeMap::new(),
+        }
+    }
+
+    /// Add a file and its hexadecimal digest to the list of its directory.
+    pub fn add(&mut self, path: &Path, digest: &str) {
+        let Some(name) = path.file_name() else {
+            return;
+        };
+        let dir = match path.parent() {
+            Some(dir) if !dir.as_os_str().is_empty() => dir,
+            _ => Path::new("."),
+        };
+        self.dirs
+            .entry(dir.to_path_buf())
+            .or_default()
+            .push((name.to_owned(), digest.to_owned()));
+    }
+
+    /// Write each directory's list, sorted by name, replacing any that's there. Returns how many
+    /// were written. A directory that can't be written to is reported, and the others are still
+    /// written.
+    pub fn write(self) -> usize {
+        let mut written = 0;
+        for (dir, mut files) in self.dirs {
+            files.sort();
+            let path = dir.join(&self.name);
+            let result = AtomicFile::create(&path).and_then(|mut file| {
+                for (name, digest) in &files {
+                    file.write_all(&format_line(digest, Path::new(name)))?;
+                }
+                file.commit()
+            });
+            match result {
+                Ok(()) => written += 1,
+                Err(err) => eprintln!("{}: {:#}", path.display(), err),
+            }
+        }
+        written
+    }
+}
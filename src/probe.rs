@@ -0,0 +1,280 @@
+// This module finds out which io_uring features the running kernel supports, to choose a
+// strategy and to explain why one can't be used.
+use std::{ffi::CStr, io, mem};
+
+use anyhow::Result;
+use io_uring::{opcode, IoUring, Probe};
+#[allow(unused_imports)]
+use log::{debug, error, info, trace, warn};
+
+use crate::{with_provided_buffers::BufferRing, *};
+
+/// The requests this tool makes, or that show what the kernel can do, by name.
+const OPCODES: &[(&str, u8)] = &[
+    ("read", opcode::Read::CODE),
+    ("read-fixed", opcode::ReadFixed::CODE),
+    ("openat", opcode::OpenAt::CODE),
+    ("statx", opcode::Statx::CODE),
+    ("close", opcode::Close::CODE),
+    ("fadvise", opcode::Fadvise::CODE),
+    ("files-update", opcode::FilesUpdate::CODE),
+    ("timeout", opcode::Timeout::CODE),
+    ("link-timeout", opcode::LinkTimeout::CODE),
+    ("async-cancel", opcode::AsyncCancel::CODE),
+    ("provide-buffers", opcode::ProvideBuffers::CODE),
+    // Not used, but it arrived with opens into the file table (Linux 5.15):
+    ("mkdirat", opcode::MkDirAt::CODE),
+];
+
+/// The strategies `--strategy auto` chooses from, fastest first.
+const AUTO_ORDER: &[&str] = &[
+    "fixed-buffers",
+    "register-files",
+    "simple-uring",
+    "no-uring",
+];
+
+/// What the running kernel and limits allow.
+#[derive(Debug, Clone)]
+pub struct Capabilities {
+    /// The kernel's release, like "6.1.0-18-amd64"
+    pub kernel: String,
+    /// Why a ring can't be set up, if it can't
+    pub ring_error: Option<String>,
+    /// Whether each of `OPCODES` is supported
+    pub opcodes: Vec<(&'static str, bool)>,
+    pub register_files: bool,
+    /// Registering an empty file table in one call (Linux 5.19)
+    pub register_sparse_files: bool,
+    pub register_buffers: bool,
+    /// Registering the ring's own descriptor (Linux 5.18)
+    pub register_ring_fd: bool,
+    /// Rings of buffers the kernel picks from (Linux 5.19)
+    pub register_buffer_ring: bool,
+    /// Whether each optional setup flag is accepted
+    pub setup_flags: Vec<(&'static str, bool)>,
+}
+
+impl Capabilities {
+    /// Try each feature on throwaway rings.
+    pub fn detect() -> Capabilities {
+        let mut capabilities = Capabilities {
+            kernel: kernel_release(),
+            ring_error: None,
+            opcodes: OPCODES.iter().map(|&(name, _)| (name, false)).collect(),
+            register_files: false,
+            register_sparse_files: false,
+            register_buffers: false,
+            register_ring_fd: false,
+            register_buffer_ring: false,
+            setup_flags: Vec::new(),
+        };
+        // The buffer must outlive the ring it's registered with:
+        let mut buffer = AlignedBuffer::with_capacity(ALIGNMENT);
+        // io_uring may be missing, or blocked, as by seccomp in some containers:
+        let ring = match ring::new_ring(2, &Default::default()) {
+            Ok(ring) => ring,
+            Err(err) => {
+                debug!("Could not set up a ring: {}", err);
+                capabilities.ring_error = Some(err.to_string());
+                return capabilities;
+            }
+        };
+        let mut probe = Probe::new();
+        if ring.submitter().register_probe(&mut probe).is_ok() {
+            for (&(_, code), (_, supported)) in OPCODES.iter().zip(&mut capabilities.opcodes) {
+                *supported = probe.is_supported(code);
+            }
+        }
+        capabilities.register_ring_fd = ring.is_registered();
+        capabilities.register_files = succeeds(ring::register_file_slots(&ring, 1));
+        capabilities.register_sparse_files =
+            succeeds(IoUring::new(2).and_then(|ring| ring.submitter().register_files_sparse(1)));
+        // One small buffer is enough, since the fixed-buffers strategy registers fewer or smaller
+        // buffers to fit in the locked memory limit:
+        let iovec = libc::iovec {
+            iov_base: buffer.as_mut_ptr() as *mut _,
+            iov_len: buffer.len(),
+        };
+        // unsafe: nothing is read into the buffer:
+        capabilities.register_buffers =
+            succeeds(unsafe { ring.submitter().register_buffers(&[iovec]) });
+        let buffer_ring = BufferRing::new(&ring, 1, ALIGNMENT);
+        capabilities.register_buffer_ring = match &buffer_ring {
+            Ok(_) => true,
+            Err(err) => {
+                debug!("{}", err);
+                false
+            }
+        };
+        // The buffer ring must outlive the ring too:
+        drop(ring);
+        drop(buffer_ring);
+
+        let mut builder = IoUring::builder();
+        builder.setup_sqpoll(ring::DEFAULT_SQPOLL_IDLE_MS);
+        capabilities.setup_flags.push(("sqpoll", builds(&builder)));
+        let mut builder = IoUring::builder();
+        builder.setup_coop_taskrun();
+        capabilities
+            .setup_flags
+            .push(("coop-taskrun", builds(&builder)));
+        let mut builder = IoUring::builder();
+        builder.setup_single_issuer();
+        capabilities
+            .setup_flags
+            .push(("single-issuer", builds(&builder)));
+        builder.setup_defer_taskrun();
+        capabilities
+            .setup_flags
+            .push(("defer-taskrun", builds(&builder)));
+
+        capabilities
+    }
+
+    /// Whether the request named in `OPCODES` is supported.
+    pub fn supports(&self, opcode: &str) -> bool {
+        self.opcodes
+            .iter()
+            .any(|&(name, supported)| name == opcode && supported)
+    }
+
+    /// Whether `strategy` can run, or why not.
+    pub fn usable(&self, strategy: &str) -> std::result::Result<(), String> {
+        if strategy == "no-uring" {
+            return Ok(());
+        }
+        if let Some(err) = &self.ring_error {
+            return Err(format!("io_uring can't be used: {}", err));
+        }
+        if !self.supports("read") {
+            return Err("Read requests aren't supported".to_string());
+        }
+        match strategy {
+            "register-files" | "fixed-buffers" if !self.register_files => {
+                Err("files can't be registered".to_string())
+            }
+            "fixed-buffers" if !self.supports("read-fixed") => {
+                Err("ReadFixed requests aren't supported".to_string())
+            }
+            "fixed-buffers" if !self.register_buffers => {
+                Err("buffers can't be registered (is the locked memory limit 0?)".to_string())
+            }
+            "provided-buffers" if !self.register_buffer_ring => {
+                Err("buffer rings aren't supported (Linux 5.19)".to_string())
+            }
+            _ => Ok(()),
+        }
+    }
+
+    /// The fastest strategy that can run, which `--strategy auto` chooses.
+    pub fn best_strategy(&self) -> &'static str {
+        AUTO_ORDER
+            .iter()
+            .find(|strategy| self.usable(strategy).is_ok())
+            .expect("no-uring is always usable")
+    }
+}
+
+/// Whether a probing step worked, logging why if it didn't.
+fn succeeds<T>(result: io::Result<T>) -> bool {
+    match result {
+        Ok(_) => true,
+        Err(err) => {
+            debug!("Probing failed: {}", err);
+            false
+        }
+    }
+}
+
+fn builds(builder: &io_uring::Builder) -> bool {
+    builder.build(2).is_ok()
+}
+
+/// The release of the running kernel, from uname(2).
+pub fn kernel_release() -> String {
+    // unsafe: a utsname struct is arrays of bytes, for which zeros are valid:
+    let mut uts: libc::utsname = unsafe { mem::zeroed() };
+    // unsafe: the kernel only writes to the struct we pass in:
+    if unsafe { libc::uname(&mut uts) } != 0 {
+        return "unknown".to_string();
+    }
+    // unsafe: the kernel ends the field with NUL:
+    unsafe { CStr::from_ptr(uts.release.as_ptr()) }
+        .to_string_lossy()
+        .into_owned()
+}
+
+/// A resource limit as "SOFT (hard HARD)", without raising it.
+fn limit(resource: libc::__rlimit_resource_t) -> String {
+    let mut limit = libc::rlimit {
+        rlim_cur: 0,
+        rlim_max: 0,
+    };
+    // unsafe: the kernel only writes to the struct we pass in:
+    if unsafe { libc::getrlimit(resource, &mut limit) } != 0 {
+        return io::Error::last_os_error().to_string();
+    }
+    let show = |value: libc::rlim_t| {
+        if value == libc::RLIM_INFINITY {
+            "unlimited".to_string()
+        } else {
+            value.to_string()
+        }
+    };
+    format!("{} (hard {})", show(limit.rlim_cur), show(limit.rlim_max))
+}
+
+/// Print what the kernel supports, the limits that matter, and which strategies can run.
+pub fn run() -> Result<()> {
+    let capabilities = Capabilities::detect();
+    let yes_no = |supported: bool| if supported { "yes" } else { "no" };
+
+    println!("Kernel:   {}", capabilities.kernel);
+    match &capabilities.ring_error {
+        Some(err) => println!("io_uring: not available ({})", err),
+        None => println!("io_uring: available"),
+    }
+
+    println!("\nRequests:");
+    for &(name, supported) in &capabilities.opcodes {
+        println!("  {:<24}{}", name, yes_no(supported));
+    }
+
+    println!("\nRegistration:");
+    for (name, supported) in [
+        ("files", capabilities.register_files),
+        ("sparse files", capabilities.register_sparse_files),
+        ("buffers", capabilities.register_buffers),
+        ("ring descriptor", capabilities.register_ring_fd),
+        ("buffer ring", capabilities.register_buffer_ring),
+    ] {
+        println!("  {:<24}{}", name, yes_no(supported));
+    }
+
+    println!("\nSetup flags:");
+    for &(name, supported) in &capabilities.setup_flags {
+        println!("  {:<24}{}", name, yes_no(supported));
+    }
+
+    println!("\nLimits:");
+    println!(
+        "  {:<24}{}",
+        "locked memory (bytes)",
+        limit(libc::RLIMIT_MEMLOCK)
+    );
+    println!("  {:<24}{}", "open files", limit(libc::RLIMIT_NOFILE));
+
+    println!("\nStrategies:");
+    for strategy in STRATEGIES.iter().map(|strategy| strategy.name()) {
+        match capabilities.usable(strategy) {
+            Ok(()) => println!("  {:<24}usable", strategy),
+            Err(reason) => println!("  {:<24}not usable: {}", strategy, reason),
+        }
+    }
+    println!(
+        "\n--strategy auto chooses {}.",
+        capabilities.best_strategy()
+    );
+    Ok(())
+}
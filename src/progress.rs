@@ -0,0 +1,163 @@
+// This module shows how much of the input has been hashed so far. The strategies add to
+// process-wide counts of bytes and reads as reads complete, and a thread redraws the totals on
+// stderr.
+use std::{
+    collections::HashSet,
+    fs,
+    io::{self, Write},
+    path::PathBuf,
+    sync::{
+        atomic::{AtomicBool, AtomicU64, Ordering},
+        Arc, Mutex,
+    },
+    thread::{self, JoinHandle},
+    time::Duration,
+};
+
+#[allow(unused_imports)]
+use log::{debug, error, info, trace, warn};
+
+use crate::input;
+
+/// How often the progress line is redrawn.
+const REDRAW_INTERVAL: Duration = Duration::from_millis(200);
+
+/// An update on one file, sent as its reads complete when `Config::file_progress` is set: its
+/// path, how many of its bytes have been read so far, and its length, or 0 while that isn't known.
+pub type FileProgress = (PathBuf, u64, u64);
+
+static BYTES_HASHED: AtomicU64 = AtomicU64::new(0);
+static READS_SUBMITTED: AtomicU64 = AtomicU64::new(0);
+
+/// Count bytes that have been read and added to a digest.
+pub fn add_bytes(len: u64) {
+    BYTES_HASHED.fetch_add(len, Ordering::Relaxed);
+}
+
+/// How many bytes every strategy has hashed since the process started.
+pub fn bytes_hashed() -> u64 {
+    BYTES_HASHED.load(Ordering::Relaxed)
+}
+
+/// Count a read that was submitted to a ring.
+pub fn add_read() {
+    READS_SUBMITTED.fetch_add(1, Ordering::Relaxed);
+}
+
+/// How many reads every strategy has submitted since the process started. Strategies that
+/// don't use io_uring don't submit any.
+pub fn reads_submitted() -> u64 {
+    READS_SUBMITTED.load(Ordering::Relaxed)
+}
+
+/// A progress line on stderr with the files completed and bytes hashed out of the totals.
+pub struct ProgressBar {
+    /// Held while the line is drawn, so other output can clear it first
+    line: Arc<Mutex<()>>,
+    files_done: Arc<AtomicU64>,
+    stop: Arc<AtomicBool>,
+    handle: Option<JoinHandle<()>>,
+}
+
+impl ProgressBar {
+    /// Start drawing progress for hashing `files`. Their sizes are looked up now, so the total
+    /// is known from the start.
+    pub fn start(files: &[PathBuf]) -> ProgressBar {
+        let total_files = files.len() as u64;
+        // A file with several paths is only read once:
+        let mut seen = HashSet::new();
+        let total_bytes: u64 = files
+            .iter()
+            .filter_map(|path| fs::metadata(path).ok())
+            .filter(|metadata| input::file_id(metadata).is_none_or(|id| seen.insert(id)))
+            .map(|metadata| metadata.len())
+            .sum();
+        let line = Arc::new(Mutex::new(()));
+        let files_done = Arc::new(AtomicU64::new(0));
+        let stop = Arc::new(AtomicBool::new(false));
+
+        let handle = {
+            let line = line.clone();
+            let files_done = files_done.clone();
+            let stop = stop.clone();
+            // Only count bytes hashed from now on:
+            let start_bytes = bytes_hashed();
+            thread::spawn(move || loop {
+                let done = stop.load(Ordering::Relaxed);
+                let _line = line.lock().unwrap();
+                draw(
+                    files_done.load(Ordering::Relaxed),
+                    total_files,
+                    bytes_hashed() - start_bytes,
+                    total_bytes,
+                );
+                if done {
+                    eprintln!();
+                    break;
+                }
+                thread::park_timeout(REDRAW_INTERVAL);
+            })
+        };
+
+        ProgressBar {
+            line,
+            files_done,
+            stop,
+            handle: Some(handle),
+        }
+    }
+
+    /// Count a file as finished, whether or not it could be read.
+    pub fn file_done(&self) {
+        self.files_done.fetch_add(1, Ordering::Relaxed);
+    }
+
+    /// Clear the progress line while `print` writes other output to the terminal. It's redrawn
+    /// within the redraw interval.
+    pub fn suspend<R>(&self, print: impl FnOnce() -> R) -> R {
+        let _line = self.line.lock().unwrap();
+        let _ = write!(io::stderr(), "\r\x1b[K");
+        print()
+    }
+
+    /// Draw the final totals and stop.
+    pub fn finish(mut self) {
+        self.stop();
+    }
+
+    fn stop(&mut self) {
+        if let Some(handle) = self.handle.take() {
+            self.stop.store(true, Ordering::Relaxed);
+            handle.thread().unpark();
+            let _ = handle.join();
+        }
+    }
+}
+
+impl Drop for ProgressBar {
+    fn drop(&mut self) {
+        self.stop();
+    }
+}
+
+fn draw(files_done: u64, total_files: u64, bytes: u64, total_bytes: u64) {
+    // Files can grow while they're read, so the percentage may not end at exactly 100:
+    let percent = if total_bytes == 0 {
+        100.0
+    } else {
+        bytes as f64 * 100.0 / total_bytes as f64
+    };
+    let mib = |bytes: u64| bytes as f64 / (1024.0 * 1024.0);
+    let mut stderr = io::stderr().lock();
+    // Return to the start of the line and clear it:
+    let _ = write!(
+        stderr,
+        "\r\x1b[K{}/{} files, {:.1}/{:.1} MiB ({:.0}%)",
+        files_done,
+        total_files,
+        mib(bytes),
+        mib(total_bytes),
+        percent
+    );
+    let _ = stderr.flush();
+}
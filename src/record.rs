@@ -0,0 +1,118 @@
+// This module prints each file's result with the file's metadata, as JSON lines or CSV, so one
+// pass makes both an integrity manifest and an inventory of the files.
+use std::{fs, path::Path, str::FromStr};
+
+use anyhow::Result;
+#[allow(unused_imports)]
+use log::{debug, error, info, trace, warn};
+use serde::Serialize;
+
+use crate::*;
+
+/// How results are printed instead of as checksum lines.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum OutputFormat {
+    /// One JSON object per line
+    Json,
+    /// A header line, then a line per file
+    Csv,
+}
+
+impl FromStr for OutputFormat {
+    type Err = String;
+
+    fn from_str(s: &str) -> std::result::Result<Self, Self::Err> {
+        match s {
+            "json" => Ok(OutputFormat::Json),
+            "csv" => Ok(OutputFormat::Csv),
+            _ => Err(format!("unknown format {:?}; use \"json\" or \"csv\"", s)),
+        }
+    }
+}
+
+/// The CSV header line, naming the fields of `Record` in order.
+pub const CSV_HEADER: &[u8] = b"path,digest,size,mtime,mode,uid,gid,error\n";
+
+/// A file's result and what the file looked like when it was hashed. Fields that aren't known,
+/// like the metadata of stdin or the digest of a file that failed, are null in JSON and empty in
+/// CSV.
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize)]
+pub struct Record {
+    /// The path, with names that aren't UTF-8 converted lossily
+    pub path: String,
+    /// In lowercase hexadecimal
+    pub digest: Option<String>,
+    pub size: Option<u64>,
+    /// The modification time in RFC 3339 format, in UTC
+    pub mtime: Option<String>,
+    /// The permission bits in octal, like 0644
+    pub mode: Option<String>,
+    pub uid: Option<u32>,
+    pub gid: Option<u32>,
+    pub error: Option<String>,
+}
+
+impl Record {
+    /// The record of `path`, looking up its metadata now. The result is the file's digest in
+    /// hexadecimal, or why it couldn't be hashed.
+    pub fn new(path: &Path, result: std::result::Result<&str, &anyhow::Error>) -> Record {
+        let mut record = Record {
+            path: path.to_string_lossy().into_owned(),
+            ..Default::default()
+        };
+        match result {
+            Ok(digest) => record.digest = Some(digest.to_owned()),
+            Err(err) => record.error = Some(err.to_string()),
+        }
+        let metadata = match fs::metadata(path) {
+            Ok(metadata) if !input::is_stdin(path) => metadata,
+            _ => return record,
+        };
+        record.size = Some(metadata.len());
+        record.mtime = metadata
+            .modified()
+            .ok()
+            .map(|mtime| humantime::format_rfc3339_nanos(mtime).to_string());
+        #[cfg(unix)]
+        {
+            use std::os::unix::fs::MetadataExt;
+            record.mode = Some(format!("{:04o}", metadata.mode() & 0o7777));
+            record.uid = Some(metadata.uid());
+            record.gid = Some(metadata.gid());
+        }
+        record
+    }
+
+    /// The record as a line of `format`.
+    pub fn to_line(&self, format: OutputFormat) -> Result<Vec<u8>> {
+        match format {
+            OutputFormat::Json => {
+                let mut line = serde_json::to_vec(self)?;
+                line.push(b'\n');
+                Ok(line)
+            }
+            OutputFormat::Csv => {
+                let fields = [
+                    csv_field(&self.path),
+                    csv_field(self.digest.as_deref().unwrap_or_default()),
+                    self.size.map(|size| size.to_string()).unwrap_or_default(),
+                    self.mtime.clone().unwrap_or_default(),
+                    self.mode.clone().unwrap_or_default(),
+                    self.uid.map(|uid| uid.to_string()).unwrap_or_default(),
+                    self.gid.map(|gid| gid.to_string()).unwrap_or_default(),
+                    csv_field(self.error.as_deref().unwrap_or_default()),
+                ];
+                Ok(format!("{}\n", fields.join(",")).into_bytes())
+            }
+        }
+    }
+}
+
+/// Quote a CSV field if it has a comma, quote or line break in it, as RFC 4180 says.
+fn csv_field(field: &str) -> String {
+    if field.contains([',', '"', '\n', '\r']) {
+        format!("\"{}\"", field.replace('"', "\"\""))
+    } else {
+        field.to_owned()
+    }
+}
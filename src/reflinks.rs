@@ -0,0 +1,140 @@
+// This module finds files that are reflinked copies of each other, as on btrfs and XFS, where a
+// copy or a snapshot shares the original's extents instead of duplicating them. Two files of the
+// same size whose data is in the very same extents at the same offsets have the same contents, so
+// only one needs reading. FIEMAP tells where each file's extents are. The digest is only reused
+// when the user trusts that, since it's taken from the extent map at one moment and not from
+// reading the data.
+use std::{
+    collections::{hash_map::Entry, HashMap},
+    path::PathBuf,
+};
+
+#[allow(unused_imports)]
+use log::{debug, error, info, trace, warn};
+
+#[allow(unused_imports)]
+use crate::*;
+
+/// Where a file's data is on disk. Files with equal maps have the same contents.
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+struct ExtentMap {
+    device: u64,
+    size: u64,
+    /// The logical offset, physical address, length and whether it reads as zeros, of each extent
+    extents: Vec<(u64, u64, u64, bool)>,
+}
+
+/// Leave out the paths of `files` whose extents are all the same as those of a file named
+/// earlier. Returns the files to hash, and the paths left out for each of them, in order.
+pub fn split_reflinks(files: Vec<PathBuf>) -> (Vec<PathBuf>, HashMap<PathBuf, Vec<PathBuf>>) {
+    let mut first_path: HashMap<ExtentMap, PathBuf> = HashMap::new();
+    let mut links: HashMap<PathBuf, Vec<PathBuf>> = HashMap::new();
+    let mut to_hash = Vec::with_capacity(files.len());
+    for path in files {
+        let Some(map) = extent_map(&path) else {
+            to_hash.push(path);
+            continue;
+        };
+        match first_path.entry(map) {
+            Entry::Occupied(first) => {
+                debug!(
+                    "{} shares its extents with {}",
+                    path.display(),
+                    first.get().display()
+                );
+                links.entry(first.get().clone()).or_default().push(path);
+            }
+            Entry::Vacant(entry) => {
+                entry.insert(path.clone());
+                to_hash.push(path);
+            }
+        }
+    }
+    (to_hash, links)
+}
+
+#[cfg(target_os = "linux")]
+mod fiemap {
+    use std::{fs::File, os::unix::fs::MetadataExt, os::unix::io::AsRawFd, path::Path};
+
+    use super::ExtentMap;
+    use crate::input::{FiemapExtent, FS_IOC_FIEMAP};
+
+    /// Flush dirty data first, so delayed allocations have their extents.
+    const FIEMAP_FLAG_SYNC: u32 = 0x1;
+    const FIEMAP_EXTENT_LAST: u32 = 0x1;
+    const FIEMAP_EXTENT_UNWRITTEN: u32 = 0x800;
+    const FIEMAP_EXTENT_SHARED: u32 = 0x2000;
+    /// Extents whose physical address doesn't say where exactly the data is: unknown, delayed,
+    /// compressed, encrypted, unaligned, inline and tail-packed ones. Two files can be mapped to
+    /// the same compressed extent and still hold different parts of it.
+    const FIEMAP_EXTENT_IMPRECISE: u32 = 0x2 | 0x4 | 0x8 | 0x80 | 0x100 | 0x200 | 0x400;
+
+    /// How many extents each FIEMAP call asks for.
+    const BATCH: usize = 32;
+
+    /// `struct fiemap` with room for a batch of extents.
+    #[repr(C)]
+    #[derive(Default)]
+    struct Fiemap {
+        start: u64,
+        length: u64,
+        flags: u32,
+        mapped_extents: u32,
+        extent_count: u32,
+        reserved: u32,
+        extents: [FiemapExtent; BATCH],
+    }
+
+    /// The extent map of a regular file whose every extent is shared with some other file, or
+    /// None if any of its extents isn't, or the filesystem can't say.
+    pub(super) fn extent_map(path: &Path) -> Option<ExtentMap> {
+        let file = File::open(path).ok()?;
+        let metadata = file.metadata().ok()?;
+        if !metadata.is_file() || metadata.len() == 0 {
+            return None;
+        }
+        let mut extents = Vec::new();
+        let mut start = 0;
+        loop {
+            let mut fiemap = Fiemap {
+                start,
+                length: u64::MAX - start,
+                flags: FIEMAP_FLAG_SYNC,
+                extent_count: BATCH as u32,
+                ..Default::default()
+            };
+            // unsafe: the kernel writes at most `extent_count` extents into the struct we pass in:
+            let result = unsafe { libc::ioctl(file.as_raw_fd(), FS_IOC_FIEMAP, &mut fiemap) };
+            if result != 0 || fiemap.mapped_extents == 0 {
+                return None;
+            }
+            for extent in &fiemap.extents[..fiemap.mapped_extents as usize] {
+                if extent.flags & FIEMAP_EXTENT_SHARED == 0
+                    || extent.flags & FIEMAP_EXTENT_IMPRECISE != 0
+                {
+                    return None;
+                }
+                let unwritten = extent.flags & FIEMAP_EXTENT_UNWRITTEN != 0;
+                extents.push((extent.logical, extent.physical, extent.length, unwritten));
+                if extent.flags & FIEMAP_EXTENT_LAST != 0 {
+                    return Some(ExtentMap {
+                        device: metadata.dev(),
+                        size: metadata.len(),
+                        extents,
+                    });
+                }
+                start = extent.logical + extent.length;
+            }
+        }
+    }
+}
+
+#[cfg(target_os = "linux")]
+use fiemap::extent_map;
+
+/// Only Linux has FIEMAP, so no file is known to share its extents.
+#[cfg(not(target_os = "linux"))]
+fn extent_map(_path: &std::path::Path) -> Option<ExtentMap> {
+    None
+}
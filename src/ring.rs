@@ -0,0 +1,914 @@
+// This module sets up the rings of the io_uring strategies with the features that were asked
+// for, falling back to a plain ring when the kernel or our privileges don't allow them.
+use std::{
+    collections::HashMap,
+    fs::File,
+    io,
+    marker::PhantomData,
+    mem,
+    ops::{Deref, DerefMut},
+    os::unix::io::{AsRawFd, IntoRawFd, RawFd},
+    ptr, slice,
+    sync::{
+        atomic::{AtomicUsize, Ordering},
+        Once,
+    },
+    time::Duration,
+};
+
+use anyhow::Result;
+use io_uring::{cqueue, opcode, squeue, types, IoUring, Probe};
+#[allow(unused_imports)]
+use log::{debug, error, info, trace, warn};
+
+pub use crate::config::{RingOptions, DEFAULT_SQPOLL_IDLE_MS};
+use crate::{ChecksumError, Config};
+
+// From linux/io_uring.h, which the io-uring crate doesn't export:
+const IORING_ENTER_GETEVENTS: u32 = 1 << 0;
+const IORING_ENTER_SQ_WAKEUP: u32 = 1 << 1;
+const IORING_ENTER_REGISTERED_RING: u32 = 1 << 4;
+const IORING_REGISTER_RING_FDS: u32 = 20;
+const IORING_UNREGISTER_RING_FDS: u32 = 21;
+
+/// The user data of Close and Fadvise requests, whose completions aren't passed on to the
+/// strategies.
+const BACKGROUND_USER_DATA: u64 = u64::MAX;
+/// The user data of the timeouts linked to reads, whose completions aren't passed on either.
+const WATCHDOG_USER_DATA: u64 = u64::MAX - 1;
+/// How many times a read that's interrupted or told to try again is tried again before its
+/// error is passed on.
+pub const MAX_READ_RETRIES: u32 = 8;
+
+/// `struct io_uring_rsrc_update`.
+#[repr(C)]
+struct RsrcUpdate {
+    offset: u32,
+    resv: u32,
+    data: u64,
+}
+
+/// Set up a ring with `entries` entries and the features and read settings of `config`. If the
+/// features can't be used, a warning is logged once and a plain ring is set up instead. If no
+/// ring can be set up, the error is a `ChecksumError::RingSetup`.
+/// With `config.attach_wq`, the ring shares that ring's kernel worker threads, or has its own if
+/// it can't.
+pub fn new_ring(entries: u32, config: &Config) -> Result<Ring> {
+    let options = &config.ring_options;
+    let ring = match config.attach_wq {
+        Some(fd) => build_io_uring(entries, options, Some(fd)).or_else(|err| {
+            debug!("Could not share the worker threads of ring {}: {}", fd, err);
+            build_io_uring(entries, options, None)
+        }),
+        None => build_io_uring(entries, options, None),
+    };
+    Ok(Ring::new(ring.map_err(ChecksumError::RingSetup)?, config))
+}
+
+/// Set up a ring whose kernel worker threads the rings of `config` can share, by setting
+/// `config.attach_wq` to its descriptor while it's open. Otherwise, with --jobs, each ring starts
+/// its own workers for reads that can't complete right away, which adds up to a great many
+/// threads. With SQPOLL, the rings share its polling thread as well. None if the kernel can't
+/// share them (before Linux 5.6).
+pub fn new_shared_workers(config: &Config) -> Option<IoUring> {
+    let ring = build_io_uring(1, &config.ring_options, None).ok()?;
+    // Rings could attach to others in the same release as this feature arrived:
+    ring.params().is_feature_cur_personality().then_some(ring)
+}
+
+fn build_io_uring(
+    entries: u32,
+    options: &RingOptions,
+    attach_wq: Option<RawFd>,
+) -> io::Result<IoUring> {
+    if *options == RingOptions::default() {
+        return build_with_task_run(entries, options, attach_wq);
+    }
+    static WARN_ONCE: Once = Once::new();
+    let fall_back = |reason: String| -> io::Result<IoUring> {
+        WARN_ONCE.call_once(|| {
+            warn!(
+                "{}, so rings are set up without {}.",
+                reason,
+                options.describe()
+            );
+        });
+        build_with_task_run(entries, &RingOptions::default(), attach_wq)
+    };
+
+    match build_with_task_run(entries, options, attach_wq) {
+        // Before Linux 5.11, a polled ring could only read registered files:
+        Ok(ring)
+            if options.sqpoll_idle.is_some() && !ring.params().is_feature_sqpoll_nonfixed() =>
+        {
+            fall_back("This kernel only polls registered files".to_string())
+        }
+        Ok(ring) => {
+            debug!("Set up a ring with {}.", options.describe());
+            Ok(ring)
+        }
+        // SQPOLL fails with EPERM before Linux 5.13 without CAP_SYS_NICE, and anything fails
+        // with EINVAL if the kernel doesn't know it:
+        Err(err) => fall_back(format!(
+            "Could not set up a ring with {} ({})",
+            options.describe(),
+            err
+        )),
+    }
+}
+
+/// Flags that make completions cheaper to deliver, since only the thread that set up a ring
+/// submits to it. They're tried from first to last, each needing an older kernel than the one
+/// before.
+#[derive(Debug, Clone, Copy)]
+enum TaskRun {
+    /// SINGLE_ISSUER, COOP_TASKRUN and DEFER_TASKRUN (Linux 6.1): completions are only
+    /// delivered when we wait for them, instead of interrupting the thread.
+    Deferred,
+    /// SINGLE_ISSUER and COOP_TASKRUN (Linux 6.0)
+    SingleIssuer,
+    /// COOP_TASKRUN (Linux 5.19): completions wait for the thread to enter the kernel instead of
+    /// interrupting it.
+    Cooperative,
+    Plain,
+}
+
+impl TaskRun {
+    const ALL: [TaskRun; 4] = [
+        TaskRun::Deferred,
+        TaskRun::SingleIssuer,
+        TaskRun::Cooperative,
+        TaskRun::Plain,
+    ];
+
+    fn setup(self, builder: &mut io_uring::Builder, sqpoll: bool) {
+        if matches!(self, TaskRun::Deferred | TaskRun::SingleIssuer) {
+            builder.setup_single_issuer();
+        }
+        // A polling kernel thread runs the task work itself, so these aren't allowed with it:
+        if sqpoll {
+            return;
+        }
+        if matches!(self, TaskRun::Deferred) {
+            builder.setup_defer_taskrun();
+        }
+        if !matches!(self, TaskRun::Plain) {
+            builder.setup_coop_taskrun().setup_taskrun_flag();
+        }
+    }
+}
+
+/// The index in `TaskRun::ALL` of the first flags worth trying, because newer ones have failed.
+static TASK_RUN_START: AtomicUsize = AtomicUsize::new(0);
+
+/// Set up a ring with `options` and the newest `TaskRun` flags the kernel accepts. Newer flags
+/// fail with EINVAL on older kernels, which is remembered so later rings don't try them again.
+fn build_with_task_run(
+    entries: u32,
+    options: &RingOptions,
+    attach_wq: Option<RawFd>,
+) -> io::Result<IoUring> {
+    let start = TASK_RUN_START.load(Ordering::Relaxed);
+    for (i, task_run) in TaskRun::ALL.iter().enumerate().skip(start) {
+        let mut builder = IoUring::builder();
+        if let Some(idle) = options.sqpoll_idle {
+            builder.setup_sqpoll(idle);
+        }
+        if options.iopoll {
+            builder.setup_iopoll();
+        }
+        if let Some(fd) = attach_wq {
+            builder.setup_attach_wq(fd);
+        }
+        task_run.setup(&mut builder, options.sqpoll_idle.is_some());
+        match builder.build(entries) {
+            Ok(ring) => {
+                trace!("Set up a ring with {:?} task running.", task_run);
+                // Only plain rings are learned from, since the other options can also fail:
+                if *options == RingOptions::default() {
+                    TASK_RUN_START.fetch_max(i, Ordering::Relaxed);
+                }
+                return Ok(ring);
+            }
+            Err(err)
+                if err.raw_os_error() == Some(libc::EINVAL)
+                    && !matches!(task_run, TaskRun::Plain) =>
+            {
+                debug!(
+                    "Could not set up a ring with {:?} task running: {}",
+                    task_run, err
+                );
+            }
+            Err(err) => return Err(err),
+        }
+    }
+    unreachable!("a plain ring either succeeds or returns its error");
+}
+
+/// Submit what's queued and wait until there's at least one completion. On an IOPOLL ring,
+/// completions are only found by polling, which the kernel does while we wait, so this doesn't
+/// enter the kernel to wait when a completion is already there from earlier polling.
+pub fn submit_and_reap(ring: &mut Ring) -> io::Result<()> {
+    if ring.completion().is_empty() {
+        ring.submit_and_wait(1)?;
+    } else {
+        ring.submit()?;
+    }
+    Ok(())
+}
+
+/// Wait for the requests in flight on `ring` after an error, before `buffers`, which they may
+/// point into, are freed. If they can't be waited for, the buffers are leaked, which is safer
+/// than letting the kernel write to freed memory.
+pub fn settle<T>(ring: &mut Ring, buffers: T) {
+    if let Err(err) = ring.wait_for_all() {
+        error!(
+            "Could not wait for reads before freeing their buffers: {}",
+            err
+        );
+        mem::forget(buffers);
+    }
+}
+
+/// Whether the lengths of files can be looked up with Statx requests on `ring`: the kernel must
+/// support them (Linux 5.6), and a polled ring only takes reads.
+pub fn can_statx(ring: &Ring, probe: &Probe) -> bool {
+    probe.is_supported(opcode::Statx::CODE) && !ring.params().is_setup_iopoll()
+}
+
+/// Whether files can be opened straight into slots of the registered file table on `ring`
+/// (Linux 5.15). That can't be probed for, but MkDirAt requests arrived in the same release.
+pub fn can_open_into_slots(ring: &Ring, probe: &Probe) -> bool {
+    probe.is_supported(opcode::MkDirAt::CODE) && !ring.params().is_setup_iopoll()
+}
+
+/// A request to look up the length of the open file `fd`, which the kernel puts in `statx`.
+/// `statx` must not move until the request completes.
+pub fn statx_entry(fd: RawFd, statx: &mut libc::statx) -> squeue::Entry {
+    // An empty path with AT_EMPTY_PATH means the file `fd` itself:
+    let empty_path = c"".as_ptr();
+    opcode::Statx::new(
+        types::Fd(fd),
+        empty_path,
+        statx as *mut libc::statx as *mut types::statx,
+    )
+    .flags(libc::AT_EMPTY_PATH)
+    .mask(libc::STATX_SIZE)
+    .build()
+}
+
+/// The lengths of `files`, looked up with Statx requests on `ring` so the kernel can work on
+/// several at once instead of one `fstat` after another. Nothing else may be in flight on the
+/// ring. If the ring can't take Statx requests, `fstat` is used.
+pub fn file_lens(ring: &mut Ring, files: &[&File]) -> io::Result<Vec<io::Result<u64>>> {
+    let mut probe = Probe::new();
+    ring.submitter().register_probe(&mut probe)?;
+    if !can_statx(ring, &probe) {
+        return Ok(files
+            .iter()
+            .map(|file| Ok(file.metadata()?.len()))
+            .collect());
+    }
+
+    // unsafe: a statx struct is plain integers, for which zeros are valid:
+    let mut statxs: Vec<libc::statx> = vec![unsafe { mem::zeroed() }; files.len()];
+    let mut lens: Vec<Option<io::Result<u64>>> = files.iter().map(|_| None).collect();
+    let capacity = ring.params().sq_entries() as usize;
+    let mut next = 0;
+    let mut in_flight = 0;
+    let mut look_up = || -> io::Result<()> {
+        while next < files.len() || in_flight > 0 {
+            while next < files.len() && in_flight < capacity {
+                let statx_e =
+                    statx_entry(files[next].as_raw_fd(), &mut statxs[next]).user_data(next as u64);
+                // unsafe: the statx structs don't move until every request has completed, or
+                // are leaked by `settle()`:
+                unsafe { ring.push(&statx_e)? };
+                next += 1;
+                in_flight += 1;
+            }
+            submit_and_reap(ring)?;
+            while let Some(cqe) = ring.pop_completion() {
+                let i = cqe.user_data() as usize;
+                lens[i] = Some(if cqe.result() < 0 {
+                    Err(io::Error::from_raw_os_error(-cqe.result()))
+                } else {
+                    Ok(statxs[i].stx_size)
+                });
+                in_flight -= 1;
+            }
+        }
+        Ok(())
+    };
+    if let Err(err) = look_up() {
+        settle(ring, statxs);
+        return Err(err);
+    }
+    Ok(lens
+        .into_iter()
+        .map(|len| len.expect("every Statx request completed"))
+        .collect())
+}
+
+/// Register a file table of `len` empty slots, which `set_file_slot()` fills in as reads of the
+/// files start. Only the files being read are in the table, so it stays small however many
+/// files there are.
+pub fn register_file_slots(ring: &Ring, len: u32) -> io::Result<()> {
+    match ring.submitter().register_files_sparse(len) {
+        // Before Linux 5.19, empty slots are registered as -1:
+        Err(err) if err.raw_os_error() == Some(libc::EINVAL) => {
+            ring.submitter().register_files(&vec![-1; len as usize])
+        }
+        result => result,
+    }
+}
+
+/// Put `fd` in `slot` of the file table, or empty the slot if it's None. Reads of the file in
+/// the slot must have completed before it's replaced.
+pub fn set_file_slot(ring: &Ring, slot: u32, fd: Option<RawFd>) -> io::Result<()> {
+    ring.submitter()
+        .register_files_update(slot, &[fd.unwrap_or(-1)])?;
+    Ok(())
+}
+
+/// Give the kernel `advice` about the whole of `file`, like `POSIX_FADV_DONTNEED`.
+pub fn fadvise(file: &File, advice: i32) -> io::Result<()> {
+    // unsafe: this only passes integers:
+    match unsafe { libc::posix_fadvise(file.as_raw_fd(), 0, 0, advice) } {
+        0 => Ok(()),
+        err => Err(io::Error::from_raw_os_error(err)),
+    }
+}
+
+/// A ring whose own file descriptor is registered with the kernel when it's supported (Linux
+/// 5.18), so entering the ring skips looking the descriptor up. The io-uring crate only
+/// remembers the registration in one `Submitter`, but `IoUring::submitter()` makes a new one
+/// each time, so this enters the ring itself.
+///
+/// Files can also be closed and advised about through the ring, so the thread that submits reads
+/// doesn't wait for `close()` or `posix_fadvise()`. Their completions are left out of
+/// `pop_completion()` and `next_completion()`.
+///
+/// Reads queued with `push_read()` that fail with EINTR or EAGAIN, as on network filesystems or
+/// under heavy signal load, are queued again at the same offset, up to `MAX_READ_RETRIES` times.
+/// With `Config::read_timeout`, they're also cancelled if they don't finish in time, and the
+/// completions of the timeouts are left out too. With `Config::nowait_first`, reads queued with
+/// `push_read_with()` are first tried without blocking, and only the completion of the last try
+/// is passed on.
+///
+/// A registered descriptor belongs to the thread that registered it, so a `Ring` isn't `Send`.
+pub struct Ring {
+    ring: IoUring,
+    /// The index of the registered descriptor
+    registered: Option<u32>,
+    /// Whether the ring takes Close and Fadvise requests (Linux 5.6), which a polled ring
+    /// doesn't
+    background_on_ring: bool,
+    /// Close, Fadvise and timeout requests that haven't completed
+    background_in_flight: usize,
+    /// Every request queued that hasn't had its completion taken, including the background ones
+    in_flight: usize,
+    /// Whether to keep the files out of the page cache, from `Config::no_cache_pollution`
+    no_cache_pollution: bool,
+    /// How long reads may take, from `Config::read_timeout`, and the same time for the kernel,
+    /// which it reads when each timeout is submitted
+    watchdog: Option<(Duration, Box<types::Timespec>)>,
+    /// Whether to try reads with RWF_NOWAIT first, from `Config::nowait_first`
+    nowait_first: bool,
+    /// The reads to try again without RWF_NOWAIT if their first try doesn't read them whole, and
+    /// their lengths, by user data
+    nowait_retries: HashMap<u64, (squeue::Entry, u32)>,
+    /// The reads in flight, by user data, and how many times each has been tried again
+    reads: HashMap<u64, (squeue::Entry, u32)>,
+    /// Reads to try again, with how many times they have been, to be queued with the next
+    /// submission
+    retries_due: Vec<(squeue::Entry, u32)>,
+    _not_send: PhantomData<*const ()>,
+}
+
+impl Ring {
+    pub fn new(ring: IoUring, config: &Config) -> Ring {
+        let mut probe = Probe::new();
+        // A polled ring only takes reads:
+        let probed =
+            ring.submitter().register_probe(&mut probe).is_ok() && !ring.params().is_setup_iopoll();
+        let background_on_ring = probed
+            && probe.is_supported(opcode::Close::CODE)
+            && probe.is_supported(opcode::Fadvise::CODE);
+        let watchdog = config.read_timeout.and_then(|timeout| {
+            if probed && probe.is_supported(opcode::LinkTimeout::CODE) {
+                return Some((timeout, Box::new(timeout.into())));
+            }
+            static WARN_ONCE: Once = Once::new();
+            WARN_ONCE.call_once(|| {
+                warn!(
+                    "Reads can't be timed out on this ring (Linux 5.5, and not with --iopoll), so \
+                     they're waited for."
+                );
+            });
+            None
+        });
+
+        let mut update = RsrcUpdate {
+            // Let the kernel choose the index:
+            offset: u32::MAX,
+            resv: 0,
+            data: ring.as_raw_fd() as u64,
+        };
+        // unsafe: the kernel only writes the index into the struct we pass in:
+        let result = unsafe {
+            libc::syscall(
+                libc::SYS_io_uring_register,
+                ring.as_raw_fd(),
+                IORING_REGISTER_RING_FDS,
+                &mut update as *mut RsrcUpdate,
+                1,
+            )
+        };
+        let registered = if result == 1 {
+            Some(update.offset)
+        } else {
+            debug!(
+                "Could not register the ring's descriptor: {}",
+                io::Error::last_os_error()
+            );
+            None
+        };
+        Ring {
+            ring,
+            registered,
+            background_on_ring,
+            background_in_flight: 0,
+            in_flight: 0,
+            no_cache_pollution: config.no_cache_pollution,
+            watchdog,
+            nowait_first: config.nowait_first,
+            nowait_retries: HashMap::new(),
+            reads: HashMap::new(),
+            retries_due: Vec::new(),
+            _not_send: PhantomData,
+        }
+    }
+
+    /// Whether the ring's descriptor is registered.
+    pub fn is_registered(&self) -> bool {
+        self.registered.is_some()
+    }
+
+    /// Submit what's queued and wait for at least `want` completions, like
+    /// `IoUring::submit_and_wait`.
+    pub fn submit_and_wait(&mut self, want: usize) -> io::Result<usize> {
+        if !self.retries_due.is_empty() {
+            let due = mem::take(&mut self.retries_due);
+            for (retry, retries) in &due {
+                // unsafe: a retry reads into the same buffer as its first try, which the caller
+                // keeps valid until the read completes:
+                unsafe { self.push_read_try(retry, *retries)? };
+            }
+        }
+        let Some(index) = self.registered else {
+            return self.ring.submit_and_wait(want);
+        };
+
+        // The same decisions as `Submitter::submit_and_wait`:
+        let params = self.ring.params().clone();
+        let sq = self.ring.submission();
+        let to_submit = sq.len();
+        let pending_work = (sq.cq_overflow() && params.is_feature_nodrop()) || sq.taskrun();
+        let mut flags = IORING_ENTER_REGISTERED_RING;
+        if want > 0 || params.is_setup_iopoll() || pending_work {
+            flags |= IORING_ENTER_GETEVENTS;
+        }
+        if params.is_setup_sqpoll() {
+            if sq.need_wakeup() {
+                flags |= IORING_ENTER_SQ_WAKEUP;
+            } else if want == 0 && !pending_work {
+                // The kernel thread is awake and will find the entries itself:
+                return Ok(to_submit);
+            }
+        }
+        drop(sq);
+
+        // unsafe: the arguments follow io_uring_enter(2), with no extra argument:
+        let result = unsafe {
+            libc::syscall(
+                libc::SYS_io_uring_enter,
+                index,
+                to_submit as u32,
+                want as u32,
+                flags,
+                ptr::null::<libc::c_void>(),
+                0usize,
+            )
+        };
+        if result < 0 {
+            return Err(io::Error::last_os_error());
+        }
+        Ok(result as usize)
+    }
+
+    /// Submit what's queued without waiting, like `IoUring::submit`.
+    pub fn submit(&mut self) -> io::Result<usize> {
+        self.submit_and_wait(0)
+    }
+
+    /// Queue `entry`. If the queue is full, what's queued is submitted first, and on an SQPOLL
+    /// ring this waits for the kernel thread to make room.
+    ///
+    /// # Safety
+    ///
+    /// Everything `entry` points to must stay valid until it completes.
+    pub unsafe fn push(&mut self, entry: &squeue::Entry) -> io::Result<()> {
+        self.push_all(slice::from_ref(entry))
+    }
+
+    /// Queue a read, linked to a timeout that cancels it if it hasn't finished within the time
+    /// from `Config::read_timeout`. A cancelled read completes with -ECANCELED, which
+    /// `timeout_error()` turns into an error for its file.
+    ///
+    /// # Safety
+    ///
+    /// Everything `entry` points to must stay valid until it completes.
+    pub unsafe fn push_read(&mut self, entry: &squeue::Entry) -> io::Result<()> {
+        self.push_read_try(entry, 0)
+    }
+
+    /// Queue a read that has been tried `retries` times before, like `push_read()`.
+    ///
+    /// # Safety
+    ///
+    /// Everything `entry` points to must stay valid until it completes.
+    unsafe fn push_read_try(&mut self, entry: &squeue::Entry, retries: u32) -> io::Result<()> {
+        self.reads
+            .insert(entry.get_user_data(), (entry.clone(), retries));
+        let Some((_, timespec)) = &self.watchdog else {
+            return self.push(entry);
+        };
+        let entries = [
+            entry.clone().flags(squeue::Flags::IO_LINK),
+            opcode::LinkTimeout::new(&**timespec)
+                .build()
+                .user_data(WATCHDOG_USER_DATA),
+        ];
+        self.push_all(&entries)?;
+        self.background_in_flight += 1;
+        Ok(())
+    }
+
+    /// Queue a read of `len` bytes that `read` builds with the given flags for preadv2(2). With
+    /// `Config::nowait_first`, it's first tried with RWF_NOWAIT, so data in the page cache is read
+    /// right away instead of by a kernel worker. If that reads less than `len` bytes, as when some
+    /// of the data isn't cached, it's tried again without the flag, and only the completion of the
+    /// second try is passed on.
+    ///
+    /// # Safety
+    ///
+    /// Everything the read points to must stay valid until it completes, including its second
+    /// try.
+    pub unsafe fn push_read_with<F>(&mut self, len: u32, read: F) -> io::Result<()>
+    where
+        F: Fn(i32) -> squeue::Entry,
+    {
+        if !self.nowait_first {
+            return self.push_read(&read(0));
+        }
+        let first = read(libc::RWF_NOWAIT);
+        self.push_read(&first)?;
+        self.nowait_retries
+            .insert(first.get_user_data(), (read(0), len));
+        Ok(())
+    }
+
+    /// The error for a read queued with `push_read()` that completed with `result`, if it was
+    /// cancelled because it timed out.
+    pub fn timeout_error(&self, result: i32) -> Option<io::Error> {
+        let (timeout, _) = self.watchdog.as_ref()?;
+        (result == -libc::ECANCELED).then(|| {
+            io::Error::new(
+                io::ErrorKind::TimedOut,
+                format!("A read did not finish within {:?}", timeout),
+            )
+        })
+    }
+
+    /// Queue all of `entries` together, so a chain of linked requests isn't split between
+    /// submissions.
+    ///
+    /// # Safety
+    ///
+    /// Everything the entries point to must stay valid until they complete.
+    unsafe fn push_all(&mut self, entries: &[squeue::Entry]) -> io::Result<()> {
+        #[cfg(test)]
+        let entries = &faults::shorten_reads(entries);
+        let room = |ring: &mut IoUring| {
+            let sq = ring.submission();
+            sq.capacity() - sq.len()
+        };
+        if room(&mut self.ring) < entries.len() {
+            self.submit()?;
+            // A polling kernel thread takes the entries in its own time:
+            while room(&mut self.ring) < entries.len() {
+                self.ring.submitter().squeue_wait()?;
+            }
+        }
+        self.ring
+            .submission()
+            .push_multiple(entries)
+            .map_err(|_| io::Error::other("submission queue is full"))?;
+        self.in_flight += entries.len();
+        Ok(())
+    }
+
+    /// Advise the kernel that `file` is about to be read from start to end, if
+    /// `Config::no_cache_pollution` turned that on.
+    pub fn advise_sequential(&mut self, file: &File) -> io::Result<()> {
+        if !self.no_cache_pollution {
+            return Ok(());
+        }
+        if !self.background_on_ring {
+            return fadvise(file, libc::POSIX_FADV_SEQUENTIAL);
+        }
+        let fadvise_e =
+            opcode::Fadvise::new(types::Fd(file.as_raw_fd()), 0, libc::POSIX_FADV_SEQUENTIAL)
+                .build()
+                .user_data(BACKGROUND_USER_DATA);
+        // unsafe: the request only refers to the descriptor, which the caller keeps open until
+        // after the file is closed through the ring, which happens later:
+        unsafe { self.push(&fadvise_e)? };
+        self.background_in_flight += 1;
+        Ok(())
+    }
+
+    /// Advise the kernel that the file in `slot` of the file table is about to be read from start
+    /// to end, like `advise_sequential()`.
+    pub fn advise_sequential_slot(&mut self, slot: u32) -> io::Result<()> {
+        if !self.no_cache_pollution || !self.background_on_ring {
+            return Ok(());
+        }
+        let fadvise_e = opcode::Fadvise::new(types::Fixed(slot), 0, libc::POSIX_FADV_SEQUENTIAL)
+            .build()
+            .user_data(BACKGROUND_USER_DATA);
+        // unsafe: the request only refers to the slot:
+        unsafe { self.push(&fadvise_e)? };
+        self.background_in_flight += 1;
+        Ok(())
+    }
+
+    /// Close `file` with a request queued on the ring, or right away if the ring can't. With
+    /// `Config::no_cache_pollution`, its pages are dropped from the cache first.
+    pub fn close(&mut self, file: File) {
+        if !self.background_on_ring {
+            if self.no_cache_pollution {
+                if let Err(err) = fadvise(&file, libc::POSIX_FADV_DONTNEED) {
+                    debug!("Could not drop a file from the cache: {}", err);
+                }
+            }
+            return;
+        }
+        let fd = types::Fd(file.as_raw_fd());
+        let mut entries = Vec::with_capacity(2);
+        if self.no_cache_pollution {
+            // Hard-linked, so the close waits for the advice even if it fails:
+            entries.push(
+                opcode::Fadvise::new(fd, 0, libc::POSIX_FADV_DONTNEED)
+                    .build()
+                    .flags(squeue::Flags::IO_HARDLINK)
+                    .user_data(BACKGROUND_USER_DATA),
+            );
+        }
+        entries.push(
+            opcode::Close::new(fd)
+                .build()
+                .user_data(BACKGROUND_USER_DATA),
+        );
+        // unsafe: the requests only refer to the descriptor, which is left open for them:
+        match unsafe { self.push_all(&entries) } {
+            Ok(()) => {
+                let _ = file.into_raw_fd();
+                self.background_in_flight += entries.len();
+            }
+            Err(err) => debug!("Closing a file directly: {}", err),
+        }
+    }
+
+    /// Close the file in `slot` of the file table with a request queued on the ring, like
+    /// `close()`. Anything opened into the slot afterwards is queued after the close, so the
+    /// close can't empty the slot again.
+    pub fn close_slot(&mut self, slot: u32) {
+        if !self.background_on_ring {
+            if let Err(err) = set_file_slot(self, slot, None) {
+                debug!("Could not empty a file slot: {}", err);
+            }
+            return;
+        }
+        let mut entries = Vec::with_capacity(2);
+        if self.no_cache_pollution {
+            // Not linked: a close that waited for the advice could empty the slot after a later
+            // open filled it. The advice holds on to the file once the slot is emptied.
+            entries.push(
+                opcode::Fadvise::new(types::Fixed(slot), 0, libc::POSIX_FADV_DONTNEED)
+                    .build()
+                    .user_data(BACKGROUND_USER_DATA),
+            );
+        }
+        entries.push(
+            opcode::Close::new(types::Fixed(slot))
+                .build()
+                .user_data(BACKGROUND_USER_DATA),
+        );
+        // unsafe: the requests only refer to the slot:
+        match unsafe { self.push_all(&entries) } {
+            Ok(()) => self.background_in_flight += entries.len(),
+            Err(err) => {
+                debug!("Emptying a file slot directly: {}", err);
+                if let Err(err) = set_file_slot(self, slot, None) {
+                    debug!("Could not empty a file slot: {}", err);
+                }
+            }
+        }
+    }
+
+    /// Take the next completion that isn't of a Close, Fadvise or timeout request, without
+    /// waiting.
+    pub fn pop_completion(&mut self) -> Option<cqueue::Entry> {
+        loop {
+            let cqe = self.ring.completion().next()?;
+            self.in_flight -= 1;
+            if cqe.user_data() == WATCHDOG_USER_DATA {
+                // -ETIME if the read was cancelled, or else -ECANCELED:
+                self.background_in_flight -= 1;
+                continue;
+            }
+            #[cfg(test)]
+            let cqe = {
+                let is_read = self.reads.contains_key(&cqe.user_data());
+                faults::fail_read(cqe, is_read)
+            };
+            let read = self.reads.remove(&cqe.user_data());
+            if let Some((retry, len)) = self.nowait_retries.remove(&cqe.user_data()) {
+                let result = cqe.result();
+                // A read that can't start without blocking fails with EAGAIN, or EOPNOTSUPP on
+                // some filesystems, and one that reaches data that isn't cached stops short. A
+                // read of nothing is the end of the file:
+                if result == -libc::EAGAIN
+                    || result == -libc::EOPNOTSUPP
+                    || (result > 0 && (result as u32) < len)
+                {
+                    trace!("Reading again without RWF_NOWAIT after {}", result);
+                    self.retries_due.push((retry, 0));
+                    continue;
+                }
+                return Some(cqe);
+            }
+            if let Some((entry, retries)) = read {
+                let result = cqe.result();
+                if (result == -libc::EINTR || result == -libc::EAGAIN) && retries < MAX_READ_RETRIES
+                {
+                    debug!(
+                        "Reading again after {}",
+                        io::Error::from_raw_os_error(-result)
+                    );
+                    self.retries_due.push((entry, retries + 1));
+                    continue;
+                }
+                return Some(cqe);
+            }
+            if cqe.user_data() != BACKGROUND_USER_DATA {
+                return Some(cqe);
+            }
+            self.background_in_flight -= 1;
+            if cqe.result() < 0 {
+                debug!(
+                    "A close or fadvise failed: {}",
+                    io::Error::from_raw_os_error(-cqe.result())
+                );
+            }
+        }
+    }
+
+    /// Submit what's queued and wait until every request queued so far has completed, leaving out
+    /// their completions, so what they point to can be freed. Reads due to be tried again aren't
+    /// queued again.
+    pub fn wait_for_all(&mut self) -> io::Result<()> {
+        while self.in_flight > 0 {
+            self.retries_due.clear();
+            match self.submit_and_wait(1) {
+                Ok(_) => while self.pop_completion().is_some() {},
+                Err(err) if err.raw_os_error() == Some(libc::EINTR) => {}
+                Err(err) => return Err(err),
+            }
+        }
+        self.retries_due.clear();
+        Ok(())
+    }
+
+    /// Submit what's queued and wait for the next completion that isn't of a Close, Fadvise or
+    /// timeout request.
+    pub fn next_completion(&mut self) -> io::Result<cqueue::Entry> {
+        loop {
+            submit_and_reap(self)?;
+            if let Some(cqe) = self.pop_completion() {
+                return Ok(cqe);
+            }
+        }
+    }
+}
+
+impl Deref for Ring {
+    type Target = IoUring;
+
+    fn deref(&self) -> &IoUring {
+        &self.ring
+    }
+}
+
+impl DerefMut for Ring {
+    fn deref_mut(&mut self) -> &mut IoUring {
+        &mut self.ring
+    }
+}
+
+impl Drop for Ring {
+    fn drop(&mut self) {
+        // Closes that are still queued would be lost with the ring, leaking their descriptors:
+        while self.background_in_flight > 0 {
+            if self.submit_and_wait(1).is_err() {
+                break;
+            }
+            while self.pop_completion().is_some() {}
+        }
+
+        let Some(index) = self.registered else {
+            return;
+        };
+        let update = RsrcUpdate {
+            offset: index,
+            resv: 0,
+            data: 0,
+        };
+        // unsafe: the kernel only reads the struct we pass in:
+        unsafe {
+            libc::syscall(
+                libc::SYS_io_uring_register,
+                self.ring.as_raw_fd(),
+                IORING_UNREGISTER_RING_FDS,
+                &update as *const RsrcUpdate,
+                1,
+            );
+        }
+    }
+}
+
+/// Faults that tests inject into the requests of the rings on their own thread.
+#[cfg(test)]
+pub(crate) mod faults {
+    use std::cell::Cell;
+
+    use io_uring::{cqueue, opcode, squeue};
+
+    thread_local! {
+        static MAX_READ_LEN: Cell<Option<u32>> = const { Cell::new(None) };
+        static READ_FAILURES: Cell<(u32, i32)> = const { Cell::new((0, 0)) };
+    }
+
+    /// Make the reads this thread queues from now on ask for at most `len` bytes, so they come
+    /// back short as they can on network filesystems or when a signal arrives. None undoes it.
+    pub fn set_max_read_len(len: Option<u32>) {
+        MAX_READ_LEN.with(|max| max.set(len));
+    }
+
+    /// `entries`, with their reads cut down to the length from `set_max_read_len()`.
+    pub(super) fn shorten_reads(entries: &[squeue::Entry]) -> Vec<squeue::Entry> {
+        let mut entries = entries.to_vec();
+        let Some(max) = MAX_READ_LEN.with(Cell::get) else {
+            return entries;
+        };
+        for entry in &mut entries {
+            // unsafe: an entry is a `struct io_uring_sqe`, which starts with the opcode and has
+            // the length at byte 24:
+            let sqe = unsafe { &mut *(entry as *mut squeue::Entry as *mut [u8; 64]) };
+            if [opcode::Read::CODE, opcode::ReadFixed::CODE].contains(&sqe[0]) {
+                let len = u32::from_ne_bytes(sqe[24..28].try_into().unwrap());
+                sqe[24..28].copy_from_slice(&len.min(max).to_ne_bytes());
+            }
+        }
+        entries
+    }
+
+    /// Make the next `count` reads that complete on this thread fail with `errno`.
+    pub fn fail_reads(count: u32, errno: i32) {
+        READ_FAILURES.with(|failures| failures.set((count, errno)));
+    }
+
+    /// `cqe`, failed as `fail_reads()` asked if it's of a read.
+    pub(super) fn fail_read(mut cqe: cqueue::Entry, is_read: bool) -> cqueue::Entry {
+        let (count, errno) = READ_FAILURES.with(Cell::get);
+        if is_read && count > 0 {
+            READ_FAILURES.with(|failures| failures.set((count - 1, errno)));
+            // unsafe: an entry is a `struct io_uring_cqe`, which has the result at byte 8:
+            let cqe_bytes = unsafe { &mut *(&mut cqe as *mut cqueue::Entry as *mut [u8; 16]) };
+            cqe_bytes[8..12].copy_from_slice(&(-errno).to_ne_bytes());
+        }
+        cqe
+    }
+}
@@ -0,0 +1,301 @@
+// This module checks every checksum strategy against known test vectors.
+use std::{
+    collections::HashMap,
+    fs,
+    io::Write,
+    path::{Path, PathBuf},
+    sync::mpsc::channel,
+    thread,
+};
+
+use anyhow::{anyhow, Result};
+#[allow(unused_imports)]
+use log::{debug, error, info, trace, warn};
+
+use crate::*;
+
+/// Test vectors from RFC 1321, appendix A.5.
+const RFC_1321_VECTORS: &[(&str, &str)] = &[
+    ("", "d41d8cd98f00b204e9800998ecf8427e"),
+    ("a", "0cc175b9c0f1b6a831c399e269772661"),
+    ("abc", "900150983cd24fb0d6963f7d28e17f72"),
+    ("message digest", "f96b697d7cb7938d525a2f31aaf161d0"),
+    (
+        "abcdefghijklmnopqrstuvwxyz",
+        "c3fcd3d76192e4007dfb496cca67e13b",
+    ),
+    (
+        "ABCDEFGHIJKLMNOPQRSTUVWXYZabcdefghijklmnopqrstuvwxyz0123456789",
+        "d174ab98d277d9f5a5611c2c9f419d9f",
+    ),
+    (
+        "12345678901234567890123456789012345678901234567890123456789012345678901234567890",
+        "57edf4a22be3c955ac49da2e2107b67a",
+    ),
+];
+
+/// Test vectors from FIPS 180-2, appendix B.
+const FIPS_180_SHA256_VECTORS: &[(&str, &str)] = &[
+    (
+        "",
+        "e3b0c44298fc1c149afbf4c8996fb92427ae41e4649b934ca495991b7852b855",
+    ),
+    (
+        "abc",
+        "ba7816bf8f01cfea414140de5dae2223b00361a396177a9cb410ff61f20015ad",
+    ),
+    (
+        "abcdbcdecdefdefgefghfghighijhijkijkljklmklmnlmnomnopnopq",
+        "248d6a61d20638b8e5c026930c3e6039a33ce45964ff2167f6ecedd419db06c1",
+    ),
+];
+
+/// Test vectors from FIPS 180-2, appendix D.
+const FIPS_180_SHA384_VECTORS: &[(&str, &str)] = &[
+    (
+        "",
+        "38b060a751ac96384cd9327eb1b1e36a21fdb71114be07434c0cc7bf63f6e1da274edebfe76f65fbd51ad2f14898b95b",
+    ),
+    (
+        "abc",
+        "cb00753f45a35e8bb5a03d699ac65007272c32ab0eded1631a8b605a43ff5bed8086072ba1e7cc2358baeca134c825a7",
+    ),
+];
+
+/// Test vectors from FIPS 180-2, appendix C.
+const FIPS_180_SHA512_VECTORS: &[(&str, &str)] = &[
+    (
+        "",
+        "cf83e1357eefb8bdf1542850d66d8007d620e4050b5715dc83f4a921d36ce9ce47d0d13c5d85f2b0ff8318d2877eec2f63b931bd47417a81a538327af927da3e",
+    ),
+    (
+        "abc",
+        "ddaf35a193617abacc417349ae20413112e6fa4e89a97ea20a9eeee64b55d39a2192992a274fc1a836ba3c23a3feebbd454d4423643ce80e2a9ac94fa54ca49f",
+    ),
+];
+
+/// Test vectors from the BLAKE3 reference implementation.
+const BLAKE3_VECTORS: &[(&str, &str)] = &[
+    (
+        "",
+        "af1349b9f5f9a1a6a0404dea36dcc9499bcb25c9adc112b7cc9a93cae41f3262",
+    ),
+    (
+        "abc",
+        "6437b3ac38465133ffb63b75273a8db548c558465d79db03fd359c6cd5bd9d85",
+    ),
+];
+
+/// Test vectors from RFC 7693, appendices A and B.
+const BLAKE2B_VECTORS: &[(&str, &str)] = &[(
+    "abc",
+    "ba80a53f981c4d0d6a2797b69f12f6e94c212f14685ac4b74b12bb6fdbffa2d17d87c5392aab792dc252d5de4533cc9518d38aa8dbf1925ab92386edd4009923",
+)];
+const BLAKE2S_VECTORS: &[(&str, &str)] = &[(
+    "abc",
+    "508c5e8c327c14e2e1a72ba34eeb452f37458b209ed63a294d999b4c86675982",
+)];
+
+/// Test vectors from GB/T 32905-2016, appendix A.
+const SM3_VECTORS: &[(&str, &str)] = &[
+    (
+        "abc",
+        "66c7f0f462eeedd9d1f2d46bdc10e4e24167c4875cf2f7a2297da02b8f4ba8e0",
+    ),
+    (
+        "abcdabcdabcdabcdabcdabcdabcdabcdabcdabcdabcdabcdabcdabcdabcdabcd",
+        "debe9ff92275b8a138604889c18e5a4d6fdb70e5387e5765293dcba39c0c5732",
+    ),
+];
+
+/// Test vectors from the xxHash reference implementation.
+const XXH3_VECTORS: &[(&str, &str)] = &[("", "2d06800538d394c2")];
+const XXH128_VECTORS: &[(&str, &str)] = &[("", "99aa06d3014798d86001c324468d497f")];
+
+/// The check value from RFC 3720, appendix B.4.
+const CRC32C_VECTORS: &[(&str, &str)] = &[("", "00000000"), ("123456789", "e3069283")];
+
+/// The check values from the catalogue of parametrised CRC algorithms.
+const CRC64_XZ_VECTORS: &[(&str, &str)] =
+    &[("", "0000000000000000"), ("123456789", "995dc9bbdf1939fa")];
+const CRC64_ECMA_VECTORS: &[(&str, &str)] =
+    &[("", "0000000000000000"), ("123456789", "6c40df5f0b497347")];
+
+/// The published test vectors of an algorithm, and where they're from.
+fn known_vectors(
+    algorithm: HashAlgorithm,
+) -> (&'static str, &'static [(&'static str, &'static str)]) {
+    match algorithm {
+        HashAlgorithm::Md5 => ("rfc1321", RFC_1321_VECTORS),
+        HashAlgorithm::Sha256 => ("fips180", FIPS_180_SHA256_VECTORS),
+        HashAlgorithm::Sha384 => ("fips180", FIPS_180_SHA384_VECTORS),
+        HashAlgorithm::Sha512 => ("fips180", FIPS_180_SHA512_VECTORS),
+        HashAlgorithm::Blake3 => ("blake3", BLAKE3_VECTORS),
+        HashAlgorithm::Blake2b => ("rfc7693", BLAKE2B_VECTORS),
+        HashAlgorithm::Blake2s => ("rfc7693", BLAKE2S_VECTORS),
+        HashAlgorithm::Sm3 => ("gbt32905", SM3_VECTORS),
+        HashAlgorithm::Xxh3 => ("xxhash", XXH3_VECTORS),
+        HashAlgorithm::Xxh128 => ("xxhash", XXH128_VECTORS),
+        HashAlgorithm::Crc32c => ("crc32c", CRC32C_VECTORS),
+        HashAlgorithm::Crc64Xz => ("crc64", CRC64_XZ_VECTORS),
+        HashAlgorithm::Crc64Ecma => ("crc64", CRC64_ECMA_VECTORS),
+    }
+}
+
+/// Sizes of generated files, chosen to land on either side of read boundaries.
+const PATTERN_SIZES: &[usize] = &[
+    1,
+    ALIGNMENT - 1,
+    ALIGNMENT,
+    MAX_READ_SIZE - 1,
+    MAX_READ_SIZE,
+    MAX_READ_SIZE + 1,
+    MAX_READ_SIZE * 3 + ALIGNMENT / 2,
+];
+
+/// A file written for the self test, along with the digest it should produce.
+struct TestVector {
+    name: String,
+    path: PathBuf,
+    expected: String,
+}
+
+/// Write the test vectors of every algorithm into `dir`, run them through every strategy, and
+/// print a line per check, with the other settings of `config`. Returns whether every check
+/// passed.
+pub fn run(dir: &Path, config: &Config) -> Result<bool> {
+    let dir = dir.join(format!("md5sum-uring-self-test-{}", std::process::id()));
+    fs::create_dir(&dir)?;
+    let result = run_in(&dir, config);
+    if let Err(err) = fs::remove_dir_all(&dir) {
+        warn!("Could not remove {}: {}", dir.display(), err);
+    }
+    result
+}
+
+fn run_in(dir: &Path, config: &Config) -> Result<bool> {
+    let mut passed = 0;
+    let mut failed = 0;
+    for &algorithm in HashAlgorithm::ALL {
+        let algorithm_dir = dir.join(algorithm.name());
+        fs::create_dir(&algorithm_dir)?;
+        let vectors = write_vectors(&algorithm_dir, algorithm)?;
+        let (algorithm_passed, algorithm_failed) =
+            check_strategies(&vectors, &config.clone().algorithm(algorithm));
+        passed += algorithm_passed;
+        failed += algorithm_failed;
+    }
+
+    println!("{} passed, {} failed", passed, failed);
+    Ok(failed == 0)
+}
+
+/// Returns how many checks passed and failed.
+fn check_strategies(vectors: &[TestVector], config: &Config) -> (usize, usize) {
+    let algorithm = config.algorithm;
+    let mut passed = 0;
+    let mut failed = 0;
+
+    for &strategy in STRATEGIES {
+        let name = strategy.name();
+        if config.o_direct && name == "no-uring" {
+            // This strategy doesn't support O_DIRECT.
+            continue;
+        }
+
+        let results = match run_strategy(vectors, &config.clone().with_strategy(strategy)) {
+            Ok(results) => results,
+            Err(err) => {
+                println!(
+                    "FAILED  {:<16} {:<7} (could not run: {})",
+                    name, algorithm, err
+                );
+                failed += vectors.len();
+                continue;
+            }
+        };
+
+        for (vector, actual) in vectors.iter().zip(results) {
+            match actual {
+                Ok(actual) if actual == vector.expected => {
+                    println!("OK      {:<16} {:<7} {}", name, algorithm, vector.name);
+                    passed += 1;
+                }
+                Ok(actual) => {
+                    println!(
+                        "FAILED  {:<16} {:<7} {} (expected {}, got {})",
+                        name, algorithm, vector.name, vector.expected, actual
+                    );
+                    failed += 1;
+                }
+                Err(err) => {
+                    println!(
+                        "FAILED  {:<16} {:<7} {} ({})",
+                        name, algorithm, vector.name, err
+                    );
+                    failed += 1;
+                }
+            }
+        }
+    }
+
+    (passed, failed)
+}
+
+fn write_vectors(dir: &Path, algorithm: HashAlgorithm) -> Result<Vec<TestVector>> {
+    let mut vectors = Vec::new();
+    let mut write = |name: String, data: &[u8], expected: String| -> Result<()> {
+        let path = dir.join(&name);
+        fs::File::create(&path)?.write_all(data)?;
+        vectors.push(TestVector {
+            name,
+            path,
+            expected,
+        });
+        Ok(())
+    };
+
+    let (source, known) = known_vectors(algorithm);
+    for (i, (input, expected)) in known.iter().enumerate() {
+        write(
+            format!("{}-{}", source, i),
+            input.as_bytes(),
+            expected.to_string(),
+        )?;
+    }
+
+    for &size in PATTERN_SIZES {
+        // A pattern that doesn't repeat on any power of two, so misplaced reads are caught:
+        let data: Vec<u8> = (0..size).map(|i| (i % 251) as u8).collect();
+        let mut ctx = Hasher::new(algorithm);
+        ctx.update(&data);
+        let expected = to_hex(&ctx.finalize());
+        write(format!("pattern-{}", size), &data, expected)?;
+    }
+
+    Ok(vectors)
+}
+
+/// Returns the hex digest or error for each vector, in order.
+fn run_strategy(vectors: &[TestVector], config: &Config) -> Result<Vec<Result<String>>> {
+    let paths: Vec<_> = vectors.iter().map(|vector| vector.path.clone()).collect();
+    let (tx, rx) = channel();
+    let config = config.clone();
+    let handle = thread::spawn(move || get_checksums(paths, tx, &config));
+
+    let mut results: HashMap<PathBuf, Result<String>> = HashMap::new();
+    for result in rx {
+        let path = result.path.clone();
+        results.insert(path, result.into_result().map(|digest| to_hex(&digest)));
+    }
+    handle.join().unwrap()?;
+
+    Ok(vectors
+        .iter()
+        .map(|vector| {
+            results
+                .remove(&vector.path)
+                .unwrap_or_else(|| Err(anyhow!("no result was returned")))
+        })
+        .collect())
+}
@@ -0,0 +1,103 @@
+// This module lets a long-running program, like a service that's asked to hash files again and
+// again, set up a ring once and keep it. Setting up a ring, probing the kernel and registering
+// buffers can take longer than hashing a few small files, so a `ChecksumSession` does it when it's
+// made and then hashes each batch of files it's given with what's already set up.
+use std::path::PathBuf;
+
+use anyhow::Result;
+#[allow(unused_imports)]
+use log::{debug, error, info, trace, warn};
+
+use crate::*;
+
+/// What a strategy keeps between the batches of a `ChecksumSession`.
+pub trait StrategySession {
+    /// Hash `files` as the strategy's `get_checksums()` does. Once this returns Ok, every file's
+    /// result has been sent and the next batch can be given.
+    fn checksum(&mut self, files: Vec<PathBuf>, tx: &dyn ResultSink<ChecksumResult>) -> Result<()>;
+}
+
+/// Runs a strategy that keeps nothing between batches, setting it up for each one.
+#[derive(Debug)]
+pub struct PerBatch {
+    pub strategy: &'static dyn ChecksumStrategy,
+    pub config: Config,
+}
+
+impl StrategySession for PerBatch {
+    fn checksum(&mut self, files: Vec<PathBuf>, tx: &dyn ResultSink<ChecksumResult>) -> Result<()> {
+        self.strategy.get_checksums(files, tx, &self.config)
+    }
+}
+
+/// Hashes batch after batch of files with `config.strategy`, which is set up once:
+///
+/// ```no_run
+/// use md5sum_uring::{ChecksumSession, Config};
+/// use std::sync::mpsc::channel;
+///
+/// let mut session = ChecksumSession::new(Config::default())?;
+/// for batch in [vec!["a".into()], vec!["b".into(), "c".into()]] {
+///     let (tx, rx) = channel();
+///     session.checksum(batch, tx)?;
+///     for result in rx {
+///         println!("{}: {:?}", result.path.display(), result.digest);
+///     }
+/// }
+/// # Ok::<(), anyhow::Error>(())
+/// ```
+///
+/// Each batch is read on the calling thread with one ring, so `config.jobs` and `config.numa`
+/// aren't used. A ring's registered descriptor belongs to the thread that registered it, so a
+/// session isn't `Send`, and is used on the thread that made it.
+pub struct ChecksumSession {
+    config: Config,
+    /// What the strategy set up, which is None after a batch failed until it's set up again
+    session: Option<Box<dyn StrategySession>>,
+}
+
+impl ChecksumSession {
+    /// Set up `config.strategy` for the batches to come.
+    pub fn new(config: Config) -> Result<ChecksumSession> {
+        let session = Some(start_session(&config)?);
+        Ok(ChecksumSession { config, session })
+    }
+
+    /// Hash `files`, sending each file's result to `tx`, which is dropped once every file has a
+    /// result. As with `get_checksums()`, a file named by several paths is only read once. After
+    /// an error the session is set up again for the next batch, since the ring may still have
+    /// reads of the failed batch in flight.
+    pub fn checksum(
+        &mut self,
+        files: Vec<PathBuf>,
+        tx: impl ResultSink<ChecksumResult>,
+    ) -> Result<()> {
+        let session = match &mut self.session {
+            Some(session) => session,
+            None => self.session.insert(start_session(&self.config)?),
+        };
+        let (files, links) = split_same_files(files, &self.config);
+        let result = if links.is_empty() {
+            session.checksum(files, &tx)
+        } else {
+            let tx = hard_links::LinkSink { inner: &tx, links };
+            session.checksum(files, &tx)
+        };
+        if result.is_err() {
+            self.session = None;
+        }
+        result
+    }
+}
+
+/// What `config.strategy` keeps between batches, or a `PerBatch` if it keeps nothing.
+fn start_session(config: &Config) -> Result<Box<dyn StrategySession>> {
+    let strategy = config.strategy;
+    let session = strategy.start_session(config)?.unwrap_or_else(|| {
+        Box::new(PerBatch {
+            strategy,
+            config: config.clone(),
+        })
+    });
+    Ok(session)
+}
@@ -1,235 +1,900 @@
-// This module uses io_uring without any fancy options.
+// This module uses io_uring without any fancy options. Each file can have several reads in flight,
+// which are hashed in order as they complete. Each file also has a spare buffer, so its next read
+// is already submitted while the last one is hashed. With `Config::hash_threads`, the hashing is
+// handed to worker threads.
 use std::{
     cmp::min,
+    ffi::CString,
     fs::File,
     hash::BuildHasherDefault,
-    os::unix::io::AsRawFd,
+    io, mem,
+    os::unix::{
+        ffi::OsStrExt,
+        io::{AsRawFd, FromRawFd},
+    },
     path::{Path, PathBuf},
     pin::Pin,
-    sync::mpsc::Sender,
+    sync::mpsc::{channel, Receiver},
 };
 
 use anyhow::{bail, Result};
-use io_uring::{opcode, types, IoUring, Probe};
+use io_uring::{opcode, types, Probe};
 #[allow(unused_imports)]
 use log::{debug, error, info, trace, warn};
-use md5::{Digest, Md5};
+use md5::digest::Update;
 use nohash_hasher::NoHashHasher;
 
-use crate::*;
+use crate::{hash_pool::HashPool, ring::Ring, *};
 
 type HashMap<K, V> = std::collections::HashMap<K, V, BuildHasherDefault<NoHashHasher<K>>>;
 
+/// The length of a file opened into a slot of the file table, which Statx can't look up. The file
+/// is read until a read returns nothing.
+const UNKNOWN_LEN: u64 = u64::MAX;
+
+/// The bits of a request's user data that hold which of its file's chunks it reads into. The rest
+/// hold the file's slot.
+const CHUNK_BITS: u32 = 8;
+
+/// The user data of a request for the file in slot `idx`, reading into chunk `chunk` if it's a
+/// read.
+fn user_data(idx: usize, chunk: usize) -> u64 {
+    ((idx as u64) << CHUNK_BITS) | chunk as u64
+}
+
+/// How reads refer to a file.
+enum OpenFile {
+    Fd(File),
+    /// A slot of the registered file table, which the file was opened straight into
+    Slot(u32),
+}
+
+impl OpenFile {
+    /// Close the file through the ring.
+    fn close(self, ring: &mut Ring) {
+        match self {
+            OpenFile::Fd(fd) => ring.close(fd),
+            OpenFile::Slot(slot) => ring.close_slot(slot),
+        }
+    }
+}
+
+/// One of the reads of a file that can be in flight at once, and the buffer it reads into.
+struct Chunk {
+    buf: Pin<Box<AlignedBuffer>>,
+    /// Where in the file the read starts
+    offset: u64,
+    /// How many bytes of the read have arrived, when it came back short and the rest is being
+    /// read
+    filled: usize,
+    state: ChunkState,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum ChunkState {
+    Free,
+    Reading,
+    /// The read completed with this many bytes, which wait to be hashed until the bytes before
+    /// them are
+    Read(usize),
+}
+
+impl Chunk {
+    fn new(read_size: usize) -> Chunk {
+        Chunk {
+            buf: Box::pin(AlignedBuffer::with_capacity(read_size)),
+            offset: 0,
+            filled: 0,
+            state: ChunkState::Free,
+        }
+    }
+}
+
 /// This struct holds the state and buffers of a file that's being read, particularly
 /// when one read finishes but more reads are required to finish the file.
-struct Buffer {
+struct Buffer<D> {
     pub path: PathBuf,
-    pub fd: File,
+    pub fd: OpenFile,
     file_len: u64,
-    pub buf: Pin<Box<AlignedBuffer>>,
-    /// How many bytes have been read
+    /// A chunk for each read that can be in flight, and a spare one that's read into while
+    /// another is hashed
+    chunks: Vec<Chunk>,
+    /// How many reads may be in flight, from `Config::reads_per_file`
+    max_in_flight: usize,
+    /// How many bytes have been hashed
     pub position: u64,
-    /// The md5 state is updated as more bytes are read
-    ctx: Md5,
+    /// Where the next read starts
+    next_read: u64,
+    /// How many reads are in flight
+    in_flight: usize,
+    /// Whether a read of a file of unknown length has returned nothing
+    eof: bool,
+    /// Why the file failed, kept while its other reads finish
+    error: Option<anyhow::Error>,
+    /// The digest state is updated as more bytes are read. It's None once it's been handed to a
+    /// hashing worker.
+    ctx: Option<D>,
+    /// Where the kernel puts the file's length while it's looked up through the ring
+    statx: Option<Box<libc::statx>>,
 }
 
-impl Buffer {
-    pub fn new(path: &Path, o_direct: bool) -> Result<Buffer> {
-        let fd = open(path, o_direct)?;
-        let file_len = fd.metadata()?.len();
-        let mut ret = Buffer {
+impl<D: Update + Send + 'static> Buffer<D> {
+    pub fn new(path: &Path, ctx: D, stat_on_ring: bool, config: &Config) -> Result<Buffer<D>> {
+        Self::from_file(
+            path,
+            open(path, config.o_direct).map_err(|err| ChecksumError::open_failed(path, err))?,
+            ctx,
+            stat_on_ring,
+            config,
+        )
+    }
+
+    /// Get ready to read a file that's already open. With `stat_on_ring`, the file's length
+    /// isn't known until a Statx request from `submit_first()` completes.
+    pub fn from_file(
+        path: &Path,
+        fd: File,
+        ctx: D,
+        stat_on_ring: bool,
+        config: &Config,
+    ) -> Result<Buffer<D>> {
+        let (file_len, statx) = if stat_on_ring {
+            // unsafe: a statx struct is plain integers, for which zeros are valid:
+            (0, Some(Box::new(unsafe { mem::zeroed() })))
+        } else {
+            (fd.metadata()?.len(), None)
+        };
+        Ok(Self::with_len(
+            path,
+            OpenFile::Fd(fd),
+            file_len,
+            ctx,
+            statx,
+            config,
+        ))
+    }
+
+    /// Get ready to read a file that was opened into `slot` of the file table.
+    fn from_slot(path: &Path, slot: u32, ctx: D, config: &Config) -> Buffer<D> {
+        Self::with_len(path, OpenFile::Slot(slot), UNKNOWN_LEN, ctx, None, config)
+    }
+
+    fn with_len(
+        path: &Path,
+        fd: OpenFile,
+        file_len: u64,
+        ctx: D,
+        statx: Option<Box<libc::statx>>,
+        config: &Config,
+    ) -> Buffer<D> {
+        // Where a file ends is only found by reading it, so there's one read at a time, which
+        // can't start until the last one's length is known:
+        let (max_in_flight, chunks) = if file_len == UNKNOWN_LEN {
+            (1, 1)
+        } else {
+            (config.reads_per_file, config.reads_per_file + 1)
+        };
+        Buffer {
             path: path.to_owned(),
             fd,
             file_len,
-            buf: Box::pin(Default::default()),
+            chunks: (0..chunks).map(|_| Chunk::new(config.read_size)).collect(),
+            max_in_flight,
             position: 0,
-            ctx: Md5::new(),
-        };
-        ret.set_buffer_size();
-        Ok(ret)
-    }
-
-    /// Reset the buffer size, useful whenever the read position changes.
-    pub fn set_buffer_size(&mut self) {
-        let needed_bytes = min(self.file_len - self.position, MAX_READ_SIZE as u64);
-        trace!(
-            "Set the buffer size to {} because we read {} of a {} file.",
-            needed_bytes,
-            self.position,
-            self.file_len
-        );
-        self.buf.resize(needed_bytes as usize);
+            next_read: 0,
+            in_flight: 0,
+            eof: false,
+            error: None,
+            ctx: Some(ctx),
+            statx,
+        }
     }
-}
 
-/// Get all checksums and send the results through a channel.
-pub fn get_checksums(
-    files: Vec<PathBuf>,
-    tx: Sender<(PathBuf, Result<Md5>)>,
-    o_direct: bool,
-) -> Result<()> {
-    // Set up shared state that's applicable to all individual reads or for choosing what to read:
-    let mut ring = IoUring::new(RING_SIZE as u32)?;
-    let mut probe = Probe::new();
-    ring.submitter().register_probe(&mut probe)?;
-    if !probe.is_supported(opcode::Read::CODE) {
-        bail!("Reading files is not supported. Try a newer kernel.");
-    }
-
-    // This is a list of buffers that needs to be indexed by the "user data" handle
-    // that is submitted to the kernel with each job and later returned.
-    let mut shared_buffers: HashMap<usize, Buffer> = Default::default();
-    let mut files = files.into_iter().peekable();
-    let mut free_index_list: Vec<_> = (0..RING_SIZE).into_iter().collect();
-
-    loop {
-        let mut new_work_queued = false;
-
-        // Only proceed if there's both a free index and a file:
-        while let Some(free_idx) = free_index_list.pop() {
-            debug_assert!(
-                !ring.submission().is_full(),
-                "Submission queue must have a free spot if there's a free shared buffer",
+    /// Hash the chunks that have been read, in the order they're in the file, until one that
+    /// hasn't been read yet. With `pool`, the chunks' buffers are handed to the worker that hashes
+    /// the file, which is numbered `idx`, and swapped for spare ones.
+    fn hash_ready(&mut self, pool: Option<&HashPool<D>>, idx: usize) {
+        while let Some(chunk) = self.chunks.iter_mut().find(|chunk| {
+            chunk.offset == self.position && matches!(chunk.state, ChunkState::Read(_))
+        }) {
+            let ChunkState::Read(len) = chunk.state else {
+                unreachable!();
+            };
+            trace!(
+                "Incorporating {} bytes at {} into checksum ({:?})",
+                len,
+                self.position,
+                &self.path,
             );
-
-            if let Some(ref path) = files.next() {
-                // Queue a read with this file:
-                let buffer = match Buffer::new(path, o_direct) {
-                    Ok(buffer) => buffer,
-                    Err(err) => {
-                        // We didn't use this buffer index
-                        free_index_list.push(free_idx);
-                        tx.send((path.to_owned(), Err(err))).unwrap();
-                        continue;
+            match pool {
+                Some(pool) => {
+                    if let Some(ctx) = self.ctx.take() {
+                        pool.start(idx, self.path.clone(), ctx);
                     }
-                };
-
-                shared_buffers.insert(free_idx, buffer);
-                debug_assert_eq!(
-                    free_index_list.len(),
-                    RING_SIZE - shared_buffers.len(),
-                    "The free index list is out of sync with the work buffers (1)"
-                );
-                let buffer_ref = shared_buffers.get_mut(&free_idx).unwrap();
-                new_work_queued = true;
-                submit_for_read(&mut ring, buffer_ref, free_idx);
-            } else {
-                // We didn't use this buffer index
-                free_index_list.push(free_idx);
+                    pool.hash(idx, pool.replace_buffer(&mut chunk.buf), len);
+                }
+                None => {
+                    let ctx = self
+                        .ctx
+                        .as_mut()
+                        .expect("only a pool takes the digest state");
+                    ctx.update(&chunk.buf[..len]);
+                    progress::add_bytes(len as u64);
+                }
+            }
+            chunk.state = ChunkState::Free;
+            self.position += len as u64;
+            if len == 0 {
+                self.eof = true;
                 break;
             }
         }
+    }
 
-        if new_work_queued || files.peek().is_some() {
-            if files.peek().is_some() {
-                debug_assert_eq!(
-                    free_index_list.len(),
-                    0,
-                    "We should have filled all the slots"
-                );
+    /// Close the file, and send its digest, or `err`, through `tx` or have the worker hashing it
+    /// send it.
+    fn close<R: From<(PathBuf, Result<D>)>>(
+        self,
+        ring: &mut Ring,
+        pool: Option<&HashPool<D>>,
+        idx: usize,
+        tx: &impl ResultSink<R>,
+        err: Option<anyhow::Error>,
+    ) -> Result<()> {
+        self.fd.close(ring);
+        match (self.ctx, pool) {
+            (Some(ctx), _) => tx.send((self.path, err.map_or(Ok(ctx), Err)).into())?,
+            (None, Some(pool)) => match err {
+                Some(err) => pool.fail(idx, err),
+                None => pool.finish(idx),
+            },
+            (None, None) => unreachable!("only a pool takes the digest state"),
+        }
+        Ok(())
+    }
+
+    /// Whether every byte has been read and hashed.
+    fn finished(&self) -> bool {
+        self.in_flight == 0
+            && if self.file_len == UNKNOWN_LEN {
+                self.eof
+            } else {
+                self.position == self.file_len
             }
+    }
+}
 
-            // Wait for a result since the jobs list is full or we just added something
-            trace!("Waiting for / handling a result");
-            submit_wait_and_handle_result(
-                &mut ring,
-                &mut shared_buffers,
-                &tx,
-                &mut free_index_list,
-            )?;
-        } else {
-            // There's no more work that can be added right now, but we still need to handle any
-            // active buffers
-            while free_index_list.len() < RING_SIZE {
-                trace!(
-                    "Did not submit work, waiting for old work. {}/{} free indices",
-                    free_index_list.len(),
-                    RING_SIZE
-                );
+/// A file whose open is queued on the ring.
+struct Opening<D> {
+    path: PathBuf,
+    /// The path as the kernel reads it, which must last until the open completes
+    c_path: CString,
+    ctx: D,
+    /// Whether the file is opened into the slot of the file table with the same index as its
+    /// slot of the ring, instead of getting a descriptor
+    into_slot: bool,
+    /// Whether the file is opened with O_DIRECT, which is given up if its filesystem rejects it
+    o_direct: bool,
+}
+
+impl<D> Opening<D> {
+    fn new(path: &Path, ctx: D, into_slot: bool, o_direct: bool) -> Result<Opening<D>> {
+        Ok(Opening {
+            path: path.to_owned(),
+            c_path: CString::new(path.as_os_str().as_bytes())?,
+            ctx,
+            into_slot,
+            o_direct,
+        })
+    }
+}
+
+/// The default strategy: reads files through a ring into buffers of its own.
+#[derive(Debug)]
+pub struct SimpleUring;
+
+impl ChecksumStrategy for SimpleUring {
+    fn name(&self) -> &'static str {
+        "simple-uring"
+    }
+
+    fn get_checksums(
+        &self,
+        files: Vec<PathBuf>,
+        tx: &dyn ResultSink<ChecksumResult>,
+        config: &Config,
+    ) -> Result<()> {
+        get_checksums(files, tx, config, || Hasher::new(config.algorithm))
+    }
+
+    fn start_session(&self, config: &Config) -> Result<Option<Box<dyn StrategySession>>> {
+        Ok(Some(Box::new(Session::<Hasher>::new(config)?)))
+    }
+}
+
+/// Get all checksums and send the results through a channel. Each file gets a new digest from
+/// `new_digest`, such as `Md5::new`, and is sent as an `R` made from its path and digest, such
+/// as a `ChecksumResult` or the pair itself.
+pub fn get_checksums<D, F, R>(
+    files: Vec<PathBuf>,
+    tx: impl ResultSink<R>,
+    config: &Config,
+    new_digest: F,
+) -> Result<()>
+where
+    D: Update + Send + 'static,
+    F: Fn() -> D,
+    R: From<(PathBuf, Result<D>)>,
+{
+    Session::new(config)?.run(files, tx, new_digest)
+}
+
+/// A ring with its file table and hashing workers, set up once to read batch after batch of
+/// files.
+pub struct Session<D> {
+    ring: Ring,
+    /// How many files are read at once
+    slots: usize,
+    pool: Option<HashPool<D>>,
+    /// The results of the hashing workers
+    pool_rx: Receiver<(PathBuf, Result<D>)>,
+    async_open: bool,
+    stat_on_ring: bool,
+    open_into_slots: bool,
+    config: Config,
+}
+
+impl<D: Update + Send + 'static> Session<D> {
+    /// Set up the ring as `config` says, probe what the kernel supports, and start the hashing
+    /// workers.
+    pub fn new(config: &Config) -> Result<Session<D>> {
+        // Set up shared state that's applicable to all individual reads or for choosing what to
+        // read:
+        let ring_size = config.ring_size;
+        let ring = ring::new_ring(ring_size as u32, config)?;
+        // The reads in flight are shared out between the files, so fewer files are read at once
+        // when each has more reads:
+        let slots = (ring_size / config.reads_per_file).max(1);
+        // The workers' results are passed on from this thread, so `tx` needn't be shared with them:
+        let (pool_tx, pool_rx) = channel();
+        let pool = (config.hash_threads > 0)
+            .then(|| HashPool::new(config.hash_threads, config.read_size, &pool_tx));
+        drop(pool_tx);
+        let mut probe = Probe::new();
+        ring.submitter()
+            .register_probe(&mut probe)
+            .map_err(ChecksumError::RingSetup)?;
+        if !probe.is_supported(opcode::Read::CODE) {
+            bail!("Reading files is not supported. Try a newer kernel.");
+        }
+        // A polled ring only takes reads, so files are opened here then:
+        let async_open = config.async_open && !ring.params().is_setup_iopoll();
+        if async_open && !probe.is_supported(opcode::OpenAt::CODE) {
+            bail!("Opening files through io_uring is not supported. Try a newer kernel (5.6).");
+        }
+        let stat_on_ring = ring::can_statx(&ring, &probe);
+        // Files opened through the ring go straight into the file table when the kernel can do
+        // that, so they never take up a descriptor. Each slot of the ring has the same slot of the
+        // table:
+        let open_into_slots = async_open && ring::can_open_into_slots(&ring, &probe) && {
+            // The table can't have more slots than the open file limit, which this raises:
+            max_open_files();
+            match ring::register_file_slots(&ring, slots as u32) {
+                Ok(()) => true,
+                Err(err) => {
+                    debug!(
+                        "Could not register a file table to open files into: {}",
+                        err
+                    );
+                    false
+                }
+            }
+        };
+        Ok(Session {
+            ring,
+            slots,
+            pool,
+            pool_rx,
+            async_open,
+            stat_on_ring,
+            open_into_slots,
+            config: config.clone(),
+        })
+    }
+
+    /// Hash `files` as `get_checksums()` does, reusing what was set up. Once this returns Ok,
+    /// every file's result has been sent and the session can take another batch.
+    pub fn run<F, R>(
+        &mut self,
+        files: Vec<PathBuf>,
+        tx: impl ResultSink<R>,
+        new_digest: F,
+    ) -> Result<()>
+    where
+        F: Fn() -> D,
+        R: From<(PathBuf, Result<D>)>,
+    {
+        // This is a list of buffers that needs to be indexed by the "user data" handle
+        // that is submitted to the kernel with each job and later returned.
+        let mut shared_buffers: HashMap<usize, Buffer<D>> = Default::default();
+        // Slots of files that are still being opened, when they're opened through the ring:
+        let mut openings: HashMap<usize, Opening<D>> = Default::default();
+        let result = self.read_all(&mut shared_buffers, &mut openings, files, tx, new_digest);
+        if result.is_err() {
+            // The reads and opens still in flight point into the buffers and openings:
+            ring::settle(&mut self.ring, (shared_buffers, openings));
+        }
+        result
+    }
+
+    /// Read `files` until each one's result has been sent, with the files being read in
+    /// `shared_buffers` and those being opened in `openings`.
+    fn read_all<F, R>(
+        &mut self,
+        shared_buffers: &mut HashMap<usize, Buffer<D>>,
+        openings: &mut HashMap<usize, Opening<D>>,
+        files: Vec<PathBuf>,
+        tx: impl ResultSink<R>,
+        new_digest: F,
+    ) -> Result<()>
+    where
+        F: Fn() -> D,
+        R: From<(PathBuf, Result<D>)>,
+    {
+        let Session {
+            ring,
+            slots,
+            pool,
+            pool_rx,
+            async_open,
+            stat_on_ring,
+            open_into_slots,
+            config,
+        } = self;
+        let (slots, async_open, stat_on_ring, open_into_slots) =
+            (*slots, *async_open, *stat_on_ring, *open_into_slots);
+
+        let mut files = files.into_iter().peekable();
+        let mut free_index_list: Vec<_> = (0..slots).collect();
+
+        loop {
+            let mut new_work_queued = false;
+            forward_hashed(pool_rx, &tx)?;
+
+            if config.cancel.is_cancelled() {
+                // No more files are started, and those being read fail as their reads complete:
+                cancel::send_cancelled(files.by_ref(), &tx);
+            }
+
+            // Only proceed if there's both a free index and a file:
+            while let Some(free_idx) = free_index_list.pop() {
+                if let Some(ref path) = files.next() {
+                    if async_open {
+                        // Queue the open, and the first read once it's done:
+                        match Opening::new(path, new_digest(), open_into_slots, config.o_direct) {
+                            Ok(opening) => {
+                                submit_for_open(ring, &opening, free_idx)?;
+                                openings.insert(free_idx, opening);
+                                new_work_queued = true;
+                            }
+                            Err(err) => {
+                                free_index_list.push(free_idx);
+                                tx.send((path.to_owned(), Err(err)).into())?;
+                            }
+                        }
+                        continue;
+                    }
+
+                    // Queue a read with this file:
+                    let buffer = match Buffer::new(path, new_digest(), stat_on_ring, config) {
+                        Ok(buffer) => buffer,
+                        Err(err) => {
+                            // We didn't use this buffer index
+                            free_index_list.push(free_idx);
+                            tx.send((path.to_owned(), Err(err)).into())?;
+                            continue;
+                        }
+                    };
+
+                    shared_buffers.insert(free_idx, buffer);
+                    debug_assert_eq!(
+                        free_index_list.len(),
+                        slots - shared_buffers.len() - openings.len(),
+                        "The free index list is out of sync with the work buffers (1)"
+                    );
+                    let buffer_ref = shared_buffers.get_mut(&free_idx).unwrap();
+                    new_work_queued = true;
+                    submit_first(ring, buffer_ref, free_idx)?;
+                } else {
+                    // We didn't use this buffer index
+                    free_index_list.push(free_idx);
+                    break;
+                }
+            }
+
+            if new_work_queued || files.peek().is_some() {
+                if files.peek().is_some() {
+                    debug_assert_eq!(
+                        free_index_list.len(),
+                        0,
+                        "We should have filled all the slots"
+                    );
+                }
+
+                // Wait for a result since the jobs list is full or we just added something
+                trace!("Waiting for / handling a result");
                 submit_wait_and_handle_result(
-                    &mut ring,
-                    &mut shared_buffers,
+                    ring,
+                    shared_buffers,
+                    openings,
                     &tx,
+                    pool.as_ref(),
                     &mut free_index_list,
+                    slots,
+                    stat_on_ring,
+                    config,
                 )?;
+            } else {
+                // There's no more work that can be added right now, but we still need to handle any
+                // active buffers
+                while free_index_list.len() < slots {
+                    trace!(
+                        "Did not submit work, waiting for old work. {}/{} free indices",
+                        free_index_list.len(),
+                        slots
+                    );
+                    submit_wait_and_handle_result(
+                        ring,
+                        shared_buffers,
+                        openings,
+                        &tx,
+                        pool.as_ref(),
+                        &mut free_index_list,
+                        slots,
+                        stat_on_ring,
+                        config,
+                    )?;
+                    forward_hashed(pool_rx, &tx)?;
+                }
+                break;
             }
-            break;
         }
+
+        // Wait for the workers to finish hashing, but keep them for the next batch:
+        if let Some(pool) = pool {
+            pool.wait();
+        }
+        forward_hashed(pool_rx, &tx)
     }
+}
+
+impl StrategySession for Session<Hasher> {
+    fn checksum(&mut self, files: Vec<PathBuf>, tx: &dyn ResultSink<ChecksumResult>) -> Result<()> {
+        let algorithm = self.config.algorithm;
+        self.run(files, tx, || Hasher::new(algorithm))
+    }
+}
 
+/// Pass on the results the hashing workers have sent.
+fn forward_hashed<D, R: From<(PathBuf, Result<D>)>>(
+    rx: &Receiver<(PathBuf, Result<D>)>,
+    tx: &impl ResultSink<R>,
+) -> Result<()> {
+    for (path, result) in rx.try_iter() {
+        tx.send((path, result).into())?;
+    }
     Ok(())
 }
 
-fn submit_wait_and_handle_result(
-    ring: &mut IoUring,
-    shared_buffers: &mut HashMap<usize, Buffer>,
-    tx: &Sender<(PathBuf, Result<md5::Md5, anyhow::Error>)>,
+#[allow(clippy::too_many_arguments)]
+fn submit_wait_and_handle_result<D, R>(
+    ring: &mut Ring,
+    shared_buffers: &mut HashMap<usize, Buffer<D>>,
+    openings: &mut HashMap<usize, Opening<D>>,
+    tx: &impl ResultSink<R>,
+    pool: Option<&HashPool<D>>,
     free_index_list: &mut Vec<usize>,
-) -> Result<()> {
+    slots: usize,
+    stat_on_ring: bool,
+    config: &Config,
+) -> Result<()>
+where
+    D: Update + Send + 'static,
+    R: From<(PathBuf, Result<D>)>,
+{
     debug_assert_eq!(
         free_index_list.len(),
-        RING_SIZE - shared_buffers.len(),
+        slots - shared_buffers.len() - openings.len(),
         "The free index list is out of sync with the work buffers (2)"
     );
 
-    ring.submit_and_wait(1)?;
-    let completed_idx = ring
-        .completion()
-        .next()
-        .expect("completion queue is empty")
-        .user_data() as usize;
+    let cqe = ring.next_completion().map_err(ChecksumError::Completion)?;
+    let completed_idx = (cqe.user_data() >> CHUNK_BITS) as usize;
+    let chunk_idx = (cqe.user_data() & ((1 << CHUNK_BITS) - 1)) as usize;
+
+    if let Some(mut opening) = openings.remove(&completed_idx) {
+        if cqe.result() == -libc::EINVAL && opening.o_direct {
+            debug!(
+                "{:?} can't be opened with O_DIRECT, so it's opened without it.",
+                opening.path
+            );
+            opening.o_direct = false;
+            submit_for_open(ring, &opening, completed_idx)?;
+            openings.insert(completed_idx, opening);
+            return Ok(());
+        }
+        // The result of an open is the new descriptor:
+        let buffer = if cqe.result() < 0 {
+            let err = io::Error::from_raw_os_error(-cqe.result());
+            Err(ChecksumError::open_failed(&opening.path, err).into())
+        } else if opening.into_slot {
+            // The file is in the slot of the table with the same index:
+            Ok(Buffer::from_slot(
+                &opening.path,
+                completed_idx as u32,
+                opening.ctx,
+                config,
+            ))
+        } else {
+            // unsafe: the descriptor was just opened for us and nothing else owns it:
+            let fd = unsafe { File::from_raw_fd(cqe.result()) };
+            Buffer::from_file(&opening.path, fd, opening.ctx, stat_on_ring, config)
+        };
+        match buffer {
+            Ok(buffer) => {
+                shared_buffers.insert(completed_idx, buffer);
+                submit_first(
+                    ring,
+                    shared_buffers.get_mut(&completed_idx).unwrap(),
+                    completed_idx,
+                )?;
+            }
+            Err(err) => {
+                free_index_list.push(completed_idx);
+                tx.send((opening.path, Err(err)).into())?;
+            }
+        }
+        return Ok(());
+    }
 
-    // Next, consume and handle bytes in the buffer:
-    let mut buffer = shared_buffers
+    let buffer = shared_buffers
         .get_mut(&completed_idx)
-        .expect("should exist because we chose its index");
+        .ok_or_else(|| ChecksumError::unexpected_completion(cqe.user_data()))?;
 
-    buffer.position += buffer.buf.len() as u64;
+    if let Some(statx) = buffer.statx.take() {
+        if cqe.result() < 0 {
+            let buffer = shared_buffers.remove(&completed_idx).unwrap();
+            free_index_list.push(completed_idx);
+            let err = io::Error::from_raw_os_error(-cqe.result());
+            tx.send((buffer.path, Err(err.into())).into())?;
+        } else {
+            buffer.file_len = statx.stx_size;
+            submit_reads(ring, buffer, completed_idx)?;
+        }
+        return Ok(());
+    }
 
+    // Next, note the chunk that was read. A file of unknown length is read until a read returns
+    // nothing, so only those reads' results are needed:
+    buffer.in_flight -= 1;
+    let chunk = &mut buffer.chunks[chunk_idx];
+    if let Some(err) = ring.timeout_error(cqe.result()) {
+        chunk.state = ChunkState::Free;
+        buffer.error.get_or_insert(err.into());
+    } else if cqe.result() < 0 {
+        chunk.state = ChunkState::Free;
+        let err = io::Error::from_raw_os_error(-cqe.result());
+        buffer.error.get_or_insert(err.into());
+    } else {
+        // Reads ask for whole blocks, so the kernel may go past the end of a file that grew:
+        let read_len = min(cqe.result() as usize, chunk.buf.len() - chunk.filled);
+        chunk.filled += read_len;
+        if chunk.filled == chunk.buf.len() || buffer.file_len == UNKNOWN_LEN {
+            chunk.state = ChunkState::Read(chunk.filled);
+        } else if read_len == 0 {
+            chunk.state = ChunkState::Free;
+            let end = chunk.offset + chunk.filled as u64;
+            buffer
+                .error
+                .get_or_insert(ended_early(end, buffer.file_len));
+        } else {
+            // A short read, as from a network filesystem or when a signal arrives. The rest is
+            // read into the same chunk:
+            trace!(
+                "Read {} of {} bytes at {}, so reading the rest ({:?})",
+                chunk.filled,
+                chunk.buf.len(),
+                chunk.offset,
+                &buffer.path,
+            );
+            submit_rest(ring, buffer, completed_idx, chunk_idx)?;
+            return Ok(());
+        }
+    }
+
+    if config.cancel.is_cancelled() {
+        buffer.error.get_or_insert(cancel::cancelled().into());
+    }
+    if buffer.error.is_some() {
+        // The file's buffers can only be freed once none of its reads are in flight:
+        if buffer.in_flight == 0 {
+            let mut buffer = shared_buffers.remove(&completed_idx).unwrap();
+            free_index_list.push(completed_idx);
+            let err = buffer.error.take();
+            buffer.close(ring, pool, completed_idx, tx, err)?;
+        }
+        return Ok(());
+    }
+
+    // Submit the next reads before hashing, so the kernel works on them meanwhile:
+    if submit_reads(ring, buffer, completed_idx)? > 0 {
+        ring.submit().map_err(ChecksumError::Submit)?;
+    }
+    buffer.hash_ready(pool, completed_idx);
+    let total = if buffer.file_len == UNKNOWN_LEN {
+        0
+    } else {
+        buffer.file_len
+    };
+    config.report_progress(&buffer.path, buffer.position, total);
     trace!(
-        "Incorporating {} bytes into checksum. Finished?: {} ({:?})",
-        buffer.buf.len(),
-        buffer.position as u64 + buffer.buf.len() as u64 == buffer.file_len,
+        "Hashed {} bytes. Finished?: {} ({:?})",
+        buffer.position,
+        buffer.finished(),
         &buffer.path,
     );
-    buffer.ctx.update(&*buffer.buf);
-    buffer.set_buffer_size();
-    if buffer.buf.len() == 0 {
+    if buffer.finished() {
         // It's finished, so free the slot (and get an owned object):
         let buffer = shared_buffers.remove(&completed_idx).unwrap();
         free_index_list.push(completed_idx);
         debug_assert_eq!(
             free_index_list.len(),
-            RING_SIZE - shared_buffers.len(),
+            slots - shared_buffers.len() - openings.len(),
             "The free index list is out of sync with the work buffers (3)"
         );
-        tx.send((buffer.path, Ok(buffer.ctx))).unwrap();
+        buffer.close(ring, pool, completed_idx, tx, None)?;
     } else {
         trace!("Checksum not finished, resubmitting for read");
-        submit_for_read(
-            ring,
-            shared_buffers.get_mut(&completed_idx).unwrap(),
-            completed_idx,
-        );
+        submit_reads(ring, buffer, completed_idx)?;
     }
 
     Ok(())
 }
 
-/// Put a job in the read queue and submit it to the kernel. The buffer struct tracks
-/// how much has been read already and how much more is needed.
-fn submit_for_read(ring: &mut IoUring, buffer_ref: &mut Buffer, idx: usize) {
+/// Queue the first request for a file: a Statx to learn its length if that's looked up through
+/// the ring, or else its first reads.
+fn submit_first<D>(ring: &mut Ring, buffer_ref: &mut Buffer<D>, idx: usize) -> Result<()> {
+    let fd = match &buffer_ref.fd {
+        OpenFile::Fd(fd) => fd,
+        OpenFile::Slot(slot) => {
+            ring.advise_sequential_slot(*slot)?;
+            return submit_reads(ring, buffer_ref, idx).map(drop);
+        }
+    };
+    ring.advise_sequential(fd)?;
+    let Some(statx) = buffer_ref.statx.as_mut() else {
+        return submit_reads(ring, buffer_ref, idx).map(drop);
+    };
+    let statx_e = ring::statx_entry(fd.as_raw_fd(), statx).user_data(user_data(idx, 0));
+
+    unsafe { ring.push(&statx_e).map_err(ChecksumError::Submit)? };
+    Ok(())
+}
+
+/// Queue a read into each free chunk of a file, up to the end of the file and the most reads
+/// that may be in flight. A file of unknown length has one read at a time, and an empty file gets
+/// one read of nothing, whose completion finishes it. Returns how many reads were queued.
+fn submit_reads<D>(ring: &mut Ring, buffer_ref: &mut Buffer<D>, idx: usize) -> Result<usize> {
+    let mut queued = 0;
+    for chunk_idx in 0..buffer_ref.chunks.len() {
+        if buffer_ref.in_flight >= buffer_ref.max_in_flight {
+            break;
+        }
+        if buffer_ref.chunks[chunk_idx].state != ChunkState::Free {
+            continue;
+        }
+        let capacity = buffer_ref.chunks[chunk_idx].buf.capacity() as u64;
+        let len = if buffer_ref.file_len == UNKNOWN_LEN {
+            if buffer_ref.in_flight > 0 || buffer_ref.eof {
+                break;
+            }
+            buffer_ref.next_read = buffer_ref.position;
+            capacity
+        } else if buffer_ref.next_read < buffer_ref.file_len {
+            min(buffer_ref.file_len - buffer_ref.next_read, capacity)
+        } else if buffer_ref.file_len == 0
+            && buffer_ref
+                .chunks
+                .iter()
+                .all(|chunk| chunk.state == ChunkState::Free)
+        {
+            0
+        } else {
+            break;
+        };
+        submit_for_read(ring, buffer_ref, idx, chunk_idx, len as usize)?;
+        queued += 1;
+    }
+    Ok(queued)
+}
+
+/// Put a read of `len` bytes from where the last one ended into a chunk in the queue, to be
+/// submitted with the next batch.
+fn submit_for_read<D>(
+    ring: &mut Ring,
+    buffer_ref: &mut Buffer<D>,
+    idx: usize,
+    chunk_idx: usize,
+    len: usize,
+) -> Result<()> {
+    let chunk = &mut buffer_ref.chunks[chunk_idx];
+    chunk.buf.resize(len);
+    chunk.offset = buffer_ref.next_read;
+    chunk.filled = 0;
+    buffer_ref.next_read += len as u64;
+    submit_rest(ring, buffer_ref, idx, chunk_idx)
+}
+
+/// Put a read of the part of a chunk that hasn't arrived yet in the queue. The length is rounded
+/// up to whole blocks, so it stays aligned for O_DIRECT at the end of the file.
+fn submit_rest<D>(
+    ring: &mut Ring,
+    buffer_ref: &mut Buffer<D>,
+    idx: usize,
+    chunk_idx: usize,
+) -> Result<()> {
+    let chunk = &mut buffer_ref.chunks[chunk_idx];
+    chunk.state = ChunkState::Reading;
+    buffer_ref.in_flight += 1;
+
     // get data uring needs to queue a read:
-    let raw_fd = buffer_ref.fd.as_raw_fd();
-    let buf = &mut buffer_ref.buf;
-    let read_e = opcode::Read::new(types::Fd(raw_fd), buf.as_mut_ptr(), buf.len() as _)
-        .offset(buffer_ref.position as i64)
+    let wanted = chunk.buf.len() - chunk.filled;
+    let len = wanted
+        .next_multiple_of(ALIGNMENT)
+        .min(chunk.buf.capacity() - chunk.filled) as u32;
+    // unsafe: the read stays within the buffer's capacity:
+    let ptr = unsafe { chunk.buf.as_mut_ptr().add(chunk.filled) };
+    let offset = chunk.offset + chunk.filled as u64;
+    let read = |rw_flags| {
+        match &buffer_ref.fd {
+            OpenFile::Fd(fd) => opcode::Read::new(types::Fd(fd.as_raw_fd()), ptr, len),
+            OpenFile::Slot(slot) => opcode::Read::new(types::Fixed(*slot), ptr, len),
+        }
+        .offset(offset)
+        .rw_flags(rw_flags)
         .build()
-        .user_data(idx as u64);
+        .user_data(user_data(idx, chunk_idx))
+    };
 
     unsafe {
-        ring.submission()
-            .push(&read_e)
-            .expect("submission queue is full");
+        ring.push_read_with(wanted as u32, read)
+            .map_err(ChecksumError::Submit)?
+    };
+    progress::add_read();
+    Ok(())
+}
+
+/// Put an open of a file in the queue. Its result is the descriptor, or an error. A file opened
+/// into a slot of the file table has no descriptor, and its result is 0.
+fn submit_for_open<D>(ring: &mut Ring, opening: &Opening<D>, idx: usize) -> Result<()> {
+    let mut flags = libc::O_RDONLY;
+    // A file in a slot has no descriptor to close on exec, and the kernel rejects the flag:
+    if !opening.into_slot {
+        flags |= libc::O_CLOEXEC;
     }
+    if opening.o_direct {
+        flags |= libc::O_DIRECT;
+    }
+    let slot = opening.into_slot.then(|| {
+        types::DestinationSlot::try_from_slot_target(idx as u32)
+            .expect("the ring is smaller than the largest slot")
+    });
+    let open_e = opcode::OpenAt::new(types::Fd(libc::AT_FDCWD), opening.c_path.as_ptr())
+        .file_index(slot)
+        .flags(flags)
+        .build()
+        .user_data(user_data(idx, 0));
+
+    unsafe { ring.push(&open_e).map_err(ChecksumError::Submit)? };
+    Ok(())
 }
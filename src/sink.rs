@@ -0,0 +1,60 @@
+// This module lets results be sent to whatever the caller reads them from, so a program built on
+// crossbeam or flume channels, or one that wants a callback, gets each file's result straight from
+// the strategy instead of through a thread that moves it from one channel to another.
+use std::sync::mpsc::{Sender, SyncSender};
+
+use anyhow::Result;
+
+use crate::ChecksumError;
+
+/// Somewhere the strategies send each file's result. It's shared between the threads of a run,
+/// so it must be `Sync`.
+pub trait ResultSink<T>: Send + Sync {
+    /// Hand over one result. An error, usually `ChecksumError::SinkClosed`, means nothing is
+    /// receiving results any more.
+    fn send(&self, result: T) -> Result<()>;
+}
+
+fn hung_up<E>(_: E) -> anyhow::Error {
+    ChecksumError::SinkClosed.into()
+}
+
+impl<T: Send> ResultSink<T> for Sender<T> {
+    fn send(&self, result: T) -> Result<()> {
+        Sender::send(self, result).map_err(hung_up)
+    }
+}
+
+impl<T: Send> ResultSink<T> for SyncSender<T> {
+    fn send(&self, result: T) -> Result<()> {
+        SyncSender::send(self, result).map_err(hung_up)
+    }
+}
+
+/// A callback, which is called on whichever thread finished the file.
+impl<T> ResultSink<T> for Box<dyn Fn(T) + Send + Sync + '_> {
+    fn send(&self, result: T) -> Result<()> {
+        self(result);
+        Ok(())
+    }
+}
+
+impl<T, S: ResultSink<T> + ?Sized> ResultSink<T> for &S {
+    fn send(&self, result: T) -> Result<()> {
+        (**self).send(result)
+    }
+}
+
+#[cfg(feature = "crossbeam-channel")]
+impl<T: Send> ResultSink<T> for crossbeam_channel::Sender<T> {
+    fn send(&self, result: T) -> Result<()> {
+        crossbeam_channel::Sender::send(self, result).map_err(hung_up)
+    }
+}
+
+#[cfg(feature = "flume")]
+impl<T: Send> ResultSink<T> for flume::Sender<T> {
+    fn send(&self, result: T) -> Result<()> {
+        flume::Sender::send(self, result).map_err(hung_up)
+    }
+}
@@ -0,0 +1,114 @@
+// This module verifies data the caller already has in memory, such as a download in progress,
+// against an expected digest.
+use std::io;
+
+use anyhow::{bail, Result};
+#[allow(unused_imports)]
+use log::{debug, error, info, trace, warn};
+
+use crate::*;
+
+/// How much data has been verified so far.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Progress {
+    pub bytes: u64,
+    /// The expected length, if the caller gave one
+    pub total: Option<u64>,
+}
+
+/// The outcome of a verification.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Verdict {
+    Match,
+    Mismatch {
+        /// The expected digest in lowercase hexadecimal
+        expected: String,
+        /// The digest of the data that was pushed, in lowercase hexadecimal
+        actual: String,
+    },
+}
+
+impl Verdict {
+    pub fn is_match(&self) -> bool {
+        *self == Verdict::Match
+    }
+}
+
+/// Hash data as it arrives and compare it with an expected digest at the end. This also
+/// implements `Write`, so it can be the destination of `io::copy`.
+pub struct StreamVerifier {
+    ctx: Hasher,
+    expected: Vec<u8>,
+    progress: Progress,
+}
+
+impl StreamVerifier {
+    /// Start verifying against a digest in hexadecimal, as md5sum and the like print it.
+    pub fn new(algorithm: HashAlgorithm, expected_hex: &str) -> Result<StreamVerifier> {
+        let Some(expected) = from_hex(expected_hex) else {
+            bail!("{:?} is not a hexadecimal digest", expected_hex);
+        };
+        Self::from_digest(algorithm, &expected)
+    }
+
+    /// Start verifying against a raw digest.
+    pub fn from_digest(algorithm: HashAlgorithm, expected: &[u8]) -> Result<StreamVerifier> {
+        if expected.len() != algorithm.output_len() {
+            bail!(
+                "A {} digest is {} bytes, not {}",
+                algorithm,
+                algorithm.output_len(),
+                expected.len()
+            );
+        }
+        Ok(StreamVerifier {
+            ctx: Hasher::new(algorithm),
+            expected: expected.to_vec(),
+            progress: Progress {
+                bytes: 0,
+                total: None,
+            },
+        })
+    }
+
+    /// Set how many bytes are expected, so progress can be reported as a fraction.
+    pub fn with_total(mut self, total: u64) -> StreamVerifier {
+        self.progress.total = Some(total);
+        self
+    }
+
+    /// Hash the next chunk of data.
+    pub fn push(&mut self, data: &[u8]) -> Progress {
+        self.ctx.update(data);
+        self.progress.bytes += data.len() as u64;
+        self.progress
+    }
+
+    pub fn progress(&self) -> Progress {
+        self.progress
+    }
+
+    /// Compare everything pushed so far with the expected digest.
+    pub fn finish(self) -> Verdict {
+        let actual = self.ctx.finalize();
+        if actual[..] == self.expected[..] {
+            Verdict::Match
+        } else {
+            Verdict::Mismatch {
+                expected: to_hex(&self.expected),
+                actual: to_hex(&actual),
+            }
+        }
+    }
+}
+
+impl io::Write for StreamVerifier {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.push(buf);
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
@@ -0,0 +1,91 @@
+// This module makes the piece hashes of a BitTorrent v1 torrent. The files are taken as one stream
+// in the order they're given, which is the order of the torrent's file list, and the stream is cut
+// into pieces of the piece length that are each hashed with SHA-1. A piece can span the end of one
+// file and the start of the next, so the files are read one at a time, in order.
+use std::path::PathBuf;
+
+use anyhow::{anyhow, Result};
+#[allow(unused_imports)]
+use log::{debug, error, info, trace, warn};
+use sha1::{Digest, Sha1};
+
+use crate::{manifest::display_path, *};
+
+/// The smallest piece length clients commonly accept.
+pub const MIN_PIECE_LENGTH: u64 = 16 * 1024;
+
+/// The length of a SHA-1 piece hash.
+pub const PIECE_HASH_LEN: usize = 20;
+
+/// Hashes a stream of data a piece at a time.
+pub struct PieceHasher {
+    piece_length: u64,
+    ctx: Sha1,
+    /// How many bytes of the current piece have been hashed
+    filled: u64,
+    hashes: Vec<[u8; PIECE_HASH_LEN]>,
+}
+
+impl PieceHasher {
+    pub fn new(piece_length: u64) -> PieceHasher {
+        assert!(piece_length > 0, "The piece length must not be zero");
+        PieceHasher {
+            piece_length,
+            ctx: Sha1::new(),
+            filled: 0,
+            hashes: Vec::new(),
+        }
+    }
+
+    pub fn update(&mut self, mut data: &[u8]) {
+        while !data.is_empty() {
+            let needed = (self.piece_length - self.filled).min(data.len() as u64);
+            let (now, later) = data.split_at(needed as usize);
+            self.ctx.update(now);
+            self.filled += needed;
+            data = later;
+            if self.filled == self.piece_length {
+                self.hashes.push(self.ctx.finalize_reset().into());
+                self.filled = 0;
+            }
+        }
+    }
+
+    /// The hash of each piece, the last of which may be shorter than the piece length.
+    pub fn finish(mut self) -> Vec<[u8; PIECE_HASH_LEN]> {
+        if self.filled > 0 {
+            self.hashes.push(self.ctx.finalize().into());
+        }
+        self.hashes
+    }
+}
+
+/// The pieces of a set of files.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Pieces {
+    pub piece_length: u64,
+    /// The length of all the files together
+    pub total_length: u64,
+    pub hashes: Vec<[u8; PIECE_HASH_LEN]>,
+}
+
+/// Read `files` in order and hash their pieces. Every file must be read in full for the pieces
+/// after it to be right, so this fails at the first file that can't be.
+pub fn piece_hashes(files: &[PathBuf], piece_length: u64, o_direct: bool) -> Result<Pieces> {
+    let mut hasher = PieceHasher::new(piece_length);
+    let mut total_length = 0;
+    for path in files {
+        read_whole(path, o_direct, |data| {
+            hasher.update(data);
+            total_length += data.len() as u64;
+            progress::add_bytes(data.len() as u64);
+            Ok(true)
+        })
+        .map_err(|err| anyhow!("{}: {}", display_path(path), err))?;
+    }
+    Ok(Pieces {
+        piece_length,
+        total_length,
+        hashes: hasher.finish(),
+    })
+}
@@ -0,0 +1,128 @@
+// This module folds the digests of every file under a directory into one root digest, like the
+// root of a Merkle tree, so two copies of a dataset can be compared by exchanging one line. Each
+// directory's digest is the digest of its listing: a checksum line for each entry, sorted by name,
+// where a subdirectory's line has the subdirectory's digest and its name ends with a slash. Only
+// regular files are listed, so a directory with no files under it doesn't change the root digest.
+use std::{
+    collections::BTreeMap,
+    ffi::OsString,
+    path::{Component, Path, PathBuf},
+    sync::mpsc::channel,
+    thread,
+};
+
+use anyhow::{anyhow, Result};
+#[allow(unused_imports)]
+use log::{debug, error, info, trace, warn};
+
+use crate::{
+    input::Excludes,
+    manifest::{display_path, format_line},
+    *,
+};
+
+enum Node {
+    File(Vec<u8>),
+    Dir(BTreeMap<OsString, Node>),
+}
+
+impl Node {
+    fn digest(&self, algorithm: HashAlgorithm) -> Vec<u8> {
+        let entries = match self {
+            Node::File(digest) => return digest.clone(),
+            Node::Dir(entries) => entries,
+        };
+        let mut hasher = Hasher::new(algorithm);
+        for (name, node) in entries {
+            let mut name = name.clone();
+            if let Node::Dir(_) = node {
+                name.push("/");
+            }
+            let line = format_line(&to_hex(&node.digest(algorithm)), Path::new(&name));
+            hasher.update(line);
+        }
+        hasher.finalize()
+    }
+}
+
+/// The root digest of the files with these digests, given by their paths relative to the root.
+pub fn fold(algorithm: HashAlgorithm, files: &BTreeMap<PathBuf, Vec<u8>>) -> Result<Vec<u8>> {
+    let mut root = BTreeMap::new();
+    for (path, digest) in files {
+        let mut names = path.components().map(|component| match component {
+            Component::Normal(name) => Ok(name.to_owned()),
+            _ => Err(anyhow!("{} is not a relative path", path.display())),
+        });
+        let Some(mut name) = names.next().transpose()? else {
+            return Err(anyhow!("A file has an empty path"));
+        };
+        let mut dir = &mut root;
+        for next in names {
+            let node = dir
+                .entry(name)
+                .or_insert_with(|| Node::Dir(BTreeMap::new()));
+            let Node::Dir(entries) = node else {
+                return Err(anyhow!("{} is under a file", path.display()));
+            };
+            dir = entries;
+            name = next?;
+        }
+        dir.insert(name, Node::File(digest.clone()));
+    }
+    Ok(Node::Dir(root).digest(algorithm))
+}
+
+/// Hash every file under `dir` as `config` says, leaving out what `excludes` matches, and fold
+/// the digests into the root digest. If any file can't be read, its error is printed and there's
+/// no root digest.
+pub fn digest_dir(
+    dir: &Path,
+    excludes: &Excludes,
+    follow_links: bool,
+    max_depth: Option<usize>,
+    config: &Config,
+) -> Result<Vec<u8>> {
+    if !dir.is_dir() {
+        return Err(anyhow!("not a directory"));
+    }
+    let mut files = input::walk(dir, excludes, follow_links, max_depth);
+    // A pipe or device has no length to hash up to, and a pipe with no writer is waited on
+    // forever, so only regular files are part of the tree:
+    files.retain(|path| {
+        let special = input::is_special(path);
+        if special {
+            eprintln!("{}: not a regular file, skipping", display_path(path));
+        }
+        !special
+    });
+
+    let (tx, rx) = channel();
+    let thread_config = config.clone();
+    let handle = thread::spawn(move || get_checksums(files, tx, &thread_config));
+    let mut digests = BTreeMap::new();
+    let mut failed = 0;
+    for result in rx {
+        match result.error {
+            None => {
+                let relative = result.path.strip_prefix(dir)?.to_owned();
+                digests.insert(relative, result.digest);
+            }
+            Some(err) => {
+                failed += 1;
+                eprintln!("{}: {}", display_path(&result.path), err);
+            }
+        }
+    }
+    handle
+        .join()
+        .map_err(|_| anyhow!("the strategy panicked"))??;
+
+    if failed > 0 {
+        return Err(anyhow!(
+            "{} {} could not be read",
+            failed,
+            if failed == 1 { "file" } else { "files" }
+        ));
+    }
+    fold(config.algorithm, &digests)
+}
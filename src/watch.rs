@@ -0,0 +1,254 @@
+// This module watches a directory tree with fanotify and verifies files against a checksum list
+// as they're opened, which makes a real-time integrity monitor out of the hashing engine.
+use std::{
+    collections::HashMap,
+    ffi::CString,
+    fs::{self, File},
+    io::{self, Read, Write},
+    mem::size_of,
+    os::unix::{
+        ffi::OsStrExt,
+        fs::MetadataExt,
+        io::{AsRawFd, FromRawFd},
+    },
+    path::{Path, PathBuf},
+    ptr, slice,
+};
+
+use anyhow::{bail, Result};
+#[allow(unused_imports)]
+use log::{debug, error, info, trace, warn};
+
+use crate::{
+    diagnose::describe_first_mismatch,
+    manifest::{display_path, read_checksum_list, ManifestEntry},
+    read_sequentially, to_hex, HashAlgorithm, Hasher,
+};
+
+/// What was decided about a file, remembered until the file changes.
+struct Verdict {
+    mtime: (i64, i64),
+    len: u64,
+    matches: bool,
+}
+
+/// Verify each file listed in `manifest` with `algorithm` whenever it is opened under `dir`.
+/// Mismatches are printed, and if `deny` is set, the open fails with EPERM. If `diagnose` is set,
+/// the first block that differs is printed too. This runs until it's killed, and
+/// needs root (CAP_SYS_ADMIN).
+pub fn run(
+    dir: &Path,
+    manifest: &Path,
+    algorithm: HashAlgorithm,
+    deny: bool,
+    diagnose: bool,
+) -> Result<()> {
+    let dir = fs::canonicalize(dir)?;
+    let cwd = std::env::current_dir()?;
+    // A digest of the wrong length was made with a different algorithm, and would fail its file
+    // on every open:
+    let checksum_list = read_checksum_list(manifest, algorithm.output_len() * 2)?;
+    if let Some(line_number) = checksum_list.malformed.first() {
+        bail!(
+            "{}: {}: improperly formatted {} checksum line",
+            display_path(manifest),
+            line_number,
+            algorithm.name().to_uppercase()
+        );
+    }
+    // Event paths are absolute and resolved, so resolve the manifest paths the same way:
+    let expected: HashMap<PathBuf, ManifestEntry> = checksum_list
+        .entries
+        .into_iter()
+        .map(|entry| {
+            let path = cwd.join(&entry.path);
+            (fs::canonicalize(&path).unwrap_or(path), entry)
+        })
+        .collect();
+
+    // Only the content class may answer permission events:
+    let class = if deny {
+        libc::FAN_CLASS_CONTENT
+    } else {
+        libc::FAN_CLASS_NOTIF
+    };
+    let fan_fd = unsafe {
+        libc::fanotify_init(
+            class | libc::FAN_CLOEXEC,
+            (libc::O_RDONLY | libc::O_LARGEFILE) as libc::c_uint,
+        )
+    };
+    if fan_fd < 0 {
+        bail!(
+            "Could not start fanotify (are you running without root?): {}",
+            io::Error::last_os_error()
+        );
+    }
+    let mut fan = unsafe { File::from_raw_fd(fan_fd) };
+
+    let mask = if deny {
+        libc::FAN_OPEN_PERM
+    } else {
+        libc::FAN_OPEN
+    };
+    let c_dir = CString::new(dir.as_os_str().as_bytes())?;
+    // A directory mark only covers its immediate children, so watch the whole mount and filter
+    // events by path instead:
+    let marked = unsafe {
+        libc::fanotify_mark(
+            fan_fd,
+            libc::FAN_MARK_ADD | libc::FAN_MARK_MOUNT,
+            mask,
+            libc::AT_FDCWD,
+            c_dir.as_ptr(),
+        )
+    };
+    if marked != 0 {
+        bail!(
+            "Could not watch {}: {}",
+            dir.display(),
+            io::Error::last_os_error()
+        );
+    }
+    info!(
+        "Watching {} for opens of {} listed files",
+        dir.display(),
+        expected.len()
+    );
+
+    let our_pid = std::process::id() as i32;
+    let mut verdicts: HashMap<PathBuf, Verdict> = HashMap::new();
+    let mut events = vec![0u8; 64 * 1024];
+    loop {
+        let len = match fan.read(&mut events) {
+            Ok(len) => len,
+            Err(err) if err.kind() == io::ErrorKind::Interrupted => continue,
+            Err(err) => return Err(err.into()),
+        };
+
+        let mut offset = 0;
+        while offset + size_of::<libc::fanotify_event_metadata>() <= len {
+            // unsafe: the kernel wrote whole metadata structs, but the buffer isn't aligned:
+            let metadata: libc::fanotify_event_metadata =
+                unsafe { ptr::read_unaligned(events[offset..].as_ptr() as *const _) };
+            if metadata.vers != libc::FANOTIFY_METADATA_VERSION {
+                bail!("Unsupported fanotify metadata version {}", metadata.vers);
+            }
+            offset += metadata.event_len as usize;
+
+            if metadata.fd < 0 {
+                warn!("The fanotify queue overflowed, so some opens were not verified.");
+                continue;
+            }
+            // Take ownership so the event's descriptor gets closed:
+            let file = unsafe { File::from_raw_fd(metadata.fd) };
+
+            // Our own reads go through the event's descriptor and never open a watched file, but
+            // never block ourselves just in case:
+            let allow = metadata.pid == our_pid
+                || verify(&file, &dir, &expected, &mut verdicts, algorithm, diagnose);
+            if deny {
+                respond(&mut fan, &file, allow)?;
+            }
+        }
+    }
+}
+
+/// Returns whether the open should be allowed.
+fn verify(
+    file: &File,
+    dir: &Path,
+    expected: &HashMap<PathBuf, ManifestEntry>,
+    verdicts: &mut HashMap<PathBuf, Verdict>,
+    algorithm: HashAlgorithm,
+    diagnose: bool,
+) -> bool {
+    let path = match fs::read_link(format!("/proc/self/fd/{}", file.as_raw_fd())) {
+        Ok(path) => path,
+        Err(err) => {
+            debug!("Could not find the path of an opened file: {}", err);
+            return true;
+        }
+    };
+    if !path.starts_with(dir) {
+        return true;
+    }
+    let Some(expected) = expected.get(&path) else {
+        trace!("{} is not in the checksum list", path.display());
+        return true;
+    };
+
+    let metadata = match file.metadata() {
+        Ok(metadata) => metadata,
+        Err(err) => {
+            println!("{}: FAILED open or read ({})", path.display(), err);
+            return false;
+        }
+    };
+    let mtime = (metadata.mtime(), metadata.mtime_nsec());
+    if let Some(verdict) = verdicts.get(&path) {
+        if verdict.mtime == mtime && verdict.len == metadata.len() {
+            trace!("{} is unchanged since it was verified", path.display());
+            return verdict.matches;
+        }
+    }
+
+    // Hash through the event's descriptor, since opening the path again would wait on ourselves:
+    let mut ctx = Hasher::new(algorithm);
+    let hashed = read_sequentially(file, 0, metadata.len(), |data| {
+        ctx.update(data);
+        Ok(true)
+    });
+    let actual = match hashed {
+        Ok(()) => to_hex(&ctx.finalize()),
+        Err(err) => {
+            println!("{}: FAILED open or read ({})", path.display(), err);
+            return false;
+        }
+    };
+
+    let matches = actual == expected.digest;
+    if matches {
+        info!("{}: OK", path.display());
+    } else {
+        println!(
+            "{}: FAILED (expected {}, got {})",
+            path.display(),
+            expected.digest,
+            actual
+        );
+        if diagnose {
+            println!("    {}", describe_first_mismatch(file, expected, algorithm));
+        }
+    }
+    verdicts.insert(
+        path,
+        Verdict {
+            mtime,
+            len: metadata.len(),
+            matches,
+        },
+    );
+    matches
+}
+
+/// Answer a permission event.
+fn respond(fan: &mut File, file: &File, allow: bool) -> Result<()> {
+    let response = libc::fanotify_response {
+        fd: file.as_raw_fd(),
+        response: if allow {
+            libc::FAN_ALLOW
+        } else {
+            libc::FAN_DENY
+        },
+    };
+    // unsafe: the struct is plain old data:
+    let bytes = unsafe {
+        slice::from_raw_parts(
+            &response as *const _ as *const u8,
+            size_of::<libc::fanotify_response>(),
+        )
+    };
+    fan.write_all(bytes)?;
+    Ok(())
+}
@@ -1,22 +1,18 @@
-// This module pre-registers files and buffers with io_uring before the reads start.
+// This module registers buffers with io_uring before the reads start, and files as their reads
+// start.
 use std::{
-    cmp::min,
-    fs::File,
-    hash::BuildHasherDefault,
-    os::unix::io::AsRawFd,
-    path::{Path, PathBuf},
+    cmp::min, fs::File, hash::BuildHasherDefault, io, os::unix::io::AsRawFd, path::PathBuf,
     pin::Pin,
-    sync::mpsc::Sender,
 };
 
 use anyhow::{bail, Result};
-use io_uring::{opcode, types, IoUring, Probe};
+use io_uring::{opcode, types, Probe};
 #[allow(unused_imports)]
 use log::{debug, error, info, trace, warn};
-use md5::{Digest, Md5};
+use md5::digest::Update;
 use nohash_hasher::NoHashHasher;
 
-use crate::*;
+use crate::{ring::Ring, *};
 
 type HashMap<K, V> = std::collections::HashMap<K, V, BuildHasherDefault<NoHashHasher<K>>>;
 
@@ -24,47 +20,69 @@ type HashMap<K, V> = std::collections::HashMap<K, V, BuildHasherDefault<NoHashHa
 /// when one read finishes but more reads are required to finish the file.
 /// This struct is called "Buffer" in other modules, but in this case the buffer
 /// needs to be stored separately.
-struct ReadState {
+struct ReadState<D> {
     pub path: PathBuf,
     pub fd: File,
     file_len: u64,
-    /// How many bytes have been read
+    /// How many bytes have been hashed
     pub position: u64,
-    /// The md5 state is updated as more bytes are read
-    ctx: Md5,
+    /// Where the next read starts, which is past `position` while the last read is hashed
+    next_read: u64,
+    /// The digest state is updated as more bytes are read
+    ctx: D,
+    /// The slot of the file table the file is in while it's read
     pub file_idx: u32,
     pub buf: Option<Pin<Box<AlignedBuffer>>>,
     pub buf_idx: Option<u16>,
+    /// A second buffer and its index, which the next read goes into while the last one is hashed
+    spare: Option<(Pin<Box<AlignedBuffer>>, u16)>,
 }
 
-impl ReadState {
-    pub fn new(path: &Path, file_idx: u32, o_direct: bool) -> Result<ReadState> {
-        let fd = open(path, o_direct)?;
-        let file_len = fd.metadata()?.len();
-        Ok(ReadState {
-            path: path.to_owned(),
+impl<D: Update> ReadState<D> {
+    pub fn new(path: PathBuf, fd: File, file_len: u64, ctx: D) -> ReadState<D> {
+        ReadState {
+            path,
             fd,
             file_len,
             position: 0,
-            ctx: Md5::new(),
-            file_idx,
+            next_read: 0,
+            ctx,
+            file_idx: 0,
             buf: None,
             buf_idx: None,
-        })
+            spare: None,
+        }
     }
 
     /// Get ready to read file data into a buffer. This takes ownership of the buffer
-    /// and free index.
-    fn initialize(&mut self, mut buf: Pin<Box<AlignedBuffer>>, buf_idx: u16) {
+    /// and free index, and of a spare buffer if there is one.
+    fn initialize(
+        &mut self,
+        mut buf: Pin<Box<AlignedBuffer>>,
+        buf_idx: u16,
+        spare: Option<(Pin<Box<AlignedBuffer>>, u16)>,
+    ) {
         self.buf_idx.replace(buf_idx);
         Self::set_buffer_size(&mut buf, self.file_len, self.position);
         self.buf.replace(buf);
+        self.spare = spare;
+    }
+
+    /// Give back the buffers, wiped, with their indices.
+    fn take_buffers(&mut self) -> impl Iterator<Item = (u16, Pin<Box<AlignedBuffer>>)> {
+        let buf = self.buf.take().zip(self.buf_idx.take());
+        buf.into_iter()
+            .chain(self.spare.take())
+            .map(|(mut buf, idx)| {
+                buf.wipe();
+                (idx, buf)
+            })
     }
 
     /// Reset the buffer size, useful whenever the read position changes.
     /// Returns whether the file has been fully read.
     pub fn set_buffer_size(buf: &mut AlignedBuffer, file_len: u64, position: u64) -> bool {
-        let needed_bytes = min(file_len - position, MAX_READ_SIZE as u64);
+        let needed_bytes = min(file_len - position, buf.capacity() as u64);
         trace!(
             "Set the buffer size to {} because we read {} of a {} byte file.",
             needed_bytes,
@@ -76,178 +94,475 @@ impl ReadState {
         needed_bytes == 0
     }
 
-    /// Returns whether the file has been fully read.
-    pub(crate) fn update(&mut self) -> bool {
-        let mut buf = self.buf.as_mut().unwrap();
-        self.ctx.update(&buf[..]);
-        self.position += buf.len() as u64;
-        let finished = Self::set_buffer_size(&mut buf, self.file_len, self.position);
+    /// Hash the first `read_len` bytes of the buffer, which a read may have stopped short of
+    /// filling, as on a network filesystem or when a signal arrives. The next read starts where
+    /// it stopped. Returns whether the file has been fully read.
+    pub(crate) fn update(&mut self, read_len: usize) -> bool {
+        let buf = self.buf.as_mut().unwrap();
+        self.ctx.update(&buf[..read_len]);
+        progress::add_bytes(read_len as u64);
+        self.position += read_len as u64;
+        self.next_read = self.position;
+
+        Self::set_buffer_size(buf, self.file_len, self.position)
+    }
 
-        finished
+    /// Make the spare buffer the one the next read goes into, after the `read_len` bytes just
+    /// read, which stay in the other buffer until `update_spare()` hashes them. Returns false,
+    /// swapping nothing, if there's no spare or those bytes end the file.
+    fn swap_buffers(&mut self, read_len: usize) -> bool {
+        let next_read = self.position + read_len as u64;
+        if read_len == 0 || next_read >= self.file_len {
+            return false;
+        }
+        let Some((mut spare, spare_idx)) = self.spare.take() else {
+            return false;
+        };
+        Self::set_buffer_size(&mut spare, self.file_len, next_read);
+        let buf = self.buf.replace(spare).unwrap();
+        let buf_idx = self.buf_idx.replace(spare_idx).unwrap();
+        self.spare = Some((buf, buf_idx));
+        self.next_read = next_read;
+        true
+    }
+
+    /// Hash the `read_len` bytes that `swap_buffers()` left in the spare buffer.
+    fn update_spare(&mut self, read_len: usize) {
+        let (spare, _) = self.spare.as_ref().unwrap();
+        self.ctx.update(&spare[..read_len]);
+        progress::add_bytes(read_len as u64);
+        self.position += read_len as u64;
     }
 }
 
-/// Get all checksums and send the results through a channel.
-pub fn get_checksums(
-    files: Vec<PathBuf>,
-    tx: Sender<(PathBuf, Result<Md5>)>,
-    o_direct: bool,
-) -> Result<()> {
-    // Set up shared state that's applicable to all individual reads or for choosing what to read:
-    let mut ring = IoUring::new(RING_SIZE as u32)?;
-    let mut probe = Probe::new();
-    ring.submitter().register_probe(&mut probe)?;
-    if !probe.is_supported(opcode::Read::CODE) {
-        bail!("Reading files is not supported. Try a newer kernel.");
+/// Reads registered files into buffers registered with the ring.
+#[derive(Debug)]
+pub struct FixedBuffers;
+
+impl ChecksumStrategy for FixedBuffers {
+    fn name(&self) -> &'static str {
+        "fixed-buffers"
     }
-    // opcode::sys::IORING_REGISTER_FILES is private, so just use its number "2"
-    if !probe.is_supported(2) {
-        bail!("Registering files is not supported. Try a newer kernel.");
+
+    fn get_checksums(
+        &self,
+        files: Vec<PathBuf>,
+        tx: &dyn ResultSink<ChecksumResult>,
+        config: &Config,
+    ) -> Result<()> {
+        get_checksums(files, tx, config, || Hasher::new(config.algorithm))
     }
-    if !probe.is_supported(opcode::ReadFixed::CODE) {
-        bail!("Reading into fixed buffers is not supported. Try a newer kernel.");
+
+    fn start_session(&self, config: &Config) -> Result<Option<Box<dyn StrategySession>>> {
+        match Session::new(config)? {
+            Some(session) => Ok(Some(Box::new(session))),
+            None => Ok(Some(Box::new(session::PerBatch {
+                strategy: &with_register_files::RegisteredFiles,
+                config: config.clone(),
+            }))),
+        }
     }
+}
 
-    let mut file_idx = 0;
-    let mut read_states: HashMap<usize, ReadState> = Default::default();
-    let mut shared_buffers: HashMap<usize, Pin<Box<AlignedBuffer>>> = Default::default();
-    let mut iovecs: Vec<libc::iovec> = Vec::new();
-    for i in 0..RING_SIZE {
-        let mut buffer: Pin<Box<AlignedBuffer>> = Box::pin(Default::default());
-        let buffer_ptr = buffer.as_mut().as_mut_ptr();
-        iovecs.push(libc::iovec {
-            iov_base: buffer_ptr as *mut _,
-            iov_len: buffer.len(),
-        });
-        shared_buffers.insert(i, buffer);
+/// Get all checksums and send the results through a channel. Each file gets a new digest from
+/// `new_digest`, such as `Md5::new`, and is sent as an `R` made from its path and digest, such
+/// as a `ChecksumResult` or the pair itself.
+pub fn get_checksums<D, F, R>(
+    paths: Vec<PathBuf>,
+    tx: impl ResultSink<R>,
+    config: &Config,
+    new_digest: F,
+) -> Result<()>
+where
+    D: Update,
+    F: Fn() -> D,
+    R: From<(PathBuf, Result<D>)>,
+{
+    match Session::new(config)? {
+        Some(mut session) => session.run(paths, tx, new_digest),
+        None => with_register_files::get_checksums(paths, tx, config, new_digest),
     }
+}
 
-    let mut free_index_list: Vec<_> = (0..RING_SIZE).into_iter().collect();
-    let mut raw_fds = Vec::new();
-    let mut files = files
-        .into_iter()
-        .filter_map(|path| match ReadState::new(&path, file_idx, o_direct) {
-            Ok(buffer) => {
-                file_idx += 1;
-                raw_fds.push(buffer.fd.as_raw_fd());
-                Some(buffer)
-            }
-            Err(err) => {
-                tx.send((path.to_owned(), Err(err))).unwrap();
-                None
-            }
-        })
-        // Reverse so we can pop the first files off the end
-        .rev()
-        .collect::<Vec<_>>();
-
-    if raw_fds.len() > 0 {
-        ring.submitter().register_files(&raw_fds)?;
-
-        if let Err(err) = ring.submitter().register_buffers(&iovecs) {
-            bail!(
-                "Failed to register fixed buffers (are you running without root?): {}",
-                err
+/// A ring with its buffers and file table registered, set up once to read batch after batch of
+/// files.
+pub struct Session {
+    ring: Ring,
+    /// The registered buffers that aren't being read into, by index
+    shared_buffers: HashMap<usize, Pin<Box<AlignedBuffer>>>,
+    buffers_per_file: usize,
+    /// How many files are read at once
+    slots: usize,
+    config: Config,
+}
+
+impl Session {
+    /// Set up the ring as `config` says, probe what the kernel supports, and register the buffers
+    /// and file table. Returns None if not even one buffer fits in the locked memory limit, so
+    /// files have to be read without fixed buffers.
+    pub fn new(config: &Config) -> Result<Option<Session>> {
+        // Set up shared state that's applicable to all individual reads or for choosing what to
+        // read:
+        let ring_size = config.ring_size;
+        let ring = ring::new_ring(ring_size as u32, config)?;
+        let mut probe = Probe::new();
+        ring.submitter()
+            .register_probe(&mut probe)
+            .map_err(ChecksumError::RingSetup)?;
+        if !probe.is_supported(opcode::Read::CODE) {
+            bail!("Reading files is not supported. Try a newer kernel.");
+        }
+        // opcode::sys::IORING_REGISTER_FILES is private, so just use its number "2"
+        if !probe.is_supported(2) {
+            bail!("Registering files is not supported. Try a newer kernel.");
+        }
+        if !probe.is_supported(opcode::ReadFixed::CODE) {
+            bail!("Reading into fixed buffers is not supported. Try a newer kernel.");
+        }
+
+        let Some(shared_buffers) = register_buffers_within_limit(&ring, config)? else {
+            warn!(
+                "Not even one fixed buffer fits in the locked memory limit, so files are read \
+                 without fixed buffers. Raise the limit with `ulimit -l`."
             );
+            return Ok(None);
+        };
+        // There may be fewer or more buffers than entries in the ring, and a read needs a buffer.
+        // With two for each entry, each file gets a spare:
+        let buffers_per_file = if shared_buffers.len() >= 2 * ring_size {
+            2
+        } else {
+            1
+        };
+        let slots = shared_buffers.len() / buffers_per_file;
+        debug!(
+            "Reading {} files at once with {} fixed buffers each.",
+            slots, buffers_per_file
+        );
+
+        // Files are slotted into the table as their reads start, one slot for each read in flight:
+        ring::register_file_slots(&ring, slots as u32).map_err(ChecksumError::RingSetup)?;
+        Ok(Some(Session {
+            ring,
+            shared_buffers,
+            buffers_per_file,
+            slots,
+            config: config.clone(),
+        }))
+    }
+
+    /// Hash `paths` as `get_checksums()` does, reusing what was registered. Once this returns Ok,
+    /// every file's result has been sent and the session can take another batch.
+    pub fn run<D, F, R>(
+        &mut self,
+        paths: Vec<PathBuf>,
+        tx: impl ResultSink<R>,
+        new_digest: F,
+    ) -> Result<()>
+    where
+        D: Update,
+        F: Fn() -> D,
+        R: From<(PathBuf, Result<D>)>,
+    {
+        let mut read_states: HashMap<usize, ReadState<D>> = Default::default();
+        let result = self.read_all(&mut read_states, paths, tx, new_digest);
+        if result.is_err() {
+            // The reads still in flight point into the buffers the read states hold:
+            ring::settle(&mut self.ring, read_states);
         }
+        result
     }
 
-    loop {
-        let mut new_work_queued = false;
+    /// Read `paths` until each one's result has been sent, with the files being read in
+    /// `read_states`.
+    fn read_all<D, F, R>(
+        &mut self,
+        read_states: &mut HashMap<usize, ReadState<D>>,
+        paths: Vec<PathBuf>,
+        tx: impl ResultSink<R>,
+        new_digest: F,
+    ) -> Result<()>
+    where
+        D: Update,
+        F: Fn() -> D,
+        R: From<(PathBuf, Result<D>)>,
+    {
+        let Session {
+            ring,
+            shared_buffers,
+            buffers_per_file,
+            slots,
+            config,
+        } = self;
+        let (buffers_per_file, slots) = (*buffers_per_file, *slots);
 
-        // Only proceed if there's both a free index and a file:
-        while let Some(free_idx) = free_index_list.pop() {
-            debug_assert!(
-                !ring.submission().is_full(),
-                "Submission queue must have a free spot if there's a free read state slot",
-            );
+        // Only keep as many files open as the descriptor limit allows. The rest are queued
+        // for later batches.
+        let mut paths = paths.into_iter().peekable();
+        while paths.peek().is_some() {
+            let batch = paths.by_ref().take(max_open_files());
+            let mut free_index_list: Vec<_> = (0..slots).collect();
+            let opened = open_batch(batch, config.o_direct, &tx)?;
+            let fds: Vec<_> = opened.iter().map(|(_, fd)| fd).collect();
+            let lens = ring::file_lens(ring, &fds).map_err(ChecksumError::Submit)?;
+            let mut files = Vec::with_capacity(opened.len());
+            for ((path, fd), len) in opened.into_iter().zip(lens) {
+                match len {
+                    Ok(len) => files.push(ReadState::new(path, fd, len, new_digest())),
+                    Err(err) => tx.send((path, Err(err.into())).into())?,
+                }
+            }
+            // Reverse so we can pop the first files off the end
+            files.reverse();
 
-            if let Some(mut state) = files.pop() {
-                state.initialize(shared_buffers.remove(&free_idx).unwrap(), free_idx as u16);
-                read_states.insert(free_idx, state);
-                debug_assert_eq!(
-                    free_index_list.len(),
-                    RING_SIZE - read_states.len(),
-                    "The free index list is out of sync with the work read states (1)"
-                );
-                let read_state_ref = read_states.get_mut(&free_idx).unwrap();
-                new_work_queued = true;
-                submit_for_read(&mut ring, read_state_ref, free_idx);
-            } else {
-                // We didn't use this index
-                free_index_list.push(free_idx);
-                break;
+            if files.is_empty() {
+                continue;
+            }
+
+            loop {
+                let mut new_work_queued = false;
+
+                if config.cancel.is_cancelled() {
+                    // No more files are started, and those being read fail as their reads complete:
+                    let unstarted = files.drain(..).rev().map(|state| state.path);
+                    cancel::send_cancelled(unstarted.chain(paths.by_ref()), &tx);
+                }
+
+                // Only proceed if there's both a free index and a file:
+                while let Some(free_idx) = free_index_list.pop() {
+                    if let Some(mut state) = files.pop() {
+                        ring::set_file_slot(ring, free_idx as u32, Some(state.fd.as_raw_fd()))
+                            .map_err(ChecksumError::RingSetup)?;
+                        state.file_idx = free_idx as u32;
+                        // A slot's buffers are the one of its index and the one a set further on:
+                        let spare_idx = free_idx + slots;
+                        let spare = (buffers_per_file == 2).then(|| {
+                            (shared_buffers.remove(&spare_idx).unwrap(), spare_idx as u16)
+                        });
+                        state.initialize(
+                            shared_buffers.remove(&free_idx).unwrap(),
+                            free_idx as u16,
+                            spare,
+                        );
+                        read_states.insert(free_idx, state);
+                        debug_assert_eq!(
+                            free_index_list.len(),
+                            slots - read_states.len(),
+                            "The free index list is out of sync with the work read states (1)"
+                        );
+                        let read_state_ref = read_states.get_mut(&free_idx).unwrap();
+                        new_work_queued = true;
+                        ring.advise_sequential(&read_state_ref.fd)
+                            .map_err(ChecksumError::Submit)?;
+                        submit_for_read(ring, read_state_ref, free_idx)?;
+                    } else {
+                        // We didn't use this index
+                        free_index_list.push(free_idx);
+                        break;
+                    }
+                }
+
+                if new_work_queued || !files.is_empty() {
+                    if !files.is_empty() {
+                        debug_assert_eq!(
+                            free_index_list.len(),
+                            0,
+                            "We should have filled all the slots"
+                        );
+                    }
+
+                    // Wait for a result since the jobs list is full or we just added something
+                    trace!("Waiting for / handling a result");
+                    submit_wait_and_handle_result(
+                        ring,
+                        read_states,
+                        &tx,
+                        &mut free_index_list,
+                        shared_buffers,
+                        slots,
+                        config,
+                    )?;
+                } else {
+                    // There's no more work that can be added right now, but we still need to
+                    // handle any active read states
+                    while free_index_list.len() < slots {
+                        trace!(
+                            "Did not submit work, waiting for old work. {}/{} free indices",
+                            free_index_list.len(),
+                            slots
+                        );
+                        submit_wait_and_handle_result(
+                            ring,
+                            read_states,
+                            &tx,
+                            &mut free_index_list,
+                            shared_buffers,
+                            slots,
+                            config,
+                        )?;
+                    }
+                    break;
+                }
             }
         }
 
-        if new_work_queued || files.len() > 0 {
-            if files.len() > 0 {
-                debug_assert_eq!(
-                    free_index_list.len(),
-                    0,
-                    "We should have filled all the slots"
+        Ok(())
+    }
+}
+
+impl StrategySession for Session {
+    fn checksum(&mut self, files: Vec<PathBuf>, tx: &dyn ResultSink<ChecksumResult>) -> Result<()> {
+        let algorithm = self.config.algorithm;
+        self.run(files, tx, || Hasher::new(algorithm))
+    }
+}
+
+/// Register `config.fixed_buffers` buffers the size of a read, or one for each entry of the ring,
+/// as `config` sizes them. If they don't fit in the locked memory limit, which is raised as far
+/// as it can be first, fewer or smaller buffers are registered. Returns None if not even one
+/// buffer of `ALIGNMENT` bytes can be registered.
+fn register_buffers_within_limit(
+    ring: &Ring,
+    config: &Config,
+) -> Result<Option<HashMap<usize, Pin<Box<AlignedBuffer>>>>> {
+    let limit = raise_memlock_limit();
+    let count = config.fixed_buffers.unwrap_or(config.ring_size);
+    let wanted = (count, config.read_size);
+    let mut next = Some(wanted);
+    while let Some((count, size)) = next {
+        match register_buffers(ring, count, size, config.huge_pages) {
+            Ok(buffers) => {
+                if (count, size) != wanted {
+                    warn!(
+                        "Registered {} buffers of {} bytes instead of {} of {} to fit in the \
+                         locked memory limit ({} bytes). Raise it with `ulimit -l`.",
+                        count, size, wanted.0, wanted.1, limit
+                    );
+                }
+                return Ok(Some(buffers));
+            }
+            // Only a process that may lock any amount of memory can go over the limit:
+            Err(err) if err.raw_os_error() == Some(libc::ENOMEM) => {
+                debug!(
+                    "Could not register {} buffers of {} bytes: {}",
+                    count, size, err
                 );
+                next = shrink_buffers(count, size, limit);
             }
+            Err(err) => return Err(ChecksumError::RingSetup(err).into()),
+        }
+    }
+    Ok(None)
+}
 
-            // Wait for a result since the jobs list is full or we just added something
-            trace!("Waiting for / handling a result");
-            submit_wait_and_handle_result(
-                &mut ring,
-                &mut read_states,
-                &tx,
-                &mut free_index_list,
-                &mut shared_buffers,
-            )?;
+/// The next smaller set of `count` buffers of `size` bytes to try registering: the size is halved
+/// down to the default read size, then the count down to one, then the size down to `ALIGNMENT`.
+/// Sets larger than `limit` bytes are skipped.
+pub(crate) fn shrink_buffers(count: usize, size: usize, limit: u64) -> Option<(usize, usize)> {
+    let (mut count, mut size) = (count, size);
+    loop {
+        if size > MAX_READ_SIZE {
+            size = (size / 2 / ALIGNMENT).max(1) * ALIGNMENT;
+        } else if count > 1 {
+            count /= 2;
+        } else if size > ALIGNMENT {
+            size = (size / 2 / ALIGNMENT).max(1) * ALIGNMENT;
         } else {
-            // There's no more work that can be added right now, but we still need to handle any
-            // active read states
-            while free_index_list.len() < RING_SIZE {
-                trace!(
-                    "Did not submit work, waiting for old work. {}/{} free indices",
-                    free_index_list.len(),
-                    RING_SIZE
-                );
-                submit_wait_and_handle_result(
-                    &mut ring,
-                    &mut read_states,
-                    &tx,
-                    &mut free_index_list,
-                    &mut shared_buffers,
-                )?;
-            }
-            break;
+            return None;
+        }
+        if (count * size) as u64 <= limit {
+            return Some((count, size));
         }
     }
+}
 
-    Ok(())
+/// Allocate `count` buffers of `size` bytes, by slot, on huge pages if asked, and register them
+/// with `ring`.
+fn register_buffers(
+    ring: &Ring,
+    count: usize,
+    size: usize,
+    huge_pages: bool,
+) -> io::Result<HashMap<usize, Pin<Box<AlignedBuffer>>>> {
+    let mut buffers: HashMap<usize, Pin<Box<AlignedBuffer>>> = Default::default();
+    let mut iovecs: Vec<libc::iovec> = Vec::with_capacity(count);
+    let allocated = if huge_pages {
+        AlignedBuffer::with_huge_pages(count, size)?
+    } else {
+        (0..count)
+            .map(|_| AlignedBuffer::with_capacity(size))
+            .collect()
+    };
+    for (i, buffer) in allocated.into_iter().enumerate() {
+        let mut buffer = Box::pin(buffer);
+        iovecs.push(libc::iovec {
+            iov_base: buffer.as_mut().as_mut_ptr() as *mut _,
+            iov_len: buffer.len(),
+        });
+        buffers.insert(i, buffer);
+    }
+    // unsafe: the buffers are pinned, and every read into them completes before they are freed:
+    unsafe { ring.submitter().register_buffers(&iovecs)? };
+    Ok(buffers)
 }
 
-fn submit_wait_and_handle_result(
-    ring: &mut IoUring,
-    read_states: &mut HashMap<usize, ReadState>,
-    tx: &Sender<(PathBuf, Result<md5::Md5, anyhow::Error>)>,
+fn submit_wait_and_handle_result<D: Update, R: From<(PathBuf, Result<D>)>>(
+    ring: &mut Ring,
+    read_states: &mut HashMap<usize, ReadState<D>>,
+    tx: &impl ResultSink<R>,
     free_index_list: &mut Vec<usize>,
     shared_buffers: &mut HashMap<usize, Pin<Box<AlignedBuffer>>>,
+    slots: usize,
+    config: &Config,
 ) -> Result<()> {
     debug_assert_eq!(
         free_index_list.len(),
-        RING_SIZE - read_states.len(),
+        slots - read_states.len(),
         "The free index list is out of sync with the read states (2)"
     );
 
-    ring.submit_and_wait(1)?;
-    let completed_idx = ring
-        .completion()
-        .next()
-        .expect("completion queue is empty")
-        .user_data() as usize;
+    let cqe = ring.next_completion().map_err(ChecksumError::Completion)?;
+    let completed_idx = cqe.user_data() as usize;
 
     // Next, consume and handle bytes in the buffer:
     let read_state = read_states
         .get_mut(&completed_idx)
-        .expect("should exist because we chose its index");
+        .ok_or_else(|| ChecksumError::unexpected_completion(cqe.user_data()))?;
+    let buf_len = read_state.buf.as_ref().unwrap().len();
+    // Reads ask for whole blocks, so the kernel may go past the end of a file that grew:
+    let read_len = min(usize::try_from(cqe.result()).unwrap_or(0), buf_len);
+    let error = match ring.timeout_error(cqe.result()) {
+        Some(err) => Some(err.into()),
+        // A read that fails, as on a bad sector, fails the file, and the rest are still hashed:
+        None if cqe.result() < 0 => Some(io::Error::from_raw_os_error(-cqe.result()).into()),
+        None if read_len == 0 && buf_len > 0 => {
+            Some(ended_early(read_state.position, read_state.file_len))
+        }
+        None if config.cancel.is_cancelled() => Some(cancel::cancelled().into()),
+        None => None,
+    };
+    if let Some(err) = error {
+        let mut read_state = read_states.remove(&completed_idx).unwrap();
+        free_index_list.push(completed_idx);
+        for (idx, buf) in read_state.take_buffers() {
+            shared_buffers.insert(idx as usize, buf);
+        }
+        ring::set_file_slot(ring, read_state.file_idx, None).map_err(ChecksumError::RingSetup)?;
+        ring.close(read_state.fd);
+        tx.send((read_state.path, Err(err)).into())?;
+        return Ok(());
+    }
 
-    let finished = read_state.update();
+    let swapped = read_state.swap_buffers(read_len);
+    let finished = if swapped {
+        // The next read goes on in the spare buffer while this one is hashed:
+        submit_for_read(ring, read_state, completed_idx)?;
+        read_state.update_spare(read_len);
+        false
+    } else {
+        read_state.update(read_len)
+    };
+    config.report_progress(&read_state.path, read_state.position, read_state.file_len);
     trace!(
         "Incorporated bytes into checksum. Finished?: {} ({:?})",
         finished,
@@ -259,20 +574,24 @@ fn submit_wait_and_handle_result(
         free_index_list.push(completed_idx);
         debug_assert_eq!(
             free_index_list.len(),
-            RING_SIZE - read_states.len(),
+            slots - read_states.len(),
             "The free index list is out of sync with the read states (3)"
         );
-        // Also return the fixed buffer:
-        shared_buffers.insert(completed_idx, read_state.buf.take().unwrap());
+        // Also return the fixed buffers, which outlive the file:
+        for (idx, buf) in read_state.take_buffers() {
+            shared_buffers.insert(idx as usize, buf);
+        }
 
-        tx.send((read_state.path, Ok(read_state.ctx))).unwrap();
-    } else {
+        ring::set_file_slot(ring, read_state.file_idx, None).map_err(ChecksumError::RingSetup)?;
+        ring.close(read_state.fd);
+        tx.send((read_state.path, Ok(read_state.ctx)).into())?;
+    } else if !swapped {
         trace!("Checksum not finished, resubmitting for read");
         submit_for_read(
             ring,
             read_states.get_mut(&completed_idx).unwrap(),
             completed_idx,
-        );
+        )?;
     }
 
     Ok(())
@@ -280,22 +599,33 @@ fn submit_wait_and_handle_result(
 
 /// Put a job in the read queue and submit it to the kernel. The read state struct tracks
 /// how much has been read already and how much more is needed.
-fn submit_for_read(ring: &mut IoUring, read_state_ref: &mut ReadState, idx: usize) {
+fn submit_for_read<D>(
+    ring: &mut Ring,
+    read_state_ref: &mut ReadState<D>,
+    idx: usize,
+) -> Result<()> {
     // get data uring needs to queue a read:
     let buf = read_state_ref.buf.as_mut().unwrap();
-    let read_e = opcode::ReadFixed::new(
-        types::Fixed(read_state_ref.file_idx),
-        buf.as_mut_ptr(),
-        buf.len() as _,
-        read_state_ref.buf_idx.unwrap(),
-    )
-    .offset(read_state_ref.position as i64)
-    .build()
-    .user_data(idx as u64);
+    let (ptr, len) = (buf.as_mut_ptr(), buf.len() as u32);
+    // Ask for whole blocks, so the length stays aligned for O_DIRECT at the end of the file:
+    let request_len = buf.len().next_multiple_of(ALIGNMENT) as u32;
+    let read = |rw_flags| {
+        opcode::ReadFixed::new(
+            types::Fixed(read_state_ref.file_idx),
+            ptr,
+            request_len,
+            read_state_ref.buf_idx.unwrap(),
+        )
+        .offset(read_state_ref.next_read)
+        .rw_flags(rw_flags)
+        .build()
+        .user_data(idx as u64)
+    };
 
     unsafe {
-        ring.submission()
-            .push(&read_e)
-            .expect("submission queue is full");
-    }
+        ring.push_read_with(len, read)
+            .map_err(ChecksumError::Submit)?
+    };
+    progress::add_read();
+    Ok(())
 }
@@ -0,0 +1,421 @@
+// This module gives io_uring a ring of buffers to choose from, so each read is put in whichever
+// buffer is free when it completes instead of one chosen when it's submitted.
+use std::{
+    alloc::{self, Layout},
+    cmp::min,
+    fs::File,
+    hash::BuildHasherDefault,
+    io, iter, mem,
+    os::unix::io::AsRawFd,
+    path::{Path, PathBuf},
+    pin::Pin,
+    ptr,
+    sync::atomic::{AtomicU16, Ordering},
+};
+
+use anyhow::{bail, Result};
+use io_uring::{cqueue, opcode, squeue, types, Probe};
+#[allow(unused_imports)]
+use log::{debug, error, info, trace, warn};
+use md5::digest::Update;
+use nohash_hasher::NoHashHasher;
+
+use crate::{ring::Ring, *};
+
+type HashMap<K, V> = std::collections::HashMap<K, V, BuildHasherDefault<NoHashHasher<K>>>;
+
+/// The ID of the one group of buffers the reads choose from.
+const BUFFER_GROUP: u16 = 0;
+
+/// This struct holds the state of a file that's being read. It has no buffer: each read is put in
+/// a buffer the kernel picks.
+struct ReadState<D> {
+    pub path: PathBuf,
+    pub fd: File,
+    file_len: u64,
+    /// How many bytes have been read
+    pub position: u64,
+    /// The digest state is updated as more bytes are read
+    ctx: D,
+    /// Where the kernel puts the file's length while it's looked up through the ring
+    statx: Option<Box<libc::statx>>,
+}
+
+impl<D: Update> ReadState<D> {
+    /// Open a file. With `stat_on_ring`, its length isn't known until a Statx request from
+    /// `submit_first()` completes.
+    pub fn new(path: &Path, o_direct: bool, ctx: D, stat_on_ring: bool) -> Result<ReadState<D>> {
+        let fd = open(path, o_direct).map_err(|err| ChecksumError::open_failed(path, err))?;
+        let (file_len, statx) = if stat_on_ring {
+            // unsafe: a statx struct is plain integers, for which zeros are valid:
+            (0, Some(Box::new(unsafe { mem::zeroed() })))
+        } else {
+            (fd.metadata()?.len(), None)
+        };
+        Ok(ReadState {
+            path: path.to_owned(),
+            fd,
+            file_len,
+            position: 0,
+            ctx,
+            statx,
+        })
+    }
+
+    /// Hash the data that a read put at the start of `buf`, leaving out anything past the length
+    /// the file had when it was opened. Returns whether the file has been fully read.
+    fn update(&mut self, buf: &[u8]) -> bool {
+        let len = min(buf.len() as u64, self.file_len - self.position);
+        self.ctx.update(&buf[..len as usize]);
+        progress::add_bytes(len);
+        self.position += len;
+
+        self.position == self.file_len
+    }
+}
+
+/// The buffers the kernel picks from, and the ring shared with the kernel that lists which are
+/// free. A buffer is handed back by adding it at the tail of the ring.
+pub(crate) struct BufferRing {
+    entries: *mut types::BufRingEntry,
+    buffers: Vec<Pin<Box<AlignedBuffer>>>,
+    /// The tail the kernel has been told about
+    tail: u16,
+}
+
+impl BufferRing {
+    fn layout(len: usize) -> Layout {
+        // The ring must start on a page:
+        Layout::from_size_align(len * std::mem::size_of::<types::BufRingEntry>(), 4096).unwrap()
+    }
+
+    /// Allocate `len` buffers of `size` bytes, a power of two of them, and register them with
+    /// `ring`, all of them free.
+    pub(crate) fn new(ring: &Ring, len: usize, size: usize) -> Result<BufferRing> {
+        // unsafe: the layout has a non-zero size, and zeros are valid entries:
+        let entries = unsafe { alloc::alloc_zeroed(Self::layout(len)) } as *mut types::BufRingEntry;
+        if entries.is_null() {
+            alloc::handle_alloc_error(Self::layout(len));
+        }
+        let mut buffer_ring = BufferRing {
+            entries,
+            buffers: (0..len)
+                .map(|_| Box::pin(AlignedBuffer::with_capacity(size)))
+                .collect(),
+            tail: 0,
+        };
+        for bid in 0..len as u16 {
+            buffer_ring.give_back(bid);
+        }
+
+        // unsafe: the entries stay allocated until the ring is dropped, which the caller
+        // makes sure happens first:
+        let result = unsafe {
+            ring.submitter().register_buf_ring_with_flags(
+                entries as u64,
+                len as u16,
+                BUFFER_GROUP,
+                0,
+            )
+        };
+        if let Err(err) = result {
+            let err = io::Error::new(
+                err.kind(),
+                format!(
+                    "Provided buffer rings are not supported. Try a newer kernel (5.19): {}",
+                    err
+                ),
+            );
+            return Err(ChecksumError::RingSetup(err).into());
+        }
+        Ok(buffer_ring)
+    }
+
+    fn buffer(&self, bid: u16) -> &[u8] {
+        &self.buffers[bid as usize][..]
+    }
+
+    /// The size of each buffer, which is how much each read asks for.
+    fn buffer_size(&self) -> usize {
+        self.buffers[0].capacity()
+    }
+
+    /// Make a buffer free for the kernel to pick again.
+    fn give_back(&mut self, bid: u16) {
+        let buffer = &mut self.buffers[bid as usize];
+        buffer.wipe();
+        let addr = buffer.as_mut().as_mut_ptr() as u64;
+        let len = buffer.capacity() as u32;
+        // unsafe: the index is within the ring, and the kernel doesn't read an entry until
+        // the tail moves past it:
+        unsafe {
+            let entry = &mut *self.entries.add(self.tail as usize % self.buffers.len());
+            entry.set_addr(addr);
+            entry.set_len(len);
+            entry.set_bid(bid);
+        }
+        self.tail = self.tail.wrapping_add(1);
+        // unsafe: the tail is a u16 in the first entry, which the kernel reads concurrently:
+        let tail = unsafe { &*(types::BufRingEntry::tail(self.entries) as *const AtomicU16) };
+        tail.store(self.tail, Ordering::Release);
+    }
+}
+
+impl Drop for BufferRing {
+    fn drop(&mut self) {
+        // unsafe: the memory came from `alloc_zeroed` with the same layout:
+        unsafe { alloc::dealloc(self.entries as *mut u8, Self::layout(self.buffers.len())) };
+    }
+}
+
+/// Reads into buffers the kernel picks from a ring of provided buffers.
+#[derive(Debug)]
+pub struct ProvidedBuffers;
+
+impl ChecksumStrategy for ProvidedBuffers {
+    fn name(&self) -> &'static str {
+        "provided-buffers"
+    }
+
+    fn get_checksums(
+        &self,
+        files: Vec<PathBuf>,
+        tx: &dyn ResultSink<ChecksumResult>,
+        config: &Config,
+    ) -> Result<()> {
+        get_checksums(files, tx, config, || Hasher::new(config.algorithm))
+    }
+}
+
+/// Get all checksums and send the results through a channel. Each file gets a new digest from
+/// `new_digest`, such as `Md5::new`, and is sent as an `R` made from its path and digest, such
+/// as a `ChecksumResult` or the pair itself.
+pub fn get_checksums<D, F, R>(
+    files: Vec<PathBuf>,
+    tx: impl ResultSink<R>,
+    config: &Config,
+    new_digest: F,
+) -> Result<()>
+where
+    D: Update,
+    F: Fn() -> D,
+    R: From<(PathBuf, Result<D>)>,
+{
+    let mut probe = Probe::new();
+    let ring_size = config.ring_size;
+    let ring = ring::new_ring(ring_size as u32, config)?;
+    ring.submitter()
+        .register_probe(&mut probe)
+        .map_err(ChecksumError::RingSetup)?;
+    if !probe.is_supported(opcode::Read::CODE) {
+        bail!("Reading files is not supported. Try a newer kernel.");
+    }
+    let stat_on_ring = ring::can_statx(&ring, &probe);
+    // The buffers must outlive the ring they're registered with, so the ring is moved after them
+    // to be dropped first:
+    let mut buffer_ring = BufferRing::new(&ring, ring_size, config.read_size)?;
+    let mut ring = ring;
+
+    // Each read's user data is the ID of its file, so no slots need to be handed out. There are
+    // no more reads in flight than buffers, so the kernel always has one to pick.
+    let mut read_states: HashMap<u64, ReadState<D>> = Default::default();
+    let result = read_all(
+        &mut ring,
+        &mut buffer_ring,
+        &mut read_states,
+        files,
+        &tx,
+        config,
+        new_digest,
+        stat_on_ring,
+    );
+    if result.is_err() {
+        // The reads still in flight point into the buffers, and Statx requests into the states:
+        ring::settle(&mut ring, (buffer_ring, read_states));
+    }
+    result
+}
+
+/// Read `files` until each one's result has been sent, with the reads in flight in
+/// `read_states`.
+#[allow(clippy::too_many_arguments)]
+fn read_all<D, F, R>(
+    ring: &mut Ring,
+    buffer_ring: &mut BufferRing,
+    read_states: &mut HashMap<u64, ReadState<D>>,
+    files: Vec<PathBuf>,
+    tx: &impl ResultSink<R>,
+    config: &Config,
+    new_digest: F,
+    stat_on_ring: bool,
+) -> Result<()>
+where
+    D: Update,
+    F: Fn() -> D,
+    R: From<(PathBuf, Result<D>)>,
+{
+    let ring_size = config.ring_size;
+    let mut files = files.into_iter();
+    let mut next_id = 0;
+    loop {
+        if config.cancel.is_cancelled() {
+            // No more files are started, and those being read fail as their reads complete:
+            cancel::send_cancelled(files.by_ref(), tx);
+        }
+        while read_states.len() < ring_size {
+            let Some(path) = files.next() else {
+                break;
+            };
+            let mut state = match ReadState::new(&path, config.o_direct, new_digest(), stat_on_ring)
+            {
+                Ok(state) => state,
+                Err(err) => {
+                    tx.send((path, Err(err)).into())?;
+                    continue;
+                }
+            };
+            if state.statx.is_none() && state.file_len == 0 {
+                tx.send((state.path, Ok(state.ctx)).into())?;
+                continue;
+            }
+            submit_first(ring, &mut state, next_id, config.read_size)?;
+            read_states.insert(next_id, state);
+            next_id += 1;
+        }
+        if read_states.is_empty() {
+            break;
+        }
+
+        trace!("Waiting for / handling results");
+        ring::submit_and_reap(ring).map_err(ChecksumError::Completion)?;
+        let completions: Vec<_> = iter::from_fn(|| ring.pop_completion())
+            .map(|cqe| (cqe.user_data(), cqe.result(), cqe.flags()))
+            .collect();
+        for (id, result, flags) in completions {
+            handle_result(
+                ring,
+                buffer_ring,
+                read_states,
+                tx,
+                id,
+                result,
+                flags,
+                config,
+            )?;
+        }
+    }
+
+    Ok(())
+}
+
+/// Hash the data of one completed read, then read more of the file or send its result.
+#[allow(clippy::too_many_arguments)]
+fn handle_result<D: Update, R: From<(PathBuf, Result<D>)>>(
+    ring: &mut Ring,
+    buffer_ring: &mut BufferRing,
+    read_states: &mut HashMap<u64, ReadState<D>>,
+    tx: &impl ResultSink<R>,
+    id: u64,
+    result: i32,
+    flags: u32,
+    config: &Config,
+) -> Result<()> {
+    let read_state = read_states
+        .get_mut(&id)
+        .ok_or_else(|| ChecksumError::unexpected_completion(id))?;
+
+    let outcome = if let Some(statx) = read_state.statx.take() {
+        if result < 0 {
+            Err(io::Error::from_raw_os_error(-result).into())
+        } else {
+            read_state.file_len = statx.stx_size;
+            Ok(read_state.file_len == 0)
+        }
+    } else if let Some(err) = ring.timeout_error(result) {
+        Err(err.into())
+    } else if result < 0 {
+        Err(io::Error::from_raw_os_error(-result).into())
+    } else if result == 0 {
+        Err(ended_early(read_state.position, read_state.file_len))
+    } else if config.cancel.is_cancelled() {
+        Err(cancel::cancelled().into())
+    } else {
+        let bid = cqueue::buffer_select(flags).expect("a read with data has a buffer");
+        let finished = read_state.update(&buffer_ring.buffer(bid)[..result as usize]);
+        config.report_progress(&read_state.path, read_state.position, read_state.file_len);
+        trace!(
+            "Incorporated {} bytes into checksum. Finished?: {} ({:?})",
+            result,
+            finished,
+            &read_state.path,
+        );
+        Ok(finished)
+    };
+    // A read that failed may still have taken a buffer:
+    if let Some(bid) = cqueue::buffer_select(flags) {
+        buffer_ring.give_back(bid);
+    }
+
+    match outcome {
+        Ok(false) => {
+            trace!("Checksum not finished, resubmitting for read");
+            submit_for_read(ring, read_state, id, buffer_ring.buffer_size())?;
+        }
+        Ok(true) => {
+            let read_state = read_states.remove(&id).unwrap();
+            ring.close(read_state.fd);
+            tx.send((read_state.path, Ok(read_state.ctx)).into())?;
+        }
+        Err(err) => {
+            let read_state = read_states.remove(&id).unwrap();
+            tx.send((read_state.path, Err(err)).into())?;
+        }
+    }
+
+    Ok(())
+}
+
+/// Queue the first request for a file: a Statx to learn its length if that's looked up through
+/// the ring, or else its first read.
+fn submit_first<D>(
+    ring: &mut Ring,
+    read_state: &mut ReadState<D>,
+    id: u64,
+    read_size: usize,
+) -> Result<()> {
+    ring.advise_sequential(&read_state.fd)
+        .map_err(ChecksumError::Submit)?;
+    let Some(statx) = read_state.statx.as_mut() else {
+        return submit_for_read(ring, read_state, id, read_size);
+    };
+    let statx_e = ring::statx_entry(read_state.fd.as_raw_fd(), statx).user_data(id);
+
+    // Closes share the queue, so it can fill up before every file has a request:
+    unsafe { ring.push(&statx_e).map_err(ChecksumError::Submit)? };
+    Ok(())
+}
+
+/// Put a read of `read_size` bytes of the next part of a file in the queue, to be submitted with
+/// the next batch. The kernel picks the buffer, and always fills the whole buffer if the file is
+/// long enough, so the length stays aligned for O_DIRECT.
+fn submit_for_read<D>(
+    ring: &mut Ring,
+    read_state: &ReadState<D>,
+    id: u64,
+    read_size: usize,
+) -> Result<()> {
+    let read_e = opcode::Read::new(
+        types::Fd(read_state.fd.as_raw_fd()),
+        ptr::null_mut(),
+        read_size as _,
+    )
+    .offset(read_state.position)
+    .buf_group(BUFFER_GROUP)
+    .build()
+    .flags(squeue::Flags::BUFFER_SELECT)
+    .user_data(id);
+
+    unsafe { ring.push_read(&read_e).map_err(ChecksumError::Submit)? };
+    progress::add_read();
+    Ok(())
+}
@@ -1,59 +1,54 @@
-// This module pre-registers files with io_uring before the reads start.
+// This module registers files with io_uring as their reads start, so the reads skip looking up
+// the descriptors.
 use std::{
-    cmp::min,
-    fs::File,
-    hash::BuildHasherDefault,
-    os::unix::io::AsRawFd,
-    path::{Path, PathBuf},
+    cmp::min, fs::File, hash::BuildHasherDefault, io, os::unix::io::AsRawFd, path::PathBuf,
     pin::Pin,
-    sync::mpsc::Sender,
 };
 
 use anyhow::{bail, Result};
-use io_uring::{opcode, types, IoUring, Probe};
+use io_uring::{opcode, types, Probe};
 #[allow(unused_imports)]
 use log::{debug, error, info, trace, warn};
-use md5::{Digest, Md5};
+use md5::digest::Update;
 use nohash_hasher::NoHashHasher;
 
-use crate::*;
+use crate::{ring::Ring, *};
 
 type HashMap<K, V> = std::collections::HashMap<K, V, BuildHasherDefault<NoHashHasher<K>>>;
 
 /// This struct holds the state and buffers of a file that's being read, particularly
 /// when one read finishes but more reads are required to finish the file.
-struct Buffer {
+struct Buffer<D> {
     pub path: PathBuf,
     pub fd: File,
     file_len: u64,
     pub buf: Pin<Box<AlignedBuffer>>,
     /// How many bytes have been read
     pub position: u64,
-    /// The md5 state is updated as more bytes are read
-    ctx: Md5,
+    /// The digest state is updated as more bytes are read
+    ctx: D,
+    /// The slot of the file table the file is in while it's read
     pub file_idx: u32,
 }
 
-impl Buffer {
-    pub fn new(path: &Path, file_idx: u32, o_direct: bool) -> Result<Buffer> {
-        let fd = open(path, o_direct)?;
-        let file_len = fd.metadata()?.len();
+impl<D: Update> Buffer<D> {
+    pub fn new(path: PathBuf, fd: File, file_len: u64, ctx: D, read_size: usize) -> Buffer<D> {
         let mut ret = Buffer {
-            path: path.to_owned(),
+            path,
             fd,
             file_len,
-            buf: Box::pin(Default::default()),
+            buf: Box::pin(AlignedBuffer::with_capacity(read_size)),
             position: 0,
-            ctx: Md5::new(),
-            file_idx,
+            ctx,
+            file_idx: 0,
         };
         ret.set_buffer_size();
-        Ok(ret)
+        ret
     }
 
     /// Reset the buffer size, useful whenever the read position changes.
     pub fn set_buffer_size(&mut self) {
-        let needed_bytes = min(self.file_len - self.position, MAX_READ_SIZE as u64);
+        let needed_bytes = min(self.file_len - self.position, self.buf.capacity() as u64);
         trace!(
             "Set the buffer size to {} because we read {} of a {} byte file.",
             needed_bytes,
@@ -64,16 +59,46 @@ impl Buffer {
     }
 }
 
-/// Get all checksums and send the results through a channel.
-pub fn get_checksums(
-    files: Vec<PathBuf>,
-    tx: Sender<(PathBuf, Result<Md5>)>,
-    o_direct: bool,
-) -> Result<()> {
+/// Reads through a ring from files registered in its file table.
+#[derive(Debug)]
+pub struct RegisteredFiles;
+
+impl ChecksumStrategy for RegisteredFiles {
+    fn name(&self) -> &'static str {
+        "register-files"
+    }
+
+    fn get_checksums(
+        &self,
+        files: Vec<PathBuf>,
+        tx: &dyn ResultSink<ChecksumResult>,
+        config: &Config,
+    ) -> Result<()> {
+        get_checksums(files, tx, config, || Hasher::new(config.algorithm))
+    }
+}
+
+/// Get all checksums and send the results through a channel. Each file gets a new digest from
+/// `new_digest`, such as `Md5::new`, and is sent as an `R` made from its path and digest, such
+/// as a `ChecksumResult` or the pair itself.
+pub fn get_checksums<D, F, R>(
+    paths: Vec<PathBuf>,
+    tx: impl ResultSink<R>,
+    config: &Config,
+    new_digest: F,
+) -> Result<()>
+where
+    D: Update,
+    F: Fn() -> D,
+    R: From<(PathBuf, Result<D>)>,
+{
     // Set up shared state that's applicable to all individual reads or for choosing what to read:
-    let mut ring = IoUring::new(RING_SIZE as u32)?;
+    let ring_size = config.ring_size;
+    let mut ring = ring::new_ring(ring_size as u32, config)?;
     let mut probe = Probe::new();
-    ring.submitter().register_probe(&mut probe)?;
+    ring.submitter()
+        .register_probe(&mut probe)
+        .map_err(ChecksumError::RingSetup)?;
     if !probe.is_supported(opcode::Read::CODE) {
         bail!("Reading files is not supported. Try a newer kernel.");
     }
@@ -82,149 +107,217 @@ pub fn get_checksums(
         bail!("Registering files is not supported. Try a newer kernel.");
     }
 
-    let mut file_idx = 0;
+    // Files are slotted into the table as their reads start, one slot for each read in flight:
+    ring::register_file_slots(&ring, ring_size as u32).map_err(ChecksumError::RingSetup)?;
 
     // This is a list of buffers that needs to be indexed by the "user data" handle
     // that is submitted to the kernel with each job and later returned.
-    let mut shared_buffers: HashMap<usize, Buffer> = Default::default();
-    let mut free_index_list: Vec<_> = (0..RING_SIZE).into_iter().collect();
-    let mut raw_fds = Vec::new();
-    let mut files = files
-        .into_iter()
-        .filter_map(|path| match Buffer::new(&path, file_idx, o_direct) {
-            Ok(buffer) => {
-                file_idx += 1;
-                raw_fds.push(buffer.fd.as_raw_fd());
-                Some(buffer)
-            }
-            Err(err) => {
-                tx.send((path.to_owned(), Err(err))).unwrap();
-                None
+    let mut shared_buffers: HashMap<usize, Buffer<D>> = Default::default();
+    let result = read_all(
+        &mut ring,
+        &mut shared_buffers,
+        paths,
+        &tx,
+        config,
+        new_digest,
+    );
+    if result.is_err() {
+        // The reads still in flight point into the buffers:
+        ring::settle(&mut ring, shared_buffers);
+    }
+    result
+}
+
+/// Read `paths` until each one's result has been sent, with the files being read in
+/// `shared_buffers`.
+fn read_all<D, F, R>(
+    ring: &mut Ring,
+    shared_buffers: &mut HashMap<usize, Buffer<D>>,
+    paths: Vec<PathBuf>,
+    tx: &impl ResultSink<R>,
+    config: &Config,
+    new_digest: F,
+) -> Result<()>
+where
+    D: Update,
+    F: Fn() -> D,
+    R: From<(PathBuf, Result<D>)>,
+{
+    let ring_size = config.ring_size;
+    // Only keep as many files open as the descriptor limit allows. The rest are queued
+    // for later batches.
+    let mut paths = paths.into_iter().peekable();
+    while paths.peek().is_some() {
+        let batch = paths.by_ref().take(max_open_files());
+        let mut free_index_list: Vec<_> = (0..ring_size).collect();
+        let opened = open_batch(batch, config.o_direct, tx)?;
+        let fds: Vec<_> = opened.iter().map(|(_, fd)| fd).collect();
+        let lens = ring::file_lens(ring, &fds).map_err(ChecksumError::Submit)?;
+        let mut files = Vec::with_capacity(opened.len());
+        for ((path, fd), len) in opened.into_iter().zip(lens) {
+            match len {
+                Ok(len) => files.push(Buffer::new(path, fd, len, new_digest(), config.read_size)),
+                Err(err) => tx.send((path, Err(err.into())).into())?,
             }
-        })
+        }
         // Reverse so we can pop the first files off the end
-        .rev()
-        .collect::<Vec<_>>();
-    ring.submitter().register_files(&raw_fds)?;
-
-    loop {
-        let mut new_work_queued = false;
-
-        // Only proceed if there's both a free index and a file:
-        while let Some(free_idx) = free_index_list.pop() {
-            debug_assert!(
-                !ring.submission().is_full(),
-                "Submission queue must have a free spot if there's a free shared buffer",
-            );
-
-            if let Some(buffer) = files.pop() {
-                shared_buffers.insert(free_idx, buffer);
-                debug_assert_eq!(
-                    free_index_list.len(),
-                    RING_SIZE - shared_buffers.len(),
-                    "The free index list is out of sync with the work buffers (1)"
-                );
-                let buffer_ref = shared_buffers.get_mut(&free_idx).unwrap();
-                new_work_queued = true;
-                submit_for_read(&mut ring, buffer_ref, free_idx);
-            } else {
-                // We didn't use this buffer index
-                free_index_list.push(free_idx);
-                break;
-            }
+        files.reverse();
+        if files.is_empty() {
+            continue;
         }
 
-        if new_work_queued || files.len() > 0 {
-            if files.len() > 0 {
-                debug_assert_eq!(
-                    free_index_list.len(),
-                    0,
-                    "We should have filled all the slots"
-                );
+        loop {
+            let mut new_work_queued = false;
+
+            if config.cancel.is_cancelled() {
+                // No more files are started, and those being read fail as their reads complete:
+                let unstarted = files.drain(..).rev().map(|buffer| buffer.path);
+                cancel::send_cancelled(unstarted.chain(paths.by_ref()), tx);
+            }
+
+            // Only proceed if there's both a free index and a file:
+            while let Some(free_idx) = free_index_list.pop() {
+                if let Some(mut buffer) = files.pop() {
+                    ring::set_file_slot(ring, free_idx as u32, Some(buffer.fd.as_raw_fd()))
+                        .map_err(ChecksumError::RingSetup)?;
+                    buffer.file_idx = free_idx as u32;
+                    shared_buffers.insert(free_idx, buffer);
+                    debug_assert_eq!(
+                        free_index_list.len(),
+                        ring_size - shared_buffers.len(),
+                        "The free index list is out of sync with the work buffers (1)"
+                    );
+                    let buffer_ref = shared_buffers.get_mut(&free_idx).unwrap();
+                    new_work_queued = true;
+                    ring.advise_sequential(&buffer_ref.fd)
+                        .map_err(ChecksumError::Submit)?;
+                    submit_for_read(ring, buffer_ref, free_idx)?;
+                } else {
+                    // We didn't use this buffer index
+                    free_index_list.push(free_idx);
+                    break;
+                }
             }
 
-            // Wait for a result since the jobs list is full or we just added something
-            trace!("Waiting for / handling a result");
-            submit_wait_and_handle_result(
-                &mut ring,
-                &mut shared_buffers,
-                &tx,
-                &mut free_index_list,
-            )?;
-        } else {
-            // There's no more work that can be added right now, but we still need to handle any
-            // active buffers
-            while free_index_list.len() < RING_SIZE {
-                trace!(
-                    "Did not submit work, waiting for old work. {}/{} free indices",
-                    free_index_list.len(),
-                    RING_SIZE
-                );
+            if new_work_queued || !files.is_empty() {
+                if !files.is_empty() {
+                    debug_assert_eq!(
+                        free_index_list.len(),
+                        0,
+                        "We should have filled all the slots"
+                    );
+                }
+
+                // Wait for a result since the jobs list is full or we just added something
+                trace!("Waiting for / handling a result");
                 submit_wait_and_handle_result(
-                    &mut ring,
-                    &mut shared_buffers,
-                    &tx,
+                    ring,
+                    shared_buffers,
+                    tx,
                     &mut free_index_list,
+                    config,
                 )?;
+            } else {
+                // There's no more work that can be added right now, but we still need to handle any
+                // active buffers
+                while free_index_list.len() < ring_size {
+                    trace!(
+                        "Did not submit work, waiting for old work. {}/{} free indices",
+                        free_index_list.len(),
+                        ring_size
+                    );
+                    submit_wait_and_handle_result(
+                        ring,
+                        shared_buffers,
+                        tx,
+                        &mut free_index_list,
+                        config,
+                    )?;
+                }
+                break;
             }
-            break;
         }
     }
 
     Ok(())
 }
 
-fn submit_wait_and_handle_result(
-    ring: &mut IoUring,
-    shared_buffers: &mut HashMap<usize, Buffer>,
-    tx: &Sender<(PathBuf, Result<md5::Md5, anyhow::Error>)>,
+fn submit_wait_and_handle_result<D: Update, R: From<(PathBuf, Result<D>)>>(
+    ring: &mut Ring,
+    shared_buffers: &mut HashMap<usize, Buffer<D>>,
+    tx: &impl ResultSink<R>,
     free_index_list: &mut Vec<usize>,
+    config: &Config,
 ) -> Result<()> {
+    // The ring has an entry for each slot:
+    let ring_size = ring.params().sq_entries() as usize;
     debug_assert_eq!(
         free_index_list.len(),
-        RING_SIZE - shared_buffers.len(),
+        ring_size - shared_buffers.len(),
         "The free index list is out of sync with the work buffers (2)"
     );
 
-    ring.submit_and_wait(1)?;
-    let completed_idx = ring
-        .completion()
-        .next()
-        .expect("completion queue is empty")
-        .user_data() as usize;
+    let cqe = ring.next_completion().map_err(ChecksumError::Completion)?;
+    let completed_idx = cqe.user_data() as usize;
 
     // Next, consume and handle bytes in the buffer:
-    let mut buffer = shared_buffers
+    let buffer = shared_buffers
         .get_mut(&completed_idx)
-        .expect("should exist because we chose its index");
+        .ok_or_else(|| ChecksumError::unexpected_completion(cqe.user_data()))?;
+    // Reads ask for whole blocks, so the kernel may go past the end of a file that grew:
+    let read_len = min(usize::try_from(cqe.result()).unwrap_or(0), buffer.buf.len());
+    let error = match ring.timeout_error(cqe.result()) {
+        Some(err) => Some(err.into()),
+        // A read that fails, as on a bad sector, fails the file, and the rest are still hashed:
+        None if cqe.result() < 0 => Some(io::Error::from_raw_os_error(-cqe.result()).into()),
+        None if read_len == 0 && !buffer.buf.is_empty() => {
+            Some(ended_early(buffer.position, buffer.file_len))
+        }
+        None if config.cancel.is_cancelled() => Some(cancel::cancelled().into()),
+        None => None,
+    };
+    if let Some(err) = error {
+        let buffer = shared_buffers.remove(&completed_idx).unwrap();
+        free_index_list.push(completed_idx);
+        ring::set_file_slot(ring, buffer.file_idx, None).map_err(ChecksumError::RingSetup)?;
+        ring.close(buffer.fd);
+        tx.send((buffer.path, Err(err)).into())?;
+        return Ok(());
+    }
 
-    buffer.position += buffer.buf.len() as u64;
+    // A read can stop short, as on a network filesystem or when a signal arrives, and the next
+    // read starts where it stopped:
+    buffer.position += read_len as u64;
+    config.report_progress(&buffer.path, buffer.position, buffer.file_len);
 
     trace!(
         "Incorporating {} bytes into checksum. Finished?: {} ({:?})",
-        buffer.buf.len(),
-        buffer.position as u64 + buffer.buf.len() as u64 == buffer.file_len,
+        read_len,
+        buffer.position == buffer.file_len,
         &buffer.path,
     );
-    buffer.ctx.update(&*buffer.buf);
+    buffer.ctx.update(&buffer.buf[..read_len]);
+    progress::add_bytes(read_len as u64);
     buffer.set_buffer_size();
-    if buffer.buf.len() == 0 {
+    if buffer.buf.is_empty() {
         // It's finished, so free the slot (and get an owned object):
         let buffer = shared_buffers.remove(&completed_idx).unwrap();
         free_index_list.push(completed_idx);
         debug_assert_eq!(
             free_index_list.len(),
-            RING_SIZE - shared_buffers.len(),
+            ring_size - shared_buffers.len(),
             "The free index list is out of sync with the work buffers (3)"
         );
-        tx.send((buffer.path, Ok(buffer.ctx))).unwrap();
+        ring::set_file_slot(ring, buffer.file_idx, None).map_err(ChecksumError::RingSetup)?;
+        ring.close(buffer.fd);
+        tx.send((buffer.path, Ok(buffer.ctx)).into())?;
     } else {
         trace!("Checksum not finished, resubmitting for read");
         submit_for_read(
             ring,
             shared_buffers.get_mut(&completed_idx).unwrap(),
             completed_idx,
-        );
+        )?;
     }
 
     Ok(())
@@ -232,21 +325,24 @@ fn submit_wait_and_handle_result(
 
 /// Put a job in the read queue and submit it to the kernel. The buffer struct tracks
 /// how much has been read already and how much more is needed.
-fn submit_for_read(ring: &mut IoUring, buffer_ref: &mut Buffer, idx: usize) {
+fn submit_for_read<D>(ring: &mut Ring, buffer_ref: &mut Buffer<D>, idx: usize) -> Result<()> {
     // get data uring needs to queue a read:
     let buf = &mut buffer_ref.buf;
-    let read_e = opcode::Read::new(
-        types::Fixed(buffer_ref.file_idx),
-        buf.as_mut_ptr(),
-        buf.len() as _,
-    )
-    .offset(buffer_ref.position as i64)
-    .build()
-    .user_data(idx as u64);
+    let (ptr, len) = (buf.as_mut_ptr(), buf.len() as u32);
+    // Ask for whole blocks, so the length stays aligned for O_DIRECT at the end of the file:
+    let request_len = buf.len().next_multiple_of(ALIGNMENT) as u32;
+    let read = |rw_flags| {
+        opcode::Read::new(types::Fixed(buffer_ref.file_idx), ptr, request_len)
+            .offset(buffer_ref.position)
+            .rw_flags(rw_flags)
+            .build()
+            .user_data(idx as u64)
+    };
 
     unsafe {
-        ring.submission()
-            .push(&read_e)
-            .expect("submission queue is full");
-    }
+        ring.push_read_with(len, read)
+            .map_err(ChecksumError::Submit)?
+    };
+    progress::add_read();
+    Ok(())
 }
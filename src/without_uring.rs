@@ -1,26 +1,79 @@
 // This module uses calculates checksums without io_uring.
-use std::{path::PathBuf, sync::mpsc::Sender};
+use std::path::PathBuf;
 
 use anyhow::Result;
-use md5::{Digest, Md5};
+use md5::digest::Update;
 use memmap2::MmapOptions;
 
-use crate::open;
+#[cfg(target_os = "linux")]
+use crate::ring::fadvise;
+use crate::{
+    cancel, open, progress, ChecksumError, ChecksumResult, ChecksumStrategy, Config, Hasher,
+    ResultSink,
+};
 
-pub fn get_checksums(
+/// Maps each file into memory and hashes it, one file at a time, without io_uring.
+#[derive(Debug)]
+pub struct NoUring;
+
+impl ChecksumStrategy for NoUring {
+    fn name(&self) -> &'static str {
+        "no-uring"
+    }
+
+    fn get_checksums(
+        &self,
+        files: Vec<PathBuf>,
+        tx: &dyn ResultSink<ChecksumResult>,
+        config: &Config,
+    ) -> Result<()> {
+        get_checksums(files, tx, config, || Hasher::new(config.algorithm))
+    }
+}
+
+/// Get all checksums and send the results through a channel. Each file gets a new digest from
+/// `new_digest`, such as `Md5::new`, and is sent as an `R` made from its path and digest, such
+/// as a `ChecksumResult` or the pair itself.
+pub fn get_checksums<D, F, R>(
     files: Vec<PathBuf>,
-    tx: Sender<(PathBuf, Result<Md5>)>,
-    o_direct: bool,
-) -> Result<()> {
-    for path in files {
+    tx: impl ResultSink<R>,
+    config: &Config,
+    new_digest: F,
+) -> Result<()>
+where
+    D: Update,
+    F: Fn() -> D,
+    R: From<(PathBuf, Result<D>)>,
+{
+    let mut files = files.into_iter();
+    while let Some(path) = files.next() {
+        if config.cancel.is_cancelled() {
+            cancel::send_cancelled(std::iter::once(path).chain(files), &tx);
+            break;
+        }
         let result = (|| {
-            let file = open(&path, o_direct)?;
-            let mut md5 = Md5::new();
+            let file = open(&path, config.o_direct)
+                .map_err(|err| ChecksumError::open_failed(&path, err))?;
+            // Other systems don't take the advice, so their page cache fills as usual:
+            #[cfg(target_os = "linux")]
+            if config.no_cache_pollution {
+                fadvise(&file, libc::POSIX_FADV_SEQUENTIAL)?;
+            }
+            let mut ctx = new_digest();
             let mmap = unsafe { MmapOptions::new().map(&file)? };
-            md5.update(&mmap);
-            Ok(md5)
+            ctx.update(&mmap);
+            progress::add_bytes(mmap.len() as u64);
+            config.report_progress(&path, mmap.len() as u64, mmap.len() as u64);
+            // Pages that are still mapped can't be dropped:
+            drop(mmap);
+            #[cfg(target_os = "linux")]
+            if config.no_cache_pollution {
+                fadvise(&file, libc::POSIX_FADV_DONTNEED)?;
+            }
+            Ok(ctx)
         })();
-        tx.send((path, result))?;
+        // The digest may not be Send, so the error can't be converted as is:
+        tx.send((path, result).into())?;
     }
     Ok(())
 }
@@ -0,0 +1,115 @@
+// This module caches each file's digest in an extended attribute, like cshatag, so a routine run
+// only reads the files that changed.
+use std::{io, path::Path, time::Duration};
+
+use anyhow::Result;
+#[allow(unused_imports)]
+use log::{debug, error, info, trace, warn};
+
+use crate::{
+    cache::{CacheEntry, DigestCache, Stamp},
+    *,
+};
+
+/// The attribute the digest of `algorithm` is cached in, like `user.checksum.md5`.
+fn attribute(algorithm: HashAlgorithm) -> String {
+    format!("user.checksum.{}", algorithm.name())
+}
+
+/// The attribute's value, like `1714566600.123456789 4096 d41d8cd98f00b204e9800998ecf8427e`.
+fn to_value(entry: &CacheEntry) -> String {
+    format!(
+        "{}.{:09} {} {}",
+        entry.stamp.mtime.as_secs(),
+        entry.stamp.mtime.subsec_nanos(),
+        entry.stamp.size,
+        to_hex(&entry.digest)
+    )
+}
+
+fn from_value(value: &[u8]) -> Option<CacheEntry> {
+    let mut fields = std::str::from_utf8(value).ok()?.split_whitespace();
+    let (secs, nanos) = fields.next()?.split_once('.')?;
+    let mtime = Duration::new(secs.parse().ok()?, nanos.parse().ok()?);
+    let size = fields.next()?.parse().ok()?;
+    let digest = from_hex(fields.next()?)?;
+    Some(CacheEntry {
+        stamp: Stamp { mtime, size },
+        digest,
+    })
+}
+
+/// Digests kept in each file's `user.checksum.<algorithm>` extended attribute. Writing one
+/// doesn't change the file's modification time.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct XattrCache;
+
+impl DigestCache for XattrCache {
+    fn load(&self, path: &Path, algorithm: HashAlgorithm) -> Option<CacheEntry> {
+        let entry = from_value(&get_attribute(path, &attribute(algorithm))?);
+        // A digest of the wrong length wasn't written by us:
+        entry.filter(|entry| entry.digest.len() == algorithm.output_len())
+    }
+
+    fn store(&self, path: &Path, algorithm: HashAlgorithm, entry: &CacheEntry) -> Result<()> {
+        Ok(set_attribute(
+            path,
+            &attribute(algorithm),
+            to_value(entry).as_bytes(),
+        )?)
+    }
+}
+
+#[cfg(target_os = "linux")]
+fn get_attribute(path: &Path, name: &str) -> Option<Vec<u8>> {
+    use std::{ffi::CString, os::unix::ffi::OsStrExt};
+
+    let path = CString::new(path.as_os_str().as_bytes()).ok()?;
+    let name = CString::new(name).ok()?;
+    // Room for the longest digest we make, in hexadecimal, and the stamp:
+    let mut value = vec![0u8; 256];
+    // unsafe: the kernel writes at most `value.len()` bytes into the buffer:
+    let len = unsafe {
+        libc::getxattr(
+            path.as_ptr(),
+            name.as_ptr(),
+            value.as_mut_ptr().cast(),
+            value.len(),
+        )
+    };
+    value.truncate(usize::try_from(len).ok()?);
+    Some(value)
+}
+
+#[cfg(target_os = "linux")]
+fn set_attribute(path: &Path, name: &str, value: &[u8]) -> io::Result<()> {
+    use std::{ffi::CString, os::unix::ffi::OsStrExt};
+
+    let path = CString::new(path.as_os_str().as_bytes())?;
+    let name = CString::new(name)?;
+    // unsafe: the kernel only reads the strings and the value we pass in:
+    let result = unsafe {
+        libc::setxattr(
+            path.as_ptr(),
+            name.as_ptr(),
+            value.as_ptr().cast(),
+            value.len(),
+            0,
+        )
+    };
+    if result != 0 {
+        return Err(io::Error::last_os_error());
+    }
+    Ok(())
+}
+
+/// Only Linux attributes are used, so nothing is cached elsewhere.
+#[cfg(not(target_os = "linux"))]
+fn get_attribute(_path: &Path, _name: &str) -> Option<Vec<u8>> {
+    None
+}
+
+#[cfg(not(target_os = "linux"))]
+fn set_attribute(_path: &Path, _name: &str, _value: &[u8]) -> io::Result<()> {
+    Err(io::ErrorKind::Unsupported.into())
+}